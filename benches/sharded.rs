@@ -0,0 +1,78 @@
+// Throughput of a single-lock map against the sharded one as threads are
+// added. Run with `cargo bench --bench sharded`; SHARD_COUNT overrides the
+// shard count.
+#[path = "../sharded.rs"]
+#[allow(dead_code, unused_imports)]
+mod sharded;
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use sharded::ShardedMap;
+
+const KEYS: u64 = 100_000;
+const RUN_FOR: Duration = Duration::from_secs(2);
+// One operation in this many is a write.
+const WRITE_EVERY: u64 = 10;
+
+fn run(map: Arc<ShardedMap<String, String>>, threads: usize) -> f64 {
+    let stop = Arc::new(AtomicBool::new(false));
+    let operations = Arc::new(AtomicU64::new(0));
+    let workers: Vec<_> = (0..threads)
+        .map(|t| {
+            let (map, stop, operations) = (Arc::clone(&map), Arc::clone(&stop), Arc::clone(&operations));
+            thread::spawn(move || {
+                // A per-thread xorshift keeps key choice off any shared state.
+                let mut state = 0x9E37_79B9_7F4A_7C15u64 ^ (t as u64 + 1);
+                let mut done = 0;
+                while !stop.load(Ordering::Relaxed) {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    let key = format!("key:{}", state % KEYS);
+                    if state.is_multiple_of(WRITE_EVERY) {
+                        let value = done.to_string();
+                        map.lock(&key).insert(key, value);
+                    } else {
+                        let _ = map.lock(&key).get(&key).cloned();
+                    }
+                    done += 1;
+                }
+                operations.fetch_add(done, Ordering::Relaxed);
+            })
+        })
+        .collect();
+    let started = Instant::now();
+    thread::sleep(RUN_FOR);
+    stop.store(true, Ordering::Relaxed);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    operations.load(Ordering::Relaxed) as f64 / started.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let cores = thread::available_parallelism().map_or(1, |n| n.get());
+    let shards = sharded::default_shard_count();
+    let fill = |map: &ShardedMap<String, String>| {
+        for i in 0..KEYS {
+            let key = format!("key:{}", i);
+            map.lock(&key).insert(key, i.to_string());
+        }
+    };
+    let single = Arc::new(ShardedMap::with_shards(1));
+    let striped = Arc::new(ShardedMap::with_shards(shards));
+    fill(&single);
+    fill(&striped);
+
+    println!("{} cores, {} shards, {}% writes", cores, shards, 100 / WRITE_EVERY);
+    println!("{:>8} {:>16} {:>16} {:>8}", "threads", "1 lock ops/s", "sharded ops/s", "speedup");
+    let mut threads = 1;
+    while threads <= cores * 2 {
+        let one = run(Arc::clone(&single), threads);
+        let many = run(Arc::clone(&striped), threads);
+        println!("{:>8} {:>16.0} {:>16.0} {:>7.2}x", threads, one, many, many / one);
+        threads *= 2;
+    }
+}
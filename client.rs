@@ -1,92 +1,656 @@
-use std::collections::HashMap;
+// The client only uses the cluster client side of kv_store.
+#[allow(dead_code)]
+mod kv_store;
+
+use std::collections::{HashMap, VecDeque};
 use std::env;
-use std::io::{self, Write, Read};
-use std::net::TcpStream;
-use serde::{Deserialize, Serialize};
-use serde_json;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process;
+use std::time::{Duration, Instant};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper};
+use serde_json::{self, json};
+use kv_store::cluster_client::{ClusterClient, Delivery};
+use kv_store::watch::WatchTarget;
+
+// Exit codes for the non-interactive subcommands.
+const EXIT_OK: i32 = 0;
+const EXIT_MISS: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+const USAGE: &str = "usage: kv [--output json|raw|table] <command>
+  get KEY
+  set KEY VALUE [--ttl SECONDS]
+  del KEY
+  scan --prefix PREFIX
+  exec FILE [--continue-on-error]
+  watch KEY|--prefix PREFIX [--from NODE=SEQ,...]
+  publish CHANNEL MESSAGE
+  subscribe CHANNEL...
+  psubscribe PATTERN...
+  cdc subscribe NODE FROM_SEQ|oldest";
+
+const REPL_HELP: &str = "Commands (values with spaces go in quotes, \\xHH writes an ASCII character):
+  get KEY
+  set KEY VALUE [--ttl SECONDS]
+  del KEY
+  scan --prefix PREFIX
+  exec FILE [--continue-on-error]
+  publish CHANNEL MESSAGE
+  .connect ADDR    switch to another cluster
+  .timing on|off   show how long each command took
+  .help            show this help
+  .quit            leave the client";
+
+const COMMAND_NAMES: &[&str] = &["get", "set", "del", "scan", "exec", "publish", ".connect", ".timing", ".help", ".quit"];
+const RECENT_KEYS: usize = 200;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct KeyValueRequest {
-    action: String,
-    key: String,
-    value: Option<String>,
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum OutputFormat {
+    Json,
+    Raw,
+    Table,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct KeyValueResponse {
-    result: String,
+#[derive(Debug)]
+enum CliCommand {
+    Get { key: String },
+    Set { key: String, value: String, ttl: Option<u64> },
+    Delete { key: String },
+    Scan { prefix: String },
+    Exec { file: String, continue_on_error: bool },
+    Watch { target: WatchTarget, resume: HashMap<String, u64> },
+    Publish { channel: String, message: String },
+    Subscribe { channels: Vec<String>, patterns: Vec<String> },
+    CdcSubscribe { node: String, from_seq: Option<u64> },
 }
 
-fn main() -> io::Result<()> {
-    dotenv::dotenv().expect("Failed to load .env file");
-  
-    let server_address = env::var("KV_STORE_SERVER_ADDRESS")
-        .unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+impl CliCommand {
+    // Commands that run until interrupted rather than finishing.
+    fn streams(&self) -> bool {
+        matches!(self, CliCommand::Watch { .. } | CliCommand::Subscribe { .. } | CliCommand::CdcSubscribe { .. })
+    }
+}
 
-    let mut tcp_connection = TcpStream::connect(server_address)
-        .expect("Failed to connect to server");
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    Success,
+    Miss,
+    Failed,
+}
 
-    loop {
-        println!("Enter command [GET, SET, DELETE] followed by key and optionally value for SET:");
-        let mut user_input = String::new();
-        if io::stdin().read_line(&mut user_input).is_err() {
-            eprintln!("Error reading input. Please try again.");
-            continue;
+impl Outcome {
+    fn exit_code(&self) -> i32 {
+        match self {
+            Outcome::Success => EXIT_OK,
+            Outcome::Miss => EXIT_MISS,
+            Outcome::Failed => EXIT_ERROR,
         }
+    }
+}
 
-        let input_tokens: Vec<&str> = user_input.trim().split_whitespace().collect();
-        if input_tokens.len() < 2 {
-            eprintln!("Invalid command format. Please specify at least a command and a key.");
-            continue;
+fn parse_output(args: &mut Vec<String>) -> Result<OutputFormat, String> {
+    let position = match args.iter().position(|a| a == "--output") {
+        Some(position) => position,
+        None => return Ok(OutputFormat::Raw),
+    };
+    if position + 1 >= args.len() {
+        return Err("--output needs a value".to_string());
+    }
+    let format = match args[position + 1].as_str() {
+        "json" => OutputFormat::Json,
+        "raw" => OutputFormat::Raw,
+        "table" => OutputFormat::Table,
+        other => return Err(format!("Unknown output format: {}", other)),
+    };
+    args.drain(position..position + 2);
+    Ok(format)
+}
+
+// Splits a command line into arguments. Single or double quotes group words,
+// and backslash escapes (\n, \t, \\, \", \', \xHH) work inside and outside quotes.
+// Values are UTF-8 text end to end, so \xHH only covers ASCII, 00 to 7f.
+fn tokenize(line: &str) -> Result<Vec<String>, String> {
+    let mut tokens = Vec::new();
+    let mut current = String::new();
+    let mut in_token = false;
+    let mut quote: Option<char> = None;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                in_token = true;
+                match chars.next() {
+                    Some('n') => current.push('\n'),
+                    Some('t') => current.push('\t'),
+                    Some('r') => current.push('\r'),
+                    Some('x') => {
+                        let hex: String = (0..2).filter_map(|_| chars.next()).collect();
+                        match u8::from_str_radix(&hex, 16) {
+                            Ok(byte) if byte.is_ascii() => current.push(byte as char),
+                            Ok(_) => return Err(format!("Invalid escape \\x{}, values are UTF-8 text so \\x only covers 00 to 7f", hex)),
+                            Err(_) => return Err(format!("Invalid escape \\x{}", hex)),
+                        }
+                    }
+                    Some(other) => current.push(other),
+                    None => return Err("Line ends with a lone backslash".to_string()),
+                }
+            }
+            '"' | '\'' if quote.is_none() => {
+                quote = Some(c);
+                in_token = true;
+            }
+            c if Some(c) == quote => quote = None,
+            c if c.is_whitespace() && quote.is_none() => {
+                if in_token {
+                    tokens.push(std::mem::take(&mut current));
+                    in_token = false;
+                }
+            }
+            c => {
+                in_token = true;
+                current.push(c);
+            }
         }
+    }
+    if quote.is_some() {
+        return Err("Unterminated quote".to_string());
+    }
+    if in_token {
+        tokens.push(current);
+    }
+    Ok(tokens)
+}
 
-        let kv_request = match input_tokens[0].to_uppercase().as_str() {
-            "GET" | "DELETE" if input_tokens.len() >= 2 => KeyValueRequest {
-                action: input_tokens[0].to_uppercase(),
-                key: input_tokens[1].to_string(),
-                value: None,
-            },
-            "SET" if input_tokens.len() >= 3 => KeyValueRequest {
-                action: "SET".to_string(),
-                key: input_tokens[1].to_string(),
-                value: input_tokens.get(2).map(|v| v.to_string()),
-            },
-            _ => {
-                println!("Unknown command or incorrect number of arguments.");
-                continue;
+fn parse_command(args: &[String]) -> Result<CliCommand, String> {
+    let args: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    match args.as_slice() {
+        ["get", key] => Ok(CliCommand::Get { key: key.to_string() }),
+        ["set", key, value] => Ok(CliCommand::Set { key: key.to_string(), value: value.to_string(), ttl: None }),
+        ["set", key, value, "--ttl", ttl] => Ok(CliCommand::Set {
+            key: key.to_string(),
+            value: value.to_string(),
+            ttl: Some(match ttl.parse() {
+                Ok(0) | Err(_) => return Err(format!("Invalid TTL: {}, expected a whole number of seconds above 0", ttl)),
+                Ok(seconds) => seconds,
+            }),
+        }),
+        ["del", key] => Ok(CliCommand::Delete { key: key.to_string() }),
+        ["scan", "--prefix", prefix] => Ok(CliCommand::Scan { prefix: prefix.to_string() }),
+        ["exec", file] => Ok(CliCommand::Exec { file: file.to_string(), continue_on_error: false }),
+        ["exec", file, "--continue-on-error"] => Ok(CliCommand::Exec { file: file.to_string(), continue_on_error: true }),
+        ["watch", rest @ ..] => parse_watch(rest),
+        ["publish", channel, message] => Ok(CliCommand::Publish { channel: channel.to_string(), message: message.to_string() }),
+        ["subscribe", channels @ ..] if !channels.is_empty() => Ok(CliCommand::Subscribe {
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            patterns: Vec::new(),
+        }),
+        ["cdc", "subscribe", node, "oldest"] => Ok(CliCommand::CdcSubscribe { node: node.to_string(), from_seq: None }),
+        ["cdc", "subscribe", node, from_seq] => Ok(CliCommand::CdcSubscribe {
+            node: node.to_string(),
+            from_seq: Some(from_seq.parse().map_err(|_| format!("Invalid sequence number: {}", from_seq))?),
+        }),
+        ["psubscribe", patterns @ ..] if !patterns.is_empty() => Ok(CliCommand::Subscribe {
+            channels: Vec::new(),
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+        }),
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+fn parse_watch(args: &[&str]) -> Result<CliCommand, String> {
+    let (target, rest) = match args {
+        ["--prefix", prefix, rest @ ..] => (WatchTarget::Prefix(prefix.to_string()), rest),
+        [key, rest @ ..] if !key.starts_with("--") => (WatchTarget::Key(key.to_string()), rest),
+        _ => return Err(USAGE.to_string()),
+    };
+    let mut resume = HashMap::new();
+    match rest {
+        [] => {}
+        ["--from", positions] => {
+            for position in positions.split(',') {
+                let (node, seq) = position.rsplit_once('=').ok_or_else(|| format!("Invalid position: {}", position))?;
+                let seq = seq.parse().map_err(|_| format!("Invalid sequence number: {}", seq))?;
+                resume.insert(node.to_string(), seq);
             }
-        };
+        }
+        _ => return Err(USAGE.to_string()),
+    }
+    Ok(CliCommand::Watch { target, resume })
+}
+
+// Raw output leaves out the key for a single get, where the caller already
+// knows it, and prefixes each value with its key and a tab for a scan.
+fn print_entries(entries: &[(String, Vec<String>)], output: OutputFormat, with_keys: bool) {
+    match output {
+        OutputFormat::Raw => {
+            for (key, values) in entries {
+                for value in values {
+                    if with_keys {
+                        println!("{}\t{}", key, value);
+                    } else {
+                        println!("{}", value);
+                    }
+                }
+            }
+        }
+        OutputFormat::Json => {
+            for (key, values) in entries {
+                println!("{}", json!({ "key": key, "values": values }));
+            }
+        }
+        OutputFormat::Table => {
+            let width = entries.iter().map(|(key, _)| key.len()).max().unwrap_or(0).max("KEY".len());
+            println!("{:width$}  VALUE", "KEY", width = width);
+            for (key, values) in entries {
+                for value in values {
+                    println!("{:width$}  {}", key, value, width = width);
+                }
+            }
+        }
+    }
+}
 
-        let serialized_kv_request = match serde_json::to_string(&kv_request) {
-            Ok(s) => s,
+fn print_status(key: &str, status: &str, output: OutputFormat) {
+    match output {
+        OutputFormat::Json => println!("{}", json!({ "key": key, "status": status })),
+        _ => println!("{}", status),
+    }
+}
+
+fn run_command(client: &mut ClusterClient, command: &CliCommand, output: OutputFormat) -> Outcome {
+    let result = match command {
+        CliCommand::Get { key } => client.get(key).map(|siblings| match siblings {
+            Some(siblings) => {
+                print_entries(&[(key.clone(), siblings.values)], output, false);
+                Outcome::Success
+            }
+            None => {
+                if output == OutputFormat::Json {
+                    println!("{}", json!({ "key": key, "values": [] }));
+                }
+                Outcome::Miss
+            }
+        }),
+        CliCommand::Set { key, value, ttl } => client
+            .put_with_ttl(key, value, None, ttl.map(Duration::from_secs))
+            .map(|_| {
+                print_status(key, "OK", output);
+                Outcome::Success
+            }),
+        CliCommand::Delete { key } => client.delete(key, None).map(|_| {
+            print_status(key, "OK", output);
+            Outcome::Success
+        }),
+        CliCommand::Scan { prefix } => client.scan(prefix).map(|entries| {
+            print_entries(&entries, output, true);
+            if entries.is_empty() {
+                Outcome::Miss
+            } else {
+                Outcome::Success
+            }
+        }),
+        CliCommand::Exec { file, continue_on_error } => return run_script(client, file, *continue_on_error, output),
+        CliCommand::Watch { target, resume } => run_watch(client, target, resume, output),
+        CliCommand::Publish { channel, message } => client.publish(channel, message).map(|receivers| {
+            match output {
+                OutputFormat::Json => println!("{}", json!({ "channel": channel, "receivers": receivers })),
+                _ => println!("{}", receivers),
+            }
+            Outcome::Success
+        }),
+        CliCommand::Subscribe { channels, patterns } => run_subscribe(client, channels, patterns, output),
+        CliCommand::CdcSubscribe { node, from_seq } => run_cdc(client, node, *from_seq),
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("Error: {}", e);
+        Outcome::Failed
+    })
+}
+
+// Prints changes until interrupted. Each line names the node and sequence
+// number it came from, to pass to --from when restarting.
+fn run_watch(
+    client: &mut ClusterClient,
+    target: &WatchTarget,
+    resume: &HashMap<String, u64>,
+    output: OutputFormat,
+) -> io::Result<Outcome> {
+    let mut watch = client.watch(target.clone(), resume)?;
+    while let Some(event) = watch.next() {
+        let event = event?;
+        let positions: Vec<String> = watch.positions().iter().map(|(node, seq)| format!("{}={}", node, seq)).collect();
+        match output {
+            OutputFormat::Json => println!(
+                "{}",
+                json!({ "key": event.key, "kind": format!("{:?}", event.kind), "values": event.values, "context": event.context, "from": positions.join(",") })
+            ),
+            _ => println!("{:?} {} {} (from {})", event.kind, event.key, event.values.join(" "), positions.join(",")),
+        }
+    }
+    Ok(Outcome::Success)
+}
+
+fn run_subscribe(client: &mut ClusterClient, channels: &[String], patterns: &[String], output: OutputFormat) -> io::Result<Outcome> {
+    let channels: Vec<&str> = channels.iter().map(|c| c.as_str()).collect();
+    let patterns: Vec<&str> = patterns.iter().map(|p| p.as_str()).collect();
+    let subscription = client.subscribe(&channels, &patterns)?;
+    for delivery in subscription {
+        match (delivery?, output) {
+            (Delivery::Message { channel, pattern, message }, OutputFormat::Json) => {
+                println!("{}", json!({ "channel": channel, "pattern": pattern, "message": message }))
+            }
+            (Delivery::Message { channel, message, .. }, _) => println!("{} {}", channel, message),
+            (Delivery::Dropped(count), OutputFormat::Json) => println!("{}", json!({ "dropped": count })),
+            (Delivery::Dropped(count), _) => eprintln!("Warning: {} messages were dropped, reading too slowly", count),
+        }
+    }
+    Ok(Outcome::Success)
+}
+
+// Prints one JSON line per log entry whatever the output format, since
+// entries carry whole versions.
+fn run_cdc(client: &mut ClusterClient, node: &str, from_seq: Option<u64>) -> io::Result<Outcome> {
+    let entries = client.cdc_subscribe(node, from_seq)?;
+    eprintln!("Reading {} (log holds {} to {})", node, entries.first_seq, entries.last_seq);
+    for entry in entries {
+        println!("{}", serde_json::to_string(&entry?)?);
+    }
+    Ok(Outcome::Success)
+}
+
+// Runs one command per line; blank lines and lines starting with '#' are
+// skipped. The script fails if any command failed, misses do not count.
+fn run_script(client: &mut ClusterClient, file: &str, continue_on_error: bool, output: OutputFormat) -> Outcome {
+    let script = match fs::read_to_string(file) {
+        Ok(script) => script,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", file, e);
+            return Outcome::Failed;
+        }
+    };
+    let mut outcome = Outcome::Success;
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let result = match tokenize(line).and_then(|args| parse_command(&args)) {
+            Ok(CliCommand::Exec { .. }) => {
+                eprintln!("{}:{}: exec cannot be nested", file, number + 1);
+                Outcome::Failed
+            }
+            Ok(command) if command.streams() => {
+                eprintln!("{}:{}: {} never finishes, run it on its own", file, number + 1, line.split_whitespace().next().unwrap_or(line));
+                Outcome::Failed
+            }
+            Ok(command) => run_command(client, &command, output),
             Err(e) => {
-                eprintln!("Failed to serialize request: {}", e);
-                continue;
+                eprintln!("{}:{}: invalid command: {}", file, number + 1, e.lines().next().unwrap_or(line));
+                Outcome::Failed
             }
         };
+        if result == Outcome::Failed {
+            outcome = Outcome::Failed;
+            if !continue_on_error {
+                break;
+            }
+        }
+    }
+    outcome
+}
+
+fn run_cli(server_address: &str, mut args: Vec<String>) -> i32 {
+    let output = match parse_output(&mut args) {
+        Ok(output) => output,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_ERROR;
+        }
+    };
+    let command = match parse_command(&args) {
+        Ok(command) => command,
+        Err(e) => {
+            eprintln!("{}", e);
+            return EXIT_ERROR;
+        }
+    };
+    let mut client = match connect(server_address) {
+        Ok(client) => client,
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {}", server_address, e);
+            return EXIT_ERROR;
+        }
+    };
+    run_command(&mut client, &command, output).exit_code()
+}
+
+fn connect(server_address: &str) -> io::Result<ClusterClient> {
+    let seeds: Vec<&str> = server_address.split(',').map(|s| s.trim()).collect();
+    let mut client = ClusterClient::connect(&seeds, Duration::from_secs(5))?;
+    if let Some(capacity) = env::var("KV_CLIENT_CACHE_SIZE").ok().and_then(|v| v.parse().ok()) {
+        client.enable_cache(capacity);
+    }
+    Ok(client)
+}
 
-        if let Err(e) = tcp_connection.write_all(serialized_kv_request.as_bytes()) {
-            eprintln!("Failed to send data to server: {}", e);
+// Completes command names at the start of the line and recently used keys
+// after them.
+struct ReplHelper {
+    recent_keys: VecDeque<String>,
+}
+
+impl ReplHelper {
+    fn remember(&mut self, key: &str) {
+        self.recent_keys.retain(|k| k != key);
+        self.recent_keys.push_front(key.to_string());
+        self.recent_keys.truncate(RECENT_KEYS);
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> rustyline::Result<(usize, Vec<String>)> {
+        let before = &line[..pos];
+        let start = before.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let word = &before[start..];
+        let candidates = if before[..start].trim().is_empty() {
+            COMMAND_NAMES.iter().filter(|c| c.starts_with(&word.to_lowercase())).map(|c| c.to_string()).collect()
+        } else {
+            self.recent_keys.iter().filter(|k| k.starts_with(word)).cloned().collect()
+        };
+        Ok((start, candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+fn history_path() -> PathBuf {
+    match env::var("KV_HISTORY_FILE") {
+        Ok(path) => PathBuf::from(path),
+        Err(_) => env::var("HOME").map(PathBuf::from).unwrap_or_default().join(".kv_history"),
+    }
+}
+
+fn run_repl(mut server_address: String) -> rustyline::Result<()> {
+    let mut editor: Editor<ReplHelper, DefaultHistory> = Editor::new()?;
+    editor.set_helper(Some(ReplHelper { recent_keys: VecDeque::new() }));
+    let history = history_path();
+    // A missing history file just means this is the first session.
+    let _ = editor.load_history(&history);
+
+    let mut client = match connect(&server_address) {
+        Ok(client) => Some(client),
+        Err(e) => {
+            eprintln!("Failed to connect to {}: {} (use .connect ADDR)", server_address, e);
+            None
+        }
+    };
+    let mut timing = false;
+    println!("Connected to {}. Type .help for commands.", server_address);
+
+    loop {
+        let line = match editor.readline("kv> ") {
+            Ok(line) => line,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => return Err(e),
+        };
+        let line = line.trim();
+        if line.is_empty() {
             continue;
         }
+        editor.add_history_entry(line)?;
 
-        let mut response_buffer = Vec::new();
-        match tcp_connection.read_to_end(&mut response_buffer) {
-            Ok(_) => (),
+        let mut args = match tokenize(line) {
+            Ok(args) => args,
             Err(e) => {
-                eprintln!("Failed to read response from server: {}", e);
+                eprintln!("{}", e);
                 continue;
             }
         };
+        args[0] = args[0].to_lowercase();
+        match args[0].as_str() {
+            ".quit" | ".exit" => break,
+            ".help" => {
+                println!("{}", REPL_HELP);
+                continue;
+            }
+            ".timing" => {
+                match args.get(1).map(|a| a.as_str()) {
+                    Some("on") => timing = true,
+                    Some("off") => timing = false,
+                    _ => eprintln!("usage: .timing on|off"),
+                }
+                continue;
+            }
+            ".connect" => {
+                match args.get(1) {
+                    Some(address) => match connect(address) {
+                        Ok(new_client) => {
+                            client = Some(new_client);
+                            server_address = address.clone();
+                            println!("Connected to {}", server_address);
+                        }
+                        Err(e) => eprintln!("Failed to connect to {}: {}", address, e),
+                    },
+                    None => eprintln!("usage: .connect ADDR"),
+                }
+                continue;
+            }
+            "delete" => args[0] = "del".to_string(),
+            _ => {}
+        }
 
-        let server_response = match String::from_utf8(response_buffer) {
-            Ok(s) => s,
+        let command = match parse_command(&args) {
+            Ok(command) => command,
             Err(e) => {
-                eprintln!("Failed to decode server response: {}", e);
+                eprintln!("{}", e);
                 continue;
             }
         };
+        if command.streams() {
+            eprintln!("{} runs until interrupted, use it as a subcommand: kv {} ...", args[0], args[0]);
+            continue;
+        }
+        let client = match client.as_mut() {
+            Some(client) => client,
+            None => {
+                eprintln!("Not connected, use .connect ADDR");
+                continue;
+            }
+        };
+        if let CliCommand::Get { key } | CliCommand::Set { key, .. } | CliCommand::Delete { key } = &command {
+            if let Some(helper) = editor.helper_mut() {
+                helper.remember(key);
+            }
+        }
+        let started = Instant::now();
+        if run_command(client, &command, OutputFormat::Raw) == Outcome::Miss {
+            println!("(nil)");
+        }
+        if timing {
+            println!("({:.3} ms)", started.elapsed().as_secs_f64() * 1000.0);
+        }
+    }
+
+    editor.save_history(&history)
+}
+
+fn main() -> io::Result<()> {
+    dotenv::dotenv().ok();
+  
+    let server_address = env::var("KV_STORE_SERVER_ADDRESS")
+        .unwrap_or_else(|_| "127.0.0.1:8080".to_string());
+
+    // With arguments the client runs a single command and exits, for scripts.
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        process::exit(run_cli(&server_address, args));
+    }
+
+    if let Err(e) = run_repl(server_address) {
+        eprintln!("REPL error: {}", e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        tokenize(line).unwrap()
+    }
 
-        println!("Response: {}", server_response);
+    #[test]
+    fn tokenize_groups_quotes_and_unescapes() {
+        assert_eq!(args(r#"set k "a b" 'c d'"#), vec!["set", "k", "a b", "c d"]);
+        assert_eq!(args(r"set k a\ b\n\x41"), vec!["set", "k", "a b\nA"]);
+        assert_eq!(args(r#"set k """#), vec!["set", "k", ""]);
+        assert!(tokenize("set k \"open").is_err());
+        assert!(tokenize("set k a\\").is_err());
+        assert_eq!(args("set k é"), vec!["set", "k", "é"]);
+        assert!(tokenize(r"set k \xzz").is_err());
+        assert!(tokenize(r"set k \xff").is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn set_takes_a_positive_ttl() {
+        match parse_command(&args("set k v --ttl 30")).unwrap() {
+            CliCommand::Set { key, value, ttl } => assert_eq!((key.as_str(), value.as_str(), ttl), ("k", "v", Some(30))),
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_command(&args("set k v --ttl 0")).is_err());
+        assert!(parse_command(&args("set k v --ttl -1")).is_err());
+        assert!(parse_command(&args("set k")).is_err());
+    }
+
+    #[test]
+    fn watch_parses_resume_positions() {
+        match parse_command(&args("watch --prefix user: --from a=3,b=7")).unwrap() {
+            CliCommand::Watch { resume, .. } => {
+                assert_eq!(resume.get("a"), Some(&3));
+                assert_eq!(resume.get("b"), Some(&7));
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(parse_command(&args("watch --from a=1")).is_err());
+    }
+}
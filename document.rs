@@ -0,0 +1,302 @@
+use serde_json::{Number, Value};
+use std::fmt;
+use crate::value::document_size;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Field(String),
+    // Negative indexes count back from the end of the array.
+    Index(i64),
+}
+
+// A single-location path: `$`, then any mix of `.field`, `['field']` and
+// `[index]`, as in `$.users[0]['first-name']`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Path {
+    segments: Vec<Segment>,
+}
+
+impl Path {
+    pub fn parse(path: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid path '{}'", path);
+        let mut rest = path.strip_prefix('$').ok_or_else(invalid)?;
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after) = rest.strip_prefix('.') {
+                let end = after.find(['.', '[']).unwrap_or(after.len());
+                if end == 0 {
+                    return Err(invalid());
+                }
+                segments.push(Segment::Field(after[..end].to_string()));
+                rest = &after[end..];
+            } else if let Some(after) = rest.strip_prefix('[') {
+                let end = after.find(']').ok_or_else(invalid)?;
+                let inner = &after[..end];
+                let quoted = inner.len() >= 2
+                    && ((inner.starts_with('\'') && inner.ends_with('\'')) || (inner.starts_with('"') && inner.ends_with('"')));
+                if quoted {
+                    segments.push(Segment::Field(inner[1..inner.len() - 1].to_string()));
+                } else {
+                    segments.push(Segment::Index(inner.parse().map_err(|_| invalid())?));
+                }
+                rest = &after[end + 1..];
+            } else {
+                return Err(invalid());
+            }
+        }
+        Ok(Path { segments })
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+}
+
+// Writes the path back in a form `parse` reads, so it can be saved.
+impl fmt::Display for Path {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "$")?;
+        for segment in &self.segments {
+            match segment {
+                Segment::Field(name) if name.contains(['.', '[']) => write!(f, "['{}']", name)?,
+                Segment::Field(name) => write!(f, ".{}", name)?,
+                Segment::Index(index) => write!(f, "[{}]", index)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn resolve_index(array: &[Value], index: i64) -> Option<usize> {
+    let len = array.len() as i64;
+    let index = if index < 0 { len + index } else { index };
+    if index >= 0 && index < len {
+        Some(index as usize)
+    } else {
+        None
+    }
+}
+
+fn child<'a>(value: &'a Value, segment: &Segment) -> Option<&'a Value> {
+    match (value, segment) {
+        (Value::Object(fields), Segment::Field(name)) => fields.get(name),
+        (Value::Array(items), Segment::Index(index)) => resolve_index(items, *index).map(|i| &items[i]),
+        _ => None,
+    }
+}
+
+fn child_mut<'a>(value: &'a mut Value, segment: &Segment) -> Option<&'a mut Value> {
+    match (value, segment) {
+        (Value::Object(fields), Segment::Field(name)) => fields.get_mut(name),
+        (Value::Array(items), Segment::Index(index)) => resolve_index(items, *index).map(move |i| &mut items[i]),
+        _ => None,
+    }
+}
+
+pub fn get<'a>(document: &'a Value, path: &Path) -> Option<&'a Value> {
+    path.segments.iter().try_fold(document, child)
+}
+
+pub fn get_mut<'a>(document: &'a mut Value, path: &Path) -> Option<&'a mut Value> {
+    path.segments.iter().try_fold(document, child_mut)
+}
+
+// Replaces the value at `path`, or adds it as a new field of an existing
+// object. Returns false if the parent does not exist or cannot hold it.
+pub fn set(document: &mut Value, path: &Path, value: Value) -> bool {
+    let (last, parent) = match path.segments.split_last() {
+        Some(split) => split,
+        None => {
+            *document = value;
+            return true;
+        }
+    };
+    let parent = match parent.iter().try_fold(document, child_mut) {
+        Some(parent) => parent,
+        None => return false,
+    };
+    match (parent, last) {
+        (Value::Object(fields), Segment::Field(name)) => {
+            fields.insert(name.clone(), value);
+            true
+        }
+        (Value::Array(items), Segment::Index(index)) => match resolve_index(items, *index) {
+            Some(i) => {
+                items[i] = value;
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+// The bytes `set` would add to the document by writing a value of `added`
+// bytes, negative if it would free some, or None if it would fail. Only the
+// value it replaces is measured.
+pub fn set_growth(document: &Value, path: &Path, added: usize) -> Option<isize> {
+    let added = added as isize;
+    let (last, parent) = match path.segments.split_last() {
+        Some(split) => split,
+        None => return Some(added - document_size(document) as isize),
+    };
+    match (parent.iter().try_fold(document, child)?, last) {
+        (Value::Object(fields), Segment::Field(name)) => Some(match fields.get(name) {
+            Some(old) => added - document_size(old) as isize,
+            None => (name.len() as isize) + added,
+        }),
+        (Value::Array(items), Segment::Index(index)) => {
+            resolve_index(items, *index).map(|i| added - document_size(&items[i]) as isize)
+        }
+        _ => None,
+    }
+}
+
+// The bytes `delete` would free, as a negative number.
+pub fn delete_growth(document: &Value, path: &Path) -> isize {
+    match (path.segments.last(), get(document, path)) {
+        (Some(Segment::Field(name)), Some(old)) => -((name.len() + document_size(old)) as isize),
+        (Some(Segment::Index(_)), Some(old)) => -(document_size(old) as isize),
+        _ => 0,
+    }
+}
+
+// Removes the value at `path`, which must not be the root. Returns whether
+// there was one.
+pub fn delete(document: &mut Value, path: &Path) -> bool {
+    let (last, parent) = match path.segments.split_last() {
+        Some(split) => split,
+        None => return false,
+    };
+    match (parent.iter().try_fold(document, child_mut), last) {
+        (Some(Value::Object(fields)), Segment::Field(name)) => fields.remove(name).is_some(),
+        (Some(Value::Array(items)), Segment::Index(index)) => match resolve_index(items, *index) {
+            Some(i) => {
+                items.remove(i);
+                true
+            }
+            None => false,
+        },
+        _ => false,
+    }
+}
+
+// Appends to the array at `path` and returns its new length.
+pub fn array_append(document: &mut Value, path: &Path, values: Vec<Value>) -> Result<usize, &'static str> {
+    match get_mut(document, path) {
+        Some(Value::Array(items)) => {
+            items.extend(values);
+            Ok(items.len())
+        }
+        Some(_) => Err("the path does not hold an array"),
+        None => Err("the path does not exist"),
+    }
+}
+
+// Adds `delta` to the number at `path`, keeping it an integer while both
+// sides are and the sum fits.
+pub fn increment(document: &mut Value, path: &Path, delta: &Number) -> Result<Number, &'static str> {
+    let current = match get_mut(document, path) {
+        Some(Value::Number(current)) => current,
+        Some(_) => return Err("the path does not hold a number"),
+        None => return Err("the path does not exist"),
+    };
+    let sum = match (current.as_i64(), delta.as_i64()) {
+        (Some(a), Some(b)) if a.checked_add(b).is_some() => Number::from(a + b),
+        _ => {
+            let sum = current.as_f64().unwrap_or(0.0) + delta.as_f64().unwrap_or(0.0);
+            Number::from_f64(sum).ok_or("increment would produce NaN or Infinity")?
+        }
+    };
+    *current = sum.clone();
+    Ok(sum)
+}
+
+// Parses whitespace-separated JSON values, such as the tail of a request.
+pub fn parse_values(text: &str) -> Result<Vec<Value>, String> {
+    serde_json::Deserializer::from_str(text)
+        .into_iter::<Value>()
+        .collect::<Result<Vec<Value>, _>>()
+        .map_err(|e| format!("invalid JSON: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn path(path: &str) -> Path {
+        Path::parse(path).unwrap()
+    }
+
+    #[test]
+    fn paths_parse_fields_quoted_fields_and_indexes() {
+        let parsed = path("$.users[0]['first-name'][-1]");
+        assert_eq!(
+            parsed.segments,
+            vec![
+                Segment::Field("users".to_string()),
+                Segment::Index(0),
+                Segment::Field("first-name".to_string()),
+                Segment::Index(-1),
+            ]
+        );
+        assert!(path("$").is_root());
+        for text in ["$", "$.a[0]['b.c'][-2].d"].iter() {
+            assert_eq!(path(text).to_string(), *text);
+        }
+        assert_eq!(path("$['plain']").to_string(), "$.plain");
+        for invalid in ["users", "$.", "$..a", "$[x]", "$[0"].iter() {
+            assert!(Path::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn set_get_and_delete_follow_the_path() {
+        let mut document = json!({"users": [{"name": "ada"}]});
+        assert!(set(&mut document, &path("$.users[-1].age"), json!(36)));
+        assert!(!set(&mut document, &path("$.users[5].age"), json!(1)));
+        assert!(!set(&mut document, &path("$.missing.age"), json!(1)));
+        assert_eq!(get(&document, &path("$.users[0].age")), Some(&json!(36)));
+        assert!(delete(&mut document, &path("$.users[0].name")));
+        assert!(!delete(&mut document, &path("$.users[0].name")));
+        assert_eq!(document, json!({"users": [{"age": 36}]}));
+        assert_eq!(array_append(&mut document, &path("$.users"), vec![json!(1)]), Ok(2));
+        assert!(array_append(&mut document, &path("$.users[0]"), vec![json!(1)]).is_err());
+    }
+
+    #[test]
+    fn growth_matches_the_change_in_document_size() {
+        let cases = [("$.name", json!("a much longer name")), ("$.new", json!([1, 2])), ("$.tags[1]", json!({"a": 1})), ("$", json!(null))];
+        for (at, value) in cases.iter() {
+            let mut document = json!({"name": "ada", "tags": ["x", "y"]});
+            let before = document_size(&document) as isize;
+            let growth = set_growth(&document, &path(at), document_size(value)).unwrap();
+            assert!(set(&mut document, &path(at), value.clone()));
+            assert_eq!(before + growth, document_size(&document) as isize, "{}", at);
+        }
+        for at in ["$.name", "$.tags[0]", "$.missing"].iter() {
+            let mut document = json!({"name": "ada", "tags": ["x", "y"]});
+            let before = document_size(&document) as isize;
+            let growth = delete_growth(&document, &path(at));
+            delete(&mut document, &path(at));
+            assert_eq!(before + growth, document_size(&document) as isize, "{}", at);
+        }
+        assert_eq!(set_growth(&json!({}), &path("$.a.b"), 1), None);
+    }
+
+    #[test]
+    fn increments_stay_integers_while_they_fit() {
+        let mut document = json!({"n": 1, "f": 1.5});
+        assert_eq!(increment(&mut document, &path("$.n"), &Number::from(2)), Ok(Number::from(3)));
+        assert_eq!(increment(&mut document, &path("$.f"), &Number::from(1)).unwrap().as_f64(), Some(2.5));
+        assert!(increment(&mut document, &path("$.n"), &Number::from(i64::MAX)).unwrap().is_f64());
+        assert!(increment(&mut document, &path("$"), &Number::from(1)).is_err());
+    }
+
+    #[test]
+    fn values_keep_their_whitespace() {
+        assert_eq!(parse_values("\"x  y\"  [1, 2]").unwrap(), vec![json!("x  y"), json!([1, 2])]);
+        assert!(parse_values("{").is_err());
+    }
+}
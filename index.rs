@@ -0,0 +1,244 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
+use crate::document::{self, Path};
+use crate::value::{ScoreBound, Value, ELEMENT_OVERHEAD};
+
+#[derive(Debug, Clone)]
+pub struct IndexDefinition {
+    pub name: String,
+    pub prefix: String,
+    pub path: Path,
+}
+
+// An indexed field value. Numbers sort before strings, so a numeric range
+// never picks up text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum IndexKey {
+    Number(f64),
+    Text(String),
+}
+
+impl IndexKey {
+    fn of(field: &serde_json::Value) -> Option<Self> {
+        match field {
+            serde_json::Value::Number(n) => n.as_f64().map(|n| IndexKey::Number(n + 0.0)),
+            serde_json::Value::String(s) => Some(IndexKey::Text(s.clone())),
+            _ => None,
+        }
+    }
+
+    // A JSON number or string; anything else is taken as text, so bare
+    // words need no quotes.
+    pub fn parse(query: &str) -> Self {
+        match serde_json::from_str::<serde_json::Value>(query).ok().as_ref().and_then(IndexKey::of) {
+            Some(key) => key,
+            None => IndexKey::Text(query.to_string()),
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            IndexKey::Number(_) => 8,
+            IndexKey::Text(s) => s.len(),
+        }
+    }
+}
+
+impl Eq for IndexKey {}
+
+impl PartialOrd for IndexKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for IndexKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self, other) {
+            (IndexKey::Number(a), IndexKey::Number(b)) => a.total_cmp(b),
+            (IndexKey::Number(_), IndexKey::Text(_)) => Ordering::Less,
+            (IndexKey::Text(_), IndexKey::Number(_)) => Ordering::Greater,
+            (IndexKey::Text(a), IndexKey::Text(b)) => a.cmp(b),
+        }
+    }
+}
+
+pub enum IndexQuery {
+    Equals(IndexKey),
+    Between(ScoreBound, ScoreBound),
+}
+
+struct Index {
+    definition: IndexDefinition,
+    entries: BTreeMap<IndexKey, BTreeSet<String>>,
+}
+
+impl Index {
+    fn key_for(&self, key: &str, value: &Value) -> Option<IndexKey> {
+        if !key.starts_with(self.definition.prefix.as_str()) {
+            return None;
+        }
+        document::get(value.json().ok()?, &self.definition.path).and_then(IndexKey::of)
+    }
+}
+
+fn entry_size(key: &str, index_key: &IndexKey) -> usize {
+    key.len() + index_key.size() + ELEMENT_OVERHEAD
+}
+
+// The secondary indexes over one store's keys. The store calls `insert` and
+// `remove` around every change, under the same lock, so indexes never lag
+// the data. Both return the bytes the index entries take up.
+#[derive(Default)]
+pub struct Indexes {
+    indexes: Vec<Index>,
+}
+
+impl Indexes {
+    pub fn exists(&self, name: &str) -> bool {
+        self.indexes.iter().any(|i| i.definition.name == name)
+    }
+
+    pub fn definitions(&self) -> impl Iterator<Item = &IndexDefinition> + '_ {
+        self.indexes.iter().map(|i| &i.definition)
+    }
+
+    // Adds the index, filled from `existing`. Returns the bytes it takes up.
+    pub fn create<'a, I>(&mut self, definition: IndexDefinition, existing: I) -> usize
+    where
+        I: Iterator<Item = (&'a String, &'a Value)>,
+    {
+        let mut index = Index { definition, entries: BTreeMap::new() };
+        let mut bytes = 0;
+        for (key, value) in existing {
+            if let Some(index_key) = index.key_for(key, value) {
+                bytes += entry_size(key, &index_key);
+                index.entries.entry(index_key).or_default().insert(key.clone());
+            }
+        }
+        self.indexes.push(index);
+        bytes
+    }
+
+    // Removes the index and returns the bytes it took up, or None if there
+    // was no such index.
+    pub fn drop(&mut self, name: &str) -> Option<usize> {
+        let position = self.indexes.iter().position(|i| i.definition.name == name)?;
+        let index = self.indexes.remove(position);
+        Some(
+            index
+                .entries
+                .iter()
+                .map(|(index_key, keys)| keys.iter().map(|key| entry_size(key, index_key)).sum::<usize>())
+                .sum(),
+        )
+    }
+
+    pub fn insert(&mut self, key: &str, value: &Value) -> usize {
+        let mut bytes = 0;
+        for index in self.indexes.iter_mut() {
+            if let Some(index_key) = index.key_for(key, value) {
+                bytes += entry_size(key, &index_key);
+                index.entries.entry(index_key).or_default().insert(key.to_string());
+            }
+        }
+        bytes
+    }
+
+    pub fn remove(&mut self, key: &str, value: &Value) -> usize {
+        let mut bytes = 0;
+        for index in self.indexes.iter_mut() {
+            if let Some(index_key) = index.key_for(key, value) {
+                bytes += entry_size(key, &index_key);
+                if let Some(keys) = index.entries.get_mut(&index_key) {
+                    keys.remove(key);
+                    if keys.is_empty() {
+                        index.entries.remove(&index_key);
+                    }
+                }
+            }
+        }
+        bytes
+    }
+
+    // Keys matching `query`, or None if there is no such index.
+    pub fn find(&self, name: &str, query: &IndexQuery) -> Option<Vec<String>> {
+        let index = self.indexes.iter().find(|i| i.definition.name == name)?;
+        let keys = match query {
+            IndexQuery::Equals(index_key) => index.entries.get(index_key).into_iter().flatten().cloned().collect(),
+            IndexQuery::Between(min, max) => {
+                let bound = |b: &ScoreBound| {
+                    if b.exclusive {
+                        Bound::Excluded(IndexKey::Number(b.value))
+                    } else {
+                        Bound::Included(IndexKey::Number(b.value))
+                    }
+                };
+                let empty = min.value > max.value || (min.value == max.value && (min.exclusive || max.exclusive));
+                if empty {
+                    return Some(Vec::new());
+                }
+                index.entries.range((bound(min), bound(max))).flat_map(|(_, keys)| keys.iter().cloned()).collect()
+            }
+        };
+        Some(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn by_age() -> IndexDefinition {
+        IndexDefinition { name: "by_age".to_string(), prefix: "user:".to_string(), path: Path::parse("$.age").unwrap() }
+    }
+
+    fn between(min: &str, max: &str) -> IndexQuery {
+        IndexQuery::Between(ScoreBound::parse(min).unwrap(), ScoreBound::parse(max).unwrap())
+    }
+
+    #[test]
+    fn only_prefixed_documents_with_the_field_are_indexed() {
+        let mut indexes = Indexes::default();
+        let ada = Value::Json(json!({"age": 36}));
+        let existing = [("user:1".to_string(), ada.clone()), ("team:1".to_string(), ada)];
+        let bytes = indexes.create(by_age(), existing.iter().map(|(key, value)| (key, value)));
+        assert_eq!(bytes, entry_size("user:1", &IndexKey::Number(36.0)));
+        assert_eq!(indexes.insert("user:2", &Value::Json(json!({"name": "bob"}))), 0);
+        assert_eq!(indexes.insert("user:3", &Value::String("36".to_string())), 0);
+        assert_eq!(indexes.find("by_age", &IndexQuery::Equals(IndexKey::parse("36"))), Some(vec!["user:1".to_string()]));
+        assert_eq!(indexes.find("missing", &IndexQuery::Equals(IndexKey::parse("36"))), None);
+    }
+
+    #[test]
+    fn removing_gives_back_what_inserting_took() {
+        let mut indexes = Indexes::default();
+        indexes.create(by_age(), std::iter::empty());
+        let value = Value::Json(json!({"age": "unknown"}));
+        let added = indexes.insert("user:1", &value);
+        assert!(added > 0);
+        assert_eq!(indexes.remove("user:1", &value), added);
+        assert_eq!(indexes.find("by_age", &IndexQuery::Equals(IndexKey::parse("unknown"))), Some(Vec::new()));
+        assert_eq!(indexes.drop("by_age"), Some(0));
+        assert!(!indexes.exists("by_age"));
+    }
+
+    #[test]
+    fn ranges_cover_numbers_only_and_honour_exclusive_bounds() {
+        let mut indexes = Indexes::default();
+        indexes.create(by_age(), std::iter::empty());
+        for (key, age) in [("user:1", json!(20)), ("user:2", json!(30)), ("user:3", json!(40)), ("user:4", json!("30"))].iter() {
+            indexes.insert(key, &Value::Json(json!({ "age": age })));
+        }
+        let find = |query| indexes.find("by_age", &query).unwrap();
+        assert_eq!(find(between("20", "30")), vec!["user:1", "user:2"]);
+        assert_eq!(find(between("(20", "(40")), vec!["user:2"]);
+        assert_eq!(find(between("-inf", "+inf")).len(), 3);
+        assert!(find(between("(30", "30")).is_empty());
+        assert!(find(between("40", "20")).is_empty());
+        assert_eq!(find(IndexQuery::Equals(IndexKey::parse("\"30\""))), vec!["user:4"]);
+        assert_eq!(IndexKey::parse("bare"), IndexKey::Text("bare".to_string()));
+    }
+}
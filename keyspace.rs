@@ -0,0 +1,299 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::Duration;
+use crate::memory::{self, EvictionPolicy, MemoryLimit, ShardedStore, SharedUsage};
+use crate::quota::{RateLimit, RateLimiter};
+use crate::value::parse_bytes;
+
+// The keyspace every connection starts in. It always exists.
+pub const DEFAULT_KEYSPACE: &str = "default";
+
+#[derive(Debug, Clone, Copy)]
+pub struct KeyspaceSettings {
+    // Applied to keys written without a TTL of their own.
+    pub default_ttl: Option<Duration>,
+    // In estimated bytes of memory, as MAXMEMORY counts them, so each
+    // collection element adds its overhead. Zero means unlimited.
+    pub max_value_size: usize,
+    // The keyspace's own share of the server's memory, evicting by its own
+    // policy. Zero leaves it only the server-wide limit.
+    pub memory: MemoryLimit,
+    pub rate: RateLimit,
+    // Quotas on the keyspace as a whole; zero means unlimited. Unlike
+    // MAXMEMORY they never evict.
+    pub max_keys: usize,
+    pub max_bytes: usize,
+}
+
+impl KeyspaceSettings {
+    // Options as given to CREATE KEYSPACE: `TTL seconds`, `MAXVALUESIZE
+    // bytes`, `MAXMEMORY bytes`, `POLICY name`, `RATE requests`, `WRITERATE
+    // bytes`, `MAXKEYS count` and `MAXBYTES bytes`, in any order. Rates are
+    // per second. Anything left out comes from `defaults`. Per-keyspace
+    // replication, storage backends and access control are follow-ups: this
+    // server neither replicates nor authenticates, and keeps every keyspace
+    // in memory. Until then they fail like any other unknown option.
+    pub fn parse(options: &[&str], defaults: KeyspaceSettings) -> Result<Self, String> {
+        if !options.len().is_multiple_of(2) {
+            return Err("ERR syntax error, options take a value".to_string());
+        }
+        let mut settings = defaults;
+        for option in options.chunks(2) {
+            let (name, value) = (option[0].to_uppercase(), option[1]);
+            match name.as_str() {
+                "TTL" => match value.parse::<u64>() {
+                    Ok(0) => settings.default_ttl = None,
+                    Ok(seconds) => settings.default_ttl = Some(Duration::from_secs(seconds)),
+                    Err(_) => return Err("ERR invalid TTL".to_string()),
+                },
+                "MAXVALUESIZE" => {
+                    settings.max_value_size = parse_bytes(value).ok_or("ERR invalid MAXVALUESIZE")?;
+                }
+                "MAXMEMORY" => {
+                    settings.memory.max_memory = parse_bytes(value).ok_or("ERR invalid MAXMEMORY")?;
+                }
+                "POLICY" => {
+                    settings.memory.policy = EvictionPolicy::parse(value).ok_or("ERR invalid POLICY")?;
+                }
+                "RATE" => {
+                    settings.rate.requests_per_second = value.parse().map_err(|_| "ERR invalid RATE")?;
+                }
+                "WRITERATE" => {
+                    settings.rate.bytes_per_second = parse_bytes(value).ok_or("ERR invalid WRITERATE")? as u64;
+                }
+                "MAXKEYS" => {
+                    settings.max_keys = value.parse().map_err(|_| "ERR invalid MAXKEYS")?;
+                }
+                "MAXBYTES" => {
+                    settings.max_bytes = parse_bytes(value).ok_or("ERR invalid MAXBYTES")?;
+                }
+                _ => return Err(format!("ERR unknown keyspace option '{}'", option[0])),
+            }
+        }
+        Ok(settings)
+    }
+}
+
+// A named, separately configured store. Connections hold on to the keyspace
+// they use, so a dropped one is only marked here and its memory goes with
+// the last connection still holding it.
+pub struct Keyspace {
+    pub name: String,
+    // As given to CREATE KEYSPACE, so a snapshot can create it again.
+    pub options: Vec<String>,
+    pub store: ShardedStore,
+    // None when the keyspace has no rate limit, so requests skip the lock.
+    limiter: Option<Mutex<RateLimiter>>,
+    dropped: AtomicBool,
+}
+
+impl Keyspace {
+    // Admits a request writing `written` bytes, or returns how long the
+    // client should wait before retrying.
+    pub fn admit(&self, written: usize) -> Result<(), Duration> {
+        match &self.limiter {
+            Some(limiter) => limiter.lock().unwrap().admit(written),
+            None => Ok(()),
+        }
+    }
+
+    // Charges bytes written beyond what `admit` was told, such as a script's.
+    pub fn charge(&self, written: usize) {
+        if let Some(limiter) = &self.limiter {
+            limiter.lock().unwrap().charge(written);
+        }
+    }
+
+    pub fn is_dropped(&self) -> bool {
+        self.dropped.load(Ordering::SeqCst)
+    }
+}
+
+pub struct Keyspaces {
+    defaults: KeyspaceSettings,
+    // The server-wide limit, covering every keyspace's bytes as counted in
+    // `memory`.
+    limit: MemoryLimit,
+    memory: Arc<AtomicUsize>,
+    shard_count: usize,
+    keyspaces: RwLock<HashMap<String, Arc<Keyspace>>>,
+}
+
+impl Keyspaces {
+    // `limit` bounds all keyspaces together. Its policy is the default for
+    // each keyspace's evictions.
+    pub fn new(limit: MemoryLimit, shard_count: usize) -> Self {
+        let defaults = KeyspaceSettings {
+            default_ttl: None,
+            max_value_size: 0,
+            memory: MemoryLimit { max_memory: 0, policy: limit.policy },
+            rate: RateLimit::default(),
+            max_keys: 0,
+            max_bytes: 0,
+        };
+        let keyspaces = Keyspaces {
+            defaults,
+            limit,
+            memory: Arc::default(),
+            shard_count,
+            keyspaces: RwLock::new(HashMap::new()),
+        };
+        let default = keyspaces.build(DEFAULT_KEYSPACE, &[], defaults);
+        keyspaces.keyspaces.write().unwrap().insert(DEFAULT_KEYSPACE.to_string(), Arc::new(default));
+        keyspaces
+    }
+
+    fn build(&self, name: &str, options: &[&str], settings: KeyspaceSettings) -> Keyspace {
+        // Shards evict by the keyspace's policy, whichever limit they hit.
+        let limit = MemoryLimit { max_memory: self.limit.max_memory, policy: settings.memory.policy };
+        let store = memory::sharded_store(limit, self.shard_count, Arc::clone(&self.memory));
+        let usage = Arc::new(SharedUsage::default());
+        for shard in store.lock_all().iter_mut() {
+            shard.configure(settings.default_ttl, settings.max_value_size);
+            shard.limit_totals(Arc::clone(&usage), settings.memory.max_memory, settings.max_keys, settings.max_bytes);
+        }
+        let limiter = if settings.rate.is_unlimited() { None } else { Some(Mutex::new(RateLimiter::new(settings.rate))) };
+        Keyspace {
+            name: name.to_string(),
+            options: options.iter().map(|o| o.to_string()).collect(),
+            store,
+            limiter,
+            dropped: AtomicBool::new(false),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Keyspace>> {
+        self.keyspaces.read().unwrap().get(name).cloned()
+    }
+
+    // Creates a keyspace with CREATE KEYSPACE options. Returns false if a
+    // keyspace with the name already exists.
+    pub fn create(&self, name: &str, options: &[&str]) -> Result<bool, String> {
+        let settings = KeyspaceSettings::parse(options, self.defaults)?;
+        let mut keyspaces = self.keyspaces.write().unwrap();
+        if keyspaces.contains_key(name) {
+            return Ok(false);
+        }
+        keyspaces.insert(name.to_string(), Arc::new(self.build(name, options, settings)));
+        Ok(true)
+    }
+
+    // Takes the keyspace and all its keys away at once. Returns false if
+    // there was no such keyspace.
+    pub fn drop(&self, name: &str) -> bool {
+        let removed = self.keyspaces.write().unwrap().remove(name);
+        match removed {
+            Some(keyspace) => {
+                keyspace.dropped.store(true, Ordering::SeqCst);
+                // Freeing a large store takes a while; the caller need not wait.
+                thread::spawn(move || drop(keyspace));
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn all(&self) -> Vec<Arc<Keyspace>> {
+        let mut all: Vec<Arc<Keyspace>> = self.keyspaces.read().unwrap().values().cloned().collect();
+        all.sort_by(|a, b| a.name.cmp(&b.name));
+        all
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.keyspaces.read().unwrap().keys().cloned().collect();
+        names.sort();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::StoreError;
+
+    fn keyspaces(max_memory: usize) -> Keyspaces {
+        Keyspaces::new(MemoryLimit { max_memory, policy: EvictionPolicy::NoEviction }, 2)
+    }
+
+    #[test]
+    fn options_parse_in_any_order() {
+        let defaults = keyspaces(0).defaults;
+        let settings = KeyspaceSettings::parse(&["maxkeys", "10", "TTL", "60", "POLICY", "allkeys-lru", "MAXMEMORY", "1kb"], defaults).unwrap();
+        assert_eq!(settings.max_keys, 10);
+        assert_eq!(settings.default_ttl, Some(Duration::from_secs(60)));
+        assert_eq!(settings.memory.max_memory, 1024);
+        assert_eq!(settings.memory.policy, EvictionPolicy::AllKeysLru);
+        assert!(KeyspaceSettings::parse(&["TTL"], defaults).is_err());
+        assert!(KeyspaceSettings::parse(&["TTL", "soon"], defaults).is_err());
+        assert!(KeyspaceSettings::parse(&["COLOR", "red"], defaults).is_err());
+    }
+
+    #[test]
+    fn keyspaces_share_the_server_memory_limit() {
+        let keyspaces = keyspaces(1000);
+        assert_eq!(keyspaces.create("team", &[]), Ok(true));
+        assert_eq!(keyspaces.create("team", &[]), Ok(false));
+        let default = keyspaces.get(DEFAULT_KEYSPACE).unwrap();
+        let team = keyspaces.get("team").unwrap();
+        default.store.lock("a").set("a", &"x".repeat(600), None).unwrap();
+        let result = team.store.lock("b").set("b", &"x".repeat(600), None);
+        assert!(matches!(result, Err(StoreError::OutOfMemory)));
+
+        // Dropping a keyspace gives its memory back to the others.
+        assert!(keyspaces.drop(DEFAULT_KEYSPACE));
+        drop(default);
+        // It is freed on another thread; only `keyspaces` and the team
+        // keyspace's two shards hold the total once it is gone.
+        for _ in 0..1000 {
+            if Arc::strong_count(&keyspaces.memory) == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        team.store.lock("b").set("b", &"x".repeat(600), None).unwrap();
+        assert!(keyspaces.get(DEFAULT_KEYSPACE).is_none());
+    }
+
+    #[test]
+    fn quotas_cover_every_shard_and_never_evict() {
+        let keyspaces = keyspaces(0);
+        keyspaces.create("team", &["MAXKEYS", "2", "POLICY", "allkeys-lru"]).unwrap();
+        let team = keyspaces.get("team").unwrap();
+        let keys = ["a", "b", "c", "d"];
+        let stored = keys.iter().filter(|key| team.store.lock(key).set(key, "v", None).is_ok()).count();
+        assert_eq!(stored, 2);
+        assert!(matches!(team.store.lock("z").set("z", "v", None), Err(StoreError::QuotaExceeded { limit: 2, unit: "keys" })));
+        // Overwriting and deleting are always allowed, and free a slot.
+        let existing = *keys.iter().find(|key| team.store.lock(key).get(key).unwrap().is_some()).unwrap();
+        team.store.lock(existing).set(existing, "w", None).unwrap();
+        team.store.lock(existing).delete(existing);
+        team.store.lock("z").set("z", "v", None).unwrap();
+
+        keyspaces.create("small", &["MAXBYTES", "300"]).unwrap();
+        let small = keyspaces.get("small").unwrap();
+        small.store.lock("a").set("a", &"x".repeat(100), None).unwrap();
+        let result = small.store.lock("b").set("b", &"x".repeat(100), None);
+        assert!(matches!(result, Err(StoreError::QuotaExceeded { limit: 300, unit: "bytes" })));
+        assert!(small.store.lock("a").get("a").unwrap().is_some());
+    }
+
+    #[test]
+    fn a_keyspace_memory_limit_evicts_only_its_own_keys() {
+        let keyspaces = keyspaces(0);
+        keyspaces.create("cache", &["MAXMEMORY", "500", "POLICY", "allkeys-lru"]).unwrap();
+        let cache = keyspaces.get("cache").unwrap();
+        let default = keyspaces.get(DEFAULT_KEYSPACE).unwrap();
+        default.store.lock("kept").set("kept", &"x".repeat(400), None).unwrap();
+        // Shards only evict their own keys, so these share one.
+        let shard = cache.store.index_of("a");
+        let keys: Vec<String> = (0..).map(|i| format!("k{}", i)).filter(|key| cache.store.index_of(key) == shard).take(3).collect();
+        for key in &keys {
+            cache.store.lock(key).set(key, &"x".repeat(200), None).unwrap();
+        }
+        let live = keys.iter().filter(|key| cache.store.lock(key).get(key).unwrap().is_some()).count();
+        assert_eq!(live, 1);
+        assert!(default.store.lock("kept").get("kept").unwrap().is_some());
+    }
+}
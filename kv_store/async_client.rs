@@ -0,0 +1,593 @@
+use futures::future::join_all;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::TcpStream;
+use tokio::sync::RwLock;
+use tokio::time::{sleep, timeout};
+use uuid::Uuid;
+use super::clock::CausalContext;
+use super::codec::{self, Codec, TypedSiblings};
+use super::node::Siblings;
+use super::peer::{self, Delta, Member, PeerMessage};
+use super::ring::HashRing;
+
+const SCAN_BATCH: usize = 1000;
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    // Retries apply to reads, and to writes that failed before being sent.
+    pub max_retries: u32,
+    pub base_backoff: Duration,
+    pub max_backoff: Duration,
+    pub max_idle_per_node: usize,
+    // Used by `set_as`; `get_as` reads whatever codec a value was written with.
+    pub codec: Codec,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            connect_timeout: Duration::from_secs(2),
+            request_timeout: Duration::from_secs(5),
+            max_retries: 3,
+            base_backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+            max_idle_per_node: 8,
+            codec: Codec::Json,
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: OwnedWriteHalf,
+}
+
+impl Connection {
+    async fn request(&mut self, message: &PeerMessage) -> io::Result<PeerMessage> {
+        let mut line = serde_json::to_string(message)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await?;
+        let mut reply = String::new();
+        if self.reader.read_line(&mut reply).await? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Node closed the connection"));
+        }
+        match serde_json::from_str(&reply)? {
+            PeerMessage::Error(e) => Err(io::Error::other(e)),
+            reply => Ok(reply),
+        }
+    }
+}
+
+// Idle connections to one node. Connections are only returned to the pool
+// after a clean request/reply, so a pooled connection is never mid-message.
+struct Pool {
+    idle: Mutex<Vec<Connection>>,
+}
+
+struct Topology {
+    members: HashMap<Uuid, Member>,
+    ring: HashRing,
+}
+
+// Why a request failed, which decides whether retrying it is safe.
+enum Failure {
+    // The request never reached the node.
+    NotSent(io::Error),
+    Sent(io::Error),
+}
+
+impl From<Failure> for io::Error {
+    fn from(failure: Failure) -> Self {
+        match failure {
+            Failure::NotSent(e) | Failure::Sent(e) => e,
+        }
+    }
+}
+
+pub struct AsyncClient {
+    seeds: Vec<String>,
+    config: ClientConfig,
+    topology: RwLock<Topology>,
+    pools: Mutex<HashMap<String, Arc<Pool>>>,
+}
+
+impl AsyncClient {
+    pub async fn connect(seeds: Vec<String>, config: ClientConfig) -> io::Result<Self> {
+        let client = AsyncClient {
+            seeds,
+            config,
+            topology: RwLock::new(Topology {
+                members: HashMap::new(),
+                ring: HashRing::new(Vec::new(), 0),
+            }),
+            pools: Mutex::new(HashMap::new()),
+        };
+        client.refresh_topology().await?;
+        Ok(client)
+    }
+
+    pub async fn refresh_topology(&self) -> io::Result<()> {
+        let mut candidates = self.seeds.clone();
+        candidates.extend(self.topology.read().await.members.values().map(|m| m.address.clone()));
+        let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "No seed nodes configured");
+        for address in candidates {
+            match self.send(&address, &PeerMessage::Topology).await {
+                Ok(PeerMessage::TopologyReply { members, replication_factor }) => {
+                    let ring = HashRing::new(members.keys().cloned(), replication_factor);
+                    *self.topology.write().await = Topology { members, ring };
+                    return Ok(());
+                }
+                Ok(other) => last_error = peer::unexpected(other),
+                Err(Failure::NotSent(e)) | Err(Failure::Sent(e)) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    pub async fn get(&self, key: &str) -> io::Result<Option<Siblings>> {
+        match self.execute(key, PeerMessage::Get { key: key.to_string(), tracking_id: None }, true).await? {
+            PeerMessage::Value { values, context: Some(context) } if !values.is_empty() => Ok(Some(Siblings {
+                values,
+                context: parse_context(&context)?,
+            })),
+            PeerMessage::Value { .. } => Ok(None),
+            other => Err(peer::unexpected(other)),
+        }
+    }
+
+    pub async fn set(&self, key: &str, value: &str, context: Option<&CausalContext>) -> io::Result<CausalContext> {
+        let message = PeerMessage::Put {
+            key: key.to_string(),
+            value: value.to_string(),
+            context: context.map(|c| c.to_string()),
+            ttl_secs: None,
+            origin: None,
+        };
+        match self.execute(key, message, false).await? {
+            PeerMessage::Written { context } => parse_context(&context),
+            other => Err(peer::unexpected(other)),
+        }
+    }
+
+    pub async fn get_as<T: DeserializeOwned>(&self, key: &str) -> io::Result<Option<TypedSiblings<T>>> {
+        match self.get(key).await? {
+            Some(siblings) => Ok(Some(TypedSiblings {
+                values: siblings.values.iter().map(|v| codec::decode(v)).collect::<io::Result<Vec<T>>>()?,
+                context: siblings.context,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_as<T: Serialize>(&self, key: &str, value: &T, context: Option<&CausalContext>) -> io::Result<CausalContext> {
+        let encoded = self.config.codec.encode(value)?;
+        self.set(key, &encoded, context).await
+    }
+
+    pub async fn delete(&self, key: &str, context: Option<&CausalContext>) -> io::Result<CausalContext> {
+        let message = PeerMessage::Delete {
+            key: key.to_string(),
+            context: context.map(|c| c.to_string()),
+            origin: None,
+        };
+        match self.execute(key, message, false).await? {
+            PeerMessage::Written { context } => parse_context(&context),
+            other => Err(peer::unexpected(other)),
+        }
+    }
+
+    // Each key still goes to its own owner; the requests just run concurrently.
+    pub async fn mget(&self, keys: &[&str]) -> io::Result<Vec<Option<Siblings>>> {
+        join_all(keys.iter().map(|key| self.get(key))).await.into_iter().collect()
+    }
+
+    pub async fn mset(&self, pairs: &[(&str, &str)]) -> io::Result<Vec<CausalContext>> {
+        join_all(pairs.iter().map(|(key, value)| self.set(key, value, None)))
+            .await
+            .into_iter()
+            .collect()
+    }
+
+    // Every node only knows its own keys, so the scan visits all of them and
+    // merges the results; replicas of the same key collapse into one entry.
+    // Nodes that are down are skipped, since their replicas hold the same
+    // keys; it only fails when no node answers.
+    pub async fn scan(&self, prefix: &str) -> io::Result<Vec<(String, Vec<String>)>> {
+        let addresses: Vec<String> = self
+            .topology
+            .read()
+            .await
+            .members
+            .values()
+            .filter(|m| m.alive)
+            .map(|m| m.address.clone())
+            .collect();
+        let mut merged = BTreeMap::new();
+        let mut last_error = None;
+        let mut answered = false;
+        for address in addresses {
+            match self.scan_node(&address, prefix).await {
+                Ok(entries) => {
+                    merged.extend(entries);
+                    answered = true;
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        if !answered {
+            return Err(last_error.unwrap_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "No live nodes to scan")));
+        }
+        Ok(merged.into_iter().collect())
+    }
+
+    async fn scan_node(&self, address: &str, prefix: &str) -> io::Result<Vec<(String, Vec<String>)>> {
+        let mut found = Vec::new();
+        let mut after = None;
+        loop {
+            let message = PeerMessage::Scan {
+                prefix: prefix.to_string(),
+                after: after.clone(),
+                limit: SCAN_BATCH,
+            };
+            match self.send_with_retry(address, &message, true).await? {
+                PeerMessage::ScanBatch { entries, next } => {
+                    found.extend(entries);
+                    if next.is_none() {
+                        return Ok(found);
+                    }
+                    after = next;
+                }
+                other => return Err(peer::unexpected(other)),
+            }
+        }
+    }
+
+    pub async fn cas(&self, key: &str, expected: Option<&str>, value: &str) -> io::Result<bool> {
+        let message = PeerMessage::CompareAndSwap {
+            key: key.to_string(),
+            expected: expected.map(|e| e.to_string()),
+            value: value.to_string(),
+        };
+        match self.execute(key, message, false).await? {
+            PeerMessage::Swapped { swapped, .. } => Ok(swapped),
+            other => Err(peer::unexpected(other)),
+        }
+    }
+
+    // Adds `by` to the key's number and returns the sum, as the node stored
+    // it. Never resent once it may have arrived, so it is applied at most once.
+    pub async fn incr(&self, key: &str, by: Delta) -> io::Result<String> {
+        let message = PeerMessage::Increment { key: key.to_string(), by };
+        match self.execute(key, message, false).await? {
+            PeerMessage::Incremented { value, .. } => Ok(value),
+            other => Err(peer::unexpected(other)),
+        }
+    }
+
+    // Runs a script on a replica of its keys, which must all share the same
+    // replicas, and returns its reply. Sent at most once, like `incr`.
+    pub async fn eval(&self, script: &str, keys: &[&str], args: &[&str]) -> io::Result<String> {
+        let owned = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        let message = PeerMessage::Eval { script: script.to_string(), keys: owned(keys), args: owned(args) };
+        match self.execute(keys.first().copied().unwrap_or_default(), message, false).await? {
+            PeerMessage::Evaluated { reply } => Ok(reply),
+            other => Err(peer::unexpected(other)),
+        }
+    }
+
+    async fn owners(&self, key: &str) -> Vec<String> {
+        let topology = self.topology.read().await;
+        topology
+            .ring
+            .owners(key)
+            .iter()
+            .filter_map(|id| topology.members.get(id))
+            .map(|m| m.address.clone())
+            .collect()
+    }
+
+    // Same routing as the blocking cluster client: primary, then replicas,
+    // then one more pass after refreshing the ring.
+    async fn execute(&self, key: &str, message: PeerMessage, idempotent: bool) -> io::Result<PeerMessage> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "No node owns this key");
+        for attempt in 0..2 {
+            for address in self.owners(key).await {
+                match self.send_with_retry(&address, &message, idempotent).await {
+                    Ok(PeerMessage::WrongNode { .. }) => {
+                        last_error = io::Error::other(format!("{} no longer owns {}", address, key));
+                        break;
+                    }
+                    Ok(reply) => return Ok(reply),
+                    // A write that may have been applied is not sent to
+                    // another replica either.
+                    Err(Failure::Sent(e)) if !idempotent => return Err(e),
+                    Err(Failure::Sent(e)) | Err(Failure::NotSent(e)) => last_error = e,
+                }
+            }
+            if attempt == 0 {
+                self.refresh_topology().await?;
+            }
+        }
+        Err(last_error)
+    }
+
+    async fn send_with_retry(&self, address: &str, message: &PeerMessage, idempotent: bool) -> Result<PeerMessage, Failure> {
+        let mut attempt = 0;
+        loop {
+            let failure = match self.send(address, message).await {
+                Ok(reply) => return Ok(reply),
+                Err(Failure::Sent(e)) if !idempotent => return Err(Failure::Sent(e)),
+                Err(failure) => failure,
+            };
+            let error = match &failure {
+                Failure::NotSent(e) | Failure::Sent(e) => e,
+            };
+            // Errors reported by the node itself will not go away on retry.
+            if error.kind() == io::ErrorKind::Other || attempt >= self.config.max_retries {
+                return Err(failure);
+            }
+            sleep(self.backoff(attempt)).await;
+            attempt += 1;
+        }
+    }
+
+    // Exponential backoff with full jitter.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .config
+            .base_backoff
+            .checked_mul(1 << attempt.min(16))
+            .unwrap_or(self.config.max_backoff)
+            .min(self.config.max_backoff);
+        let jitter = RandomState::new().build_hasher().finish() % (ceiling.as_millis() as u64 + 1);
+        Duration::from_millis(jitter)
+    }
+
+    async fn send(&self, address: &str, message: &PeerMessage) -> Result<PeerMessage, Failure> {
+        let pool = self.pool(address);
+        let pooled = pool.idle.lock().unwrap().pop();
+        let reused = pooled.is_some();
+        let mut connection = match pooled {
+            Some(connection) => connection,
+            None => self.open(address).await.map_err(Failure::NotSent)?,
+        };
+        let reply = match timeout(self.config.request_timeout, connection.request(message)).await {
+            Ok(reply) => reply,
+            Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, format!("Request to {} timed out", address))),
+        };
+        match reply {
+            Ok(reply) => {
+                let mut idle = pool.idle.lock().unwrap();
+                if idle.len() < self.config.max_idle_per_node {
+                    idle.push(connection);
+                }
+                Ok(reply)
+            }
+            Err(e) if e.kind() == io::ErrorKind::Other => {
+                pool.idle.lock().unwrap().push(connection);
+                Err(Failure::Sent(e))
+            }
+            // An idle connection the node had already closed fails right away
+            // without the node ever reading the request.
+            Err(e) if reused && is_stale(&e) => Err(Failure::NotSent(e)),
+            Err(e) => Err(Failure::Sent(e)),
+        }
+    }
+
+    async fn open(&self, address: &str) -> io::Result<Connection> {
+        let stream = match timeout(self.config.connect_timeout, TcpStream::connect(address)).await {
+            Ok(stream) => stream?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::TimedOut, format!("Connecting to {} timed out", address))),
+        };
+        stream.set_nodelay(true)?;
+        let (reader, writer) = stream.into_split();
+        Ok(Connection {
+            reader: BufReader::new(reader),
+            writer,
+        })
+    }
+
+    fn pool(&self, address: &str) -> Arc<Pool> {
+        let mut pools = self.pools.lock().unwrap();
+        pools
+            .entry(address.to_string())
+            .or_insert_with(|| {
+                Arc::new(Pool {
+                    idle: Mutex::new(Vec::new()),
+                })
+            })
+            .clone()
+    }
+}
+
+fn is_stale(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe
+    )
+}
+
+fn parse_context(context: &str) -> io::Result<CausalContext> {
+    CausalContext::parse(context).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use super::super::clock::VectorClock;
+    use super::super::node::testing::start_cluster;
+
+    // A one-node cluster that closes every connection after two requests,
+    // as a node restarting between requests would.
+    fn serve(listener: TcpListener, accepted: Arc<AtomicUsize>) {
+        let address = listener.local_addr().unwrap().to_string();
+        let id = Uuid::new_v4();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            let address = address.clone();
+            thread::spawn(move || {
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                for _ in 0..2 {
+                    let reply = match peer::receive(&mut reader) {
+                        Ok(Some(PeerMessage::Topology)) => {
+                            let member = Member { address: address.clone(), alive: true, last_seen_millis: 0 };
+                            PeerMessage::TopologyReply { members: vec![(id, member)].into_iter().collect(), replication_factor: 1 }
+                        }
+                        Ok(Some(_)) => PeerMessage::Written { context: CausalContext::from_clock(&VectorClock::new()).to_string() },
+                        _ => return,
+                    };
+                    peer::send(&mut stream, &reply).unwrap();
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn write_on_a_closed_pooled_connection_is_retried() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        thread::spawn(move || serve(listener, counter));
+
+        let client = AsyncClient::connect(vec![address], ClientConfig::default()).await.unwrap();
+        client.set("a", "1", None).await.unwrap();
+        // The pooled connection has been closed by now; the write was never
+        // read, so it goes out again on a fresh connection.
+        client.set("a", "2", None).await.unwrap();
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn operations_reach_the_owning_nodes() {
+        let nodes = start_cluster(2, 1);
+        let client = AsyncClient::connect(vec![nodes[0].clone()], ClientConfig::default()).await.unwrap();
+        let context = client.set("a", "1", None).await.unwrap();
+        assert_eq!(client.get("a").await.unwrap().unwrap().values, vec!["1"]);
+        client.delete("a", Some(&context)).await.unwrap();
+        assert!(client.get("a").await.unwrap().is_none());
+
+        let pairs: Vec<(String, String)> = (0..20).map(|i| (format!("m:{}", i), i.to_string())).collect();
+        let pairs: Vec<(&str, &str)> = pairs.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        client.mset(&pairs).await.unwrap();
+        let keys: Vec<&str> = pairs.iter().map(|(k, _)| *k).chain(["missing"]).collect();
+        let found = client.mget(&keys).await.unwrap();
+        assert_eq!(found[3].as_ref().unwrap().values, vec!["3"]);
+        assert!(found[20].is_none());
+        // The keys are spread over both nodes; the scan collects them all.
+        let owners: HashSet<Vec<String>> = futures::future::join_all(keys.iter().map(|k| client.owners(k))).await.into_iter().collect();
+        assert_eq!(owners.len(), 2);
+        assert_eq!(client.scan("m:").await.unwrap().len(), 20);
+
+        assert!(client.cas("c", None, "1").await.unwrap());
+        assert!(!client.cas("c", None, "2").await.unwrap());
+        assert!(client.cas("c", Some("1"), "2").await.unwrap());
+        assert_eq!(client.get("c").await.unwrap().unwrap().values, vec!["2"]);
+    }
+
+    #[tokio::test]
+    async fn connections_are_reused() {
+        let nodes = start_cluster(1, 1);
+        let client = AsyncClient::connect(nodes.clone(), ClientConfig { max_idle_per_node: 2, ..ClientConfig::default() }).await.unwrap();
+        for i in 0..10 {
+            client.set("a", &i.to_string(), None).await.unwrap();
+        }
+        assert_eq!(client.pool(&nodes[0]).idle.lock().unwrap().len(), 1);
+        // Concurrent requests need more connections, but only so many stay open.
+        let keys: Vec<String> = (0..10).map(|i| format!("k{}", i)).collect();
+        let keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+        client.mget(&keys).await.unwrap();
+        assert_eq!(client.pool(&nodes[0]).idle.lock().unwrap().len(), 2);
+    }
+
+    // A one-node cluster that answers topology requests and nothing else,
+    // counting the reads and writes it is sent.
+    fn serve_silently(listener: TcpListener, reads: Arc<AtomicUsize>, writes: Arc<AtomicUsize>) {
+        let address = listener.local_addr().unwrap().to_string();
+        let id = Uuid::new_v4();
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let (address, reads, writes) = (address.clone(), reads.clone(), writes.clone());
+            thread::spawn(move || {
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                while let Ok(Some(message)) = peer::receive(&mut reader) {
+                    match message {
+                        PeerMessage::Topology => {
+                            let member = Member { address: address.clone(), alive: true, last_seen_millis: 0 };
+                            let reply = PeerMessage::TopologyReply { members: vec![(id, member)].into_iter().collect(), replication_factor: 1 };
+                            peer::send(&mut stream, &reply).unwrap();
+                        }
+                        PeerMessage::Get { .. } => {
+                            reads.fetch_add(1, Ordering::SeqCst);
+                        }
+                        _ => {
+                            writes.fetch_add(1, Ordering::SeqCst);
+                        }
+                    }
+                }
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn only_idempotent_requests_are_retried_after_a_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (reads, writes) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let (read_counter, write_counter) = (reads.clone(), writes.clone());
+        thread::spawn(move || serve_silently(listener, read_counter, write_counter));
+
+        let config = ClientConfig {
+            request_timeout: Duration::from_millis(50),
+            max_retries: 2,
+            base_backoff: Duration::from_millis(1),
+            ..ClientConfig::default()
+        };
+        let client = AsyncClient::connect(vec![address], config).await.unwrap();
+        assert_eq!(client.get("a").await.err().unwrap().kind(), io::ErrorKind::TimedOut);
+        // Three tries before the ring is refreshed and three after.
+        assert_eq!(reads.load(Ordering::SeqCst), 6);
+
+        assert_eq!(client.set("a", "1", None).await.err().unwrap().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(client.cas("a", None, "1").await.err().unwrap().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(writes.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn backoff_stays_under_the_ceiling() {
+        let config = ClientConfig { base_backoff: Duration::from_millis(10), max_backoff: Duration::from_millis(40), ..ClientConfig::default() };
+        let client = AsyncClient {
+            seeds: Vec::new(),
+            config,
+            topology: RwLock::new(Topology { members: HashMap::new(), ring: HashRing::new(Vec::new(), 0) }),
+            pools: Mutex::new(HashMap::new()),
+        };
+        for attempt in 0..40 {
+            assert!(client.backoff(attempt) <= Duration::from_millis(40));
+        }
+    }
+
+    #[test]
+    fn only_connection_failures_count_as_stale() {
+        assert!(is_stale(&io::Error::new(io::ErrorKind::UnexpectedEof, "closed")));
+        assert!(is_stale(&io::Error::new(io::ErrorKind::ConnectionReset, "reset")));
+        assert!(!is_stale(&io::Error::new(io::ErrorKind::TimedOut, "slow")));
+        assert!(!is_stale(&io::Error::other("node error")));
+    }
+}
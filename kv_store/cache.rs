@@ -0,0 +1,359 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::BuildHasher;
+
+// Rough per-entry cost of the map, ordering index and bookkeeping, so a
+// cache of tiny values still respects its budget.
+const ENTRY_OVERHEAD: usize = 96;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    Lru,
+    Lfu,
+    // A small LRU window in front of a main LRU segment; entries leaving the
+    // window only displace main entries that were used less often.
+    TinyLfu,
+}
+
+impl EvictionPolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "lru" => Some(EvictionPolicy::Lru),
+            "lfu" => Some(EvictionPolicy::Lfu),
+            "tinylfu" | "w-tinylfu" => Some(EvictionPolicy::TinyLfu),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Segment {
+    Window,
+    Main,
+}
+
+struct Entry {
+    value: String,
+    size: usize,
+    frequency: u64,
+    last_used: u64,
+    segment: Segment,
+}
+
+// Count-min sketch of recent access frequency, halved periodically so old
+// popularity fades.
+struct FrequencySketch {
+    counters: Vec<u8>,
+    mask: usize,
+    additions: usize,
+    reset_after: usize,
+    hasher: RandomState,
+}
+
+impl FrequencySketch {
+    const ROWS: usize = 4;
+
+    fn new(width: usize) -> Self {
+        let width = width.max(64).next_power_of_two();
+        FrequencySketch {
+            counters: vec![0; width * Self::ROWS],
+            mask: width - 1,
+            additions: 0,
+            reset_after: width * 10,
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn slots(&self, key: &str) -> [usize; 4] {
+        let hash = self.hasher.hash_one(key);
+        let mut slots = [0; 4];
+        for (row, slot) in slots.iter_mut().enumerate() {
+            let h = hash.rotate_left(row as u32 * 16) as usize;
+            *slot = row * (self.mask + 1) + (h & self.mask);
+        }
+        slots
+    }
+
+    fn increment(&mut self, key: &str) {
+        for slot in self.slots(key).iter() {
+            if self.counters[*slot] < 15 {
+                self.counters[*slot] += 1;
+            }
+        }
+        self.additions += 1;
+        if self.additions >= self.reset_after {
+            for counter in self.counters.iter_mut() {
+                *counter /= 2;
+            }
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate(&self, key: &str) -> u8 {
+        self.slots(key).iter().map(|slot| self.counters[*slot]).min().unwrap_or(0)
+    }
+}
+
+// Read cache bounded by the bytes its keys and values take up.
+pub struct Cache {
+    policy: EvictionPolicy,
+    capacity: usize,
+    window_capacity: usize,
+    used: usize,
+    window_used: usize,
+    tick: u64,
+    entries: HashMap<String, Entry>,
+    // Eviction order for each segment, lowest rank evicted first. LRU and
+    // LFU only use the main segment.
+    order: BTreeMap<(Segment, u64, u64), String>,
+    sketch: FrequencySketch,
+    stats: CacheStats,
+}
+
+impl Cache {
+    pub fn new(capacity: usize, policy: EvictionPolicy) -> Self {
+        Cache {
+            policy,
+            capacity,
+            window_capacity: capacity / 100,
+            used: 0,
+            window_used: 0,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            // Sized for the number of average entries the budget holds.
+            sketch: FrequencySketch::new(capacity / (ENTRY_OVERHEAD * 2)),
+            stats: CacheStats::default(),
+        }
+    }
+
+    fn rank(&self, entry: &Entry) -> (Segment, u64, u64) {
+        match self.policy {
+            EvictionPolicy::Lfu => (entry.segment, entry.frequency, entry.last_used),
+            _ => (entry.segment, entry.last_used, 0),
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<String> {
+        if self.policy == EvictionPolicy::TinyLfu {
+            self.sketch.increment(key);
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        let old_rank = match self.entries.get(key) {
+            Some(entry) => self.rank(entry),
+            None => {
+                self.stats.misses += 1;
+                return None;
+            }
+        };
+        self.order.remove(&old_rank);
+        let entry = self.entries.get_mut(key).unwrap();
+        entry.frequency += 1;
+        entry.last_used = tick;
+        let value = entry.value.clone();
+        let new_rank = self.rank(&self.entries[key]);
+        self.order.insert(new_rank, key.to_string());
+        self.stats.hits += 1;
+        Some(value)
+    }
+
+    // Values larger than the whole budget are not cached.
+    pub fn insert(&mut self, key: String, value: String) {
+        self.remove(&key);
+        let size = key.len() + value.len() + ENTRY_OVERHEAD;
+        if size > self.capacity {
+            return;
+        }
+        self.tick += 1;
+        let segment = match self.policy {
+            EvictionPolicy::TinyLfu => Segment::Window,
+            _ => Segment::Main,
+        };
+        // Room is made first, or LFU would evict the new entry itself, since
+        // it has been used the least.
+        if segment == Segment::Main {
+            while self.used + size > self.capacity && self.evict_first(Segment::Main) {}
+        }
+        let entry = Entry { value, size, frequency: 1, last_used: self.tick, segment };
+        self.order.insert(self.rank(&entry), key.clone());
+        self.entries.insert(key, entry);
+        self.used += size;
+        if segment == Segment::Window {
+            self.window_used += size;
+            self.admit_from_window();
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&self.rank(&entry));
+            self.used -= entry.size;
+            if entry.segment == Segment::Window {
+                self.window_used -= entry.size;
+            }
+        }
+    }
+
+    // Moves entries that overflow the window into the main segment, each one
+    // either displacing less frequently used main entries or being dropped.
+    fn admit_from_window(&mut self) {
+        let main_capacity = self.capacity - self.window_capacity;
+        while self.window_used > self.window_capacity {
+            let candidate = match self.first_in(Segment::Window) {
+                Some(key) => key,
+                None => break,
+            };
+            let candidate_size = self.entries[&candidate].size;
+            let candidate_frequency = self.sketch.estimate(&candidate);
+            let mut admitted = true;
+            while self.used - self.window_used + candidate_size > main_capacity {
+                let victim = match self.first_in(Segment::Main) {
+                    Some(victim) => victim,
+                    None => break,
+                };
+                if self.sketch.estimate(&victim) >= candidate_frequency {
+                    admitted = false;
+                    break;
+                }
+                self.remove(&victim);
+                self.stats.evictions += 1;
+            }
+            if admitted {
+                let mut entry = self.entries.remove(&candidate).unwrap();
+                self.order.remove(&self.rank(&entry));
+                self.window_used -= entry.size;
+                entry.segment = Segment::Main;
+                self.order.insert(self.rank(&entry), candidate.clone());
+                self.entries.insert(candidate, entry);
+            } else {
+                self.remove(&candidate);
+                self.stats.evictions += 1;
+            }
+        }
+        // An entry bigger than the main segment may have been admitted into
+        // an empty one; the total budget still holds.
+        while self.used > self.capacity {
+            let segment = if self.window_used > 0 { Segment::Window } else { Segment::Main };
+            if !self.evict_first(segment) {
+                break;
+            }
+        }
+    }
+
+    fn first_in(&self, segment: Segment) -> Option<String> {
+        self.order
+            .range((segment, 0, 0)..=(segment, u64::MAX, u64::MAX))
+            .next()
+            .map(|(_, key)| key.clone())
+    }
+
+    fn evict_first(&mut self, segment: Segment) -> bool {
+        match self.first_in(segment) {
+            Some(key) => {
+                self.remove(&key);
+                self.stats.evictions += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats { entries: self.entries.len(), bytes: self.used, ..self.stats }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(policy: EvictionPolicy, keys: &[&str]) -> Cache {
+        let mut cache = Cache::new(3 * (ENTRY_OVERHEAD + 2), policy);
+        for key in keys {
+            cache.insert(key.to_string(), "v".to_string());
+        }
+        cache
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used() {
+        let mut cache = filled(EvictionPolicy::Lru, &["a", "b", "c"]);
+        cache.get("a");
+        cache.insert("d".to_string(), "v".to_string());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some() && cache.get("c").is_some() && cache.get("d").is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.evictions, stats.misses), (3, 1, 1));
+        assert!(stats.bytes <= 3 * (ENTRY_OVERHEAD + 2));
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_frequently_used() {
+        let mut cache = filled(EvictionPolicy::Lfu, &["a", "b", "c"]);
+        for _ in 0..3 {
+            cache.get("a");
+            cache.get("c");
+        }
+        cache.get("b");
+        cache.insert("d".to_string(), "v".to_string());
+        assert!(cache.get("b").is_none());
+        assert!(cache.get("a").is_some());
+    }
+
+    #[test]
+    fn values_over_budget_and_removed_keys_are_not_cached() {
+        let mut cache = Cache::new(200, EvictionPolicy::Lru);
+        cache.insert("big".to_string(), "x".repeat(200));
+        cache.insert("k".to_string(), "v".to_string());
+        cache.remove("k");
+        assert!(cache.get("big").is_none() && cache.get("k").is_none());
+        assert_eq!(cache.stats().bytes, 0);
+    }
+
+    #[test]
+    fn tinylfu_keeps_hot_keys_through_a_scan() {
+        let run = |policy| {
+            let mut cache = Cache::new(20_000, policy);
+            let hot: Vec<String> = (0..20).map(|i| format!("hot{}", i)).collect();
+            for key in &hot {
+                cache.insert(key.clone(), "v".to_string());
+            }
+            for _ in 0..10 {
+                for key in &hot {
+                    cache.get(key);
+                }
+            }
+            for i in 0..2_000 {
+                cache.insert(format!("cold{}", i), "v".to_string());
+            }
+            assert!(cache.stats().bytes <= 20_000);
+            hot.iter().filter(|key| cache.get(key).is_some()).count()
+        };
+        assert_eq!(run(EvictionPolicy::TinyLfu), 20);
+        assert_eq!(run(EvictionPolicy::Lru), 0);
+    }
+
+    #[test]
+    fn sketch_counts_saturate_and_fade() {
+        let mut sketch = FrequencySketch::new(64);
+        for _ in 0..20 {
+            sketch.increment("a");
+        }
+        assert_eq!(sketch.estimate("a"), 15);
+        for i in 0..sketch.reset_after {
+            sketch.increment(&i.to_string());
+        }
+        assert!(sketch.estimate("a") < 15);
+        assert_eq!(EvictionPolicy::parse("W-TinyLFU"), Some(EvictionPolicy::TinyLfu));
+    }
+}
@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockOrdering {
+    Before,
+    After,
+    Equal,
+    Concurrent,
+}
+
+// One counter per node that has coordinated a write to the key.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct VectorClock {
+    counters: HashMap<String, u64>,
+}
+
+impl VectorClock {
+    pub fn new() -> Self {
+        VectorClock::default()
+    }
+
+    pub fn counter(&self, node_id: &str) -> u64 {
+        self.counters.get(node_id).cloned().unwrap_or(0)
+    }
+
+    pub fn increment(&mut self, node_id: &str) {
+        *self.counters.entry(node_id.to_string()).or_insert(0) += 1;
+    }
+
+    // Counters in node id order, for hashing a clock the same way everywhere.
+    pub fn entries(&self) -> Vec<(&String, u64)> {
+        let mut entries: Vec<(&String, u64)> = self.counters.iter().map(|(id, c)| (id, *c)).collect();
+        entries.sort();
+        entries
+    }
+
+    // Highest counter `node_id` has issued in this clock, counting the dots
+    // it gave to writes that had not seen all of its earlier ones.
+    pub fn issued_by(&self, node_id: &str) -> u64 {
+        let dot_prefix = format!("{}#", node_id);
+        self.counters
+            .keys()
+            .filter_map(|id| id.strip_prefix(&dot_prefix))
+            .filter_map(|dot| dot.parse().ok())
+            .fold(self.counter(node_id), u64::max)
+    }
+
+    // Records a write by `node_id` on top of this clock, given the highest
+    // counter the node has already issued for the key. Bumping the node's
+    // counter past writes this clock has not seen would claim to supersede
+    // them, so such a write gets a dot of its own and stays concurrent.
+    pub fn advance(&mut self, node_id: &str, issued: u64) {
+        if self.issued_by(node_id) >= issued {
+            self.counters.insert(node_id.to_string(), issued + 1);
+        } else {
+            self.counters.insert(format!("{}#{}", node_id, issued + 1), 1);
+        }
+    }
+
+    pub fn merge(&mut self, other: &VectorClock) {
+        for (node_id, counter) in &other.counters {
+            let entry = self.counters.entry(node_id.clone()).or_insert(0);
+            if *counter > *entry {
+                *entry = *counter;
+            }
+        }
+    }
+
+    pub fn compare(&self, other: &VectorClock) -> ClockOrdering {
+        let mut ahead = false;
+        let mut behind = false;
+        for node_id in self.counters.keys().chain(other.counters.keys()) {
+            let (mine, theirs) = (self.counter(node_id), other.counter(node_id));
+            if mine > theirs {
+                ahead = true;
+            } else if mine < theirs {
+                behind = true;
+            }
+        }
+        match (ahead, behind) {
+            (false, false) => ClockOrdering::Equal,
+            (true, false) => ClockOrdering::After,
+            (false, true) => ClockOrdering::Before,
+            (true, true) => ClockOrdering::Concurrent,
+        }
+    }
+
+    // True when this clock has seen everything `other` has seen.
+    pub fn descends(&self, other: &VectorClock) -> bool {
+        matches!(self.compare(other), ClockOrdering::After | ClockOrdering::Equal)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Versioned {
+    pub value: String,
+    pub clock: VectorClock,
+    pub timestamp: u64,
+    // Deletes are versions too, so they win over the writes they supersede.
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(default)]
+    pub expires_millis: Option<u64>,
+    // Cluster a replicated write was first made in; `None` for local writes.
+    #[serde(default)]
+    pub origin: Option<String>,
+}
+
+// Where a write arriving from another cluster was made, and when, so it
+// keeps its original timestamp here.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Origin {
+    pub cluster: String,
+    pub timestamp: u64,
+}
+
+impl Versioned {
+    pub fn new(value: String, clock: VectorClock) -> Self {
+        Versioned {
+            value,
+            clock,
+            timestamp: now_millis(),
+            deleted: false,
+            expires_millis: None,
+            origin: None,
+        }
+    }
+
+    // Neither deleted nor past its expiry at `now`.
+    pub fn is_live(&self, now: u64) -> bool {
+        !self.deleted && self.expires_millis.is_none_or(|expires| expires > now)
+    }
+
+    pub fn tombstone(clock: VectorClock) -> Self {
+        Versioned {
+            deleted: true,
+            ..Versioned::new(String::new(), clock)
+        }
+    }
+}
+
+// Opaque token handed to clients on read and echoed back on write, so the
+// write supersedes exactly the siblings the client has seen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CausalContext(String);
+
+impl CausalContext {
+    pub fn from_clock(clock: &VectorClock) -> Self {
+        let bytes = bincode::serialize(clock).expect("vector clock is always serializable");
+        CausalContext(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+
+    pub fn parse(token: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        CausalContext(token.to_string()).clock()?;
+        Ok(CausalContext(token.to_string()))
+    }
+
+    pub fn clock(&self) -> Result<VectorClock, Box<dyn std::error::Error>> {
+        if !self.0.is_ascii() || !self.0.len().is_multiple_of(2) {
+            return Err("Malformed causal context".into());
+        }
+        let bytes = self
+            .0
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair)?, 16).map_err(Into::into))
+            .collect::<Result<Vec<u8>, Box<dyn std::error::Error>>>()?;
+        Ok(bincode::deserialize(&bytes)?)
+    }
+}
+
+impl fmt::Display for CausalContext {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// Clock covering every sibling, used as the context returned on read.
+pub fn merged_clock(siblings: &[Versioned]) -> VectorClock {
+    let mut clock = VectorClock::new();
+    for sibling in siblings {
+        clock.merge(&sibling.clock);
+    }
+    clock
+}
+
+// Folds `incoming` into the sibling set: anything it descends from is
+// dropped, and it is discarded itself if an existing sibling already covers it.
+pub fn reconcile(siblings: &mut Vec<Versioned>, incoming: Versioned) -> bool {
+    if siblings.iter().any(|s| s.clock.descends(&incoming.clock)) {
+        return false;
+    }
+    siblings.retain(|s| !incoming.clock.descends(&s.clock));
+    siblings.push(incoming);
+    true
+}
+
+// Combines live siblings into the one value that replaces them.
+pub type MergeFn = Arc<dyn Fn(&[Versioned]) -> String + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub enum Resolver {
+    // Keep concurrent writes as siblings and let the reader decide.
+    #[default]
+    Siblings,
+    LastWriterWins,
+    Merge(MergeFn),
+}
+
+impl Resolver {
+    pub fn resolve(&self, siblings: Vec<Versioned>) -> Vec<Versioned> {
+        if siblings.len() < 2 {
+            return siblings;
+        }
+        let clock = merged_clock(&siblings);
+        let timestamp = siblings.iter().map(|s| s.timestamp).max().unwrap_or(0);
+        match self {
+            Resolver::Siblings => siblings,
+            Resolver::LastWriterWins => {
+                // Ties on timestamp fall back to the value so every replica picks the same winner.
+                let winner = siblings
+                    .into_iter()
+                    .max_by(|a, b| a.timestamp.cmp(&b.timestamp).then_with(|| a.value.cmp(&b.value)))
+                    .unwrap();
+                vec![Versioned { clock, timestamp, ..winner }]
+            }
+            Resolver::Merge(merge) => {
+                let live: Vec<Versioned> = siblings.into_iter().filter(|s| s.is_live(timestamp)).collect();
+                if live.is_empty() {
+                    return vec![Versioned { timestamp, ..Versioned::tombstone(clock) }];
+                }
+                vec![Versioned {
+                    value: merge(&live),
+                    clock,
+                    timestamp,
+                    deleted: false,
+                    expires_millis: live.iter().map(|s| s.expires_millis).max().flatten(),
+                    origin: None,
+                }]
+            }
+        }
+    }
+
+    // The resolvers that can be named in configuration. `merge:union` treats
+    // values as comma-separated sets and keeps every member of every sibling.
+    pub fn parse(setting: &str) -> Option<Self> {
+        match setting.trim().to_lowercase().as_str() {
+            "siblings" => Some(Resolver::Siblings),
+            "lww" | "last-writer-wins" => Some(Resolver::LastWriterWins),
+            "merge:union" => Some(Resolver::Merge(Arc::new(union))),
+            _ => None,
+        }
+    }
+}
+
+fn union(siblings: &[Versioned]) -> String {
+    let members: BTreeSet<&str> = siblings.iter().flat_map(|s| s.value.split(',')).filter(|m| !m.is_empty()).collect();
+    members.into_iter().collect::<Vec<&str>>().join(",")
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clock(counters: &[(&str, u64)]) -> VectorClock {
+        VectorClock { counters: counters.iter().map(|(id, c)| (id.to_string(), *c)).collect() }
+    }
+
+    #[test]
+    fn compare_orders_clocks() {
+        let a = clock(&[("a", 1)]);
+        let ab = clock(&[("a", 1), ("b", 1)]);
+        let b = clock(&[("b", 2)]);
+        assert_eq!(a.compare(&a.clone()), ClockOrdering::Equal);
+        assert_eq!(a.compare(&ab), ClockOrdering::Before);
+        assert_eq!(ab.compare(&a), ClockOrdering::After);
+        assert_eq!(ab.compare(&b), ClockOrdering::Concurrent);
+        assert!(ab.descends(&a));
+        assert!(a.descends(&a));
+        assert!(!a.descends(&ab));
+        assert!(!ab.descends(&b));
+    }
+
+    #[test]
+    fn blind_write_stays_concurrent_with_unseen_versions() {
+        let existing = clock(&[("a", 1)]);
+        let mut blind = VectorClock::new();
+        blind.advance("a", existing.issued_by("a"));
+        assert_eq!(blind.compare(&existing), ClockOrdering::Concurrent);
+
+        let mut again = VectorClock::new();
+        again.advance("a", existing.issued_by("a").max(blind.issued_by("a")));
+        assert_eq!(again.compare(&blind), ClockOrdering::Concurrent);
+        assert_eq!(again.compare(&existing), ClockOrdering::Concurrent);
+    }
+
+    #[test]
+    fn write_with_full_context_supersedes() {
+        let siblings = vec![
+            Versioned::new("x".to_string(), clock(&[("a", 1)])),
+            Versioned::new("y".to_string(), clock(&[("a#2", 1)])),
+        ];
+        let mut seen = merged_clock(&siblings);
+        let issued = siblings.iter().map(|s| s.clock.issued_by("a")).max().unwrap();
+        assert_eq!(issued, 2);
+        seen.advance("a", issued);
+        assert_eq!(seen.counter("a"), 3);
+        assert!(siblings.iter().all(|s| seen.descends(&s.clock) && seen != s.clock));
+    }
+
+    #[test]
+    fn reconcile_keeps_concurrent_siblings() {
+        let mut siblings = vec![Versioned::new("x".to_string(), clock(&[("a", 1)]))];
+        assert!(reconcile(&mut siblings, Versioned::new("y".to_string(), clock(&[("b", 1)]))));
+        assert_eq!(siblings.len(), 2);
+        assert!(!reconcile(&mut siblings, Versioned::new("old".to_string(), clock(&[("a", 1)]))));
+        assert!(reconcile(&mut siblings, Versioned::new("z".to_string(), clock(&[("a", 1), ("b", 1)]))));
+        assert_eq!(siblings.len(), 1);
+        assert_eq!(siblings[0].value, "z");
+    }
+
+    #[test]
+    fn resolvers_parse_from_their_names() {
+        let siblings = vec![
+            Versioned::new("a,b".to_string(), clock(&[("a", 1)])),
+            Versioned::new("c,a".to_string(), clock(&[("b", 1)])),
+        ];
+        let values = |resolver: Resolver| -> Vec<String> { resolver.resolve(siblings.clone()).into_iter().map(|s| s.value).collect() };
+        assert_eq!(values(Resolver::parse("siblings").unwrap()).len(), 2);
+        assert_eq!(values(Resolver::parse(" LWW ").unwrap()).len(), 1);
+        assert_eq!(values(Resolver::parse("merge:union").unwrap()), vec!["a,b,c"]);
+        assert!(Resolver::parse("merge").is_none());
+    }
+
+    #[test]
+    fn context_round_trips() {
+        let original = clock(&[("a", 3), ("b", 7)]);
+        let context = CausalContext::from_clock(&original);
+        let parsed = CausalContext::parse(&context.to_string()).unwrap();
+        assert_eq!(parsed.clock().unwrap(), original);
+    }
+
+    #[test]
+    fn malformed_context_is_an_error() {
+        assert!(CausalContext::parse("abc").is_err());
+        assert!(CausalContext::parse("aéb").is_err());
+        assert!(CausalContext::parse("zz").is_err());
+    }
+}
@@ -0,0 +1,723 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, BufReader};
+use std::net::{Shutdown, TcpStream};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+use super::clock::{self, CausalContext, Origin, Versioned};
+use super::codec::{self, Codec, TypedSiblings};
+use super::node::Siblings;
+use super::peer::{self, Member, PeerConnection, PeerMessage};
+use super::ring::HashRing;
+use super::wal::WalEntry;
+use super::watch::{ChangeEvent, ChangeKind, WatchTarget};
+
+const SCAN_BATCH: usize = 1000;
+
+// Talks to the cluster directly: requests go to a node that owns the key,
+// using a copy of the ring fetched from the nodes themselves.
+pub struct ClusterClient {
+    seeds: Vec<String>,
+    members: HashMap<Uuid, Member>,
+    ring: HashRing,
+    connections: HashMap<String, PeerConnection>,
+    timeout: Duration,
+    codec: Codec,
+    cache: Option<Arc<Mutex<ReadCache>>>,
+}
+
+// Reads kept by the client until a node pushes an invalidation for them.
+// Misses are cached too. Entries are evicted oldest first past `capacity`.
+struct ReadCache {
+    capacity: usize,
+    entries: HashMap<String, Option<Siblings>>,
+    order: VecDeque<String>,
+    // Invalidation stream per node address.
+    tracking: HashMap<String, u64>,
+    // Bumped on every invalidation, so a read that raced one is not cached.
+    generation: u64,
+}
+
+impl ReadCache {
+    fn insert(&mut self, key: &str, siblings: Option<Siblings>) {
+        if self.entries.insert(key.to_string(), siblings).is_none() {
+            self.order.push_back(key.to_string());
+        }
+        while self.entries.len() > self.capacity {
+            match self.order.pop_front() {
+                Some(oldest) => {
+                    self.entries.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn evict(&mut self, key: &str) {
+        self.generation += 1;
+        if self.entries.remove(key).is_some() {
+            self.order.retain(|k| k != key);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.generation += 1;
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+// Changes streamed from the nodes holding the watched keys. Every node
+// numbers its changes separately, so resuming needs the last sequence seen
+// from each node, as returned by `positions`.
+pub struct Watch {
+    events: Receiver<(String, io::Result<ChangeEvent>)>,
+    streams: Vec<TcpStream>,
+    positions: HashMap<String, u64>,
+    // Replicas each report the same change; only the first report is returned.
+    seen: HashMap<String, (ChangeKind, String)>,
+}
+
+impl Iterator for Watch {
+    type Item = io::Result<ChangeEvent>;
+
+    // Blocks until the next change. Returns `None` once every node has closed
+    // its stream.
+    fn next(&mut self) -> Option<io::Result<ChangeEvent>> {
+        loop {
+            let (address, event) = self.events.recv().ok()?;
+            let event = match event {
+                Ok(event) => event,
+                Err(e) => return Some(Err(io::Error::new(e.kind(), format!("{}: {}", address, e)))),
+            };
+            self.positions.insert(address, event.seq);
+            let report = (event.kind, event.context.clone());
+            if self.seen.get(&event.key) == Some(&report) {
+                continue;
+            }
+            self.seen.insert(event.key.clone(), report);
+            return Some(Ok(event));
+        }
+    }
+}
+
+impl Watch {
+    pub fn positions(&self) -> &HashMap<String, u64> {
+        &self.positions
+    }
+
+    pub fn cancel(self) {
+        for mut stream in self.streams {
+            let _ = peer::send(&mut stream, &PeerMessage::Unwatch);
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+pub enum Delivery {
+    Message { channel: String, pattern: Option<String>, message: String },
+    // This many messages were lost because we read too slowly.
+    Dropped(u64),
+}
+
+// Messages published anywhere in the cluster, received through one node.
+pub struct ChannelSubscription {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl Iterator for ChannelSubscription {
+    type Item = io::Result<Delivery>;
+
+    // Blocks until the next message. Returns `None` if the node closed the
+    // connection.
+    fn next(&mut self) -> Option<io::Result<Delivery>> {
+        loop {
+            return match peer::receive(&mut self.reader) {
+                Ok(Some(PeerMessage::Message { channel, pattern, message })) => Some(Ok(Delivery::Message { channel, pattern, message })),
+                Ok(Some(PeerMessage::MessagesDropped { count })) => Some(Ok(Delivery::Dropped(count))),
+                // Confirmation of a later `subscribe_more`.
+                Ok(Some(PeerMessage::Subscribed { .. })) => continue,
+                Ok(Some(other)) => Some(Err(peer::unexpected(other))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            };
+        }
+    }
+}
+
+impl ChannelSubscription {
+    pub fn subscribe_more(&mut self, channels: &[&str], patterns: &[&str]) -> io::Result<()> {
+        let message = PeerMessage::Subscribe {
+            channels: channels.iter().map(|c| c.to_string()).collect(),
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+        };
+        peer::send(&mut self.stream, &message)
+    }
+
+    pub fn cancel(mut self) {
+        let _ = peer::send(&mut self.stream, &PeerMessage::Unsubscribe);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+// A node's write-ahead log, from a sequence number onwards. Sequence
+// numbers are per node, so consumers read every node to see every write.
+pub struct CdcStream {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    pub first_seq: u64,
+    pub last_seq: u64,
+}
+
+impl Iterator for CdcStream {
+    type Item = io::Result<WalEntry>;
+
+    // Blocks until the next entry. Returns `None` if the node closed the
+    // connection.
+    fn next(&mut self) -> Option<io::Result<WalEntry>> {
+        match peer::receive(&mut self.reader) {
+            Ok(Some(PeerMessage::CdcEntry(entry))) => Some(Ok(entry)),
+            Ok(Some(other)) => Some(Err(peer::unexpected(other))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl CdcStream {
+    pub fn cancel(mut self) {
+        let _ = peer::send(&mut self.stream, &PeerMessage::CdcStop);
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+}
+
+impl ClusterClient {
+    pub fn connect(seeds: &[&str], timeout: Duration) -> io::Result<Self> {
+        let mut client = ClusterClient {
+            seeds: seeds.iter().map(|s| s.to_string()).collect(),
+            members: HashMap::new(),
+            ring: HashRing::new(Vec::new(), 0),
+            connections: HashMap::new(),
+            timeout,
+            codec: Codec::default(),
+            cache: None,
+        };
+        client.refresh_topology()?;
+        Ok(client)
+    }
+
+    // Asks the seeds, then any node we already know about, for the current
+    // membership and rebuilds the ring from it.
+    pub fn refresh_topology(&mut self) -> io::Result<()> {
+        let mut candidates = self.seeds.clone();
+        candidates.extend(self.members.values().map(|m| m.address.clone()));
+        let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "No seed nodes configured");
+        for address in candidates {
+            match self.request(&address, &PeerMessage::Topology) {
+                Ok(PeerMessage::TopologyReply { members, replication_factor }) => {
+                    self.ring = HashRing::new(members.keys().cloned(), replication_factor);
+                    self.members = members;
+                    return Ok(());
+                }
+                Ok(other) => last_error = peer::unexpected(other),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    // Caches up to `capacity` reads. Nodes tell the client when a cached key
+    // changes; if a node's invalidation stream drops, the whole cache is
+    // cleared since updates may have been missed.
+    pub fn enable_cache(&mut self, capacity: usize) {
+        self.cache = Some(Arc::new(Mutex::new(ReadCache {
+            capacity,
+            entries: HashMap::new(),
+            order: VecDeque::new(),
+            tracking: HashMap::new(),
+            generation: 0,
+        })));
+    }
+
+    pub fn get(&mut self, key: &str) -> io::Result<Option<Siblings>> {
+        let generation = match &self.cache {
+            Some(cache) => {
+                let cache = cache.lock().unwrap();
+                if let Some(cached) = cache.entries.get(key) {
+                    return Ok(cached.clone());
+                }
+                Some(cache.generation)
+            }
+            None => None,
+        };
+        let siblings = match self.execute(key, PeerMessage::Get { key: key.to_string(), tracking_id: None })? {
+            PeerMessage::Value { values, context: Some(context) } if !values.is_empty() => Some(Siblings {
+                values,
+                context: CausalContext::parse(&context).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?,
+            }),
+            PeerMessage::Value { .. } => None,
+            other => return Err(peer::unexpected(other)),
+        };
+        if let (Some(cache), Some(generation)) = (&self.cache, generation) {
+            let mut cache = cache.lock().unwrap();
+            if cache.generation == generation {
+                cache.insert(key, siblings.clone());
+            }
+        }
+        Ok(siblings)
+    }
+
+    pub fn set_codec(&mut self, codec: Codec) {
+        self.codec = codec;
+    }
+
+    pub fn get_as<T: DeserializeOwned>(&mut self, key: &str) -> io::Result<Option<TypedSiblings<T>>> {
+        match self.get(key)? {
+            Some(siblings) => Ok(Some(TypedSiblings {
+                values: siblings.values.iter().map(|v| codec::decode(v)).collect::<io::Result<Vec<T>>>()?,
+                context: siblings.context,
+            })),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set_as<T: Serialize>(&mut self, key: &str, value: &T, context: Option<&CausalContext>) -> io::Result<CausalContext> {
+        let encoded = self.codec.encode(value)?;
+        self.put(key, &encoded, context)
+    }
+
+    // Pass the context from a previous `get` to replace the values it returned.
+    pub fn put(&mut self, key: &str, value: &str, context: Option<&CausalContext>) -> io::Result<CausalContext> {
+        self.put_with_ttl(key, value, context, None)
+    }
+
+    pub fn put_with_ttl(
+        &mut self,
+        key: &str,
+        value: &str,
+        context: Option<&CausalContext>,
+        ttl: Option<Duration>,
+    ) -> io::Result<CausalContext> {
+        let message = PeerMessage::Put {
+            key: key.to_string(),
+            value: value.to_string(),
+            context: context.map(|c| c.to_string()),
+            ttl_secs: ttl.map(|ttl| ttl.as_secs()),
+            origin: None,
+        };
+        self.written(key, message)
+    }
+
+    pub fn delete(&mut self, key: &str, context: Option<&CausalContext>) -> io::Result<CausalContext> {
+        let message = PeerMessage::Delete {
+            key: key.to_string(),
+            context: context.map(|c| c.to_string()),
+            origin: None,
+        };
+        self.written(key, message)
+    }
+
+    // The raw versions a replica holds for `key`, including tombstones.
+    pub fn versions(&mut self, key: &str) -> io::Result<Vec<Versioned>> {
+        match self.execute(key, PeerMessage::FetchKeys { keys: vec![key.to_string()] })? {
+            PeerMessage::Versions(mut entries) => Ok(entries.pop().map(|(_, versions)| versions).unwrap_or_default()),
+            other => Err(peer::unexpected(other)),
+        }
+    }
+
+    // Writes a version copied from another cluster, keeping its timestamp
+    // and tagging it with the cluster it came from.
+    pub fn apply_replicated(
+        &mut self,
+        key: &str,
+        version: &Versioned,
+        context: Option<&CausalContext>,
+        origin: Origin,
+    ) -> io::Result<CausalContext> {
+        let message = if version.deleted {
+            PeerMessage::Delete {
+                key: key.to_string(),
+                context: context.map(|c| c.to_string()),
+                origin: Some(origin),
+            }
+        } else {
+            let now = clock::now_millis();
+            PeerMessage::Put {
+                key: key.to_string(),
+                value: version.value.clone(),
+                context: context.map(|c| c.to_string()),
+                // Rounded up to whole seconds, so the copy may outlive the original slightly.
+                ttl_secs: version.expires_millis.map(|expires| expires.saturating_sub(now).div_ceil(1000)),
+                origin: Some(origin),
+            }
+        };
+        self.written(key, message)
+    }
+
+    // Nodes only scan what they hold, so every node is asked and replicas
+    // of the same key collapse into one entry.
+    pub fn scan(&mut self, prefix: &str) -> io::Result<Vec<(String, Vec<String>)>> {
+        let addresses: Vec<String> = self.members.values().map(|m| m.address.clone()).collect();
+        let mut merged = BTreeMap::new();
+        for address in addresses {
+            let mut after = None;
+            loop {
+                let message = PeerMessage::Scan {
+                    prefix: prefix.to_string(),
+                    after: after.clone(),
+                    limit: SCAN_BATCH,
+                };
+                match self.request(&address, &message)? {
+                    PeerMessage::ScanBatch { entries, next } => {
+                        merged.extend(entries);
+                        if next.is_none() {
+                            break;
+                        }
+                        after = next;
+                    }
+                    other => return Err(peer::unexpected(other)),
+                }
+            }
+        }
+        Ok(merged.into_iter().collect())
+    }
+
+    // Watches every replica of a key, or every node for a prefix. Pass the
+    // positions of an earlier watch to resume without missing changes; a node
+    // that no longer has them answers with an error.
+    pub fn watch(&mut self, target: WatchTarget, resume: &HashMap<String, u64>) -> io::Result<Watch> {
+        let addresses = match &target {
+            WatchTarget::Key(key) => self.owners(key),
+            WatchTarget::Prefix(_) => self.members.values().map(|m| m.address.clone()).collect(),
+        };
+        if addresses.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotConnected, "No nodes to watch"));
+        }
+        let (sender, events) = mpsc::channel();
+        let mut watch = Watch { events, streams: Vec::new(), positions: HashMap::new(), seen: HashMap::new() };
+        for address in addresses {
+            let from_seq = resume.get(&address).cloned();
+            let mut stream = TcpStream::connect(&address)?;
+            stream.set_write_timeout(Some(self.timeout))?;
+            stream.set_read_timeout(Some(self.timeout))?;
+            let mut reader = BufReader::new(stream.try_clone()?);
+            peer::send(&mut stream, &PeerMessage::Watch { target: target.clone(), from_seq })?;
+            let seq = match peer::receive(&mut reader)? {
+                Some(PeerMessage::Watching { seq }) => seq,
+                Some(PeerMessage::Error(e)) => return Err(io::Error::other(format!("{}: {}", address, e))),
+                Some(other) => return Err(peer::unexpected(other)),
+                None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Peer closed the connection")),
+            };
+            stream.set_read_timeout(None)?;
+            watch.positions.insert(address.clone(), from_seq.unwrap_or(seq));
+            watch.streams.push(stream);
+
+            let sender = sender.clone();
+            thread::spawn(move || loop {
+                let event = match peer::receive(&mut reader) {
+                    Ok(Some(PeerMessage::Change(event))) => Ok(event),
+                    // The node's acknowledgement of `Unwatch`.
+                    Ok(Some(PeerMessage::Ack)) | Ok(None) => break,
+                    Ok(Some(other)) => Err(peer::unexpected(other)),
+                    Err(e) => Err(e),
+                };
+                let failed = event.is_err();
+                if sender.send((address.clone(), event)).is_err() || failed {
+                    break;
+                }
+            });
+        }
+        Ok(watch)
+    }
+
+    // Returns how many subscribers on the receiving node got the message;
+    // subscribers on other nodes are reached asynchronously.
+    pub fn publish(&mut self, channel: &str, message: &str) -> io::Result<usize> {
+        let publish = PeerMessage::Publish {
+            channel: channel.to_string(),
+            message: message.to_string(),
+            forwarded: false,
+        };
+        let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "No live nodes");
+        for address in self.live_addresses() {
+            match self.request(&address, &publish) {
+                Ok(PeerMessage::Published { receivers }) => return Ok(receivers),
+                Ok(other) => return Err(peer::unexpected(other)),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    // Every node forwards what it is sent to the others, so one node is
+    // enough to hear the whole cluster.
+    pub fn subscribe(&mut self, channels: &[&str], patterns: &[&str]) -> io::Result<ChannelSubscription> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "No live nodes");
+        for address in self.live_addresses() {
+            match self.open_subscription(&address, channels, patterns) {
+                Ok(subscription) => return Ok(subscription),
+                Err(e) => last_error = e,
+            }
+        }
+        Err(last_error)
+    }
+
+    fn open_subscription(&self, address: &str, channels: &[&str], patterns: &[&str]) -> io::Result<ChannelSubscription> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        let mut subscription = ChannelSubscription { reader: BufReader::new(stream.try_clone()?), stream };
+        subscription.subscribe_more(channels, patterns)?;
+        match peer::receive(&mut subscription.reader)? {
+            Some(PeerMessage::Subscribed { .. }) => {}
+            Some(other) => return Err(peer::unexpected(other)),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Peer closed the connection")),
+        }
+        subscription.stream.set_read_timeout(None)?;
+        Ok(subscription)
+    }
+
+    // Reads `address`'s log starting at `from_seq`, or at the oldest entry it
+    // retains. Fails if the node no longer retains `from_seq`.
+    pub fn cdc_subscribe(&self, address: &str, from_seq: Option<u64>) -> io::Result<CdcStream> {
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        peer::send(&mut stream, &PeerMessage::CdcSubscribe { from_seq })?;
+        let (first_seq, last_seq) = match peer::receive(&mut reader)? {
+            Some(PeerMessage::CdcStarted { first_seq, last_seq }) => (first_seq, last_seq),
+            Some(PeerMessage::Error(e)) => return Err(io::Error::other(e)),
+            Some(other) => return Err(peer::unexpected(other)),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Peer closed the connection")),
+        };
+        stream.set_read_timeout(None)?;
+        Ok(CdcStream { stream, reader, first_seq, last_seq })
+    }
+
+    pub fn addresses(&self) -> Vec<String> {
+        self.members.values().map(|m| m.address.clone()).collect()
+    }
+
+    fn live_addresses(&self) -> Vec<String> {
+        self.members.values().filter(|m| m.alive).map(|m| m.address.clone()).collect()
+    }
+
+    fn written(&mut self, key: &str, message: PeerMessage) -> io::Result<CausalContext> {
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().evict(key);
+        }
+        match self.execute(key, message)? {
+            PeerMessage::Written { context } => {
+                CausalContext::parse(&context).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+            }
+            other => Err(peer::unexpected(other)),
+        }
+    }
+
+    pub fn owners(&self, key: &str) -> Vec<String> {
+        self.ring
+            .owners(key)
+            .iter()
+            .filter_map(|id| self.members.get(id))
+            .map(|m| m.address.clone())
+            .collect()
+    }
+
+    // Tries the key's primary, then its replicas. If none of them can serve
+    // it, or one says it no longer owns the key, the ring is refreshed once
+    // and the request retried.
+    fn execute(&mut self, key: &str, mut message: PeerMessage) -> io::Result<PeerMessage> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotConnected, "No node owns this key");
+        for attempt in 0..2 {
+            for address in self.owners(key) {
+                // Cached reads are tracked by whichever node serves them.
+                if let PeerMessage::Get { tracking_id, .. } = &mut message {
+                    match self.tracking_id(&address) {
+                        Ok(id) => *tracking_id = id,
+                        Err(e) => {
+                            last_error = e;
+                            continue;
+                        }
+                    }
+                }
+                match self.request(&address, &message) {
+                    Ok(PeerMessage::WrongNode { .. }) => {
+                        last_error = io::Error::other(format!("{} no longer owns {}", address, key));
+                        break;
+                    }
+                    Ok(reply) => return Ok(reply),
+                    Err(e) => last_error = e,
+                }
+            }
+            if attempt == 0 {
+                self.refresh_topology()?;
+            }
+        }
+        Err(last_error)
+    }
+
+    // Connections are kept open per node and dropped after a transport error;
+    // errors reported by the node itself leave the connection usable.
+    fn request(&mut self, address: &str, message: &PeerMessage) -> io::Result<PeerMessage> {
+        if !self.connections.contains_key(address) {
+            let connection = PeerConnection::open(address, self.timeout)?;
+            self.connections.insert(address.to_string(), connection);
+        }
+        let result = self.connections.get_mut(address).unwrap().request(message);
+        if let Err(ref e) = result {
+            if e.kind() != io::ErrorKind::Other {
+                self.connections.remove(address);
+            }
+        }
+        result
+    }
+
+    // Opens an invalidation stream to the node on first use.
+    fn tracking_id(&mut self, address: &str) -> io::Result<Option<u64>> {
+        let cache = match &self.cache {
+            Some(cache) => cache.clone(),
+            None => return Ok(None),
+        };
+        if let Some(id) = cache.lock().unwrap().tracking.get(address) {
+            return Ok(Some(*id));
+        }
+        let mut stream = TcpStream::connect(address)?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        peer::send(&mut stream, &PeerMessage::TrackingSubscribe)?;
+        let id = match peer::receive(&mut reader)? {
+            Some(PeerMessage::TrackingId { id }) => id,
+            Some(other) => return Err(peer::unexpected(other)),
+            None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Peer closed the connection")),
+        };
+        stream.set_read_timeout(None)?;
+        cache.lock().unwrap().tracking.insert(address.to_string(), id);
+
+        let address = address.to_string();
+        thread::spawn(move || {
+            loop {
+                match peer::receive(&mut reader) {
+                    Ok(Some(PeerMessage::Invalidate { keys })) => {
+                        let mut cache = cache.lock().unwrap();
+                        for key in keys {
+                            cache.evict(&key);
+                        }
+                    }
+                    Ok(Some(_)) => {}
+                    Ok(None) | Err(_) => break,
+                }
+            }
+            let mut cache = cache.lock().unwrap();
+            cache.tracking.remove(&address);
+            cache.clear();
+        });
+        Ok(Some(id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use super::super::node::testing::start_cluster;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    // A key whose primary is the node at `address`.
+    fn key_on(client: &ClusterClient, address: &str) -> String {
+        (0..).map(|i| format!("k{}", i)).find(|key| client.owners(key)[0] == address).unwrap()
+    }
+
+    fn id_of(client: &ClusterClient, address: &str) -> Uuid {
+        client.members.iter().find(|(_, m)| m.address == address).map(|(id, _)| *id).unwrap()
+    }
+
+    #[test]
+    fn requests_go_to_the_nodes_that_own_the_key() {
+        let nodes = start_cluster(2, 1);
+        let mut client = ClusterClient::connect(&[&nodes[0]], TIMEOUT).unwrap();
+        assert_eq!(client.members.len(), 2);
+        for address in &nodes {
+            let key = key_on(&client, address);
+            client.put(&key, address, None).unwrap();
+            assert_eq!(client.get(&key).unwrap().unwrap().values, vec![address.clone()]);
+            // Only the owner has it; the other node turns the key away.
+            let other = nodes.iter().find(|a| *a != address).unwrap();
+            let reply = PeerConnection::open(other, TIMEOUT)
+                .unwrap()
+                .request(&PeerMessage::Get { key: key.clone(), tracking_id: None })
+                .unwrap();
+            assert!(matches!(reply, PeerMessage::WrongNode { owners } if owners == vec![address.clone()]));
+        }
+    }
+
+    #[test]
+    fn a_wrong_node_reply_refreshes_the_ring() {
+        let nodes = start_cluster(2, 1);
+        let mut client = ClusterClient::connect(&[&nodes[0]], TIMEOUT).unwrap();
+        let key = key_on(&client, &nodes[1]);
+        // A ring from before the second node joined.
+        client.ring = HashRing::new(vec![id_of(&client, &nodes[0])], 1);
+        assert_eq!(client.owners(&key), vec![nodes[0].clone()]);
+
+        client.put(&key, "1", None).unwrap();
+        assert_eq!(client.owners(&key), vec![nodes[1].clone()]);
+        assert_eq!(client.get(&key).unwrap().unwrap().values, vec!["1"]);
+    }
+
+    #[test]
+    fn a_down_primary_fails_over_to_its_replica() {
+        let nodes = start_cluster(2, 2);
+        let mut client = ClusterClient::connect(&[&nodes[0]], TIMEOUT).unwrap();
+        let key = key_on(&client, &nodes[0]);
+        client.put(&key, "1", None).unwrap();
+
+        let down = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().to_string();
+        let primary = id_of(&client, &nodes[0]);
+        client.members.get_mut(&primary).unwrap().address = down.clone();
+        assert_eq!(client.owners(&key), vec![down, nodes[1].clone()]);
+
+        let siblings = client.get(&key).unwrap().unwrap();
+        assert_eq!(siblings.values, vec!["1"]);
+        client.put(&key, "2", Some(&siblings.context)).unwrap();
+        assert_eq!(client.get(&key).unwrap().unwrap().values, vec!["2"]);
+    }
+
+    #[test]
+    fn a_connection_is_dropped_after_a_transport_error() {
+        let nodes = start_cluster(2, 1);
+        let mut client = ClusterClient::connect(&[&nodes[0]], TIMEOUT).unwrap();
+        let key = key_on(&client, &nodes[1]);
+        client.put(&key, "1", None).unwrap();
+
+        // Swap the cached connection for one the other end hangs up on.
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broken = PeerConnection::open(&listener.local_addr().unwrap().to_string(), TIMEOUT).unwrap();
+        drop(listener.accept().unwrap());
+        client.connections.insert(nodes[1].clone(), broken);
+        let error = client.request(&nodes[1], &PeerMessage::Topology).err().unwrap();
+        assert_ne!(error.kind(), io::ErrorKind::Other);
+        assert!(!client.connections.contains_key(&nodes[1]));
+
+        // The next request opens a fresh connection.
+        assert_eq!(client.get(&key).unwrap().unwrap().values, vec!["1"]);
+        assert!(client.connections.contains_key(&nodes[1]));
+    }
+
+    #[test]
+    fn errors_from_the_node_keep_the_connection() {
+        let nodes = start_cluster(1, 1);
+        let mut client = ClusterClient::connect(&[&nodes[0]], TIMEOUT).unwrap();
+        let message = PeerMessage::Put { key: "k".to_string(), value: "1".to_string(), context: Some("bad".to_string()), ttl_secs: None, origin: None };
+        let error = client.execute("k", message).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::Other);
+        assert!(client.connections.contains_key(&nodes[0]));
+    }
+}
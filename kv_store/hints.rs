@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+use super::clock::{self, Versioned};
+
+// A write that could not reach `target`, kept until it can be handed off.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hint {
+    pub target: Uuid,
+    pub key: String,
+    pub version: Versioned,
+    pub created_millis: u64,
+}
+
+// Hints are appended to a JSON-lines file as they are taken and the file is
+// rewritten whenever hints are delivered or expire, so a restart replays
+// exactly what is still pending.
+pub struct HintLog {
+    path: PathBuf,
+    ttl: Duration,
+    max_per_target: usize,
+    hints: Vec<Hint>,
+}
+
+impl HintLog {
+    pub fn open(path: PathBuf, ttl: Duration, max_per_target: usize) -> io::Result<Self> {
+        let mut hints = Vec::new();
+        if path.exists() {
+            let reader = BufReader::new(File::open(&path)?);
+            for line in reader.lines() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match serde_json::from_str::<Hint>(&line) {
+                    Ok(hint) => hints.push(hint),
+                    // A torn final line from a crash mid-append is dropped.
+                    Err(e) => println!("Skipping unreadable hint: {}", e),
+                }
+            }
+        }
+        let mut log = HintLog {
+            path,
+            ttl,
+            max_per_target,
+            hints,
+        };
+        log.expire()?;
+        Ok(log)
+    }
+
+    // Returns false when the target already has `max_per_target` hints queued;
+    // anti-entropy has to repair that replica instead.
+    pub fn record(&mut self, target: Uuid, key: String, version: Versioned) -> io::Result<bool> {
+        if self.pending_for(&target) >= self.max_per_target {
+            return Ok(false);
+        }
+        let hint = Hint {
+            target,
+            key,
+            version,
+            created_millis: clock::now_millis(),
+        };
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        let mut line = serde_json::to_string(&hint)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        self.hints.push(hint);
+        Ok(true)
+    }
+
+    pub fn pending_for(&self, target: &Uuid) -> usize {
+        self.hints.iter().filter(|h| h.target == *target).count()
+    }
+
+    pub fn pending_by_target(&self) -> HashMap<Uuid, usize> {
+        let mut counts = HashMap::new();
+        for hint in &self.hints {
+            *counts.entry(hint.target).or_insert(0) += 1;
+        }
+        counts
+    }
+
+    pub fn hints_for(&self, target: &Uuid) -> Vec<Hint> {
+        self.hints.iter().filter(|h| h.target == *target).cloned().collect()
+    }
+
+    // Drops hints that were delivered, matched by target, key and creation time.
+    pub fn remove_delivered(&mut self, delivered: &[Hint]) -> io::Result<()> {
+        self.hints.retain(|h| {
+            !delivered.iter().any(|d| {
+                d.target == h.target && d.key == h.key && d.created_millis == h.created_millis && d.version == h.version
+            })
+        });
+        self.rewrite()
+    }
+
+    pub fn expire(&mut self) -> io::Result<usize> {
+        let cutoff = clock::now_millis().saturating_sub(self.ttl.as_millis() as u64);
+        let before = self.hints.len();
+        self.hints.retain(|h| h.created_millis >= cutoff);
+        let expired = before - self.hints.len();
+        if expired > 0 {
+            self.rewrite()?;
+        }
+        Ok(expired)
+    }
+
+    fn rewrite(&self) -> io::Result<()> {
+        let temp_path = self.path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            for hint in &self.hints {
+                serde_json::to_writer(&mut writer, hint)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::clock::VectorClock;
+
+    fn temp_path() -> PathBuf {
+        std::env::temp_dir().join(format!("hints-{}.log", Uuid::new_v4()))
+    }
+
+    fn version(value: &str) -> Versioned {
+        Versioned::new(value.to_string(), VectorClock::new())
+    }
+
+    #[test]
+    fn pending_hints_survive_a_reopen() {
+        let path = temp_path();
+        let target = Uuid::new_v4();
+        let mut log = HintLog::open(path.clone(), Duration::from_secs(60), 10).unwrap();
+        assert!(log.record(target, "a".to_string(), version("1")).unwrap());
+        assert!(log.record(target, "b".to_string(), version("2")).unwrap());
+        let delivered = vec![log.hints_for(&target)[0].clone()];
+        log.remove_delivered(&delivered).unwrap();
+
+        let reopened = HintLog::open(path.clone(), Duration::from_secs(60), 10).unwrap();
+        let pending = reopened.hints_for(&target);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].key, "b");
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn torn_last_line_is_skipped() {
+        let path = temp_path();
+        let target = Uuid::new_v4();
+        let mut log = HintLog::open(path.clone(), Duration::from_secs(60), 10).unwrap();
+        log.record(target, "a".to_string(), version("1")).unwrap();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"target\":").unwrap();
+
+        let reopened = HintLog::open(path.clone(), Duration::from_secs(60), 10).unwrap();
+        assert_eq!(reopened.pending_for(&target), 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn hints_are_capped_per_target() {
+        let path = temp_path();
+        let (full, other) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut log = HintLog::open(path.clone(), Duration::from_secs(60), 1).unwrap();
+        assert!(log.record(full, "a".to_string(), version("1")).unwrap());
+        assert!(!log.record(full, "b".to_string(), version("2")).unwrap());
+        assert!(log.record(other, "b".to_string(), version("2")).unwrap());
+        assert_eq!(log.pending_by_target()[&full], 1);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn old_hints_expire() {
+        let path = temp_path();
+        let target = Uuid::new_v4();
+        let mut log = HintLog::open(path.clone(), Duration::ZERO, 10).unwrap();
+        log.record(target, "a".to_string(), version("1")).unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(log.expire().unwrap(), 1);
+        assert_eq!(log.pending_for(&target), 0);
+        fs::remove_file(path).unwrap();
+    }
+}
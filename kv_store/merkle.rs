@@ -0,0 +1,210 @@
+use std::collections::BTreeMap;
+use super::clock::Versioned;
+use super::ring::{self, TokenRange};
+
+// Leaves split the key hash space into fixed ranges; must be a power of two.
+pub const LEAF_COUNT: usize = 1024;
+
+// FNV-1a, so digests agree between nodes regardless of build or platform.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+pub fn leaf_for(key: &str) -> usize {
+    fnv1a(key.as_bytes()) as usize % LEAF_COUNT
+}
+
+// Digest of everything stored under a key, independent of sibling order.
+pub fn key_digest(key: &str, versions: &[Versioned]) -> u64 {
+    let mut sibling_digests: Vec<u64> = versions
+        .iter()
+        .map(|version| {
+            let mut bytes = version.value.as_bytes().to_vec();
+            bytes.push(version.deleted as u8);
+            bytes.extend_from_slice(&version.expires_millis.unwrap_or(0).to_be_bytes());
+            for (node_id, counter) in version.clock.entries() {
+                bytes.extend_from_slice(node_id.as_bytes());
+                bytes.extend_from_slice(&counter.to_be_bytes());
+            }
+            fnv1a(&bytes)
+        })
+        .collect();
+    sibling_digests.sort_unstable();
+    let mut bytes = key.as_bytes().to_vec();
+    for digest in sibling_digests {
+        bytes.extend_from_slice(&digest.to_be_bytes());
+    }
+    fnv1a(&bytes)
+}
+
+pub struct MerkleTree {
+    leaves: Vec<u64>,
+}
+
+impl Default for MerkleTree {
+    fn default() -> Self {
+        MerkleTree::new()
+    }
+}
+
+impl MerkleTree {
+    pub fn new() -> Self {
+        MerkleTree {
+            leaves: vec![0; LEAF_COUNT],
+        }
+    }
+
+    // Leaf hashes are the XOR of their key digests, so a write only has to
+    // swap the key's old digest for its new one.
+    pub fn update(&mut self, key: &str, old_digest: Option<u64>, new_digest: Option<u64>) {
+        let leaf = &mut self.leaves[leaf_for(key)];
+        if let Some(digest) = old_digest {
+            *leaf ^= digest;
+        }
+        if let Some(digest) = new_digest {
+            *leaf ^= digest;
+        }
+    }
+
+    // Level 0 is the root, the last level holds the leaves.
+    pub fn levels(&self) -> Vec<Vec<u64>> {
+        let mut levels = vec![self.leaves.clone()];
+        while levels[0].len() > 1 {
+            let parents = levels[0]
+                .chunks(2)
+                .map(|pair| {
+                    let mut bytes = pair[0].to_be_bytes().to_vec();
+                    bytes.extend_from_slice(&pair[1].to_be_bytes());
+                    fnv1a(&bytes)
+                })
+                .collect();
+            levels.insert(0, parents);
+        }
+        levels
+    }
+
+    pub fn root(&self) -> u64 {
+        self.levels()[0][0]
+    }
+}
+
+// One tree per token range this node replicates, so each range is compared
+// only with the other replicas of that same range.
+#[derive(Default)]
+pub struct RangeTrees {
+    // Keyed by range end, the token the ring assigns owners by.
+    trees: BTreeMap<u64, (TokenRange, MerkleTree)>,
+}
+
+impl RangeTrees {
+    pub fn new() -> Self {
+        RangeTrees::default()
+    }
+
+    pub fn ranges(&self) -> Vec<TokenRange> {
+        self.trees.values().map(|(range, _)| *range).collect()
+    }
+
+    // Starts over with an empty tree per range; the caller feeds the keys
+    // back in with `update`.
+    pub fn reset(&mut self, ranges: &[TokenRange]) {
+        self.trees = ranges.iter().map(|range| (range.end, (*range, MerkleTree::new()))).collect();
+    }
+
+    // Keys outside every tracked range are not replicated here and are skipped.
+    pub fn update(&mut self, key: &str, old_digest: Option<u64>, new_digest: Option<u64>) {
+        let token = ring::key_token(key);
+        let end = match self.trees.range(token..).next().or_else(|| self.trees.iter().next()) {
+            Some((end, _)) => *end,
+            None => return,
+        };
+        let (range, tree) = self.trees.get_mut(&end).expect("range was just found");
+        if range.contains(token) {
+            tree.update(key, old_digest, new_digest);
+        }
+    }
+
+    pub fn levels(&self, range: &TokenRange) -> Option<Vec<Vec<u64>>> {
+        self.trees.get(&range.end).filter(|(tracked, _)| tracked == range).map(|(_, tree)| tree.levels())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::clock::VectorClock;
+
+    fn version(value: &str) -> Vec<Versioned> {
+        let mut clock = VectorClock::new();
+        clock.increment("a");
+        vec![Versioned::new(value.to_string(), clock)]
+    }
+
+    fn tree_of(entries: &[(&str, &str)]) -> MerkleTree {
+        let mut tree = MerkleTree::new();
+        for (key, value) in entries {
+            tree.update(key, None, Some(key_digest(key, &version(value))));
+        }
+        tree
+    }
+
+    // Leaves that differ, found the way `sync_range` walks down the levels.
+    fn differing_leaves(mine: &MerkleTree, theirs: &MerkleTree) -> Vec<usize> {
+        let (mine, theirs) = (mine.levels(), theirs.levels());
+        let mut differing = vec![0];
+        for level in 0..mine.len() {
+            differing.retain(|i| mine[level][*i] != theirs[level][*i]);
+            if level + 1 < mine.len() {
+                differing = differing.iter().flat_map(|i| vec![2 * i, 2 * i + 1]).collect();
+            }
+        }
+        differing
+    }
+
+    #[test]
+    fn same_contents_have_the_same_root_in_any_order() {
+        let one = tree_of(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let other = tree_of(&[("c", "3"), ("a", "1"), ("b", "2")]);
+        assert_eq!(one.root(), other.root());
+        assert!(differing_leaves(&one, &other).is_empty());
+    }
+
+    #[test]
+    fn diff_finds_only_the_changed_leaf() {
+        let one = tree_of(&[("a", "1"), ("b", "2"), ("c", "3")]);
+        let other = tree_of(&[("a", "1"), ("b", "changed"), ("c", "3")]);
+        assert_ne!(one.root(), other.root());
+        assert_eq!(differing_leaves(&one, &other), vec![leaf_for("b")]);
+    }
+
+    #[test]
+    fn update_replaces_the_old_digest() {
+        let mut tree = tree_of(&[("a", "1"), ("b", "2")]);
+        let old = key_digest("b", &version("2"));
+        tree.update("b", Some(old), Some(key_digest("b", &version("3"))));
+        assert_eq!(tree.root(), tree_of(&[("a", "1"), ("b", "3")]).root());
+        tree.update("b", Some(key_digest("b", &version("3"))), None);
+        assert_eq!(tree.root(), tree_of(&[("a", "1")]).root());
+    }
+
+    #[test]
+    fn range_trees_only_track_their_ranges() {
+        let token = ring::key_token("inside");
+        let inside = TokenRange { start: token - 1, end: token };
+        let mut trees = RangeTrees::new();
+        trees.reset(&[inside]);
+        let empty = trees.levels(&inside).unwrap();
+
+        trees.update("outside", None, Some(key_digest("outside", &version("1"))));
+        assert_eq!(trees.levels(&inside).unwrap(), empty);
+        trees.update("inside", None, Some(key_digest("inside", &version("1"))));
+        assert_ne!(trees.levels(&inside).unwrap(), empty);
+
+        assert!(trees.levels(&TokenRange { start: token - 2, end: token }).is_none());
+    }
+}
@@ -1,52 +1,238 @@
+pub mod async_client;
+pub mod cache;
+pub mod clock;
+pub mod cluster_client;
+pub mod codec;
+pub mod hints;
+pub mod merkle;
+pub mod node;
+pub mod peer;
+pub mod pubsub;
+pub mod rebalance;
+pub mod replication;
+pub mod ring;
+#[path = "../script.rs"]
+pub mod script;
+#[path = "../sharded.rs"]
+pub mod sharded;
+pub mod tracking;
+#[path = "../value.rs"]
+pub mod value;
+pub mod wal;
+pub mod watch;
+
+#[allow(clippy::module_inception)]
 pub mod kv_store {
     pub mod storage {
+        use serde::{Deserialize, Serialize};
         use std::collections::HashMap;
         use std::env;
+        use std::fs::{File, OpenOptions};
+        use std::io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write};
+        use std::path::PathBuf;
+        use std::sync::Mutex;
+        use super::super::cache::{Cache, CacheStats, EvictionPolicy};
+        use super::super::value::{Value, WrongType};
+
+        // Strings are written bare, as before typed values existed, so older
+        // logs still load.
+        #[derive(Serialize, Deserialize)]
+        #[serde(untagged)]
+        enum StoredValue {
+            Plain(String),
+            Typed(Value),
+        }
+
+        impl From<Value> for StoredValue {
+            fn from(value: Value) -> Self {
+                match value {
+                    Value::String(s) => StoredValue::Plain(s),
+                    value => StoredValue::Typed(value),
+                }
+            }
+        }
+
+        impl From<StoredValue> for Value {
+            fn from(stored: StoredValue) -> Self {
+                match stored {
+                    StoredValue::Plain(s) => Value::String(s),
+                    StoredValue::Typed(value) => value,
+                }
+            }
+        }
 
+        #[derive(Serialize, Deserialize)]
+        struct Record {
+            key: String,
+            value: StoredValue,
+        }
+
+        // Records live in an append-only file; only their offsets stay in
+        // memory, plus whatever the bounded read cache holds, as JSON.
+        // Overwritten records are left in the file. This is a library engine:
+        // the node and the standalone server keep their own stores and do not
+        // use it.
         pub struct Storage {
-            records: HashMap<String, String>,
-            cache: HashMap<String, String>, // Enhanced naming for clarity
+            file: Mutex<File>,
+            index: HashMap<String, (u64, usize)>,
+            end: u64,
+            cache: Mutex<Cache>,
         }
 
         impl Storage {
-            // Function names and variable names have been updated for clarity
-            pub fn init() -> Self {
+            // Configured by STORAGE_PATH, STORAGE_CACHE_BYTES and
+            // STORAGE_CACHE_POLICY (lru, lfu or tinylfu).
+            pub fn init() -> io::Result<Self> {
+                let path = PathBuf::from(env::var("STORAGE_PATH").unwrap_or_else(|_| "storage.log".to_string()));
+                let cache_bytes = env::var("STORAGE_CACHE_BYTES")
+                    .ok()
+                    .and_then(|s| s.parse().ok())
+                    .unwrap_or(64 * 1024 * 1024);
+                let policy = match env::var("STORAGE_CACHE_POLICY") {
+                    Ok(name) => EvictionPolicy::parse(&name)
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown cache policy: {}", name)))?,
+                    Err(_) => EvictionPolicy::Lru,
+                };
+
+                let mut file = OpenOptions::new().read(true).append(true).create(true).open(&path)?;
+                let mut index = HashMap::new();
+                let mut end = 0;
+                {
+                    let mut reader = BufReader::new(&mut file);
+                    let mut line = String::new();
+                    loop {
+                        line.clear();
+                        let read = reader.read_line(&mut line)?;
+                        if read == 0 {
+                            break;
+                        }
+                        let record: Record = match serde_json::from_str(&line) {
+                            Ok(record) if line.ends_with('\n') => record,
+                            // Only the final record can be torn by a crash
+                            // mid-append; it is cut off below. A bad record
+                            // anywhere else is corruption and fails startup
+                            // rather than dropping everything after it.
+                            _ if reader.fill_buf()?.is_empty() => break,
+                            _ => {
+                                return Err(io::Error::new(
+                                    io::ErrorKind::InvalidData,
+                                    format!("Corrupt record in {} at byte {}", path.display(), end),
+                                ))
+                            }
+                        };
+                        index.insert(record.key, (end, read));
+                        end += read as u64;
+                    }
+                }
+                file.set_len(end)?;
+
                 let mut datastore = Storage {
-                    records: HashMap::new(),
-                    cache: HashMap::new(),
+                    file: Mutex::new(file),
+                    index,
+                    end,
+                    cache: Mutex::new(Cache::new(cache_bytes, policy)),
                 };
 
                 if let Ok(env_records) = env::var("STORAGE_INITIAL_DATA") {
                     for entry in env_records.split(',') {
                         let mut parts = entry.split('=');
                         if let (Some(key), Some(value)) = (parts.next(), parts.next()) {
-                            datastore.store(key.to_string(), value.to_string());
+                            datastore.store(key.to_string(), value.to_string())?;
                         }
                     }
                 }
 
-                datastore
+                Ok(datastore)
             }
 
-            // Renamed 'set' to 'store' for better clarity on the action being performed
-            pub fn store(&mut self, key: String, value: String) {
-                self.records.insert(key.clone(), value.clone());
-                self.cache.insert(key, value); // Update cache as well when setting a new value
+            pub fn store(&mut self, key: String, value: String) -> io::Result<()> {
+                self.store_value(key, Value::String(value))
+            }
+
+            pub fn store_value(&mut self, key: String, value: Value) -> io::Result<()> {
+                let mut line = serde_json::to_string(&Record { key: key.clone(), value: value.into() })?;
+                line.push('\n');
+                let mut file = self.file.lock().unwrap();
+                // A failed append is cut back off, so the next one does not
+                // land after a partial record.
+                if let Err(e) = file.write_all(line.as_bytes()).and_then(|_| file.sync_data()) {
+                    let _ = file.set_len(self.end);
+                    return Err(e);
+                }
+                self.index.insert(key.clone(), (self.end, line.len()));
+                self.end += line.len() as u64;
+                // The next read caches the new value.
+                self.cache.lock().unwrap().remove(&key);
+                Ok(())
             }
 
-            // Precision in naming maintained, but comments added for clarity
-            pub fn retrieve(&self, key: &str) -> Option<&String> {
-                // Try to fetch from cache first
-                if let Some(value) = self.cache.get(key) {
-                    return Some(value);
+            // Fails for keys holding anything but a string.
+            pub fn retrieve(&self, key: &str) -> io::Result<Option<String>> {
+                match self.retrieve_value(key)? {
+                    Some(Value::String(s)) => Ok(Some(s)),
+                    Some(_) => Err(io::Error::new(io::ErrorKind::InvalidData, WrongType.to_string())),
+                    None => Ok(None),
                 }
-                // Fallback to main records and update cache
-                if let Some(value) = self.records.get(key) {
-                    // Cloning to update cache without altering the method signature
-                    self.cache.insert(key.to_string(), value.clone());
-                    return Some(value);
+            }
+
+            pub fn retrieve_value(&self, key: &str) -> io::Result<Option<Value>> {
+                if let Some(json) = self.cache.lock().unwrap().get(key) {
+                    let stored: StoredValue = serde_json::from_str(&json)?;
+                    return Ok(Some(stored.into()));
+                }
+                let (offset, len) = match self.index.get(key) {
+                    Some(location) => *location,
+                    None => return Ok(None),
+                };
+                let mut buffer = vec![0; len];
+                {
+                    let mut file = self.file.lock().unwrap();
+                    file.seek(SeekFrom::Start(offset))?;
+                    file.read_exact(&mut buffer)?;
                 }
-                None
+                let record: Record = serde_json::from_slice(&buffer)?;
+                self.cache.lock().unwrap().insert(record.key, serde_json::to_string(&record.value)?);
+                Ok(Some(record.value.into()))
+            }
+
+            pub fn cache_stats(&self) -> CacheStats {
+                self.cache.lock().unwrap().stats()
+            }
+        }
+
+        #[cfg(test)]
+        mod tests {
+            use super::*;
+            use std::fs;
+
+            // Storage is configured through the environment, so everything
+            // touching STORAGE_PATH lives in this one test.
+            #[test]
+            fn records_survive_a_restart_and_only_a_torn_tail_is_dropped() {
+                let path = env::temp_dir().join(format!("storage-{}.log", uuid::Uuid::new_v4()));
+                env::set_var("STORAGE_PATH", &path);
+                let mut storage = Storage::init().unwrap();
+                storage.store("a".to_string(), "1".to_string()).unwrap();
+                storage.store_value("s".to_string(), Value::Set(std::iter::once("x".to_string()).collect())).unwrap();
+                storage.store("a".to_string(), "2".to_string()).unwrap();
+                assert_eq!(storage.retrieve("a").unwrap().as_deref(), Some("2"));
+                assert!(storage.retrieve("s").is_err());
+                drop(storage);
+
+                OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"key\":\"b\",\"va").unwrap();
+                let mut storage = Storage::init().unwrap();
+                assert_eq!(storage.retrieve("a").unwrap().as_deref(), Some("2"));
+                assert!(matches!(storage.retrieve_value("s").unwrap(), Some(Value::Set(_))));
+                assert_eq!(storage.retrieve("b").unwrap(), None);
+                storage.store("b".to_string(), "3".to_string()).unwrap();
+                drop(storage);
+                assert_eq!(Storage::init().unwrap().retrieve("b").unwrap().as_deref(), Some("3"));
+
+                let contents = fs::read_to_string(&path).unwrap();
+                fs::write(&path, contents.replacen("\"a\"", "\"a", 1)).unwrap();
+                assert_eq!(Storage::init().err().unwrap().kind(), io::ErrorKind::InvalidData);
+                env::remove_var("STORAGE_PATH");
+                fs::remove_file(path).unwrap();
             }
         }
     }
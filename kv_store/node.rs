@@ -1,69 +1,940 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{self, BufReader, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use uuid::Uuid;
-use std::net::TcpListener;
+use std::net::{Shutdown, TcpListener, TcpStream};
 use std::env;
+use super::clock::{self, CausalContext, Origin, Resolver, Versioned};
+use super::hints::{Hint, HintLog};
+use super::merkle::{self, RangeTrees};
+use super::peer::{self, Delta, Member, NodeStats, PeerConnection, PeerMessage, PeerPool};
+use super::pubsub::PubSub;
+use super::rebalance::{Direction, RebalanceState, Throttle, Transfer};
+use super::ring::{self, HashRing, TokenRange};
+use super::script::{self, Script, ScriptLimits, ScriptStore, Scripts, Val};
+use super::sharded::{self, ShardGuards, ShardedMap};
+use super::tracking::InvalidationTable;
+use super::wal::{Retention, Wal};
+use super::watch::{ChangeEvent, Expiring, WatchTarget};
 
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(100);
+// How long a subscriber waits at most to hear that messages were dropped.
+const DROP_NOTICE_INTERVAL: Duration = Duration::from_millis(100);
+const FORWARD_QUEUE_SIZE: usize = 10_000;
+
+// Everything a read returns: all concurrent values plus the context to write back with.
+#[derive(Debug, Clone)]
+pub struct Siblings {
+    pub values: Vec<String>,
+    pub context: CausalContext,
+}
+
+// When a quorum read finds replicas disagreeing, whether to push the
+// reconciled version back to the stale ones.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ReadRepair {
+    Always,
+    Percent(u8),
+    Off,
+}
+
+impl ReadRepair {
+    fn parse(setting: &str) -> Option<Self> {
+        match setting.trim().to_lowercase().as_str() {
+            "always" => Some(ReadRepair::Always),
+            "off" => Some(ReadRepair::Off),
+            percent => percent.trim_end_matches('%').parse().ok().filter(|p| *p <= 100).map(ReadRepair::Percent),
+        }
+    }
+
+    fn should_repair(&self) -> bool {
+        match self {
+            ReadRepair::Always => true,
+            ReadRepair::Off => false,
+            ReadRepair::Percent(percent) => (random_u64() % 100) < *percent as u64,
+        }
+    }
+}
+
+fn random_u64() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+#[derive(Clone)]
 struct Node {
     id: Uuid,
     address: String,
-    data: Arc<Mutex<HashMap<String, String>>>,
-    cache: Arc<Mutex<HashMap<String, String>>>, 
+    data: Arc<ShardedMap<String, Vec<Versioned>>>,
+    resolvers: Arc<Mutex<HashMap<String, Resolver>>>,
+    trees: Arc<Mutex<RangeTrees>>,
+    members: Arc<Mutex<HashMap<Uuid, Member>>>,
+    pool: Arc<PeerPool>,
+    stats: Arc<Mutex<NodeStats>>,
+    hints: Arc<Mutex<HintLog>>,
+    read_quorum: usize,
+    read_repair: ReadRepair,
+    replication_factor: usize,
+    rebalance: Arc<Mutex<RebalanceState>>,
+    throttle: Throttle,
+    leave_timeout: Duration,
+    tracking: Arc<Mutex<InvalidationTable>>,
+    expiring: Arc<Mutex<Expiring>>,
+    pubsub: Arc<Mutex<PubSub>>,
+    // Messages published by this node's clients, waiting to go to the others.
+    forwards: SyncSender<PeerMessage>,
+    wal: Arc<Mutex<Wal>>,
+    scripts: Arc<Scripts>,
 }
 
 impl Node {
-    fn new(id: Uuid, address: String) -> Self {
+    fn new(id: Uuid, address: String, hints: HintLog, rebalance: RebalanceState, wal: Wal) -> Self {
+        let members = Arc::new(Mutex::new(HashMap::new()));
+        let pool = Arc::new(PeerPool::new(PEER_TIMEOUT));
+        let (forwards, queued) = mpsc::sync_channel(FORWARD_QUEUE_SIZE);
+        Self::start_forwarder(queued, members.clone(), pool.clone());
         Node {
             id,
             address,
-            data: Arc::new(Mutex::new(HashMap::new())),
-            cache: Arc::new(Mutex::new(HashMap::new())),
+            data: Arc::new(ShardedMap::with_shards(sharded::default_shard_count())),
+            resolvers: Arc::new(Mutex::new(HashMap::new())),
+            trees: Arc::new(Mutex::new(RangeTrees::new())),
+            members,
+            pool,
+            stats: Arc::new(Mutex::new(NodeStats::default())),
+            hints: Arc::new(Mutex::new(hints)),
+            read_quorum: 2,
+            read_repair: ReadRepair::Always,
+            replication_factor: 3,
+            rebalance: Arc::new(Mutex::new(rebalance)),
+            throttle: Throttle { batch_size: 500, keys_per_sec: 5_000 },
+            leave_timeout: Duration::from_secs(600),
+            tracking: Arc::new(Mutex::new(InvalidationTable::new())),
+            expiring: Arc::new(Mutex::new(Expiring::default())),
+            pubsub: Arc::new(Mutex::new(PubSub::new(1_000))),
+            forwards,
+            wal: Arc::new(Mutex::new(wal)),
+            scripts: Arc::new(Scripts::new(ScriptLimits::from_env().expect("Invalid script limit settings"))),
+        }
+    }
+
+    // Loads the data recovered from the write-ahead log at startup. The
+    // range trees pick it up when they are first built.
+    fn restore(&self, recovered: HashMap<String, Vec<Versioned>>) {
+        for (key, versions) in recovered {
+            self.data.lock(&key).insert(key, versions);
+        }
+    }
+
+    fn ring(&self, include_self: bool) -> HashRing {
+        let mut nodes: Vec<Uuid> = self.members.lock().unwrap().keys().cloned().collect();
+        if include_self {
+            nodes.push(self.id);
+        }
+        HashRing::new(nodes, self.replication_factor)
+    }
+
+    // The ranges this node replicates, each with all of its owners.
+    fn owned_ranges(&self) -> Vec<(TokenRange, Vec<Uuid>)> {
+        self.ring(true).ranges().into_iter().filter(|(_, owners)| owners.contains(&self.id)).collect()
+    }
+
+    // Rebuilds the range trees from the data when the ring has moved since
+    // they were last built.
+    fn refresh_trees(&self, ranges: &[TokenRange]) {
+        if self.trees.lock().unwrap().ranges() == ranges {
+            return;
+        }
+        // Shards before trees, the same order writes take them in.
+        let shards = self.data.lock_all();
+        let mut trees = self.trees.lock().unwrap();
+        trees.reset(ranges);
+        for (key, siblings) in shards.iter().flat_map(|data| data.iter()) {
+            trees.update(key, None, Self::digest_of(key, siblings));
+        }
+    }
+
+    // The other nodes that hold replicas of `key`.
+    fn replica_peers(&self, key: &str) -> Vec<(Uuid, Member)> {
+        let owners = self.ring(true).owners(key);
+        let members = self.members.lock().unwrap();
+        owners
+            .into_iter()
+            .filter(|id| *id != self.id)
+            .filter_map(|id| members.get(&id).map(|m| (id, m.clone())))
+            .collect()
+    }
+
+    // Learns the cluster from `seed_address`, then plans pulling every range
+    // this node now owns from its previous owner. The transfers run in the
+    // background while the node keeps serving traffic.
+    fn join(&self, seed_address: &str) -> io::Result<()> {
+        println!("Joining cluster through {}", seed_address);
+        let mut connection = PeerConnection::open(seed_address, PEER_TIMEOUT)?;
+        let members = match connection.request(&PeerMessage::Join { id: self.id, address: self.address.clone() })? {
+            PeerMessage::Members(members) => members,
+            other => return Err(peer::unexpected(other)),
+        };
+        self.members.lock().unwrap().extend(members.into_iter().filter(|(id, _)| *id != self.id));
+
+        let before = self.ring(false);
+        let after = self.ring(true);
+        let members = self.members.lock().unwrap().clone();
+        let mut transfers = Vec::new();
+        for (range, owners) in after.ranges() {
+            if !owners.contains(&self.id) {
+                continue;
+            }
+            let source = before
+                .owners_of_token(range.end)
+                .into_iter()
+                .filter_map(|id| members.get(&id).map(|m| (id, m)))
+                .find(|(_, m)| m.alive);
+            if let Some((id, member)) = source {
+                transfers.push(Transfer {
+                    range,
+                    direction: Direction::Incoming,
+                    peer: id,
+                    peer_address: member.address.clone(),
+                    cursor: None,
+                    keys_moved: 0,
+                    done: false,
+                });
+            }
+        }
+        let mut state = self.rebalance.lock().unwrap();
+        state.transfers.extend(transfers);
+        state.save()
+    }
+
+    // Hands every range this node owns to the nodes that take it over once
+    // it is gone, waits for the handoff to finish, then tells the cluster.
+    // Gives up, still a member, if the handoff outlasts `leave_timeout`.
+    fn leave(&self) -> io::Result<()> {
+        let current = self.ring(true);
+        let after = self.ring(false);
+        let members = self.members.lock().unwrap().clone();
+        {
+            let mut state = self.rebalance.lock().unwrap();
+            for (range, owners) in current.ranges() {
+                if !owners.contains(&self.id) {
+                    continue;
+                }
+                for successor in after.owners_of_token(range.end) {
+                    if owners.contains(&successor) {
+                        continue;
+                    }
+                    if let Some(member) = members.get(&successor) {
+                        state.transfers.push(Transfer {
+                            range,
+                            direction: Direction::Outgoing,
+                            peer: successor,
+                            peer_address: member.address.clone(),
+                            cursor: None,
+                            keys_moved: 0,
+                            done: false,
+                        });
+                    }
+                }
+            }
+            state.save()?;
+        }
+
+        // The rebalancer thread does the streaming; wait for it to drain.
+        let deadline = Instant::now() + self.leave_timeout;
+        while !self.rebalance.lock().unwrap().pending().is_empty() {
+            if Instant::now() >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("Handoff did not finish within {}s", self.leave_timeout.as_secs()),
+                ));
+            }
+            thread::sleep(Duration::from_secs(1));
         }
+        for member in members.values() {
+            let message = PeerMessage::Leave { id: self.id };
+            if let Err(e) = PeerConnection::open(&member.address, PEER_TIMEOUT).and_then(|mut c| c.request(&message)) {
+                println!("Could not tell {} we are leaving: {}", member.address, e);
+            }
+        }
+        Ok(())
     }
 
-    fn discover(&self) -> Vec<Node> {
-        vec![]
+    fn start_rebalancer(&self, interval: Duration) {
+        let node = self.clone();
+        thread::spawn(move || loop {
+            let pending = node.rebalance.lock().unwrap().pending();
+            for index in &pending {
+                if let Err(e) = node.run_transfer(*index) {
+                    // Left pending with its cursor; the next round resumes it.
+                    println!("Rebalance transfer interrupted: {}", e);
+                }
+            }
+            // Once the ring has settled, keys this node lost are handed to
+            // their owners and dropped, a batch per round.
+            if pending.is_empty() {
+                if let Err(e) = node.release_unowned() {
+                    println!("Could not release unowned keys yet: {}", e);
+                }
+            }
+            if let Err(e) = node.rebalance.lock().unwrap().clear_finished() {
+                println!("Failed to save rebalance state: {}", e);
+            }
+            thread::sleep(interval);
+        });
     }
 
-    fn join(&mut self, other_node: &Node) {
-        println!("Joining node with address: {}", other_node.address);
+    fn run_transfer(&self, index: usize) -> io::Result<()> {
+        let transfer = self.rebalance.lock().unwrap().transfers[index].clone();
+        let mut connection = PeerConnection::open(&transfer.peer_address, PEER_TIMEOUT)?;
+        let mut cursor = transfer.cursor.clone();
+        loop {
+            let (moved, next) = match transfer.direction {
+                Direction::Incoming => {
+                    let request = PeerMessage::StreamRange {
+                        range: transfer.range,
+                        after: cursor.clone(),
+                        limit: self.throttle.batch_size,
+                    };
+                    match connection.request(&request)? {
+                        PeerMessage::RangeBatch { entries, next } => {
+                            let moved = entries.len();
+                            for (key, versions) in entries {
+                                self.apply_remote(key, versions)?;
+                            }
+                            (moved, next)
+                        }
+                        other => return Err(peer::unexpected(other)),
+                    }
+                }
+                Direction::Outgoing => {
+                    let (entries, next) = self.range_batch(transfer.range, cursor.as_deref(), self.throttle.batch_size);
+                    let moved = entries.len();
+                    if moved > 0 {
+                        connection.request(&PeerMessage::RangeBatch { entries, next: next.clone() })?;
+                    }
+                    (moved, next)
+                }
+            };
+
+            let mut state = self.rebalance.lock().unwrap();
+            let entry = &mut state.transfers[index];
+            entry.keys_moved += moved as u64;
+            entry.cursor = next.clone();
+            entry.done = next.is_none();
+            state.save()?;
+            drop(state);
+
+            if next.is_none() {
+                return Ok(());
+            }
+            cursor = next;
+            thread::sleep(self.throttle.pause_for(moved));
+        }
     }
 
-    fn leave(&mut self) {
+    // Pushes a batch of keys this node no longer replicates to their owners
+    // and drops the local copies once every owner has them. Versions written
+    // meanwhile are kept for the next round.
+    fn release_unowned(&self) -> io::Result<usize> {
+        let ring = self.ring(true);
+        let unowned: Vec<(String, Vec<Versioned>)> = {
+            let shards = self.data.lock_all();
+            shards
+                .iter()
+                .flat_map(|data| data.iter())
+                .filter(|(key, siblings)| !siblings.is_empty() && !ring.owners(key).contains(&self.id))
+                .take(self.throttle.batch_size)
+                .map(|(key, siblings)| (key.clone(), siblings.clone()))
+                .collect()
+        };
+        if unowned.is_empty() {
+            return Ok(0);
+        }
+        let mut by_owner: HashMap<Uuid, Vec<(String, Vec<Versioned>)>> = HashMap::new();
+        for (key, siblings) in &unowned {
+            for owner in ring.owners(key) {
+                by_owner.entry(owner).or_default().push((key.clone(), siblings.clone()));
+            }
+        }
+        let members = self.members.lock().unwrap().clone();
+        for (owner, entries) in by_owner {
+            let address = match members.get(&owner) {
+                Some(member) if member.alive => &member.address,
+                _ => return Err(io::Error::new(io::ErrorKind::NotConnected, format!("Owner {} is down", owner))),
+            };
+            self.pool.request(address, &PeerMessage::Repair(entries))?;
+        }
+
+        let mut released = 0;
+        for (key, handed_off) in unowned {
+            let mut data = self.data.lock(&key);
+            if data.get(&key) != Some(&handed_off) {
+                continue;
+            }
+            // Logged with no versions, so a restart does not bring it back.
+            self.log_change(&key, Self::context_of(&handed_off), &[])?;
+            data.remove(&key);
+            self.trees.lock().unwrap().update(&key, Self::digest_of(&key, &handed_off), None);
+            self.tracking.lock().unwrap().invalidate(&key);
+            released += 1;
+        }
+        thread::sleep(self.throttle.pause_for(released));
+        Ok(released)
     }
 
-    fn handle_task(&self, task: &str) {
-        println!("Handling task: {}", task);
+    // Keys in `range` after `after`, in key order. `next` is the cursor to
+    // continue from, or `None` when this batch finishes the range.
+    fn range_batch(
+        &self,
+        range: TokenRange,
+        after: Option<&str>,
+        limit: usize,
+    ) -> (Vec<(String, Vec<Versioned>)>, Option<String>) {
+        let shards = self.data.lock_all();
+        let mut entries: Vec<(&String, &Vec<Versioned>)> = shards
+            .iter()
+            .flat_map(|data| data.iter())
+            .filter(|(key, siblings)| !siblings.is_empty() && range.contains(ring::key_token(key)))
+            .filter(|(key, _)| after.is_none_or(|after| key.as_str() > after))
+            .collect();
+        entries.sort_by(|a, b| a.0.cmp(b.0));
+        let more = entries.len() > limit;
+        entries.truncate(limit);
+        let next = if more { entries.last().map(|(k, _)| k.to_string()) } else { None };
+        let entries = entries.into_iter().map(|(key, siblings)| (key.clone(), siblings.clone())).collect();
+        (entries, next)
     }
-  
-    fn set(&self, key: String, value: String) {
-        let mut data = self.data.lock().unwrap();
-        data.insert(key, value);
+
+    // Writes coordinated by this node. Siblings covered by `context` are
+    // superseded; anything the client has not seen survives as a sibling.
+    fn put(
+        &self,
+        key: String,
+        value: String,
+        context: Option<&CausalContext>,
+        ttl: Option<Duration>,
+        origin: Option<&Origin>,
+    ) -> io::Result<CausalContext> {
+        let (context, version) = self.put_local(&key, Some(value), context, ttl, origin)?;
+        self.replicate(&key, version);
+        Ok(context)
     }
 
-    fn get(&self, key: &str) -> Option<String> {
-        let data = self.data.lock().unwrap();
-        data.get(key).cloned()
+    fn delete(&self, key: String, context: Option<&CausalContext>, origin: Option<&Origin>) -> io::Result<CausalContext> {
+        let (context, version) = self.put_local(&key, None, context, None, origin)?;
+        self.replicate(&key, version);
+        Ok(context)
     }
 
-    fn expensive_computation(&self, key: &str, value: &str) -> String {
-        let mut cache = self.cache.lock().unwrap();
-        if let Some(cached_result) = cache.get(key) {
-            return cached_result.clone();
+    // A `None` value writes a tombstone.
+    fn put_local(
+        &self,
+        key: &str,
+        value: Option<String>,
+        context: Option<&CausalContext>,
+        ttl: Option<Duration>,
+        origin: Option<&Origin>,
+    ) -> io::Result<(CausalContext, Versioned)> {
+        let mut data = self.data.lock(key);
+        self.write_locked(&mut data, key, value, context, ttl, origin)
+    }
+
+    fn write_locked(
+        &self,
+        data: &mut HashMap<String, Vec<Versioned>>,
+        key: &str,
+        value: Option<String>,
+        context: Option<&CausalContext>,
+        ttl: Option<Duration>,
+        origin: Option<&Origin>,
+    ) -> io::Result<(CausalContext, Versioned)> {
+        let mut new_clock = context
+            .and_then(|c| c.clock().ok())
+            .unwrap_or_default();
+        let node_id = self.id.to_string();
+        let current = data.get(key).map(Vec::as_slice).unwrap_or(&[]);
+        let old_digest = Self::digest_of(key, current);
+        let old_context = Self::context_of(current);
+        // Issue past every counter this node has used for the key, so a write
+        // never collides with an existing version nor supersedes one its
+        // context has not seen.
+        let issued = current.iter().map(|s| s.clock.issued_by(&node_id)).max().unwrap_or(0);
+        new_clock.advance(&node_id, issued);
+        let mut version = match value {
+            Some(value) => Versioned::new(value, new_clock),
+            None => Versioned::tombstone(new_clock),
+        };
+        if let Some(origin) = origin {
+            version.timestamp = origin.timestamp;
+            version.origin = Some(origin.cluster.clone());
+        }
+        version.expires_millis = ttl.map(|ttl| clock::now_millis() + ttl.as_millis() as u64);
+        let mut siblings = current.to_vec();
+        clock::reconcile(&mut siblings, version.clone());
+        let siblings = self.resolver_for(key).resolve(siblings);
+        self.log_change(key, old_context, &siblings)?;
+        self.trees.lock().unwrap().update(key, old_digest, Self::digest_of(key, &siblings));
+        self.tracking.lock().unwrap().invalidate(key);
+        let context = CausalContext::from_clock(&clock::merged_clock(&siblings));
+        data.insert(key.to_string(), siblings);
+        Ok((context, version))
+    }
+
+    // Writes `value` only if the key currently holds exactly `expected`
+    // (or nothing, for `None`) on this node. Returns `None` if it did not.
+    fn compare_and_swap(&self, key: &str, expected: Option<String>, value: String) -> io::Result<Option<CausalContext>> {
+        let mut data = self.data.lock(key);
+        let current: Vec<&Versioned> = data.get(key).map(|s| s.iter().collect()).unwrap_or_default();
+        let now = clock::now_millis();
+        let live: Vec<&String> = current.iter().filter(|s| s.is_live(now)).map(|s| &s.value).collect();
+        let matches = match &expected {
+            Some(expected) => live.len() == 1 && live[0] == expected,
+            None => live.is_empty(),
+        };
+        if !matches {
+            return Ok(None);
         }
-        let expensive_result = format!("ExpensiveResultOf_{}", value);
-        cache.insert(key.to_string(), expensive_result.clone());
-        expensive_result
+        let seen = CausalContext::from_clock(&clock::merged_clock(data.get(key).map(|s| s.as_slice()).unwrap_or(&[])));
+        let (context, version) = self.write_locked(&mut data, key, Some(value), Some(&seen), None, None)?;
+        drop(data);
+        self.replicate(key, version);
+        Ok(Some(context))
+    }
+
+    // Adds `by` to the key's number under its lock, so increments made at
+    // this node never lose each other, and replicates the sum. Concurrent
+    // increments on two replicas still leave siblings, which later
+    // increments refuse until a write resolves them. Only single keys are
+    // supported: the node has no batches or transactions to run it in.
+    fn increment(&self, key: &str, by: Delta) -> io::Result<(String, CausalContext)> {
+        let mut data = self.data.lock(key);
+        let now = clock::now_millis();
+        let current = data.get(key).map(Vec::as_slice).unwrap_or(&[]);
+        let live: Vec<&Versioned> = current.iter().filter(|s| s.is_live(now)).collect();
+        let (value, expires_millis) = match live.as_slice() {
+            [] => ("0", None),
+            [only] => (only.value.as_str(), only.expires_millis),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "ERR key has concurrent values")),
+        };
+        let sum = by.apply(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("ERR {}", e)))?;
+        // The key keeps whatever time it had left to live.
+        let ttl = expires_millis.map(|expires| Duration::from_millis(expires.saturating_sub(now).max(1)));
+        let seen = CausalContext::from_clock(&clock::merged_clock(current));
+        let (context, version) = self.write_locked(&mut data, key, Some(sum.clone()), Some(&seen), ttl, None)?;
+        drop(data);
+        self.replicate(key, version);
+        Ok((sum, context))
+    }
+
+    // Runs a script against this node's copies of its keys, with the shards
+    // they live on locked throughout, then replicates what it wrote. Like
+    // compare_and_swap, it reads only this node's copies.
+    fn eval(&self, script: &Script, keys: Vec<String>, args: Vec<String>) -> Result<Val, String> {
+        let mut store = ScriptShards { node: self, shards: self.data.lock_many(&keys), written: Vec::new() };
+        let result = script::run(script, keys, args, &mut store, self.scripts.limits);
+        let ScriptShards { shards, written, .. } = store;
+        drop(shards);
+        for (key, version) in written {
+            self.replicate(&key, version);
+        }
+        result.map(|(value, _)| value)
+    }
+
+    // Live keys under `prefix` on this node, in key order, after `after`.
+    fn scan(&self, prefix: &str, after: Option<&str>, limit: usize) -> (Vec<(String, Vec<String>)>, Option<String>) {
+        let now = clock::now_millis();
+        let shards = self.data.lock_all();
+        let mut matches: Vec<(&String, &Vec<Versioned>)> = shards
+            .iter()
+            .flat_map(|data| data.iter())
+            .filter(|(key, siblings)| key.starts_with(prefix) && siblings.iter().any(|s| s.is_live(now)))
+            .filter(|(key, _)| after.is_none_or(|after| key.as_str() > after))
+            .collect();
+        matches.sort_by(|a, b| a.0.cmp(b.0));
+        let more = matches.len() > limit;
+        matches.truncate(limit);
+        let next = if more { matches.last().map(|(k, _)| k.to_string()) } else { None };
+        let entries = matches
+            .into_iter()
+            .map(|(key, siblings)| {
+                let values = siblings.iter().filter(|s| s.is_live(now)).map(|s| s.value.clone()).collect();
+                (key.clone(), values)
+            })
+            .collect();
+        (entries, next)
+    }
+
+    // Sends a new version to every replica peer at once over pooled
+    // connections. Peers that are down, or that fail mid-write, get a hint
+    // instead so the write is handed off later.
+    fn replicate(&self, key: &str, version: Versioned) {
+        let message = PeerMessage::Replicate { key: key.to_string(), versions: vec![version.clone()] };
+        let peers = self.replica_peers(key);
+        thread::scope(|scope| {
+            for (id, member) in peers {
+                let (message, version) = (&message, &version);
+                scope.spawn(move || {
+                    if member.alive {
+                        match self.pool.request(&member.address, message) {
+                            Ok(_) => return,
+                            Err(e) => {
+                                println!("Replica {} unreachable, storing hint: {}", member.address, e);
+                                self.mark_dead(&id);
+                            }
+                        }
+                    }
+                    self.store_hint(id, key, version.clone());
+                });
+            }
+        });
+    }
+
+    fn store_hint(&self, target: Uuid, key: &str, version: Versioned) {
+        let recorded = self.hints.lock().unwrap().record(target, key.to_string(), version);
+        let mut stats = self.stats.lock().unwrap();
+        match recorded {
+            Ok(true) => stats.hints_recorded += 1,
+            Ok(false) => stats.hints_dropped += 1,
+            Err(e) => {
+                stats.hints_dropped += 1;
+                println!("Failed to persist hint for {}: {}", target, e);
+            }
+        }
+    }
+
+    // Members this node currently hears from, itself included, as passed
+    // around by gossip.
+    fn alive_members(&self) -> HashMap<Uuid, String> {
+        let mut alive: HashMap<Uuid, String> = self
+            .members
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, m)| m.alive)
+            .map(|(id, m)| (*id, m.address.clone()))
+            .collect();
+        alive.insert(self.id, self.address.clone());
+        alive
+    }
+
+    // Adds members a peer knows about that this node has not heard of. They
+    // start out dead until the next gossip round reaches them.
+    fn learn_members(&self, gossiped: HashMap<Uuid, String>) {
+        let mut members = self.members.lock().unwrap();
+        for (id, address) in gossiped {
+            if id != self.id && !members.contains_key(&id) {
+                println!("Learned of member {} at {} through gossip", id, address);
+                members.insert(id, Member { address, alive: false, last_seen_millis: 0 });
+            }
+        }
+    }
+
+    fn mark_dead(&self, id: &Uuid) {
+        if let Some(member) = self.members.lock().unwrap().get_mut(id) {
+            member.alive = false;
+        }
+    }
+
+    // Called whenever we hear from a peer. A peer coming back from the dead
+    // gets its pending hints replayed in the background.
+    fn mark_alive(&self, id: Uuid, address: String) {
+        let was_alive = {
+            let mut members = self.members.lock().unwrap();
+            let member = members.entry(id).or_insert(Member {
+                address: address.clone(),
+                alive: false,
+                last_seen_millis: 0,
+            });
+            let was_alive = member.alive;
+            member.address = address;
+            member.alive = true;
+            member.last_seen_millis = clock::now_millis();
+            was_alive
+        };
+        if !was_alive && self.hints.lock().unwrap().pending_for(&id) > 0 {
+            let node = self.clone();
+            thread::spawn(move || node.replay_hints(id));
+        }
+    }
+
+    fn replay_hints(&self, target: Uuid) {
+        let address = match self.members.lock().unwrap().get(&target) {
+            Some(member) => member.address.clone(),
+            None => return,
+        };
+        let pending = self.hints.lock().unwrap().hints_for(&target);
+        let mut delivered: Vec<Hint> = Vec::new();
+        match PeerConnection::open(&address, PEER_TIMEOUT) {
+            Ok(mut connection) => {
+                for hint in pending {
+                    let message = PeerMessage::Replicate { key: hint.key.clone(), versions: vec![hint.version.clone()] };
+                    if let Err(e) = connection.request(&message) {
+                        println!("Hint replay to {} interrupted: {}", address, e);
+                        break;
+                    }
+                    delivered.push(hint);
+                }
+            }
+            Err(e) => println!("Hint replay to {} failed: {}", address, e),
+        }
+        if delivered.is_empty() {
+            return;
+        }
+        if let Err(e) = self.hints.lock().unwrap().remove_delivered(&delivered) {
+            println!("Failed to rewrite hint log: {}", e);
+        }
+        self.stats.lock().unwrap().hints_delivered += delivered.len() as u64;
+    }
+
+    // Versions arriving from other replicas keep their own clocks. Returns
+    // whether anything changed locally.
+    fn apply_remote(&self, key: String, versions: Vec<Versioned>) -> io::Result<bool> {
+        let resolver = self.resolver_for(&key);
+        let mut data = self.data.lock(&key);
+        let current = data.get(&key).map(Vec::as_slice).unwrap_or(&[]);
+        let old_digest = Self::digest_of(&key, current);
+        let old_context = Self::context_of(current);
+        let mut siblings = current.to_vec();
+        for version in versions {
+            clock::reconcile(&mut siblings, version);
+        }
+        let siblings = resolver.resolve(siblings);
+        let new_digest = Self::digest_of(&key, &siblings);
+        if old_digest == new_digest {
+            return Ok(false);
+        }
+        self.log_change(&key, old_context, &siblings)?;
+        self.trees.lock().unwrap().update(&key, old_digest, new_digest);
+        self.tracking.lock().unwrap().invalidate(&key);
+        data.insert(key, siblings);
+        Ok(true)
+    }
+
+    fn context_of(siblings: &[Versioned]) -> Option<String> {
+        if siblings.is_empty() {
+            None
+        } else {
+            Some(CausalContext::from_clock(&clock::merged_clock(siblings)).to_string())
+        }
+    }
+
+    // Logs the key's next state and waits for it to reach the disk, before
+    // the caller applies it. Called with the key's shard locked so the log
+    // holds the key's changes in the order they were applied; the fsync runs
+    // outside the log's own lock, so writes to other shards share it. A
+    // change that could not be logged must not be applied.
+    fn log_change(&self, key: &str, old_context: Option<String>, siblings: &[Versioned]) -> io::Result<()> {
+        let seq = self.wal.lock().unwrap().append(key, old_context, siblings.to_vec())?;
+        Wal::sync_through(&self.wal, seq)?;
+        let now = clock::now_millis();
+        let mut expiring = self.expiring.lock().unwrap();
+        for expires in siblings.iter().filter(|s| s.is_live(now)).filter_map(|s| s.expires_millis) {
+            expiring.insert(key, expires);
+        }
+        Ok(())
+    }
+
+    // Snapshots the data so covered log segments can be dropped once they
+    // pass retention.
+    fn start_checkpointer(&self, interval: Duration) {
+        let node = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            // Every shard is held so the snapshot matches the sequence.
+            let (seq, snapshot) = {
+                let shards = node.data.lock_all();
+                let seq = node.wal.lock().unwrap().last_seq();
+                let snapshot: HashMap<String, Vec<Versioned>> =
+                    shards.iter().flat_map(|data| data.iter()).map(|(k, v)| (k.clone(), v.clone())).collect();
+                (seq, snapshot)
+            };
+            if let Err(e) = node.wal.lock().unwrap().checkpoint(seq, snapshot) {
+                println!("Failed to checkpoint the write-ahead log: {}", e);
+            }
+        });
+    }
+
+    // Invalidates cached copies of keys with values that expired since the
+    // previous check. Keys whose last live values have expired are logged
+    // again unchanged, which watchers read as an expiry.
+    fn start_expiry_watch(&self, interval: Duration) {
+        let node = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let now = clock::now_millis();
+            let due = node.expiring.lock().unwrap().due(now);
+            for key in due {
+                let data = node.data.lock(&key);
+                let siblings = match data.get(&key) {
+                    Some(siblings) => siblings,
+                    None => continue,
+                };
+                let expired = siblings.iter().any(|s| !s.deleted && s.expires_millis.is_some_and(|e| e <= now));
+                if expired {
+                    node.tracking.lock().unwrap().invalidate(&key);
+                }
+                if expired && !siblings.iter().any(|s| s.is_live(now)) {
+                    if let Err(e) = node.log_change(&key, Self::context_of(siblings), siblings) {
+                        println!("Failed to log the expiry of {}: {}", key, e);
+                    }
+                }
+            }
+        });
+    }
+
+    fn digest_of(key: &str, siblings: &[Versioned]) -> Option<u64> {
+        if siblings.is_empty() {
+            None
+        } else {
+            Some(merkle::key_digest(key, siblings))
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<Siblings> {
+        let data = self.data.lock(key);
+        data.get(key).and_then(|siblings| Self::siblings_of(siblings))
+    }
+
+    fn siblings_of(versions: &[Versioned]) -> Option<Siblings> {
+        let now = clock::now_millis();
+        if !versions.iter().any(|s| s.is_live(now)) {
+            return None;
+        }
+        Some(Siblings {
+            values: versions.iter().filter(|s| s.is_live(now)).map(|s| s.value.clone()).collect(),
+            context: CausalContext::from_clock(&clock::merged_clock(versions)),
+        })
+    }
+
+    // Reads the key from this node and enough live peers to reach
+    // `read_quorum`, returning the reconciled result. Replicas that answered
+    // with something older are brought up to date in the background.
+    fn quorum_get(&self, key: &str) -> io::Result<Option<Siblings>> {
+        let mut replies: Vec<(Option<String>, Vec<Versioned>)> = Vec::new();
+        let local = self.versions_of(&[key.to_string()]).pop().map(|(_, v)| v).unwrap_or_default();
+        replies.push((None, local));
+
+        let peers: Vec<String> = self
+            .replica_peers(key)
+            .into_iter()
+            .filter(|(_, m)| m.alive)
+            .map(|(_, m)| m.address)
+            .collect();
+        for address in peers {
+            if replies.len() >= self.read_quorum {
+                break;
+            }
+            let request = PeerMessage::FetchKeys { keys: vec![key.to_string()] };
+            match PeerConnection::open(&address, PEER_TIMEOUT).and_then(|mut c| c.request(&request)) {
+                Ok(PeerMessage::Versions(mut entries)) => {
+                    let versions = entries.pop().map(|(_, v)| v).unwrap_or_default();
+                    replies.push((Some(address), versions));
+                }
+                Ok(other) => println!("Quorum read from {} failed: {}", address, peer::unexpected(other)),
+                Err(e) => println!("Quorum read from {} failed: {}", address, e),
+            }
+        }
+        if replies.len() < self.read_quorum {
+            return Err(io::Error::other(format!(
+                "Read quorum not reached: {} of {} replicas answered",
+                replies.len(),
+                self.read_quorum
+            )));
+        }
+
+        let mut merged = Vec::new();
+        for (_, versions) in &replies {
+            for version in versions {
+                clock::reconcile(&mut merged, version.clone());
+            }
+        }
+        let merged = self.resolver_for(key).resolve(merged);
+        let merged_digest = Self::digest_of(key, &merged);
+        let stale: Vec<Option<String>> = replies
+            .into_iter()
+            .filter(|(_, versions)| Self::digest_of(key, versions) != merged_digest)
+            .map(|(address, _)| address)
+            .collect();
+
+        if !stale.is_empty() {
+            if self.read_repair.should_repair() {
+                let node = self.clone();
+                let (key, versions) = (key.to_string(), merged.clone());
+                thread::spawn(move || node.read_repair(key, versions, stale));
+            } else {
+                self.stats.lock().unwrap().read_repairs_skipped += 1;
+            }
+        }
+        Ok(Self::siblings_of(&merged))
+    }
+
+    // `None` in `stale` stands for this node's own copy. Only replicas
+    // actually brought up to date count as repaired.
+    fn read_repair(&self, key: String, versions: Vec<Versioned>, stale: Vec<Option<String>>) {
+        for replica in stale {
+            match replica {
+                None => match self.apply_remote(key.clone(), versions.clone()) {
+                    Ok(_) => self.stats.lock().unwrap().read_repairs += 1,
+                    Err(e) => {
+                        self.stats.lock().unwrap().read_repairs_failed += 1;
+                        println!("Read repair of {} on this node failed: {}", key, e);
+                    }
+                },
+                Some(address) => {
+                    let message = PeerMessage::Replicate { key: key.clone(), versions: versions.clone() };
+                    match PeerConnection::open(&address, PEER_TIMEOUT).and_then(|mut c| c.request(&message)) {
+                        Ok(_) => self.stats.lock().unwrap().read_repairs += 1,
+                        Err(e) => {
+                            self.stats.lock().unwrap().read_repairs_failed += 1;
+                            println!("Read repair of {} on {} failed: {}", key, address, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    // Keyspaces are the part of the key before the first ':'; keys without
+    // one are in the "" keyspace.
+    fn set_resolver(&self, keyspace: &str, resolver: Resolver) {
+        self.resolvers.lock().unwrap().insert(keyspace.to_string(), resolver);
+    }
+
+    fn resolver_for(&self, key: &str) -> Resolver {
+        let keyspace = key.split_once(':').map_or("", |(keyspace, _)| keyspace);
+        self.resolvers.lock().unwrap().get(keyspace).cloned().unwrap_or_default()
+    }
+
+    fn stats(&self) -> NodeStats {
+        let mut stats = self.stats.lock().unwrap().clone();
+        stats.hints_pending = self.hints.lock().unwrap().pending_by_target();
+        stats
     }
 
     fn start_server(&self) {
         let listener = TcpListener::bind(&self.address).expect("Could not bind to address");
         println!("Node server running on {}", self.address);
+        self.serve(listener);
+    }
 
+    fn serve(&self, listener: TcpListener) {
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    self.handle_connection(stream);
+                    let node = self.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = node.handle_connection(stream) {
+                            println!("Peer connection ended with error: {}", e);
+                        }
+                    });
                 }
                 Err(e) => {
                     println!("Failed to handle incoming connection: {}", e);
@@ -72,8 +943,622 @@ impl Node {
         }
     }
 
-    fn handle_connection(&self, _stream: TcpListener) {
-        println!("Got connection from a node!");
+    fn handle_connection(&self, mut stream: TcpStream) -> io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        while let Some(message) = peer::receive(&mut reader)? {
+            match message {
+                PeerMessage::TrackingSubscribe => return self.serve_invalidations(stream),
+                PeerMessage::Watch { target, from_seq } => reader = self.serve_watch(&mut stream, reader, target, from_seq)?,
+                PeerMessage::Subscribe { channels, patterns } => {
+                    reader = self.serve_subscription(&mut stream, reader, channels, patterns)?
+                }
+                PeerMessage::CdcSubscribe { from_seq } => reader = self.serve_cdc(&mut stream, reader, from_seq)?,
+                message => {
+                    let reply = self.handle_message(message);
+                    peer::send(&mut stream, &reply)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Streams write-ahead log entries from `from_seq` on, following new writes,
+    // until the client sends `CdcStop`.
+    fn serve_cdc(
+        &self,
+        stream: &mut TcpStream,
+        mut reader: BufReader<TcpStream>,
+        from_seq: Option<u64>,
+    ) -> io::Result<BufReader<TcpStream>> {
+        let (mut entries, first_seq, last_seq) = {
+            let wal = self.wal.lock().unwrap();
+            match wal.reader(from_seq.unwrap_or_else(|| wal.first_seq())) {
+                Ok(entries) => (entries, wal.first_seq(), wal.last_seq()),
+                Err(e) => {
+                    peer::send(stream, &PeerMessage::Error(e.to_string()))?;
+                    return Ok(reader);
+                }
+            }
+        };
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        let cancel = thread::spawn(move || {
+            let received = peer::receive(&mut reader);
+            stop.store(true, Ordering::Relaxed);
+            (reader, received)
+        });
+
+        let mut result = peer::send(stream, &PeerMessage::CdcStarted { first_seq, last_seq });
+        while result.is_ok() && !stopped.load(Ordering::Relaxed) {
+            result = match entries.next(&self.wal) {
+                Ok(Some(entry)) => peer::send(stream, &PeerMessage::CdcEntry(entry)),
+                Ok(None) => {
+                    thread::sleep(LOG_POLL_INTERVAL);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+        }
+        if result.is_err() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let (reader, received) = cancel.join().expect("CDC cancel thread panicked");
+        result?;
+        match received? {
+            Some(PeerMessage::CdcStop) => {
+                peer::send(stream, &PeerMessage::Ack)?;
+                Ok(reader)
+            }
+            Some(other) => Err(peer::unexpected(other)),
+            None => Ok(reader),
+        }
+    }
+
+    // Delivers published messages until the client sends `Unsubscribe`. More
+    // `Subscribe` requests can be sent meanwhile to add subscriptions.
+    fn serve_subscription(
+        &self,
+        stream: &mut TcpStream,
+        mut reader: BufReader<TcpStream>,
+        channels: Vec<String>,
+        patterns: Vec<String>,
+    ) -> io::Result<BufReader<TcpStream>> {
+        let subscription = self.pubsub.lock().unwrap().subscribe();
+        let id = subscription.id;
+        self.pubsub.lock().unwrap().add(id, &channels, &patterns);
+        let pubsub = self.pubsub.clone();
+        let control = subscription.control;
+        let cancel = thread::spawn(move || loop {
+            match peer::receive(&mut reader) {
+                Ok(Some(PeerMessage::Subscribe { channels, patterns })) => {
+                    pubsub.lock().unwrap().add(id, &channels, &patterns);
+                    let _ = control.send(PeerMessage::Subscribed { channels, patterns });
+                }
+                received => {
+                    pubsub.lock().unwrap().unsubscribe(id);
+                    return (reader, received);
+                }
+            }
+        });
+
+        // Waking up even when nothing arrives tells a subscriber about drops
+        // without waiting for the next message that fits in its queue.
+        let mut result = peer::send(stream, &PeerMessage::Subscribed { channels, patterns });
+        while result.is_ok() {
+            let next = subscription.messages.recv_timeout(DROP_NOTICE_INTERVAL);
+            let missed = subscription.dropped.swap(0, Ordering::Relaxed);
+            if missed > 0 {
+                result = peer::send(stream, &PeerMessage::MessagesDropped { count: missed });
+            }
+            match next {
+                Ok(message) => result = result.and_then(|_| peer::send(stream, &message)),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        if result.is_err() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let (reader, received) = cancel.join().expect("Subscription cancel thread panicked");
+        result?;
+        match received? {
+            Some(PeerMessage::Unsubscribe) => {
+                peer::send(stream, &PeerMessage::Ack)?;
+                Ok(reader)
+            }
+            Some(other) => Err(peer::unexpected(other)),
+            None => Ok(reader),
+        }
+    }
+
+    // Delivers to this node's subscribers and, for messages published by a
+    // client, queues them for every other live node. Forwarding is best
+    // effort: when the queue is full the message only reaches this node.
+    fn publish(&self, channel: String, message: String, forwarded: bool) -> usize {
+        let receivers = self.pubsub.lock().unwrap().publish(&channel, &message);
+        if !forwarded {
+            let forward = PeerMessage::Publish { channel, message, forwarded: true };
+            if let Err(TrySendError::Full(_)) = self.forwards.try_send(forward) {
+                println!("Forward queue is full, published message stays on this node");
+            }
+        }
+        receivers
+    }
+
+    // Sends queued messages to the other live nodes, in publish order, over
+    // pooled connections.
+    fn start_forwarder(queued: Receiver<PeerMessage>, members: Arc<Mutex<HashMap<Uuid, Member>>>, pool: Arc<PeerPool>) {
+        thread::spawn(move || {
+            for forward in queued.iter() {
+                let peers: Vec<String> =
+                    members.lock().unwrap().values().filter(|m| m.alive).map(|m| m.address.clone()).collect();
+                for address in peers {
+                    if let Err(e) = pool.request(&address, &forward) {
+                        println!("Failed to forward published message to {}: {}", address, e);
+                    }
+                }
+            }
+        });
+    }
+
+    // Streams changes matching `target`, read from the write-ahead log, until
+    // the client sends `Unwatch`, then hands the connection back for
+    // ordinary requests.
+    fn serve_watch(
+        &self,
+        stream: &mut TcpStream,
+        mut reader: BufReader<TcpStream>,
+        target: WatchTarget,
+        from_seq: Option<u64>,
+    ) -> io::Result<BufReader<TcpStream>> {
+        let (mut entries, seq) = {
+            let wal = self.wal.lock().unwrap();
+            match wal.reader(from_seq.unwrap_or_else(|| wal.last_seq()) + 1) {
+                Ok(entries) => (entries, wal.last_seq()),
+                Err(e) => {
+                    peer::send(stream, &PeerMessage::Error(e.to_string()))?;
+                    return Ok(reader);
+                }
+            }
+        };
+        let stopped = Arc::new(AtomicBool::new(false));
+        let stop = stopped.clone();
+        let cancel = thread::spawn(move || {
+            let received = peer::receive(&mut reader);
+            stop.store(true, Ordering::Relaxed);
+            (reader, received)
+        });
+
+        let mut result = peer::send(stream, &PeerMessage::Watching { seq });
+        while result.is_ok() && !stopped.load(Ordering::Relaxed) {
+            result = match entries.next(&self.wal) {
+                Ok(Some(entry)) => match ChangeEvent::from_entry(entry) {
+                    Some(event) if target.matches(&event.key) => peer::send(stream, &PeerMessage::Change(event)),
+                    _ => Ok(()),
+                },
+                Ok(None) => {
+                    thread::sleep(LOG_POLL_INTERVAL);
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            };
+        }
+        if result.is_err() {
+            // Unblocks the cancel thread's read.
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        let (reader, received) = cancel.join().expect("Watch cancel thread panicked");
+        result?;
+        match received? {
+            Some(PeerMessage::Unwatch) => {
+                peer::send(stream, &PeerMessage::Ack)?;
+                Ok(reader)
+            }
+            Some(other) => Err(peer::unexpected(other)),
+            None => Ok(reader),
+        }
+    }
+
+    // Pushes invalidations for keys the client tracks until it disconnects.
+    fn serve_invalidations(&self, mut stream: TcpStream) -> io::Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        let id = self.tracking.lock().unwrap().subscribe(sender);
+        // Reads until the client hangs up. Dropping the subscription drops
+        // the only sender, which ends the loop below even if no tracked key
+        // ever changes.
+        let mut hangup = stream.try_clone()?;
+        let tracking = self.tracking.clone();
+        thread::spawn(move || {
+            let mut buffer = [0; 64];
+            while matches!(hangup.read(&mut buffer), Ok(read) if read > 0) {}
+            tracking.lock().unwrap().unsubscribe(id);
+        });
+        let mut result = peer::send(&mut stream, &PeerMessage::TrackingId { id });
+        if result.is_ok() {
+            for message in receiver.iter() {
+                result = peer::send(&mut stream, &message);
+                if result.is_err() {
+                    break;
+                }
+            }
+        }
+        self.tracking.lock().unwrap().unsubscribe(id);
+        result
+    }
+
+    fn handle_message(&self, message: PeerMessage) -> PeerMessage {
+        match message {
+            PeerMessage::TreeHashes { range, level, indices } => {
+                if level == 0 {
+                    // The ring may have moved since this node last built its trees.
+                    let ranges: Vec<TokenRange> = self.owned_ranges().into_iter().map(|(range, _)| range).collect();
+                    self.refresh_trees(&ranges);
+                }
+                let levels = match self.trees.lock().unwrap().levels(&range) {
+                    Some(levels) => levels,
+                    None => return PeerMessage::Error(format!("No tree for range {:?}", range)),
+                };
+                match levels.get(level) {
+                    Some(hashes) => PeerMessage::Hashes(
+                        indices.into_iter().filter_map(|i| hashes.get(i).map(|h| (i, *h))).collect(),
+                    ),
+                    None => PeerMessage::Error(format!("No tree level {}", level)),
+                }
+            }
+            PeerMessage::LeafDigests { range, leaves } => PeerMessage::Digests(
+                self.leaf_digests(range, &leaves.into_iter().collect()).into_iter().collect(),
+            ),
+            PeerMessage::FetchKeys { keys } => PeerMessage::Versions(self.versions_of(&keys)),
+            PeerMessage::Repair(entries) => {
+                let mut repaired = 0;
+                let mut failure = None;
+                for (key, versions) in entries {
+                    match self.apply_remote(key, versions) {
+                        Ok(true) => repaired += 1,
+                        Ok(false) => {}
+                        Err(e) => {
+                            failure = Some(e);
+                            break;
+                        }
+                    }
+                }
+                self.stats.lock().unwrap().keys_repaired += repaired;
+                match failure {
+                    Some(e) => PeerMessage::Error(e.to_string()),
+                    None => PeerMessage::Ack,
+                }
+            }
+            PeerMessage::Replicate { key, versions } => match self.apply_remote(key, versions) {
+                Ok(_) => PeerMessage::Ack,
+                Err(e) => PeerMessage::Error(e.to_string()),
+            },
+            PeerMessage::Ping { id, address } => {
+                self.mark_alive(id, address);
+                PeerMessage::Pong { id: self.id, members: self.alive_members() }
+            }
+            PeerMessage::Stats => PeerMessage::StatsReply(self.stats()),
+            PeerMessage::Publish { channel, message, forwarded } => PeerMessage::Published {
+                receivers: self.publish(channel, message, forwarded),
+            },
+            PeerMessage::Join { id, address } => {
+                self.mark_alive(id, address);
+                let mut members = self.members.lock().unwrap().clone();
+                members.insert(self.id, Member {
+                    address: self.address.clone(),
+                    alive: true,
+                    last_seen_millis: clock::now_millis(),
+                });
+                PeerMessage::Members(members)
+            }
+            PeerMessage::Leave { id } => {
+                self.members.lock().unwrap().remove(&id);
+                PeerMessage::Ack
+            }
+            PeerMessage::StreamRange { range, after, limit } => {
+                let (entries, next) = self.range_batch(range, after.as_deref(), limit);
+                PeerMessage::RangeBatch { entries, next }
+            }
+            PeerMessage::RangeBatch { entries, .. } => {
+                match entries.into_iter().try_for_each(|(key, versions)| self.apply_remote(key, versions).map(|_| ())) {
+                    Ok(()) => PeerMessage::Ack,
+                    Err(e) => PeerMessage::Error(e.to_string()),
+                }
+            }
+            PeerMessage::Topology => {
+                let mut members = self.members.lock().unwrap().clone();
+                members.insert(self.id, Member {
+                    address: self.address.clone(),
+                    alive: true,
+                    last_seen_millis: clock::now_millis(),
+                });
+                PeerMessage::TopologyReply { members, replication_factor: self.replication_factor }
+            }
+            PeerMessage::Get { key, tracking_id } => self.handle_owned(&key, || {
+                // Track before reading so a write racing the read still invalidates.
+                if let Some(id) = tracking_id {
+                    if !self.tracking.lock().unwrap().track(id, &key) {
+                        return PeerMessage::Error(format!("Unknown tracking id {}", id));
+                    }
+                }
+                match self.quorum_get(&key) {
+                    Ok(Some(siblings)) => PeerMessage::Value {
+                        values: siblings.values,
+                        context: Some(siblings.context.to_string()),
+                    },
+                    Ok(None) => PeerMessage::Value { values: vec![], context: None },
+                    Err(e) => PeerMessage::Error(e.to_string()),
+                }
+            }),
+            PeerMessage::Put { key, value, context, ttl_secs, origin } => self.handle_owned(&key, || {
+                match context.as_deref().map(CausalContext::parse).transpose() {
+                    Ok(context) => match self.put(key.clone(), value, context.as_ref(), ttl_secs.map(Duration::from_secs), origin.as_ref()) {
+                        Ok(context) => PeerMessage::Written { context: context.to_string() },
+                        Err(e) => PeerMessage::Error(e.to_string()),
+                    },
+                    Err(e) => PeerMessage::Error(e.to_string()),
+                }
+            }),
+            PeerMessage::Delete { key, context, origin } => self.handle_owned(&key, || {
+                match context.as_deref().map(CausalContext::parse).transpose() {
+                    Ok(context) => match self.delete(key.clone(), context.as_ref(), origin.as_ref()) {
+                        Ok(context) => PeerMessage::Written { context: context.to_string() },
+                        Err(e) => PeerMessage::Error(e.to_string()),
+                    },
+                    Err(e) => PeerMessage::Error(e.to_string()),
+                }
+            }),
+            PeerMessage::CompareAndSwap { key, expected, value } => self.handle_owned(&key, || {
+                match self.compare_and_swap(&key, expected, value) {
+                    Ok(Some(context)) => PeerMessage::Swapped { swapped: true, context: Some(context.to_string()) },
+                    Ok(None) => PeerMessage::Swapped { swapped: false, context: None },
+                    Err(e) => PeerMessage::Error(e.to_string()),
+                }
+            }),
+            PeerMessage::Increment { key, by } => self.handle_owned(&key, || match self.increment(&key, by) {
+                Ok((value, context)) => PeerMessage::Incremented { value, context: context.to_string() },
+                Err(e) => PeerMessage::Error(e.to_string()),
+            }),
+            PeerMessage::Eval { script, keys, args } => {
+                let ring = self.ring(true);
+                let owners = keys.first().map(|key| ring.owners(key));
+                if keys.iter().any(|key| Some(ring.owners(key)) != owners) {
+                    return PeerMessage::Error("CROSSSLOT Keys in request don't share the same replicas".to_string());
+                }
+                let run = || match self.scripts.load(&script) {
+                    Ok((_, script)) => match self.eval(&script, keys.clone(), args) {
+                        Ok(value) => PeerMessage::Evaluated { reply: script::reply(&value) },
+                        Err(e) => PeerMessage::Error(format!("ERR script failed: {}", e)),
+                    },
+                    Err(e) => PeerMessage::Error(format!("ERR error compiling script: {}", e)),
+                };
+                match keys.first() {
+                    Some(key) => self.handle_owned(key, run),
+                    None => run(),
+                }
+            }
+            PeerMessage::Scan { prefix, after, limit } => {
+                let (entries, next) = self.scan(&prefix, after.as_deref(), limit);
+                PeerMessage::ScanBatch { entries, next }
+            }
+            PeerMessage::RebalanceStatus => PeerMessage::RebalanceReport(self.rebalance.lock().unwrap().transfers.clone()),
+            PeerMessage::Decommission => {
+                let node = self.clone();
+                thread::spawn(move || match node.leave() {
+                    Ok(()) => {
+                        println!("Handed off all ranges, shutting down");
+                        std::process::exit(0);
+                    }
+                    Err(e) => println!("Decommission failed: {}", e),
+                });
+                PeerMessage::Ack
+            }
+            other => PeerMessage::Error(format!("Unsupported message: {:?}", other)),
+        }
+    }
+
+    // Client requests are only served by nodes that replicate the key, so
+    // smart clients learn when their ring is out of date.
+    fn handle_owned<F: FnOnce() -> PeerMessage>(&self, key: &str, handle: F) -> PeerMessage {
+        let owners = self.ring(true).owners(key);
+        if owners.contains(&self.id) {
+            return handle();
+        }
+        let members = self.members.lock().unwrap();
+        PeerMessage::WrongNode {
+            owners: owners.iter().filter_map(|id| members.get(id).map(|m| m.address.clone())).collect(),
+        }
+    }
+
+    fn leaf_digests(&self, range: TokenRange, leaves: &HashSet<usize>) -> HashMap<String, u64> {
+        let shards = self.data.lock_all();
+        shards
+            .iter()
+            .flat_map(|data| data.iter())
+            .filter(|(key, siblings)| !siblings.is_empty() && range.contains(ring::key_token(key)))
+            .filter(|(key, _)| leaves.contains(&merkle::leaf_for(key)))
+            .map(|(key, siblings)| (key.clone(), merkle::key_digest(key, siblings)))
+            .collect()
+    }
+
+    fn versions_of(&self, keys: &[String]) -> Vec<(String, Vec<Versioned>)> {
+        keys.iter()
+            .filter_map(|key| self.data.lock(key).get(key).filter(|s| !s.is_empty()).map(|s| (key.clone(), s.clone())))
+            .collect()
+    }
+
+    fn start_anti_entropy(&self, interval: Duration) {
+        let node = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let owned = node.owned_ranges();
+            let ranges: Vec<TokenRange> = owned.iter().map(|(range, _)| *range).collect();
+            node.refresh_trees(&ranges);
+            // Each range is only synced with the other replicas of it.
+            let mut shared: HashMap<Uuid, Vec<TokenRange>> = HashMap::new();
+            for (range, owners) in owned {
+                for owner in owners.into_iter().filter(|id| *id != node.id) {
+                    shared.entry(owner).or_default().push(range);
+                }
+            }
+            let members = node.members.lock().unwrap().clone();
+            for (id, ranges) in shared {
+                let peer_address = match members.get(&id) {
+                    Some(member) if member.alive => member.address.clone(),
+                    _ => continue,
+                };
+                let result = node.sync_with(&peer_address, &ranges);
+                let mut stats = node.stats.lock().unwrap();
+                match result {
+                    Ok(repaired) => {
+                        stats.repair_rounds += 1;
+                        stats.keys_repaired += repaired;
+                        stats.last_repair_millis = clock::now_millis();
+                    }
+                    Err(e) => {
+                        stats.failed_repair_rounds += 1;
+                        println!("Anti-entropy with {} failed: {}", peer_address, e);
+                    }
+                }
+            }
+        });
+    }
+
+    // Heartbeats every known peer and expires stale hints. A peer that
+    // answers is marked alive, which is what triggers hint replay.
+    fn start_gossip(&self, interval: Duration) {
+        let node = self.clone();
+        thread::spawn(move || loop {
+            let members: Vec<(Uuid, String)> = node
+                .members
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(id, m)| (*id, m.address.clone()))
+                .collect();
+            for (id, address) in members {
+                let ping = PeerMessage::Ping { id: node.id, address: node.address.clone() };
+                match PeerConnection::open(&address, PEER_TIMEOUT).and_then(|mut c| c.request(&ping)) {
+                    Ok(PeerMessage::Pong { id: peer_id, members }) if peer_id == id => {
+                        node.mark_alive(id, address);
+                        node.learn_members(members);
+                    }
+                    Ok(PeerMessage::Pong { id: peer_id, .. }) => {
+                        println!("Peer at {} answered as {} instead of {}", address, peer_id, id);
+                        node.mark_dead(&id);
+                    }
+                    _ => node.mark_dead(&id),
+                }
+            }
+            if let Err(e) = node.hints.lock().unwrap().expire() {
+                println!("Failed to expire hints: {}", e);
+            }
+            thread::sleep(interval);
+        });
+    }
+
+    fn sync_with(&self, peer_address: &str, ranges: &[TokenRange]) -> io::Result<u64> {
+        let mut connection = PeerConnection::open(peer_address, PEER_TIMEOUT)?;
+        let mut repaired = 0;
+        for range in ranges {
+            repaired += self.sync_range(&mut connection, *range)?;
+        }
+        Ok(repaired)
+    }
+
+    // Walks down from the range's root comparing tree hashes with the peer,
+    // then exchanges only the keys under leaves that differ.
+    fn sync_range(&self, connection: &mut PeerConnection, range: TokenRange) -> io::Result<u64> {
+        let levels = match self.trees.lock().unwrap().levels(&range) {
+            Some(levels) => levels,
+            None => return Ok(0),
+        };
+        let mut differing = vec![0];
+        for (level, mine) in levels.iter().enumerate() {
+            let theirs = match connection.request(&PeerMessage::TreeHashes { range, level, indices: differing })? {
+                PeerMessage::Hashes(hashes) => hashes,
+                other => return Err(peer::unexpected(other)),
+            };
+            differing = theirs
+                .into_iter()
+                .filter(|(i, hash)| mine[*i] != *hash)
+                .map(|(i, _)| i)
+                .collect();
+            if differing.is_empty() {
+                return Ok(0);
+            }
+            if level + 1 < levels.len() {
+                differing = differing.iter().flat_map(|i| vec![2 * i, 2 * i + 1]).collect();
+            }
+        }
+
+        let leaves: HashSet<usize> = differing.into_iter().collect();
+        let theirs: HashMap<String, u64> = match connection.request(&PeerMessage::LeafDigests {
+            range,
+            leaves: leaves.iter().cloned().collect(),
+        })? {
+            PeerMessage::Digests(digests) => digests.into_iter().collect(),
+            other => return Err(peer::unexpected(other)),
+        };
+        let mine = self.leaf_digests(range, &leaves);
+        let divergent: Vec<String> = mine
+            .keys()
+            .chain(theirs.keys())
+            .filter(|key| mine.get(*key) != theirs.get(*key))
+            .cloned()
+            .collect::<HashSet<String>>()
+            .into_iter()
+            .collect();
+        if divergent.is_empty() {
+            return Ok(0);
+        }
+
+        let mut repaired = 0;
+        match connection.request(&PeerMessage::FetchKeys { keys: divergent.clone() })? {
+            PeerMessage::Versions(entries) => {
+                for (key, versions) in entries {
+                    if self.apply_remote(key, versions)? {
+                        repaired += 1;
+                    }
+                }
+            }
+            other => return Err(peer::unexpected(other)),
+        }
+        connection.request(&PeerMessage::Repair(self.versions_of(&divergent)))?;
+        Ok(repaired)
+    }
+}
+
+// A script's view of the node's data while it runs.
+struct ScriptShards<'a> {
+    node: &'a Node,
+    shards: ShardGuards<'a, HashMap<String, Vec<Versioned>>>,
+    // Versions written, replicated once the shards are unlocked.
+    written: Vec<(String, Versioned)>,
+}
+
+impl ScriptStore for ScriptShards<'_> {
+    fn get(&mut self, key: &str) -> Result<Option<String>, String> {
+        let data = self.shards.shard(key).ok_or_else(|| format!("the shard of key '{}' is not locked", key))?;
+        let now = clock::now_millis();
+        let live: Vec<&Versioned> = data.get(key).into_iter().flatten().filter(|s| s.is_live(now)).collect();
+        match live.as_slice() {
+            [] => Ok(None),
+            [only] => Ok(Some(only.value.clone())),
+            _ => Err(format!("key '{}' has concurrent values", key)),
+        }
+    }
+
+    // Each write supersedes every version the script could have read. A
+    // write-ahead log failure stops the commit part way, as it would a run
+    // of puts.
+    fn commit(&mut self, writes: Vec<(String, Option<String>)>) -> Result<(), String> {
+        for (key, value) in writes {
+            let data = self.shards.shard(&key).ok_or_else(|| format!("the shard of key '{}' is not locked", key))?;
+            let seen = CausalContext::from_clock(&clock::merged_clock(data.get(&key).map(Vec::as_slice).unwrap_or(&[])));
+            let (_, version) = self.node.write_locked(data, &key, value, Some(&seen), None, None).map_err(|e| e.to_string())?;
+            self.written.push((key, version));
+        }
+        Ok(())
     }
 }
 
@@ -81,16 +1566,336 @@ fn main() {
     dotenv::dotenv().ok();
     let node_address = env::var("NODE_ADDRESS").expect("NODE_ADDRESS must be set");
     
-    let node_id = Uuid::new_v4();
-    let mut node = Node::new(node_id, node_address.clone());
+    // A stable id lets peers keep hints for this node across restarts.
+    let node_id = env::var("NODE_ID")
+        .ok()
+        .map(|id| Uuid::parse_str(&id).expect("NODE_ID must be a UUID"))
+        .unwrap_or_else(Uuid::new_v4);
+    let hint_log_path = env::var("HINT_LOG_PATH").unwrap_or_else(|_| "hints.log".to_string());
+    let hint_ttl_secs = env::var("HINT_TTL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(3 * 60 * 60);
+    let hint_max_per_target = env::var("HINT_MAX_PER_TARGET")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(10_000);
+    let hints = HintLog::open(PathBuf::from(hint_log_path), Duration::from_secs(hint_ttl_secs), hint_max_per_target)
+        .expect("Could not open hint log");
 
-    let _result = node.expensive_computation("example_key", "example_value");
-    
-    let _discovered_nodes = node.discover();
-    node.handle_task("Example Task");
+    let rebalance_state_path = env::var("REBALANCE_STATE_PATH").unwrap_or_else(|_| "rebalance.json".to_string());
+    let rebalance = RebalanceState::load(PathBuf::from(rebalance_state_path)).expect("Could not load rebalance state");
+
+    let wal_dir = env::var("WAL_DIR").unwrap_or_else(|_| "wal".to_string());
+    let wal_segment_bytes = env::var("WAL_SEGMENT_BYTES")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(64 * 1024 * 1024);
+    let retention = Retention {
+        max_bytes: env::var("WAL_RETENTION_BYTES")
+            .ok()
+            .and_then(|s| s.parse().ok())
+            .unwrap_or(1024 * 1024 * 1024),
+        max_age: Duration::from_secs(
+            env::var("WAL_RETENTION_SECS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(7 * 24 * 60 * 60),
+        ),
+    };
+    let (wal, recovered) = Wal::open(PathBuf::from(wal_dir), wal_segment_bytes, retention).expect("Could not open write-ahead log");
+    let checkpoint_secs = env::var("CHECKPOINT_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(60);
 
-    node.set("key1".to_string(), "value1".to_string());
-    if let Some(value) = node.get("key1") {
-        println!("Retrieved value: {}", value);
+    let mut node = Node::new(node_id, node_address.clone(), hints, rebalance, wal);
+    node.restore(recovered);
+    // Peers are listed as `id@address`, comma separated.
+    if let Ok(peers) = env::var("NODE_PEERS") {
+        let mut members = node.members.lock().unwrap();
+        for entry in peers.split(',').map(|p| p.trim()).filter(|p| !p.is_empty()) {
+            let mut parts = entry.splitn(2, '@');
+            if let (Some(id), Some(address)) = (parts.next(), parts.next()) {
+                let id = Uuid::parse_str(id).expect("NODE_PEERS entries must be id@address");
+                members.insert(id, Member { address: address.to_string(), alive: false, last_seen_millis: 0 });
+            }
+        }
+    }
+    if let Ok(quorum) = env::var("READ_QUORUM") {
+        node.read_quorum = quorum.parse().expect("READ_QUORUM must be a number");
     }
-}
\ No newline at end of file
+    if let Ok(setting) = env::var("READ_REPAIR") {
+        node.read_repair = ReadRepair::parse(&setting).expect("READ_REPAIR must be always, off or a percentage");
+    }
+    if let Ok(factor) = env::var("REPLICATION_FACTOR") {
+        node.replication_factor = factor.parse().expect("REPLICATION_FACTOR must be a number");
+    }
+    if let Ok(batch_size) = env::var("REBALANCE_BATCH_SIZE") {
+        node.throttle.batch_size = batch_size.parse().expect("REBALANCE_BATCH_SIZE must be a number");
+    }
+    if let Ok(rate) = env::var("REBALANCE_KEYS_PER_SEC") {
+        node.throttle.keys_per_sec = rate.parse().expect("REBALANCE_KEYS_PER_SEC must be a number");
+    }
+    if let Ok(secs) = env::var("LEAVE_TIMEOUT_SECS") {
+        node.leave_timeout = Duration::from_secs(secs.parse().expect("LEAVE_TIMEOUT_SECS must be a number"));
+    }
+    let gossip_secs = env::var("GOSSIP_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(1);
+    let anti_entropy_secs = env::var("ANTI_ENTROPY_INTERVAL_SECS")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(30);
+    if let Ok(size) = env::var("PUBSUB_QUEUE_SIZE") {
+        *node.pubsub.lock().unwrap() = PubSub::new(size.parse().expect("PUBSUB_QUEUE_SIZE must be a number"));
+    }
+
+    // Resolvers are listed as `keyspace=resolver`, comma separated; other
+    // keyspaces keep siblings.
+    if let Ok(resolvers) = env::var("NODE_RESOLVERS") {
+        for entry in resolvers.split(',').map(|r| r.trim()).filter(|r| !r.is_empty()) {
+            let resolver = entry
+                .split_once('=')
+                .and_then(|(keyspace, resolver)| Some((keyspace, Resolver::parse(resolver)?)));
+            match resolver {
+                Some((keyspace, resolver)) => node.set_resolver(keyspace, resolver),
+                None => panic!("NODE_RESOLVERS entries must be keyspace=siblings, lww or merge:union"),
+            }
+        }
+    }
+
+    if let Ok(seed) = env::var("JOIN_SEED") {
+        node.join(&seed).expect("Could not join cluster");
+    }
+
+    node.start_gossip(Duration::from_secs(gossip_secs));
+    node.start_anti_entropy(Duration::from_secs(anti_entropy_secs));
+    node.start_rebalancer(Duration::from_secs(5));
+    node.start_expiry_watch(Duration::from_secs(1));
+    node.start_checkpointer(Duration::from_secs(checkpoint_secs));
+    node.start_server();
+}
+
+// Nodes serving on loopback, for tests of the clients and tools that talk
+// to a cluster over the network.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+
+    // Starts `count` nodes that know each other, keeping `replication_factor`
+    // copies of every key, and returns their addresses.
+    pub fn start_cluster(count: usize, replication_factor: usize) -> Vec<String> {
+        start_nodes(count, replication_factor).into_iter().map(|node| node.address).collect()
+    }
+
+    // Reads are answered by the node asked alone; tests of quorum reads
+    // raise `read_quorum` on their own copy of a node.
+    pub(super) fn start_nodes(count: usize, replication_factor: usize) -> Vec<Node> {
+        let listeners: Vec<TcpListener> = (0..count).map(|_| TcpListener::bind("127.0.0.1:0").unwrap()).collect();
+        let nodes: Vec<Node> = listeners
+            .iter()
+            .map(|listener| {
+                let mut node = new_node(listener.local_addr().unwrap().to_string());
+                node.replication_factor = replication_factor;
+                node.read_quorum = 1;
+                node
+            })
+            .collect();
+        for node in &nodes {
+            for other in nodes.iter().filter(|other| other.id != node.id) {
+                node.mark_alive(other.id, other.address.clone());
+            }
+        }
+        for (node, listener) in nodes.iter().zip(listeners) {
+            let node = node.clone();
+            thread::spawn(move || node.serve(listener));
+        }
+        nodes
+    }
+
+    pub(super) fn new_node(address: String) -> Node {
+        let dir = std::env::temp_dir().join(format!("node-{}", Uuid::new_v4()));
+        let hints = HintLog::open(dir.with_extension("hints"), Duration::from_secs(60), 10).unwrap();
+        let rebalance = RebalanceState::load(dir.with_extension("json")).unwrap();
+        let retention = Retention { max_bytes: 1 << 20, max_age: Duration::from_secs(60) };
+        let (wal, _) = Wal::open(dir, 1 << 20, retention).unwrap();
+        Node::new(Uuid::new_v4(), address, hints, rebalance, wal)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::testing::{new_node, start_nodes};
+
+    #[test]
+    fn read_repair_settings_parse() {
+        assert_eq!(ReadRepair::parse("always"), Some(ReadRepair::Always));
+        assert_eq!(ReadRepair::parse(" OFF "), Some(ReadRepair::Off));
+        assert_eq!(ReadRepair::parse("25%"), Some(ReadRepair::Percent(25)));
+        assert_eq!(ReadRepair::parse("10"), Some(ReadRepair::Percent(10)));
+        assert_eq!(ReadRepair::parse("101"), None);
+        assert_eq!(ReadRepair::parse("sometimes"), None);
+    }
+
+    #[test]
+    fn read_repair_percent_bounds() {
+        assert!(!ReadRepair::Percent(0).should_repair());
+        assert!(ReadRepair::Percent(100).should_repair());
+    }
+
+    fn test_node() -> Node {
+        new_node("127.0.0.1:0".to_string())
+    }
+
+    fn put(node: &Node, key: &str, value: &str, context: Option<&CausalContext>) -> CausalContext {
+        node.put(key.to_string(), value.to_string(), context, None, None).unwrap()
+    }
+
+    fn values(node: &Node, key: &str) -> Vec<String> {
+        let mut values = node.get(key).map(|s| s.values).unwrap_or_default();
+        values.sort();
+        values
+    }
+
+    #[test]
+    fn concurrent_writes_stay_siblings_until_a_context_covers_them() {
+        let node = test_node();
+        let first = put(&node, "k", "a", None);
+        // Written without having read "a", so both are kept.
+        put(&node, "k", "b", None);
+        assert_eq!(values(&node, "k"), vec!["a", "b"]);
+        // A context only supersedes the siblings it was read with.
+        put(&node, "k", "c", Some(&first));
+        assert_eq!(values(&node, "k"), vec!["b", "c"]);
+        let context = node.get("k").unwrap().context;
+        put(&node, "k", "d", Some(&context));
+        assert_eq!(values(&node, "k"), vec!["d"]);
+    }
+
+    #[test]
+    fn deletes_leave_tombstones_that_stale_copies_cannot_undo() {
+        let node = test_node();
+        let context = put(&node, "k", "a", None);
+        let stale = node.versions_of(&["k".to_string()]).pop().unwrap().1;
+        node.delete("k".to_string(), Some(&context), None).unwrap();
+        assert!(node.get("k").is_none());
+        let versions = node.versions_of(&["k".to_string()]).pop().unwrap().1;
+        assert!(versions.len() == 1 && versions[0].deleted);
+
+        // A replica that missed the delete cannot bring "a" back.
+        assert!(!node.apply_remote("k".to_string(), stale).unwrap());
+        assert!(node.get("k").is_none());
+        // A write the delete did not see survives it.
+        put(&node, "k", "b", None);
+        assert_eq!(values(&node, "k"), vec!["b"]);
+    }
+
+    #[test]
+    fn each_keyspace_resolves_siblings_its_own_way() {
+        let node = test_node();
+        node.set_resolver("session", Resolver::parse("lww").unwrap());
+        node.set_resolver("tags", Resolver::parse("merge:union").unwrap());
+        for (value, tags) in [("a", "x,y"), ("b", "y,z")] {
+            put(&node, "session:1", value, None);
+            put(&node, "tags:1", tags, None);
+            put(&node, "1", value, None);
+        }
+        assert_eq!(values(&node, "session:1"), vec!["b"]);
+        assert_eq!(values(&node, "tags:1"), vec!["x,y,z"]);
+        assert_eq!(values(&node, "1"), vec!["a", "b"]);
+    }
+
+    // Polls until `done`, as repairs and transfers finish in the background.
+    fn wait_until(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + PEER_TIMEOUT;
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn quorum_reads_repair_stale_replicas() {
+        let nodes = start_nodes(2, 2);
+        let mut coordinator = nodes[0].clone();
+        coordinator.read_quorum = 2;
+        let context = put(&nodes[0], "k", "old", None);
+        assert_eq!(values(&nodes[1], "k"), vec!["old"]);
+        // Each replica misses one newer write.
+        nodes[0].put_local("k", Some("new".to_string()), Some(&context), None, None).unwrap();
+        nodes[1].put_local("j", Some("only here".to_string()), None, None, None).unwrap();
+
+        assert_eq!(coordinator.quorum_get("k").unwrap().unwrap().values, vec!["new"]);
+        wait_until(|| values(&nodes[1], "k") == vec!["new"]);
+        assert_eq!(coordinator.quorum_get("j").unwrap().unwrap().values, vec!["only here"]);
+        wait_until(|| values(&nodes[0], "j") == vec!["only here"]);
+        wait_until(|| coordinator.stats().read_repairs == 2);
+        assert_eq!(coordinator.stats().read_repairs_failed, 0);
+    }
+
+    #[test]
+    fn joining_and_leaving_move_keys_to_their_owners() {
+        let a = start_nodes(1, 1).pop().unwrap();
+        let b = start_nodes(1, 1).pop().unwrap();
+        let keys: Vec<String> = (0..100).map(|i| format!("k{}", i)).collect();
+        for key in &keys {
+            put(&a, key, "v", None);
+        }
+
+        b.join(&a.address).unwrap();
+        let pending = b.rebalance.lock().unwrap().pending();
+        assert!(!pending.is_empty());
+        for index in pending {
+            b.run_transfer(index).unwrap();
+        }
+        assert!(b.rebalance.lock().unwrap().pending().is_empty());
+        let ring = a.ring(true);
+        let (moved, kept): (Vec<&String>, Vec<&String>) = keys.iter().partition(|key| ring.owners(key) == vec![b.id]);
+        assert!(!moved.is_empty() && !kept.is_empty());
+        assert!(moved.iter().all(|key| b.get(key).is_some()));
+        assert!(kept.iter().all(|key| b.get(key).is_none()));
+        // The old owner keeps its copies until it releases them.
+        assert!(moved.iter().all(|key| a.get(key).is_some()));
+        assert_eq!(a.release_unowned().unwrap(), moved.len());
+        assert!(moved.iter().all(|key| a.get(key).is_none()));
+        assert!(kept.iter().all(|key| a.get(key).is_some()));
+
+        // Leaving hands everything back before the cluster forgets the node.
+        b.start_rebalancer(Duration::from_millis(20));
+        b.leave().unwrap();
+        assert!(keys.iter().all(|key| a.get(key).is_some()));
+        assert!(!a.members.lock().unwrap().contains_key(&b.id));
+    }
+
+    fn eval(node: &Node, script: &str, keys: &[&str], args: &[&str]) -> PeerMessage {
+        let owned = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        node.handle_message(PeerMessage::Eval { script: script.to_string(), keys: owned(keys), args: owned(args) })
+    }
+
+    #[test]
+    fn scripts_write_only_when_they_finish() {
+        let node = test_node();
+        let reply = eval(&node, "set(KEYS[1], ARGV[1]) return get(KEYS[1])", &["a"], &["1"]);
+        assert!(matches!(reply, PeerMessage::Evaluated { reply } if reply == "1\n"));
+        let reply = eval(&node, "set(KEYS[1], '2') return nosuch()", &["a"], &[]);
+        assert!(matches!(reply, PeerMessage::Error(e) if e.contains("unknown function")));
+        assert_eq!(node.get("a").unwrap().values, vec!["1"]);
+    }
+
+    #[test]
+    fn script_keys_must_share_their_replicas() {
+        let mut node = test_node();
+        node.replication_factor = 1;
+        node.mark_alive(Uuid::new_v4(), "127.0.0.1:1".to_string());
+        let ring = node.ring(true);
+        let mine = (0..).map(|i| format!("k{}", i)).find(|key| ring.owners(key) == vec![node.id]).unwrap();
+        let theirs = (0..).map(|i| format!("k{}", i)).find(|key| ring.owners(key) != vec![node.id]).unwrap();
+        let reply = eval(&node, "return 1", &[&mine, &theirs], &[]);
+        assert!(matches!(reply, PeerMessage::Error(e) if e.starts_with("CROSSSLOT")));
+        assert!(matches!(eval(&node, "return 1", &[&theirs], &[]), PeerMessage::WrongNode { .. }));
+        assert!(matches!(eval(&node, "return 1", &[&mine], &[]), PeerMessage::Evaluated { .. }));
+    }
+}
@@ -0,0 +1,320 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::Mutex;
+use std::time::Duration;
+use uuid::Uuid;
+use super::clock::{Origin, Versioned};
+use super::rebalance::Transfer;
+use super::ring::TokenRange;
+use super::wal::WalEntry;
+use super::watch::{ChangeEvent, WatchTarget};
+
+// Messages exchanged between nodes, one JSON document per line.
+#[derive(Serialize, Deserialize, Debug)]
+pub enum PeerMessage {
+    TreeHashes { range: TokenRange, level: usize, indices: Vec<usize> },
+    Hashes(Vec<(usize, u64)>),
+    LeafDigests { range: TokenRange, leaves: Vec<usize> },
+    Digests(Vec<(String, u64)>),
+    FetchKeys { keys: Vec<String> },
+    Versions(Vec<(String, Vec<Versioned>)>),
+    Repair(Vec<(String, Vec<Versioned>)>),
+    Replicate { key: String, versions: Vec<Versioned> },
+    Ping { id: Uuid, address: String },
+    // Carries the alive members the responder knows, so joins spread
+    // without going through the seed.
+    Pong {
+        id: Uuid,
+        #[serde(default)]
+        members: HashMap<Uuid, String>,
+    },
+    Join { id: Uuid, address: String },
+    Members(HashMap<Uuid, Member>),
+    Leave { id: Uuid },
+    StreamRange { range: TokenRange, after: Option<String>, limit: usize },
+    RangeBatch { entries: Vec<(String, Vec<Versioned>)>, next: Option<String> },
+    // Client requests. A node answers `WrongNode` for keys it does not own.
+    Get {
+        key: String,
+        // Subscription to notify when this key changes, for client-side caching.
+        #[serde(default)]
+        tracking_id: Option<u64>,
+    },
+    Value { values: Vec<String>, context: Option<String> },
+    Put {
+        key: String,
+        value: String,
+        context: Option<String>,
+        #[serde(default)]
+        ttl_secs: Option<u64>,
+        // Set by cross-cluster replication.
+        #[serde(default)]
+        origin: Option<Origin>,
+    },
+    Delete {
+        key: String,
+        context: Option<String>,
+        #[serde(default)]
+        origin: Option<Origin>,
+    },
+    Written { context: String },
+    WrongNode { owners: Vec<String> },
+    CompareAndSwap { key: String, expected: Option<String>, value: String },
+    Swapped { swapped: bool, context: Option<String> },
+    // Adds `by` to the number the key holds, 0 if it has none, and replies
+    // with the sum.
+    Increment { key: String, by: Delta },
+    Incremented { value: String, context: String },
+    // Runs a script on a replica of its keys, which must all share the same
+    // replicas. `reply` is the script's return value as the server prints it.
+    Eval { script: String, keys: Vec<String>, args: Vec<String> },
+    Evaluated { reply: String },
+    // Scans only cover the keys held by the node that receives them.
+    Scan { prefix: String, after: Option<String>, limit: usize },
+    ScanBatch { entries: Vec<(String, Vec<String>)>, next: Option<String> },
+    // Turns the connection into a stream of `Invalidate` messages.
+    TrackingSubscribe,
+    TrackingId { id: u64 },
+    Invalidate { keys: Vec<String> },
+    // Streams `Change` messages after `Watching` until the client sends
+    // `Unwatch`. `from_seq` resumes after a change already seen from this node.
+    Watch { target: WatchTarget, from_seq: Option<u64> },
+    Watching { seq: u64 },
+    Change(ChangeEvent),
+    Unwatch,
+    // `receivers` counts subscribers on the node that took the message;
+    // other nodes receive it with `forwarded` set.
+    Publish {
+        channel: String,
+        message: String,
+        #[serde(default)]
+        forwarded: bool,
+    },
+    Published { receivers: usize },
+    // Streams `Message`s until the client sends `Unsubscribe`. A
+    // `MessagesDropped` notice precedes the first message after any were lost.
+    Subscribe { channels: Vec<String>, patterns: Vec<String> },
+    Subscribed { channels: Vec<String>, patterns: Vec<String> },
+    Message { channel: String, pattern: Option<String>, message: String },
+    MessagesDropped { count: u64 },
+    Unsubscribe,
+    // Streams the node's write-ahead log from `from_seq`, or its oldest
+    // retained entry, as `CdcEntry`s until the client sends `CdcStop`.
+    CdcSubscribe { from_seq: Option<u64> },
+    CdcStarted { first_seq: u64, last_seq: u64 },
+    CdcEntry(WalEntry),
+    CdcStop,
+    Topology,
+    TopologyReply { members: HashMap<Uuid, Member>, replication_factor: usize },
+    // Admin commands.
+    RebalanceStatus,
+    RebalanceReport(Vec<Transfer>),
+    Decommission,
+    Stats,
+    StatsReply(NodeStats),
+    Ack,
+    Error(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Delta {
+    Integer(i64),
+    Float(f64),
+}
+
+impl Delta {
+    // The sum as the value to store, or the reason there is none.
+    pub fn apply(&self, current: &str) -> Result<String, &'static str> {
+        match *self {
+            Delta::Integer(by) => {
+                let current = current.parse::<i64>().map_err(|_| "value is not an integer or out of range")?;
+                current.checked_add(by).map(|sum| sum.to_string()).ok_or("increment or decrement would overflow")
+            }
+            Delta::Float(by) => {
+                let current = match current.parse::<f64>() {
+                    Ok(current) if current.is_finite() => current,
+                    _ => return Err("value is not a valid float"),
+                };
+                let sum = current + by;
+                if sum.is_finite() {
+                    Ok(sum.to_string())
+                } else {
+                    Err("increment would produce NaN or Infinity")
+                }
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NodeStats {
+    pub repair_rounds: u64,
+    pub failed_repair_rounds: u64,
+    pub keys_repaired: u64,
+    pub last_repair_millis: u64,
+    pub hints_recorded: u64,
+    pub hints_delivered: u64,
+    pub hints_dropped: u64,
+    pub hints_pending: HashMap<Uuid, usize>,
+    pub read_repairs: u64,
+    pub read_repairs_skipped: u64,
+    pub read_repairs_failed: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Member {
+    pub address: String,
+    pub alive: bool,
+    pub last_seen_millis: u64,
+}
+
+pub fn send(stream: &mut TcpStream, message: &PeerMessage) -> io::Result<()> {
+    let mut line = serde_json::to_string(message)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())
+}
+
+// Returns `None` once the other side has closed the connection.
+pub fn receive(reader: &mut BufReader<TcpStream>) -> io::Result<Option<PeerMessage>> {
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Ok(None);
+    }
+    let message = serde_json::from_str(&line)?;
+    Ok(Some(message))
+}
+
+pub struct PeerConnection {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl PeerConnection {
+    pub fn open(address: &str, timeout: Duration) -> io::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(PeerConnection { stream, reader })
+    }
+
+    pub fn request(&mut self, message: &PeerMessage) -> io::Result<PeerMessage> {
+        error_reply(self.exchange(message)?)
+    }
+
+    // Like `request`, but hands back `Error` replies as they are, so the
+    // caller can tell a failed request from a failed connection.
+    fn exchange(&mut self, message: &PeerMessage) -> io::Result<PeerMessage> {
+        send(&mut self.stream, message)?;
+        match receive(&mut self.reader)? {
+            Some(reply) => Ok(reply),
+            None => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Peer closed the connection")),
+        }
+    }
+}
+
+fn error_reply(reply: PeerMessage) -> io::Result<PeerMessage> {
+    match reply {
+        PeerMessage::Error(e) => Err(io::Error::other(e)),
+        reply => Ok(reply),
+    }
+}
+
+const MAX_IDLE_PER_PEER: usize = 4;
+
+// Idle connections to other nodes, kept so frequent small requests such as
+// replicated writes skip the connect.
+pub struct PeerPool {
+    timeout: Duration,
+    idle: Mutex<HashMap<String, Vec<PeerConnection>>>,
+}
+
+impl PeerPool {
+    pub fn new(timeout: Duration) -> Self {
+        PeerPool { timeout, idle: Mutex::new(HashMap::new()) }
+    }
+
+    // Only for requests that are safe to send twice: a pooled connection the
+    // peer has since dropped is retried once on a fresh one.
+    pub fn request(&self, address: &str, message: &PeerMessage) -> io::Result<PeerMessage> {
+        let pooled = self.idle.lock().unwrap().get_mut(address).and_then(|idle| idle.pop());
+        if let Some(mut connection) = pooled {
+            if let Ok(reply) = connection.exchange(message) {
+                self.release(address, connection);
+                return error_reply(reply);
+            }
+        }
+        let mut connection = PeerConnection::open(address, self.timeout)?;
+        let reply = connection.exchange(message)?;
+        self.release(address, connection);
+        error_reply(reply)
+    }
+
+    fn release(&self, address: &str, connection: PeerConnection) {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.entry(address.to_string()).or_default();
+        if connections.len() < MAX_IDLE_PER_PEER {
+            connections.push(connection);
+        }
+    }
+}
+
+pub fn unexpected(reply: PeerMessage) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected reply from peer: {:?}", reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    // Answers every request with `Ack`; the first connection is closed after
+    // its first reply, as a restarted peer would.
+    fn serve(listener: TcpListener, accepted: Arc<AtomicUsize>) {
+        for (index, stream) in listener.incoming().enumerate() {
+            let mut stream = stream.unwrap();
+            accepted.fetch_add(1, Ordering::SeqCst);
+            thread::spawn(move || {
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                while let Ok(Some(_)) = receive(&mut reader) {
+                    send(&mut stream, &PeerMessage::Ack).unwrap();
+                    if index == 0 {
+                        return;
+                    }
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn pool_reuses_connections_and_replaces_dropped_ones() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        thread::spawn(move || serve(listener, counter));
+
+        let pool = PeerPool::new(Duration::from_secs(5));
+        assert!(matches!(pool.request(&address, &PeerMessage::Stats), Ok(PeerMessage::Ack)));
+        // The pooled connection was dropped by the peer, so this one retries.
+        assert!(matches!(pool.request(&address, &PeerMessage::Stats), Ok(PeerMessage::Ack)));
+        assert!(matches!(pool.request(&address, &PeerMessage::Stats), Ok(PeerMessage::Ack)));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn increments_add_to_numbers_and_refuse_anything_else() {
+        assert_eq!(Delta::Integer(5).apply("0"), Ok("5".to_string()));
+        assert_eq!(Delta::Integer(-3).apply("10"), Ok("7".to_string()));
+        assert_eq!(Delta::Float(0.5).apply("10"), Ok("10.5".to_string()));
+        assert!(Delta::Integer(1).apply("1.5").is_err());
+        assert!(Delta::Integer(1).apply(&i64::MAX.to_string()).is_err());
+        assert!(Delta::Float(1.0).apply("abc").is_err());
+        assert!(Delta::Float(f64::MAX).apply(&f64::MAX.to_string()).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::Arc;
+use super::peer::PeerMessage;
+
+struct Subscriber {
+    channels: HashSet<String>,
+    patterns: HashSet<String>,
+    queue: SyncSender<PeerMessage>,
+    // Messages that did not fit in the queue since the subscriber last heard from us.
+    dropped: Arc<AtomicU64>,
+}
+
+// Channel and pattern subscriptions of the clients connected to this node.
+// Delivery is at-most-once: a subscriber whose queue is full loses the
+// message, and is told how many it lost before its next delivery.
+pub struct PubSub {
+    queue_size: usize,
+    next_id: u64,
+    subscribers: HashMap<u64, Subscriber>,
+}
+
+pub struct Subscription {
+    pub id: u64,
+    pub messages: Receiver<PeerMessage>,
+    pub dropped: Arc<AtomicU64>,
+    // For replies that must not be dropped, such as subscribe confirmations.
+    pub control: SyncSender<PeerMessage>,
+}
+
+impl PubSub {
+    pub fn new(queue_size: usize) -> Self {
+        PubSub {
+            queue_size,
+            next_id: 1,
+            subscribers: HashMap::new(),
+        }
+    }
+
+    pub fn subscribe(&mut self) -> Subscription {
+        let (queue, messages) = mpsc::sync_channel(self.queue_size);
+        let dropped = Arc::new(AtomicU64::new(0));
+        let id = self.next_id;
+        self.next_id += 1;
+        self.subscribers.insert(
+            id,
+            Subscriber {
+                channels: HashSet::new(),
+                patterns: HashSet::new(),
+                queue: queue.clone(),
+                dropped: dropped.clone(),
+            },
+        );
+        Subscription { id, messages, dropped, control: queue }
+    }
+
+    pub fn add(&mut self, id: u64, channels: &[String], patterns: &[String]) {
+        if let Some(subscriber) = self.subscribers.get_mut(&id) {
+            subscriber.channels.extend(channels.iter().cloned());
+            subscriber.patterns.extend(patterns.iter().cloned());
+        }
+    }
+
+    pub fn unsubscribe(&mut self, id: u64) {
+        self.subscribers.remove(&id);
+    }
+
+    // Returns how many subscribers on this node the message was queued for.
+    pub fn publish(&mut self, channel: &str, message: &str) -> usize {
+        let mut delivered = 0;
+        let mut disconnected = Vec::new();
+        for (id, subscriber) in &self.subscribers {
+            let mut matches: Vec<Option<&String>> = Vec::new();
+            if subscriber.channels.contains(channel) {
+                matches.push(None);
+            }
+            matches.extend(subscriber.patterns.iter().filter(|p| glob_matches(p, channel)).map(Some));
+            for pattern in matches {
+                let delivery = PeerMessage::Message {
+                    channel: channel.to_string(),
+                    pattern: pattern.cloned(),
+                    message: message.to_string(),
+                };
+                match subscriber.queue.try_send(delivery) {
+                    Ok(()) => delivered += 1,
+                    Err(TrySendError::Full(_)) => {
+                        subscriber.dropped.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(TrySendError::Disconnected(_)) => disconnected.push(*id),
+                }
+            }
+        }
+        for id in disconnected {
+            self.subscribers.remove(&id);
+        }
+        delivered
+    }
+}
+
+// Redis-style glob: `*` matches any run of characters, `?` any single one.
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_like_redis() {
+        assert!(glob_matches("news.*", "news.sport"));
+        assert!(glob_matches("news.*", "news."));
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("h?llo", "hello"));
+        assert!(glob_matches("*a*b", "xaxab"));
+        assert!(glob_matches("né?s", "néws"));
+        assert!(!glob_matches("h?llo", "hllo"));
+        assert!(!glob_matches("news.*", "new.sport"));
+        assert!(!glob_matches("*a*b", "xaxa"));
+    }
+
+    #[test]
+    fn publish_counts_each_matching_subscription() {
+        let mut pubsub = PubSub::new(10);
+        let subscription = pubsub.subscribe();
+        pubsub.add(subscription.id, &["news".to_string()], &["n*".to_string(), "x*".to_string()]);
+        assert_eq!(pubsub.publish("news", "hi"), 2);
+        assert_eq!(pubsub.publish("other", "hi"), 0);
+        match subscription.messages.try_recv().unwrap() {
+            PeerMessage::Message { channel, pattern: None, message } => assert_eq!((channel.as_str(), message.as_str()), ("news", "hi")),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_full_queue_counts_drops_and_a_gone_subscriber_is_removed() {
+        let mut pubsub = PubSub::new(1);
+        let subscription = pubsub.subscribe();
+        pubsub.add(subscription.id, &["c".to_string()], &[]);
+        assert_eq!(pubsub.publish("c", "1"), 1);
+        assert_eq!(pubsub.publish("c", "2"), 0);
+        assert_eq!(pubsub.publish("c", "3"), 0);
+        assert_eq!(subscription.dropped.load(Ordering::Relaxed), 2);
+
+        drop(subscription);
+        pubsub.publish("c", "4");
+        assert!(pubsub.subscribers.is_empty());
+    }
+}
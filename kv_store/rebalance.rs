@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+use uuid::Uuid;
+use super::ring::TokenRange;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    // Pulling a range we now own from its previous owner.
+    Incoming,
+    // Pushing one of our ranges to the node taking it over.
+    Outgoing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transfer {
+    pub range: TokenRange,
+    pub direction: Direction,
+    pub peer: Uuid,
+    pub peer_address: String,
+    // Last key moved, so an interrupted transfer picks up after it.
+    pub cursor: Option<String>,
+    pub keys_moved: u64,
+    pub done: bool,
+}
+
+// Planned and in-flight transfers, saved after every batch so a restart
+// resumes where it stopped.
+pub struct RebalanceState {
+    path: PathBuf,
+    pub transfers: Vec<Transfer>,
+}
+
+impl RebalanceState {
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let transfers = if path.exists() {
+            serde_json::from_reader(BufReader::new(File::open(&path)?))?
+        } else {
+            Vec::new()
+        };
+        Ok(RebalanceState { path, transfers })
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let temp_path = self.path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            serde_json::to_writer(&mut writer, &self.transfers)?;
+            writer.flush()?;
+        }
+        fs::rename(&temp_path, &self.path)
+    }
+
+    pub fn pending(&self) -> Vec<usize> {
+        (0..self.transfers.len()).filter(|i| !self.transfers[*i].done).collect()
+    }
+
+    // Drops finished transfers once nothing is left in flight.
+    pub fn clear_finished(&mut self) -> io::Result<()> {
+        if self.pending().is_empty() {
+            self.transfers.clear();
+            self.save()?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Throttle {
+    pub batch_size: usize,
+    pub keys_per_sec: u64,
+}
+
+impl Throttle {
+    // Pause after a batch of `moved` keys to stay under `keys_per_sec`.
+    pub fn pause_for(&self, moved: usize) -> Duration {
+        if self.keys_per_sec == 0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_millis(moved as u64 * 1000 / self.keys_per_sec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn transfer(done: bool) -> Transfer {
+        Transfer {
+            range: TokenRange { start: 0, end: 10 },
+            direction: Direction::Incoming,
+            peer: Uuid::new_v4(),
+            peer_address: "127.0.0.1:1".to_string(),
+            cursor: Some("k".to_string()),
+            keys_moved: 3,
+            done,
+        }
+    }
+
+    #[test]
+    fn state_survives_a_reload_and_clears_once_done() {
+        let path = std::env::temp_dir().join(format!("rebalance-{}.json", Uuid::new_v4()));
+        let mut state = RebalanceState::load(path.clone()).unwrap();
+        state.transfers = vec![transfer(true), transfer(false)];
+        state.save().unwrap();
+
+        let mut reloaded = RebalanceState::load(path.clone()).unwrap();
+        assert_eq!(reloaded.pending(), vec![1]);
+        assert_eq!(reloaded.transfers[1].cursor.as_deref(), Some("k"));
+        reloaded.clear_finished().unwrap();
+        assert_eq!(reloaded.transfers.len(), 2);
+
+        reloaded.transfers[1].done = true;
+        reloaded.clear_finished().unwrap();
+        assert!(RebalanceState::load(path.clone()).unwrap().transfers.is_empty());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn throttle_paces_batches() {
+        let throttle = Throttle { batch_size: 100, keys_per_sec: 1_000 };
+        assert_eq!(throttle.pause_for(500), Duration::from_millis(500));
+        assert_eq!(Throttle { batch_size: 100, keys_per_sec: 0 }.pause_for(500), Duration::ZERO);
+    }
+}
@@ -0,0 +1,309 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use super::clock::{self, CausalContext, Origin, Versioned};
+use super::cluster_client::ClusterClient;
+use super::wal::WalEntry;
+
+#[derive(Debug, Clone)]
+pub enum ConflictPolicy {
+    // The newest write wins, whichever cluster made it.
+    Timestamp,
+    // Writes from clusters earlier in the list win; unlisted clusters rank
+    // last. Ties fall back to the timestamp.
+    OriginPriority(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub struct AgentConfig {
+    pub source_cluster: String,
+    pub destination_cluster: String,
+    pub prefixes: Vec<String>,
+    pub policy: ConflictPolicy,
+    pub position_path: PathBuf,
+}
+
+// Next log sequence to read from each source node, saved after every entry
+// so a restarted agent carries on where it stopped.
+#[derive(Serialize, Deserialize, Default)]
+struct Positions {
+    next_seq: HashMap<String, u64>,
+}
+
+impl Positions {
+    fn load(path: &PathBuf) -> io::Result<Self> {
+        if path.exists() {
+            Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+        } else {
+            Ok(Positions::default())
+        }
+    }
+
+    // The file is synced before the rename and the directory after it, so
+    // a crash leaves either the old positions or the new ones on disk.
+    fn save(&self, path: &PathBuf) -> io::Result<()> {
+        let temp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            serde_json::to_writer(&mut writer, self)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        fs::rename(&temp_path, path)?;
+        let dir = match path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+}
+
+// Tails every source node's write-ahead log and copies matching keys into
+// the destination cluster. Copies are tagged with the cluster they were
+// first written in, and never sent back there, so two agents can replicate
+// in both directions without looping.
+pub struct ReplicationAgent {
+    config: AgentConfig,
+    source: ClusterClient,
+    destination: ClusterClient,
+    positions: Positions,
+}
+
+impl ReplicationAgent {
+    pub fn new(config: AgentConfig, source: ClusterClient, destination: ClusterClient) -> io::Result<Self> {
+        let positions = Positions::load(&config.position_path)?;
+        Ok(ReplicationAgent { config, source, destination, positions })
+    }
+
+    // Runs until a source stream fails. Nodes without a saved position are
+    // read from the oldest entry they still retain.
+    pub fn run(&mut self) -> io::Result<()> {
+        let (sender, entries) = mpsc::channel();
+        for address in self.source.addresses() {
+            let from_seq = self.positions.next_seq.get(&address).cloned();
+            let stream = self
+                .source
+                .cdc_subscribe(&address, from_seq)
+                .map_err(|e| io::Error::new(e.kind(), format!("{}: {}", address, e)))?;
+            let sender = sender.clone();
+            thread::spawn(move || {
+                for entry in stream {
+                    let failed = entry.is_err();
+                    if sender.send((address.clone(), entry)).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        for (address, entry) in entries {
+            let entry = entry.map_err(|e| io::Error::new(e.kind(), format!("{}: {}", address, e)))?;
+            self.apply(&entry)?;
+            self.positions.next_seq.insert(address, entry.seq + 1);
+            self.positions.save(&self.config.position_path)?;
+        }
+        Ok(())
+    }
+
+    fn apply(&mut self, entry: &WalEntry) -> io::Result<()> {
+        if !self.config.prefixes.iter().any(|p| entry.key.starts_with(p.as_str())) {
+            return Ok(());
+        }
+        let now = clock::now_millis();
+        // Every sibling is copied, except expired values, which the
+        // destination expires itself, and values that came from the
+        // destination in the first place. Nothing is left for a key the
+        // source node handed off to its new owners.
+        let incoming: Vec<(&Versioned, String)> = entry
+            .versions
+            .iter()
+            .filter(|v| v.deleted || v.is_live(now))
+            .map(|v| (v, v.origin.clone().unwrap_or_else(|| self.config.source_cluster.clone())))
+            .filter(|(_, origin)| *origin != self.config.destination_cluster)
+            .collect();
+        let newest = match incoming
+            .iter()
+            .max_by(|a, b| a.0.timestamp.cmp(&b.0.timestamp).then_with(|| a.0.value.cmp(&b.0.value)))
+        {
+            Some(newest) => newest,
+            None => return Ok(()),
+        };
+
+        let current = self.destination.versions(&entry.key)?;
+        let origin_of = |v: &Versioned| v.origin.clone().unwrap_or_else(|| self.config.destination_cluster.clone());
+        // Every source replica logs the same write; apply it once.
+        let copied = incoming.iter().all(|(version, origin)| {
+            current.iter().any(|existing| {
+                existing.timestamp == version.timestamp
+                    && origin_of(existing) == *origin
+                    && existing.value == version.value
+                    && existing.deleted == version.deleted
+            })
+        });
+        if copied {
+            return Ok(());
+        }
+        if let Some(existing) = current.iter().max_by_key(|v| v.timestamp) {
+            if !self.wins(newest.0, &newest.1, existing, &origin_of(existing)) {
+                return Ok(());
+            }
+        }
+        let context = if current.is_empty() {
+            None
+        } else {
+            Some(CausalContext::from_clock(&clock::merged_clock(&current)))
+        };
+        // All siblings are written with the context read before any of them,
+        // so they supersede what the destination had but stay concurrent
+        // with each other.
+        for (version, origin) in incoming {
+            let origin = Origin { cluster: origin, timestamp: version.timestamp };
+            self.destination.apply_replicated(&entry.key, version, context.as_ref(), origin)?;
+        }
+        Ok(())
+    }
+
+    fn wins(&self, incoming: &Versioned, incoming_origin: &str, existing: &Versioned, existing_origin: &str) -> bool {
+        let newer = (incoming.timestamp, incoming_origin) > (existing.timestamp, existing_origin);
+        match &self.config.policy {
+            ConflictPolicy::Timestamp => newer,
+            ConflictPolicy::OriginPriority(order) => {
+                let rank = |cluster: &str| order.iter().position(|c| c == cluster).unwrap_or(order.len());
+                match rank(incoming_origin).cmp(&rank(existing_origin)) {
+                    std::cmp::Ordering::Less => true,
+                    std::cmp::Ordering::Greater => false,
+                    std::cmp::Ordering::Equal => newer,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+    use super::super::clock::VectorClock;
+    use super::super::node::testing::start_cluster;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn connect(nodes: &[String]) -> ClusterClient {
+        let seeds: Vec<&str> = nodes.iter().map(|s| s.as_str()).collect();
+        ClusterClient::connect(&seeds, TIMEOUT).unwrap()
+    }
+
+    fn agent(source: (&str, &[String]), destination: (&str, &[String]), policy: ConflictPolicy) -> ReplicationAgent {
+        let config = AgentConfig {
+            source_cluster: source.0.to_string(),
+            destination_cluster: destination.0.to_string(),
+            prefixes: vec!["r:".to_string()],
+            policy,
+            position_path: std::env::temp_dir().join(format!("positions-{}.json", uuid::Uuid::new_v4())),
+        };
+        ReplicationAgent::new(config, connect(source.1), connect(destination.1)).unwrap()
+    }
+
+    // Polls until `found` returns something, as the agents copy in the background.
+    fn wait_for<T>(mut found: impl FnMut() -> Option<T>) -> T {
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            if let Some(value) = found() {
+                return value;
+            }
+            assert!(Instant::now() < deadline, "timed out waiting for replication");
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    fn entry(key: &str, versions: Vec<Versioned>) -> WalEntry {
+        WalEntry { seq: 0, key: key.to_string(), old_context: None, versions, timestamp: clock::now_millis() }
+    }
+
+    #[test]
+    fn writes_replicate_in_both_directions() {
+        let (east, west) = (start_cluster(1, 1), start_cluster(1, 1));
+        for mut agent in [
+            agent(("east", &east), ("west", &west), ConflictPolicy::Timestamp),
+            agent(("west", &west), ("east", &east), ConflictPolicy::Timestamp),
+        ] {
+            thread::spawn(move || agent.run());
+        }
+        let (mut east_client, mut west_client) = (connect(&east), connect(&west));
+        east_client.put("r:1", "from east", None).unwrap();
+        west_client.put("r:2", "from west", None).unwrap();
+        east_client.put("other", "not replicated", None).unwrap();
+
+        let copied = wait_for(|| west_client.versions("r:1").ok().filter(|v| !v.is_empty()));
+        assert_eq!(copied[0].value, "from east");
+        assert_eq!(copied[0].origin.as_deref(), Some("east"));
+        let copied = wait_for(|| east_client.versions("r:2").ok().filter(|v| !v.is_empty()));
+        assert_eq!(copied[0].value, "from west");
+        assert_eq!(copied[0].origin.as_deref(), Some("west"));
+
+        // An update to a copy flows back to the cluster the key came from.
+        let context = west_client.get("r:1").unwrap().unwrap().context;
+        west_client.put("r:1", "updated in west", Some(&context)).unwrap();
+        wait_for(|| east_client.get("r:1").unwrap().filter(|s| s.values == vec!["updated in west"]));
+        assert!(west_client.get("other").unwrap().is_none());
+    }
+
+    #[test]
+    fn writes_are_not_sent_back_to_their_origin() {
+        let (east, west) = (start_cluster(1, 1), start_cluster(1, 1));
+        let mut agent = agent(("west", &west), ("east", &east), ConflictPolicy::Timestamp);
+        let mut echoed = Versioned::new("from east".to_string(), VectorClock::new());
+        echoed.origin = Some("east".to_string());
+        agent.apply(&entry("r:1", vec![echoed])).unwrap();
+        assert!(agent.destination.versions("r:1").unwrap().is_empty());
+
+        // Writes made in the source are copied, tagged with it.
+        let local = Versioned::new("from west".to_string(), VectorClock::new());
+        agent.apply(&entry("r:1", vec![local])).unwrap();
+        let copied = agent.destination.versions("r:1").unwrap();
+        assert_eq!(copied.len(), 1);
+        assert_eq!(copied[0].origin.as_deref(), Some("west"));
+    }
+
+    #[test]
+    fn conflicts_are_decided_by_the_policy() {
+        let (east, west) = (start_cluster(1, 1), start_cluster(1, 1));
+        connect(&east).put("r:1", "east", None).unwrap();
+        // Older than the write already in east.
+        let mut incoming = Versioned::new("west".to_string(), VectorClock::new());
+        incoming.timestamp -= 60_000;
+        let value_after = |policy: ConflictPolicy| {
+            let mut agent = agent(("west", &west), ("east", &east), policy);
+            agent.apply(&entry("r:1", vec![incoming.clone()])).unwrap();
+            agent.destination.get("r:1").unwrap().unwrap().values
+        };
+
+        assert_eq!(value_after(ConflictPolicy::Timestamp), vec!["east"]);
+        let east_first = vec!["east".to_string(), "west".to_string()];
+        assert_eq!(value_after(ConflictPolicy::OriginPriority(east_first)), vec!["east"]);
+        let west_first = vec!["west".to_string(), "east".to_string()];
+        assert_eq!(value_after(ConflictPolicy::OriginPriority(west_first)), vec!["west"]);
+    }
+
+    #[test]
+    fn positions_survive_a_save() {
+        let dir = std::env::temp_dir().join(format!("positions-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("positions.json");
+        assert!(Positions::load(&path).unwrap().next_seq.is_empty());
+
+        let mut positions = Positions::default();
+        positions.next_seq.insert("10.0.0.1:7000".to_string(), 42);
+        positions.save(&path).unwrap();
+        positions.next_seq.insert("10.0.0.1:7000".to_string(), 43);
+        positions.save(&path).unwrap();
+        assert_eq!(Positions::load(&path).unwrap().next_seq.get("10.0.0.1:7000"), Some(&43));
+        assert!(!path.with_extension("tmp").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
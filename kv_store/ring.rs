@@ -0,0 +1,143 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
+use super::merkle::fnv1a;
+
+pub const VIRTUAL_NODES: usize = 64;
+
+pub fn key_token(key: &str) -> u64 {
+    token(key.as_bytes())
+}
+
+// FNV-1a barely moves the high bits when only the last bytes differ, which
+// would bunch a node's virtual tokens together, so its output is mixed with
+// the murmur3 finalizer before it is placed on the ring.
+fn token(bytes: &[u8]) -> u64 {
+    let mut hash = fnv1a(bytes);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+// The tokens in (start, end], wrapping past u64::MAX when start >= end.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct TokenRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl TokenRange {
+    pub fn contains(&self, token: u64) -> bool {
+        if self.start < self.end {
+            token > self.start && token <= self.end
+        } else {
+            token > self.start || token <= self.end
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HashRing {
+    tokens: BTreeMap<u64, Uuid>,
+    replication_factor: usize,
+}
+
+impl HashRing {
+    pub fn new<I: IntoIterator<Item = Uuid>>(nodes: I, replication_factor: usize) -> Self {
+        let mut tokens = BTreeMap::new();
+        for node in nodes {
+            for vnode in 0..VIRTUAL_NODES {
+                tokens.insert(token(format!("{}-{}", node, vnode).as_bytes()), node);
+            }
+        }
+        HashRing {
+            tokens,
+            replication_factor,
+        }
+    }
+
+    pub fn owners(&self, key: &str) -> Vec<Uuid> {
+        self.owners_of_token(key_token(key))
+    }
+
+    // The first `replication_factor` distinct nodes clockwise from `token`.
+    pub fn owners_of_token(&self, token: u64) -> Vec<Uuid> {
+        let mut owners = Vec::new();
+        for node in self.tokens.range(token..).chain(self.tokens.range(..token)).map(|(_, n)| *n) {
+            if owners.len() >= self.replication_factor {
+                break;
+            }
+            if !owners.contains(&node) {
+                owners.push(node);
+            }
+        }
+        owners
+    }
+
+    // Every range between adjacent tokens with the nodes that own it.
+    pub fn ranges(&self) -> Vec<(TokenRange, Vec<Uuid>)> {
+        let tokens: Vec<u64> = self.tokens.keys().cloned().collect();
+        tokens
+            .iter()
+            .enumerate()
+            .map(|(i, end)| {
+                let start = if i == 0 { tokens[tokens.len() - 1] } else { tokens[i - 1] };
+                (TokenRange { start, end: *end }, self.owners_of_token(*end))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapping_range_contains_both_ends() {
+        let range = TokenRange { start: u64::MAX - 10, end: 10 };
+        assert!(range.contains(u64::MAX));
+        assert!(range.contains(0));
+        assert!(range.contains(10));
+        assert!(!range.contains(u64::MAX - 10));
+        assert!(!range.contains(11));
+    }
+
+    #[test]
+    fn owners_are_distinct_and_capped() {
+        let nodes: Vec<Uuid> = (0..5).map(|_| Uuid::new_v4()).collect();
+        let ring = HashRing::new(nodes.clone(), 3);
+        for key in ["a", "b", "user:42", ""] {
+            let owners = ring.owners(key);
+            assert_eq!(owners.len(), 3);
+            assert!(owners.iter().all(|owner| nodes.contains(owner)));
+            assert!(owners.iter().enumerate().all(|(i, owner)| !owners[..i].contains(owner)));
+        }
+        assert_eq!(HashRing::new(nodes[..2].to_vec(), 3).owners("a").len(), 2);
+    }
+
+    #[test]
+    fn ranges_cover_every_token_once() {
+        let ring = HashRing::new((0..3).map(|_| Uuid::new_v4()), 2);
+        let ranges = ring.ranges();
+        assert_eq!(ranges.len(), 3 * VIRTUAL_NODES);
+        for key in ["a", "b", "c", "user:1", "user:2"] {
+            let token = key_token(key);
+            let containing: Vec<_> = ranges.iter().filter(|(range, _)| range.contains(token)).collect();
+            assert_eq!(containing.len(), 1);
+            assert_eq!(containing[0].1, ring.owners(key));
+        }
+    }
+
+    #[test]
+    fn adding_a_node_moves_only_some_keys() {
+        let nodes: Vec<Uuid> = (0..4).map(|_| Uuid::new_v4()).collect();
+        let before = HashRing::new(nodes[..3].to_vec(), 1);
+        let after = HashRing::new(nodes.clone(), 1);
+        let keys: Vec<String> = (0..1000).map(|i| format!("key{}", i)).collect();
+        let moved = keys.iter().filter(|k| before.owners(k) != after.owners(k)).count();
+        assert!(moved > 0 && moved < 500, "moved {}", moved);
+        assert!(keys.iter().filter(|k| before.owners(k) != after.owners(k)).all(|k| after.owners(k) == vec![nodes[3]]));
+    }
+}
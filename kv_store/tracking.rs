@@ -0,0 +1,107 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::Sender;
+use super::peer::PeerMessage;
+
+// Which clients cache which keys. Clients open a side connection that only
+// receives invalidations, and name it by id when they read a key they are
+// going to cache. Like Redis tracking, a key is forgotten once invalidated;
+// the client has to read it again to keep caching it.
+#[derive(Default)]
+pub struct InvalidationTable {
+    next_id: u64,
+    subscribers: HashMap<u64, Sender<PeerMessage>>,
+    keys: HashMap<String, HashSet<u64>>,
+}
+
+impl InvalidationTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn subscribe(&mut self, sender: Sender<PeerMessage>) -> u64 {
+        self.next_id += 1;
+        let id = self.next_id;
+        self.subscribers.insert(id, sender);
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: u64) {
+        self.subscribers.remove(&id);
+        for subscribers in self.keys.values_mut() {
+            subscribers.remove(&id);
+        }
+        self.keys.retain(|_, subscribers| !subscribers.is_empty());
+    }
+
+    // Returns false if `id` is not a live subscription.
+    pub fn track(&mut self, id: u64, key: &str) -> bool {
+        if !self.subscribers.contains_key(&id) {
+            return false;
+        }
+        self.keys.entry(key.to_string()).or_default().insert(id);
+        true
+    }
+
+    pub fn invalidate(&mut self, key: &str) {
+        let subscribers = match self.keys.remove(key) {
+            Some(subscribers) => subscribers,
+            None => return,
+        };
+        for id in subscribers {
+            let delivered = match self.subscribers.get(&id) {
+                Some(sender) => sender.send(PeerMessage::Invalidate { keys: vec![key.to_string()] }).is_ok(),
+                None => true,
+            };
+            if !delivered {
+                self.unsubscribe(id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn invalidated(message: PeerMessage) -> Vec<String> {
+        match message {
+            PeerMessage::Invalidate { keys } => keys,
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_tracked_key_is_invalidated_once() {
+        let mut table = InvalidationTable::new();
+        let (sender, receiver) = mpsc::channel();
+        let id = table.subscribe(sender);
+        assert!(table.track(id, "a"));
+        assert!(!table.track(id + 1, "a"));
+        table.invalidate("b");
+        table.invalidate("a");
+        table.invalidate("a");
+        assert_eq!(invalidated(receiver.try_recv().unwrap()), vec!["a"]);
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn gone_subscribers_are_dropped() {
+        let mut table = InvalidationTable::new();
+        let (sender, receiver) = mpsc::channel();
+        let id = table.subscribe(sender);
+        table.track(id, "a");
+        table.track(id, "b");
+        drop(receiver);
+        table.invalidate("a");
+        assert!(!table.track(id, "c"));
+        assert!(table.keys.is_empty());
+
+        let (sender, receiver) = mpsc::channel();
+        let id = table.subscribe(sender);
+        table.track(id, "a");
+        table.unsubscribe(id);
+        // Unsubscribing drops the table's sender, which ends a receiver loop.
+        assert!(receiver.recv().is_err());
+    }
+}
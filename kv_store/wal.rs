@@ -0,0 +1,408 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use super::clock::{self, Versioned};
+
+// One mutation applied on this node. The log is both how the node recovers
+// its data and what change-data-capture consumers read.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WalEntry {
+    pub seq: u64,
+    pub key: String,
+    // Causal context of the key before the mutation, if it existed.
+    pub old_context: Option<String>,
+    // The key's versions after the mutation; tombstones for a delete, and
+    // none once the node has handed the key off to its new owners.
+    pub versions: Vec<Versioned>,
+    pub timestamp: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Retention {
+    pub max_bytes: u64,
+    pub max_age: Duration,
+}
+
+struct Segment {
+    first_seq: u64,
+    path: PathBuf,
+    bytes: u64,
+    last_timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    seq: u64,
+    data: HashMap<String, Vec<Versioned>>,
+}
+
+// Segmented JSON-lines log named by the first sequence number in each
+// segment. Segments are only deleted once a snapshot covers them, and then
+// only when they fall outside the retention limits.
+pub struct Wal {
+    dir: PathBuf,
+    segment_bytes: u64,
+    retention: Retention,
+    segments: Vec<Segment>,
+    writer: File,
+    next_seq: u64,
+    // Entries up to here are known to be on disk.
+    synced_seq: u64,
+    checkpoint_seq: u64,
+}
+
+impl Wal {
+    // Returns the log and the data recovered from the snapshot plus every
+    // entry after it.
+    pub fn open(dir: PathBuf, segment_bytes: u64, retention: Retention) -> io::Result<(Self, HashMap<String, Vec<Versioned>>)> {
+        fs::create_dir_all(&dir)?;
+        let snapshot_path = dir.join("snapshot.json");
+        let (checkpoint_seq, mut data) = if snapshot_path.exists() {
+            let snapshot: Snapshot = serde_json::from_reader(BufReader::new(File::open(&snapshot_path)?))?;
+            (snapshot.seq, snapshot.data)
+        } else {
+            (0, HashMap::new())
+        };
+
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "log") {
+                if let Some(first_seq) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok()) {
+                    segments.push(Segment { first_seq, path, bytes: 0, last_timestamp: 0 });
+                }
+            }
+        }
+        segments.sort_by_key(|s| s.first_seq);
+
+        let mut next_seq = checkpoint_seq + 1;
+        let segment_count = segments.len();
+        for (index, segment) in segments.iter_mut().enumerate() {
+            let mut reader = BufReader::new(File::open(&segment.path)?);
+            let mut line = String::new();
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    break;
+                }
+                let entry: WalEntry = match serde_json::from_str(&line) {
+                    Ok(entry) if line.ends_with('\n') => entry,
+                    // A crash mid-append can only tear the last line of the
+                    // newest segment. It is cut off so later appends start
+                    // on a fresh line; anything else is corruption and is
+                    // left for an operator rather than thrown away.
+                    _ if index + 1 == segment_count && reader.fill_buf()?.is_empty() => {
+                        println!("Dropping a torn entry at the end of {}", segment.path.display());
+                        OpenOptions::new().write(true).open(&segment.path)?.set_len(segment.bytes)?;
+                        break;
+                    }
+                    _ => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Corrupt entry in {} at byte {}", segment.path.display(), segment.bytes),
+                        ))
+                    }
+                };
+                segment.bytes += line.len() as u64;
+                segment.last_timestamp = entry.timestamp;
+                next_seq = entry.seq + 1;
+                if entry.seq > checkpoint_seq {
+                    if entry.versions.is_empty() {
+                        data.remove(&entry.key);
+                    } else {
+                        data.insert(entry.key, entry.versions);
+                    }
+                }
+            }
+        }
+
+        let writer = match segments.last() {
+            Some(segment) => OpenOptions::new().append(true).open(&segment.path)?,
+            None => {
+                let path = dir.join(format!("{:020}.log", next_seq));
+                let writer = OpenOptions::new().create(true).append(true).open(&path)?;
+                segments.push(Segment { first_seq: next_seq, path, bytes: 0, last_timestamp: 0 });
+                writer
+            }
+        };
+        let wal = Wal {
+            dir,
+            segment_bytes,
+            retention,
+            segments,
+            writer,
+            next_seq,
+            synced_seq: next_seq - 1,
+            checkpoint_seq,
+        };
+        Ok((wal, data))
+    }
+
+    // Writes the entry without waiting for the disk; `sync_through` makes it
+    // durable. A failed write is cut back off so the log stays parseable.
+    pub fn append(&mut self, key: &str, old_context: Option<String>, versions: Vec<Versioned>) -> io::Result<u64> {
+        let entry = WalEntry {
+            seq: self.next_seq,
+            key: key.to_string(),
+            old_context,
+            versions,
+            timestamp: clock::now_millis(),
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let active_bytes = self.segments.last().map_or(0, |s| s.bytes);
+        if active_bytes > 0 && active_bytes + line.len() as u64 > self.segment_bytes {
+            self.roll()?;
+        }
+        if let Err(e) = self.writer.write_all(line.as_bytes()) {
+            let _ = self.writer.set_len(self.segments.last().map_or(0, |s| s.bytes));
+            return Err(e);
+        }
+        let active = self.segments.last_mut().unwrap();
+        active.bytes += line.len() as u64;
+        active.last_timestamp = entry.timestamp;
+        self.next_seq += 1;
+        Ok(entry.seq)
+    }
+
+    // Makes every entry up to `seq` durable. The fsync runs outside the log's
+    // lock, so other writers keep appending meanwhile and one fsync covers
+    // all the entries written before it started.
+    pub fn sync_through(wal: &Mutex<Wal>, seq: u64) -> io::Result<()> {
+        let (file, last_seq) = {
+            let wal = wal.lock().unwrap();
+            if wal.synced_seq >= seq {
+                return Ok(());
+            }
+            (wal.writer.try_clone()?, wal.last_seq())
+        };
+        file.sync_data()?;
+        let mut wal = wal.lock().unwrap();
+        wal.synced_seq = wal.synced_seq.max(last_seq);
+        Ok(())
+    }
+
+    // Earlier segments are synced before moving on, since `sync_through`
+    // only syncs the active one.
+    fn roll(&mut self) -> io::Result<()> {
+        self.writer.sync_data()?;
+        self.synced_seq = self.last_seq();
+        let path = self.dir.join(format!("{:020}.log", self.next_seq));
+        self.writer = OpenOptions::new().create(true).append(true).open(&path)?;
+        self.segments.push(Segment { first_seq: self.next_seq, path, bytes: 0, last_timestamp: 0 });
+        Ok(())
+    }
+
+    pub fn first_seq(&self) -> u64 {
+        self.segments.first().map_or(self.next_seq, |s| s.first_seq)
+    }
+
+    pub fn last_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    // Saves `data`, which must reflect every entry up to `seq`, then drops
+    // segments the snapshot covers that are past retention.
+    pub fn checkpoint(&mut self, seq: u64, data: HashMap<String, Vec<Versioned>>) -> io::Result<()> {
+        let path = self.dir.join("snapshot.json");
+        let temp_path = path.with_extension("tmp");
+        {
+            let mut writer = BufWriter::new(File::create(&temp_path)?);
+            serde_json::to_writer(&mut writer, &Snapshot { seq, data })?;
+            writer.flush()?;
+            writer.get_ref().sync_data()?;
+        }
+        fs::rename(&temp_path, &path)?;
+        self.checkpoint_seq = seq;
+        self.enforce_retention()
+    }
+
+    fn enforce_retention(&mut self) -> io::Result<()> {
+        let now = clock::now_millis();
+        let max_age = self.retention.max_age.as_millis() as u64;
+        let mut total: u64 = self.segments.iter().map(|s| s.bytes).sum();
+        while self.segments.len() > 1 {
+            let covered = self.segments[1].first_seq - 1 <= self.checkpoint_seq;
+            let oldest = &self.segments[0];
+            let expired = total > self.retention.max_bytes || oldest.last_timestamp + max_age < now;
+            if !covered || !expired {
+                break;
+            }
+            fs::remove_file(&oldest.path)?;
+            total -= oldest.bytes;
+            self.segments.remove(0);
+        }
+        Ok(())
+    }
+
+    // A reader starting at `from_seq`, which must still be retained.
+    pub fn reader(&self, from_seq: u64) -> io::Result<WalReader> {
+        if from_seq < self.first_seq() || from_seq > self.next_seq {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Sequence {} is not retained, the log holds {} to {}", from_seq, self.first_seq(), self.last_seq()),
+            ));
+        }
+        let segment = self.segments.iter().rev().find(|s| s.first_seq <= from_seq).unwrap_or(&self.segments[0]);
+        Ok(WalReader {
+            segment_first: segment.first_seq,
+            reader: BufReader::new(File::open(&segment.path)?),
+            next_seq: from_seq,
+        })
+    }
+
+    fn segment_after(&self, first_seq: u64) -> Option<(u64, PathBuf)> {
+        self.segments.iter().find(|s| s.first_seq > first_seq).map(|s| (s.first_seq, s.path.clone()))
+    }
+}
+
+// Follows the log from a sequence number, moving across segments as the
+// writer rolls them. Open segments stay readable after retention deletes them.
+pub struct WalReader {
+    segment_first: u64,
+    reader: BufReader<File>,
+    next_seq: u64,
+}
+
+impl WalReader {
+    // Returns `None` once caught up with the writer.
+    pub fn next(&mut self, wal: &Mutex<Wal>) -> io::Result<Option<WalEntry>> {
+        loop {
+            let mut line = String::new();
+            let read = self.reader.read_line(&mut line)?;
+            if read > 0 && line.ends_with('\n') {
+                let entry: WalEntry = serde_json::from_str(&line)?;
+                if entry.seq < self.next_seq {
+                    continue;
+                }
+                self.next_seq = entry.seq + 1;
+                return Ok(Some(entry));
+            }
+            if read > 0 {
+                // The writer is mid-append; read the whole line next time.
+                self.reader.seek(SeekFrom::Current(-(read as i64)))?;
+                return Ok(None);
+            }
+            let next_segment = wal.lock().unwrap().segment_after(self.segment_first);
+            match next_segment {
+                // Only move on once this segment has been read to its end.
+                Some((first_seq, path)) if first_seq == self.next_seq => {
+                    self.reader = BufReader::new(File::open(path)?);
+                    self.segment_first = first_seq;
+                }
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::clock::VectorClock;
+    use std::io::Read;
+
+    const RETENTION: Retention = Retention { max_bytes: u64::MAX, max_age: Duration::from_secs(3600) };
+
+    fn temp_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("wal-{}", uuid::Uuid::new_v4()))
+    }
+
+    fn version(value: &str) -> Vec<Versioned> {
+        let mut clock = VectorClock::new();
+        clock.increment("n");
+        vec![Versioned::new(value.to_string(), clock)]
+    }
+
+    fn values(data: &HashMap<String, Vec<Versioned>>, key: &str) -> Option<String> {
+        data.get(key).map(|versions| versions[0].value.clone())
+    }
+
+    #[test]
+    fn reopening_replays_every_entry() {
+        let dir = temp_dir();
+        let (mut wal, data) = Wal::open(dir.clone(), 1 << 20, RETENTION).unwrap();
+        assert!(data.is_empty());
+        wal.append("a", None, version("1")).unwrap();
+        wal.append("b", None, version("2")).unwrap();
+        let seq = wal.append("a", None, version("3")).unwrap();
+        wal.append("b", None, Vec::new()).unwrap();
+        Wal::sync_through(&Mutex::new(wal), seq).unwrap();
+
+        let (wal, data) = Wal::open(dir.clone(), 1 << 20, RETENTION).unwrap();
+        assert_eq!(values(&data, "a").as_deref(), Some("3"));
+        assert!(!data.contains_key("b"));
+        assert_eq!(wal.last_seq(), 4);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_torn_tail_is_cut_off_and_appends_continue() {
+        let dir = temp_dir();
+        let (mut wal, _) = Wal::open(dir.clone(), 1 << 20, RETENTION).unwrap();
+        wal.append("a", None, version("1")).unwrap();
+        let path = wal.segments[0].path.clone();
+        drop(wal);
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"{\"seq\":2,\"ke").unwrap();
+
+        let (mut wal, data) = Wal::open(dir.clone(), 1 << 20, RETENTION).unwrap();
+        assert_eq!(values(&data, "a").as_deref(), Some("1"));
+        assert_eq!(wal.append("b", None, version("2")).unwrap(), 2);
+        drop(wal);
+        let (_, data) = Wal::open(dir.clone(), 1 << 20, RETENTION).unwrap();
+        assert_eq!(values(&data, "b").as_deref(), Some("2"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn corruption_before_the_tail_is_an_error_and_keeps_the_file() {
+        let dir = temp_dir();
+        let (mut wal, _) = Wal::open(dir.clone(), 1 << 20, RETENTION).unwrap();
+        wal.append("a", None, version("1")).unwrap();
+        let path = wal.segments[0].path.clone();
+        OpenOptions::new().append(true).open(&path).unwrap().write_all(b"not json\n").unwrap();
+        wal.append("b", None, version("2")).unwrap();
+        drop(wal);
+        let length = fs::metadata(&path).unwrap().len();
+
+        assert_eq!(Wal::open(dir.clone(), 1 << 20, RETENTION).err().unwrap().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(fs::metadata(&path).unwrap().len(), length);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn readers_follow_rolled_segments_and_checkpoints_cover_them() {
+        let dir = temp_dir();
+        let (wal, _) = Wal::open(dir.clone(), 200, RETENTION).unwrap();
+        let wal = Mutex::new(wal);
+        for i in 0..5 {
+            wal.lock().unwrap().append(&format!("k{}", i), None, version(&i.to_string())).unwrap();
+        }
+        assert!(wal.lock().unwrap().segments.len() > 1);
+        let mut reader = wal.lock().unwrap().reader(2).unwrap();
+        let mut seqs = Vec::new();
+        while let Some(entry) = reader.next(&wal).unwrap() {
+            seqs.push(entry.seq);
+        }
+        assert_eq!(seqs, vec![2, 3, 4, 5]);
+
+        let mut data = HashMap::new();
+        data.insert("k0".to_string(), version("0"));
+        wal.lock().unwrap().checkpoint(1, data).unwrap();
+        drop(wal);
+        let (wal, data) = Wal::open(dir.clone(), 200, RETENTION).unwrap();
+        assert_eq!(data.len(), 5);
+        assert_eq!(wal.last_seq(), 5);
+        assert!(wal.reader(wal.first_seq()).is_ok());
+        assert!(wal.reader(7).is_err());
+        let mut snapshot = String::new();
+        File::open(dir.join("snapshot.json")).unwrap().read_to_string(&mut snapshot).unwrap();
+        assert!(snapshot.contains("\"seq\":1"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
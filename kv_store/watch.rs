@@ -0,0 +1,135 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use super::clock::{self, CausalContext};
+use super::wal::WalEntry;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+    Put,
+    Delete,
+    Expire,
+}
+
+// One change to a key as seen by a node. `seq` is the change's position in
+// the node's write-ahead log; other nodes number theirs independently.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChangeEvent {
+    pub seq: u64,
+    pub key: String,
+    pub kind: ChangeKind,
+    // Live values after the change, empty for deletes and expiries.
+    pub values: Vec<String>,
+    pub context: String,
+    pub timestamp: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub enum WatchTarget {
+    Key(String),
+    Prefix(String),
+}
+
+impl WatchTarget {
+    pub fn matches(&self, key: &str) -> bool {
+        match self {
+            WatchTarget::Key(k) => k == key,
+            WatchTarget::Prefix(prefix) => key.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+impl ChangeEvent {
+    // What a write-ahead log entry means to a watcher. Entries without
+    // versions only record that the node handed the key off, which is not a
+    // change to it.
+    pub fn from_entry(entry: WalEntry) -> Option<ChangeEvent> {
+        if entry.versions.is_empty() {
+            return None;
+        }
+        let values: Vec<String> =
+            entry.versions.iter().filter(|s| s.is_live(entry.timestamp)).map(|s| s.value.clone()).collect();
+        let kind = if !values.is_empty() {
+            ChangeKind::Put
+        } else if entry.versions.iter().any(|s| !s.deleted && s.expires_millis.is_some()) {
+            ChangeKind::Expire
+        } else {
+            ChangeKind::Delete
+        };
+        Some(ChangeEvent {
+            seq: entry.seq,
+            kind,
+            values,
+            context: CausalContext::from_clock(&clock::merged_clock(&entry.versions)).to_string(),
+            key: entry.key,
+            timestamp: entry.timestamp,
+        })
+    }
+}
+
+// Keys holding values that expire, by expiry time.
+#[derive(Default)]
+pub struct Expiring {
+    keys: BTreeSet<(u64, String)>,
+}
+
+impl Expiring {
+    pub fn insert(&mut self, key: &str, expires_millis: u64) {
+        self.keys.insert((expires_millis, key.to_string()));
+    }
+
+    // Keys with values that expired by `now`, to check and report.
+    pub fn due(&mut self, now: u64) -> Vec<String> {
+        let mut keys = Vec::new();
+        while self.keys.first().is_some_and(|(expires, _)| *expires <= now) {
+            keys.push(self.keys.pop_first().unwrap().1);
+        }
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use super::super::clock::{VectorClock, Versioned};
+
+    #[test]
+    fn targets_match_a_key_or_a_prefix() {
+        assert!(WatchTarget::Key("user:1".to_string()).matches("user:1"));
+        assert!(!WatchTarget::Key("user:1".to_string()).matches("user:10"));
+        assert!(WatchTarget::Prefix("user:".to_string()).matches("user:10"));
+        assert!(!WatchTarget::Prefix("user:".to_string()).matches("order:1"));
+    }
+
+    fn entry(versions: Vec<Versioned>, timestamp: u64) -> WalEntry {
+        WalEntry { seq: 7, key: "k".to_string(), old_context: None, versions, timestamp }
+    }
+
+    #[test]
+    fn log_entries_become_changes() {
+        let mut clock = VectorClock::new();
+        clock.increment("a");
+        let value = Versioned::new("v".to_string(), clock.clone());
+        let put = ChangeEvent::from_entry(entry(vec![value.clone()], value.timestamp)).unwrap();
+        assert_eq!((put.seq, put.kind, put.values), (7, ChangeKind::Put, vec!["v".to_string()]));
+
+        let deleted = ChangeEvent::from_entry(entry(vec![Versioned::tombstone(clock)], value.timestamp)).unwrap();
+        assert_eq!(deleted.kind, ChangeKind::Delete);
+
+        let expiring = Versioned { expires_millis: Some(value.timestamp + 10), ..value };
+        let expired = ChangeEvent::from_entry(entry(vec![expiring.clone()], expiring.timestamp + 10)).unwrap();
+        assert_eq!(expired.kind, ChangeKind::Expire);
+        assert!(expired.values.is_empty());
+
+        assert!(ChangeEvent::from_entry(entry(Vec::new(), 0)).is_none());
+    }
+
+    #[test]
+    fn due_returns_expired_keys_in_order() {
+        let mut expiring = Expiring::default();
+        expiring.insert("late", 30);
+        expiring.insert("early", 10);
+        expiring.insert("never", 100);
+        assert_eq!(expiring.due(30), vec!["early", "late"]);
+        assert!(expiring.due(30).is_empty());
+    }
+}
@@ -1,44 +1,62 @@
-use std::{collections::HashMap, env, io::{Read, Write}, net::{TcpListener, TcpStream}, sync::{Arc, Mutex}};
-use std::{thread};
+use std::env;
+
+// The standalone store only uses part of the modules it shares with main.rs.
+#[allow(dead_code)]
+mod document;
+#[allow(dead_code)]
+mod index;
+#[allow(dead_code)]
+mod memory;
+#[allow(dead_code)]
+mod sharded;
+#[allow(dead_code)]
+mod value;
 
 mod kv_store {
-    use std::collections::HashMap;
-    use std::sync::Mutex;
+    use std::sync::Arc;
+    use super::memory::{self, MemoryLimit, ShardedStore, StoreError};
 
     #[derive(Clone)]
     pub struct KeyValueStore {
-        store: Arc<Mutex<HashMap<String, String>>>,
+        store: Arc<ShardedStore>,
     }
 
     impl KeyValueStore {
-        pub fn new() -> KeyValueStore {
+        pub fn new(limit: MemoryLimit, shard_count: usize) -> KeyValueStore {
             KeyValueStore {
-                store: Arc::new(Mutex::new(HashMap::new())),
+                store: Arc::new(memory::sharded_store(limit, shard_count, Arc::default())),
             }
         }
 
-        pub fn set(&self, key: &str, value: &str) {
-            let mut store = self.store.lock().unwrap();
-            store.insert(key.to_string(), value.to_string());
+        pub fn set(&self, key: &str, value: &str) -> Result<(), StoreError> {
+            let mut store = self.store.lock(key);
+            store.set(key, value, None)
         }
 
-        pub fn get(&self, key: &str) -> Option<String> {
-            let store = self.store.lock().unwrap();
-            store.get(key).cloned()
+        pub fn get(&self, key: &str) -> Result<Option<String>, StoreError> {
+            let mut store = self.store.lock(key);
+            store.get(key)
         }
 
-        pub fn delete(&self, key: &str) -> Option<String> {
-            let mut store = self.store.lock().unwrap();
-            store.remove(key)
+        pub fn delete(&self, key: &str) -> bool {
+            let mut store = self.store.lock(key);
+            store.delete(key).is_some()
+        }
+
+        pub fn memory_info(&self) -> String {
+            memory::memory_info(&mut self.store.lock_all())
         }
     }
 }
 
 mod server {
     use super::kv_store::KeyValueStore;
-    use std::io::{Read, Write};
+    use super::memory::MemoryLimit;
+    use super::sharded;
+    use std::io::{self, Read, Write};
     use std::net::{TcpListener, TcpStream};
     use std::sync::Arc;
+    use std::thread;
 
     fn handle_client(mut stream: TcpStream, store: Arc<KeyValueStore>) -> std::io::Result<()> {
         let mut buffer = [0; 1024];
@@ -48,19 +66,23 @@ mod server {
                 return Ok(());
             }
             let recv = String::from_utf8_lossy(&buffer[..bytes_read]);
-            let mut parts = recv.trim().splitn(2, ' ');
+            let mut parts = recv.trim().splitn(3, ' ');
             match parts.next() {
                 Some("GET") => {
                     if let Some(key) = parts.next() {
-                        if let Some(value) = store.get(key) {
-                            stream.write_all(value.as_bytes())?;
+                        match store.get(key) {
+                            Ok(Some(value)) => stream.write_all(value.as_bytes())?,
+                            Ok(None) => {}
+                            Err(e) => stream.write_all(format!("{}\n", e).as_bytes())?,
                         }
                     }
                 }
                 Some("SET") => {
                     if let Some(key) = parts.next() {
                         if let Some(value) = parts.next() {
-                            store.set(key, value);
+                            if let Err(e) = store.set(key, value) {
+                                stream.write_all(format!("{}\n", e).as_bytes())?;
+                            }
                         }
                     }
                 }
@@ -69,14 +91,18 @@ mod server {
                         store.delete(key);
                     }
                 }
+                Some("INFO") => {
+                    stream.write_all(store.memory_info().as_bytes())?;
+                }
                 _ => {}
             }
         }
     }
 
     pub fn run_server(address: &str) -> std::io::Result<()> {
+        let limit = MemoryLimit::from_env().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
         let listener = TcpListener::bind(address)?;
-        let store = Arc::new(KeyValueStore::new());
+        let store = Arc::new(KeyValueStore::new(limit, sharded::default_shard_count()));
 
         for stream in listener.incoming() {
             match stream {
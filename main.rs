@@ -1,18 +1,50 @@
+mod document;
+mod index;
+mod keyspace;
+mod memory;
+mod quota;
+mod script;
+// Shared with server.rs and lib.rs, which use other parts of it.
+#[allow(dead_code)]
+mod sharded;
+mod snapshot;
+mod value;
+
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::sync::{Arc, Mutex};
-use std::collections::HashMap;
+use std::sync::Arc;
 use std::net::{TcpListener, TcpStream};
 use std::io::{self, Read, Write};
 use std::thread;
+use std::time::Duration;
+use document::Path;
+use index::{IndexDefinition, IndexKey, IndexQuery};
+use keyspace::{Keyspace, Keyspaces, DEFAULT_KEYSPACE};
+use memory::{BoundedStore, MemoryLimit, ShardedStore, StoreError};
+use script::{Script, ScriptLimits, ScriptStore, Scripts};
+use sharded::ShardGuards;
+use snapshot::SnapshotConfig;
+use value::{ScoreBound, SortedSet, Value, ELEMENT_OVERHEAD};
+
+type KeyspacesShared = Arc<Keyspaces>;
 
-type KeyValueStoreShared = Arc<Mutex<HashMap<String, String>>>;
+// Room made before a counter update; enough for any formatted number.
+const COUNTER_GROWTH: usize = 24;
 
 fn main() {
     dotenv::dotenv().expect("Failed to read .env file");
 
     let server_address = env::var("SERVER_ADDRESS").expect("SERVER_ADDRESS not set in .env file");
 
-    let keyValueStore: KeyValueStoreShared = Arc::new(Mutex::new(HashMap::new()));
+    let limit = MemoryLimit::from_env().expect("Invalid memory limit settings");
+    let keyspaces: KeyspacesShared = Arc::new(Keyspaces::new(limit, sharded::default_shard_count()));
+    if let Some(config) = SnapshotConfig::from_env().expect("Invalid snapshot settings") {
+        let restored = snapshot::load(&keyspaces, &config.path).expect("Failed to load snapshot");
+        println!("Restored {} keys from {}", restored, config.path.display());
+        snapshot::start(Arc::clone(&keyspaces), config);
+    }
+    let script_limits = ScriptLimits::from_env().expect("Invalid script limit settings");
+    let scripts = Arc::new(Scripts::new(script_limits));
 
     let server_listener = TcpListener::bind(&server_address).expect("Failed to bind to address");
 
@@ -21,10 +53,11 @@ fn main() {
     for stream in server_listener.incoming() {
         match stream {
             Ok(stream) => {
-                let store_clone = Arc::clone(&keyValueStore);
+                let keyspaces_clone = Arc::clone(&keyspaces);
+                let scripts_clone = Arc::clone(&scripts);
 
                 thread::spawn(move || {
-                    handle_connection(stream, store_clone);
+                    handle_connection(stream, keyspaces_clone, scripts_clone);
                 });
             }
             Err(e) => {
@@ -34,13 +67,35 @@ fn main() {
     }
 }
 
-fn handle_connection(mut stream: TcpStream, store: KeyValueStoreShared) {
+// Commands queued between MULTI and EXEC. A refused command aborts the
+// whole transaction when EXEC comes.
+#[derive(Default)]
+struct Transaction {
+    commands: Vec<String>,
+    aborted: bool,
+}
+
+// What a connection keeps between requests.
+struct Session {
+    keyspace: Arc<Keyspace>,
+    transaction: Option<Transaction>,
+}
+
+// The shards a command needs locked while it runs.
+enum KeySpec<'a> {
+    Keys(Vec<&'a str>),
+    All,
+}
+
+fn handle_connection(mut stream: TcpStream, keyspaces: KeyspacesShared, scripts: Arc<Scripts>) {
     let mut buffer = Vec::with_capacity(1024); // Dynamically grows, avoiding constant reallocation.
+    let keyspace = keyspaces.get(DEFAULT_KEYSPACE).expect("the default keyspace always exists");
+    let mut session = Session { keyspace, transaction: None };
 
     loop {
         match read_from_stream(&mut stream, &mut buffer) {
             Ok(_) => {
-                let response = process_request(&buffer, &store);
+                let response = process_request(&buffer, &keyspaces, &scripts, &mut session);
                 if let Err(e) = stream.write_all(response.as_bytes()) {
                     println!("Failed to send response: {}", e);
                     break;
@@ -56,27 +111,835 @@ fn handle_connection(mut stream: TcpStream, store: KeyValueStoreShared) {
     }
 }
 
-fn process_request(data: &[u8], store: &KeyValueStoreShared) -> String {
+fn process_request(data: &[u8], keyspaces: &KeyspacesShared, scripts: &Scripts, session: &mut Session) -> String {
     let request = String::from_utf8_lossy(data);
+    let tokens: Vec<&str> = request.split_whitespace().collect();
+
+    if let Some(reply) = keyspace_command(&tokens, keyspaces, session) {
+        return reply;
+    }
+    if session.keyspace.is_dropped() {
+        return format!("ERR keyspace '{}' was dropped, USE another\n", session.keyspace.name);
+    }
+    let written = match tokens.first() {
+        Some(command) if is_write(command) => data.len(),
+        _ => 0,
+    };
+    if let Err(wait) = session.keyspace.admit(written) {
+        let millis = (wait.as_micros() as u64).div_ceil(1000);
+        return format!("THROTTLED keyspace rate limit exceeded, retry after {} ms\n", millis);
+    }
+    if let Some(reply) = script_command(&request, &tokens, scripts, session) {
+        return reply;
+    }
+    let store = &session.keyspace.store;
+    let transaction = &mut session.transaction;
+
+    match (tokens.first().cloned(), transaction.as_mut()) {
+        (Some("MULTI"), Some(_)) => "ERR MULTI calls can not be nested\n".to_string(),
+        (Some("MULTI"), None) => {
+            *transaction = Some(Transaction::default());
+            "OK\n".to_string()
+        }
+        (Some(command @ ("EXEC" | "DISCARD")), None) => format!("ERR {} without MULTI\n", command),
+        (Some("DISCARD"), Some(_)) => {
+            *transaction = None;
+            "OK\n".to_string()
+        }
+        (Some("EXEC"), Some(_)) => exec(transaction.take().unwrap(), store),
+        (_, Some(queued)) => match command_keys(&tokens) {
+            Some(KeySpec::Keys(keys)) if keys.is_empty() => {
+                queued.aborted = true;
+                wrong_arguments(tokens[0])
+            }
+            Some(_) => {
+                queued.commands.push(request.to_string());
+                "QUEUED\n".to_string()
+            }
+            None => {
+                queued.aborted = true;
+                "Unsupported command\n".to_string()
+            }
+        },
+        (_, None) => match command_keys(&tokens) {
+            // Every key command names at least one key.
+            Some(KeySpec::Keys(keys)) if keys.is_empty() => wrong_arguments(tokens[0]),
+            Some(KeySpec::Keys(keys)) => execute(&request, &tokens, &mut store.lock_many(&keys)),
+            Some(KeySpec::All) => execute(&request, &tokens, &mut store.lock_all()),
+            None => "Unsupported command\n".to_string(),
+        },
+    }
+}
 
-    let mut tokens = request.split_whitespace();
+// CREATE KEYSPACE, DROP KEYSPACE, USE and KEYSPACES, or None for any other
+// command.
+fn keyspace_command(tokens: &[&str], keyspaces: &Keyspaces, session: &mut Session) -> Option<String> {
+    let command = match tokens {
+        [word, ..] if word.eq_ignore_ascii_case("USE") || word.eq_ignore_ascii_case("KEYSPACES") => word.to_uppercase(),
+        [word, kind, ..]
+            if (word.eq_ignore_ascii_case("CREATE") || word.eq_ignore_ascii_case("DROP")) && kind.eq_ignore_ascii_case("KEYSPACE") =>
+        {
+            format!("{} KEYSPACE", word.to_uppercase())
+        }
+        _ => return None,
+    };
+    if session.transaction.is_some() {
+        return Some(format!("ERR {} inside MULTI is not allowed\n", command));
+    }
+    let reply = match (command.as_str(), tokens) {
+        ("CREATE KEYSPACE", [_, _, name, options @ ..]) => match keyspaces.create(name, options) {
+            Ok(true) => "OK\n".to_string(),
+            Ok(false) => "ERR keyspace already exists\n".to_string(),
+            Err(e) => format!("{}\n", e),
+        },
+        ("DROP KEYSPACE", [_, _, name]) if *name == DEFAULT_KEYSPACE => "ERR the default keyspace cannot be dropped\n".to_string(),
+        ("DROP KEYSPACE", [_, _, name]) => {
+            if keyspaces.drop(name) {
+                "OK\n".to_string()
+            } else {
+                "ERR no such keyspace\n".to_string()
+            }
+        }
+        ("USE", [_, name]) => match keyspaces.get(name) {
+            Some(keyspace) => {
+                session.keyspace = keyspace;
+                "OK\n".to_string()
+            }
+            None => "ERR no such keyspace\n".to_string(),
+        },
+        ("KEYSPACES", [_]) => list_reply(Ok(keyspaces.names())),
+        _ => wrong_arguments(&command),
+    };
+    Some(reply)
+}
+
+// EVAL, EVALSHA and SCRIPT LOAD/EXISTS/FLUSH, or None for any other command.
+fn script_command(request: &str, tokens: &[&str], scripts: &Scripts, session: &mut Session) -> Option<String> {
+    let command = tokens.first()?.to_uppercase();
+    if !matches!(command.as_str(), "EVAL" | "EVALSHA" | "SCRIPT") {
+        return None;
+    }
+    if session.transaction.is_some() {
+        return Some(format!("ERR {} inside MULTI is not allowed\n", command));
+    }
+    let keyspace = &session.keyspace;
+    let reply = match (command.as_str(), tokens.get(1).map(|t| t.to_uppercase()).as_deref()) {
+        ("EVAL", _) => match script_source(request, 1) {
+            Some((source, args)) => match scripts.load(&source) {
+                Ok((_, script)) => eval(&script, &args, scripts.limits, keyspace),
+                Err(e) => format!("ERR error compiling script: {}\n", e),
+            },
+            None => wrong_arguments("EVAL"),
+        },
+        ("EVALSHA", Some(_)) => match scripts.get(tokens[1]) {
+            Some(script) => eval(&script, &tokens[2..], scripts.limits, keyspace),
+            None => "NOSCRIPT No matching script. Please use EVAL.\n".to_string(),
+        },
+        ("SCRIPT", Some("LOAD")) => match script_source(request, 2) {
+            Some((source, args)) if args.is_empty() => match scripts.load(&source) {
+                Ok((sha, _)) => format!("{}\n", sha),
+                Err(e) => format!("ERR error compiling script: {}\n", e),
+            },
+            _ => wrong_arguments("SCRIPT LOAD"),
+        },
+        ("SCRIPT", Some("EXISTS")) if tokens.len() > 2 => {
+            tokens[2..].iter().map(|sha| if scripts.get(sha).is_some() { "1\n" } else { "0\n" }).collect()
+        }
+        ("SCRIPT", Some("FLUSH")) => {
+            scripts.flush();
+            "OK\n".to_string()
+        }
+        ("SCRIPT", _) => "ERR unknown SCRIPT subcommand\n".to_string(),
+        _ => wrong_arguments(&command),
+    };
+    Some(reply)
+}
+
+// The script in a request after its first `skip` words, with the words that
+// follow it. Scripts hold spaces, so they come quoted, with \" and \\ as
+// escapes; an unquoted script is a single word.
+fn script_source(request: &str, skip: usize) -> Option<(String, Vec<&str>)> {
+    let rest = request_tail(request, skip)?;
+    let quote = rest.chars().next()?;
+    if quote != '"' && quote != '\'' {
+        let mut words = rest.split_whitespace();
+        return Some((words.next()?.to_string(), words.collect()));
+    }
+    let mut source = String::new();
+    let mut chars = rest[1..].char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some((_, escaped)) if escaped == quote || escaped == '\\' => source.push(escaped),
+                Some((_, other)) => {
+                    source.push('\\');
+                    source.push(other);
+                }
+                None => return None,
+            },
+            c if c == quote => return Some((source, rest[i + 2..].split_whitespace().collect())),
+            c => source.push(c),
+        }
+    }
+    None
+}
+
+// What follows the first `skip` words of a request, as sent, or None if it
+// has fewer words.
+fn request_tail(request: &str, skip: usize) -> Option<&str> {
+    let mut rest = request.trim_start();
+    for _ in 0..skip {
+        let end = rest.find(char::is_whitespace)?;
+        rest = rest[end..].trim_start();
+    }
+    Some(rest)
+}
+
+// Runs a script given `numkeys key... arg...`, with every shard its keys
+// live on locked throughout, so it is atomic like a transaction. What it
+// writes is charged to the keyspace's write rate once it is done.
+fn eval(script: &Script, args: &[&str], limits: ScriptLimits, keyspace: &Keyspace) -> String {
+    let count = match args.first().map(|n| n.parse::<usize>()) {
+        None => return wrong_arguments("EVAL"),
+        Some(Ok(count)) if count < args.len() => count,
+        Some(Ok(_)) => return "ERR Number of keys can't be greater than number of args\n".to_string(),
+        Some(Err(_)) => return "ERR value is not an integer or out of range\n".to_string(),
+    };
+    let keys: Vec<String> = args[1..=count].iter().map(|key| key.to_string()).collect();
+    let argv: Vec<String> = args[count + 1..].iter().map(|arg| arg.to_string()).collect();
+    let mut shards = keyspace.store.lock_many(&keys);
+    let result = script::run(script, keys, argv, &mut shards, limits);
+    drop(shards);
+    match result {
+        Ok((value, written)) => {
+            keyspace.charge(written);
+            script::reply(&value)
+        }
+        Err(e) => format!("ERR script failed: {}\n", e),
+    }
+}
+
+impl ScriptStore for ShardGuards<'_, BoundedStore> {
+    fn get(&mut self, key: &str) -> Result<Option<String>, String> {
+        let shard = self.shard(key).ok_or_else(|| format!("the shard of key '{}' is not locked", key))?;
+        shard.get(key).map_err(|e| e.to_string())
+    }
+
+    // A write the store refuses, for memory or a quota, puts back the keys
+    // written before it.
+    fn commit(&mut self, writes: Vec<(String, Option<String>)>) -> Result<(), String> {
+        let mut written = Vec::new();
+        let mut refused = None;
+        for (key, value) in writes {
+            let shard = self.shard(&key).ok_or_else(|| format!("the shard of key '{}' is not locked", key))?;
+            let saved = shard.saved(&key);
+            let result = match value {
+                Some(value) => shard.set(&key, &value, None),
+                None => {
+                    shard.delete(&key);
+                    Ok(())
+                }
+            };
+            if let Err(e) = result {
+                refused = Some(e);
+                break;
+            }
+            written.push((key, saved));
+        }
+        let error = match refused {
+            Some(error) => error,
+            None => return Ok(()),
+        };
+        for (key, saved) in written.into_iter().rev() {
+            if let Some(shard) = self.shard(&key) {
+                shard.delete(&key);
+                if let Some((value, expires_millis)) = saved {
+                    let _ = shard.restore(&key, value, expires_millis);
+                }
+            }
+        }
+        Err(error.to_string())
+    }
+}
+
+// Runs the queued commands with every shard they touch locked throughout,
+// so no other client sees part of the transaction or writes in between.
+fn exec(transaction: Transaction, store: &ShardedStore) -> String {
+    if transaction.aborted {
+        return "EXECABORT Transaction discarded because of previous errors\n".to_string();
+    }
+    if transaction.commands.is_empty() {
+        return "(empty list)\n".to_string();
+    }
+    let requests: Vec<Vec<&str>> = transaction.commands.iter().map(|c| c.split_whitespace().collect()).collect();
+    let mut keys = Vec::new();
+    let mut all = false;
+    for tokens in &requests {
+        match command_keys(tokens) {
+            Some(KeySpec::Keys(command_keys)) => keys.extend(command_keys),
+            Some(KeySpec::All) => all = true,
+            None => {}
+        }
+    }
+    let mut shards = if all { store.lock_all() } else { store.lock_many(&keys) };
+    transaction.commands.iter().zip(&requests).map(|(request, tokens)| execute(request, tokens, &mut shards)).collect()
+}
+
+// Commands that change the store, whose request bytes count against the
+// keyspace's write rate. Scripts are charged for what they write instead.
+fn is_write(command: &str) -> bool {
+    matches!(
+        command,
+        "SET" | "MSET" | "DEL" | "INCR" | "DECR" | "INCRBY" | "DECRBY" | "INCRBYFLOAT" | "LPUSH" | "RPUSH" | "LPOP"
+            | "RPOP" | "SADD" | "SREM" | "ZADD" | "HSET" | "JSON.SET" | "JSON.DEL" | "JSON.ARRAPPEND" | "JSON.NUMINCRBY"
+    )
+}
+
+// None for commands the server does not know.
+fn command_keys<'a>(tokens: &[&'a str]) -> Option<KeySpec<'a>> {
+    let args = tokens.get(1..).unwrap_or_default();
+    match *tokens.first()? {
+        "MSET" => Some(KeySpec::Keys(args.iter().step_by(2).cloned().collect())),
+        "MGET" => Some(KeySpec::Keys(args.to_vec())),
+        "INFO" | "CREATE" | "DROP" | "FIND" => Some(KeySpec::All),
+        "SET" | "GET" | "DEL" | "TYPE" | "INCR" | "DECR" | "INCRBY" | "DECRBY" | "INCRBYFLOAT" | "LPUSH" | "RPUSH"
+        | "LPOP" | "RPOP" | "LRANGE" | "SADD" | "SREM" | "SMEMBERS" | "ZADD" | "ZRANGEBYSCORE" | "HSET" | "HGET"
+        | "HGETALL" | "JSON.SET" | "JSON.GET" | "JSON.DEL" | "JSON.ARRAPPEND" | "JSON.NUMINCRBY" => Some(KeySpec::Keys(args.iter().take(1).cloned().collect())),
+        _ => None,
+    }
+}
+
+// Runs one command against shards already locked for its keys. `tokens`
+// are the words of `request`.
+fn execute(request: &str, tokens: &[&str], shards: &mut ShardGuards<BoundedStore>) -> String {
+    let mut tokens = tokens.iter().cloned();
     match tokens.next() {
-        Some("SET") => {
-            let key = tokens.next().unwrap_or_default().to_string();
-            let value = tokens.next().unwrap_or_default().to_string();
-            let mut store_lock = store.lock().unwrap();
-            store_lock.insert(key, value);
-            "Value set successfully\n".to_string()
-        }
-        Some("GET") => {
-            let key = tokens.next().unwrap_or_default();
-            let store_lock = store.lock().unwrap();
-            store_lock.get(key).unwrap_or(&"Key not found\n".to_string()).clone()
+        Some("MSET") => {
+            let args: Vec<&str> = tokens.collect();
+            if args.is_empty() || !args.len().is_multiple_of(2) {
+                return wrong_arguments("MSET");
+            }
+            // All the keys' shards are locked so no reader sees half the
+            // batch. A write refused for memory leaves the earlier ones set.
+            for pair in args.chunks(2) {
+                match shards.shard(pair[0]).map(|store| store.set(pair[0], pair[1], None)) {
+                    Some(Ok(())) => {}
+                    Some(Err(e)) => return error_reply(e),
+                    None => return not_locked(pair[0]),
+                }
+            }
+            "Values set successfully\n".to_string()
+        }
+        Some("MGET") => {
+            let keys: Vec<&str> = tokens.collect();
+            // Keys holding other types read as missing, as in Redis.
+            keys.iter()
+                .map(|key| match shards.shard(*key) {
+                    Some(store) => store.get(key).ok().flatten().unwrap_or_else(|| "Key not found".to_string()) + "\n",
+                    None => not_locked(key),
+                })
+                .collect()
+        }
+        Some("CREATE") => {
+            let args: Vec<&str> = tokens.collect();
+            let keyword = |i: usize, word: &str| args.get(i).is_some_and(|a| a.eq_ignore_ascii_case(word));
+            let well_formed = args.len() == 7 && keyword(0, "INDEX") && keyword(2, "ON") && keyword(3, "PREFIX") && keyword(5, "FIELD");
+            if !well_formed {
+                return "ERR syntax error, expected CREATE INDEX name ON PREFIX prefix FIELD path\n".to_string();
+            }
+            let path = match json_path(Some(args[6])) {
+                Ok(path) => path,
+                Err(reply) => return reply,
+            };
+            let prefix = args[4].strip_prefix('"').and_then(|p| p.strip_suffix('"')).unwrap_or(args[4]);
+            let definition = IndexDefinition { name: args[1].to_string(), prefix: prefix.to_string(), path };
+            // Every shard indexes its own keys, so all of them hold the same
+            // set of index names.
+            let mut created = false;
+            for shard in shards.iter_mut() {
+                created |= shard.create_index(definition.clone());
+            }
+            if created {
+                "OK\n".to_string()
+            } else {
+                "ERR index already exists\n".to_string()
+            }
+        }
+        Some("DROP") => {
+            let name = match (tokens.next(), tokens.next()) {
+                (Some(keyword), Some(name)) if keyword.eq_ignore_ascii_case("INDEX") => name,
+                _ => return "ERR syntax error, expected DROP INDEX name\n".to_string(),
+            };
+            let mut dropped = false;
+            for shard in shards.iter_mut() {
+                dropped |= shard.drop_index(name);
+            }
+            if dropped {
+                "OK\n".to_string()
+            } else {
+                "ERR no such index\n".to_string()
+            }
+        }
+        Some("FIND") => {
+            let name = tokens.next().unwrap_or_default();
+            let args: Vec<&str> = tokens.collect();
+            // Strings match exactly; numbers also by range, with
+            // ZRANGEBYSCORE-style bounds.
+            let query = match args.as_slice() {
+                [between, min, max] if between.eq_ignore_ascii_case("BETWEEN") => {
+                    match (ScoreBound::parse(min), ScoreBound::parse(max)) {
+                        (Some(min), Some(max)) => IndexQuery::Between(min, max),
+                        _ => return "ERR min or max is not a float\n".to_string(),
+                    }
+                }
+                [value] => IndexQuery::Equals(IndexKey::parse(value)),
+                _ => return "ERR syntax error, expected FIND index value or FIND index BETWEEN min max\n".to_string(),
+            };
+            let mut keys = Vec::new();
+            for shard in shards.iter_mut() {
+                match shard.find(name, &query) {
+                    Some(found) => keys.extend(found),
+                    None => return "ERR no such index\n".to_string(),
+                }
+            }
+            keys.sort();
+            list_reply(Ok(keys))
+        }
+        Some("INFO") => match tokens.next() {
+            None => memory::memory_info(shards),
+            Some(section) if section.eq_ignore_ascii_case("memory") => memory::memory_info(shards),
+            Some(_) => String::new(),
+        },
+        // Every other command works on the one key it names first.
+        Some(command) => match tokens.next() {
+            Some(key) => match shards.shard(key) {
+                Some(store) => execute_on_key(command, key, request, tokens, store),
+                None => not_locked(key),
+            },
+            None => wrong_arguments(command),
+        },
+        None => "Unsupported command\n".to_string(),
+    }
+}
+
+// Runs a command on one key against the shard holding it.
+fn execute_on_key<'a, I: Iterator<Item = &'a str>>(
+    command: &str,
+    key: &str,
+    request: &str,
+    mut tokens: I,
+    store: &mut BoundedStore,
+) -> String {
+    match command {
+        "SET" => {
+            let value = tokens.next().unwrap_or_default();
+            // An optional `EX seconds` gives the key a TTL.
+            let ttl = match (tokens.next(), tokens.next()) {
+                (None, _) => None,
+                (Some(option), Some(seconds)) if option.eq_ignore_ascii_case("EX") => match seconds.parse::<u64>() {
+                    Ok(seconds) if seconds > 0 => Some(Duration::from_secs(seconds)),
+                    _ => return "ERR invalid expire time\n".to_string(),
+                },
+                _ => return "ERR syntax error\n".to_string(),
+            };
+            match store.set(key, value, ttl) {
+                Ok(()) => "Value set successfully\n".to_string(),
+                Err(e) => error_reply(e),
+            }
+        }
+        "GET" => {
+            match store.get(key) {
+                Ok(value) => value.unwrap_or_else(|| "Key not found\n".to_string()),
+                Err(e) => error_reply(e),
+            }
+        }
+        "DEL" => {
+            match store.delete(key) {
+                Some(_) => "Key deleted\n".to_string(),
+                None => "Key not found\n".to_string(),
+            }
+        }
+        "INCR" | "DECR" | "INCRBY" | "DECRBY" => {
+            let delta = match command {
+                "INCR" => Some(1),
+                "DECR" => Some(-1),
+                _ => match tokens.next().map(str::parse::<i64>) {
+                    Some(Ok(delta)) if command == "INCRBY" => Some(delta),
+                    Some(Ok(delta)) => delta.checked_neg(),
+                    _ => None,
+                },
+            };
+            let delta = match delta {
+                Some(delta) => delta,
+                None => return "ERR value is not an integer or out of range\n".to_string(),
+            };
+            let result = store.update(key, COUNTER_GROWTH, || Value::String("0".to_string()), |value| {
+                let current = value
+                    .as_string()?
+                    .parse::<i64>()
+                    .map_err(|_| StoreError::InvalidValue("value is not an integer or out of range"))?;
+                let next = current
+                    .checked_add(delta)
+                    .ok_or(StoreError::InvalidValue("increment or decrement would overflow"))?;
+                *value = Value::String(next.to_string());
+                Ok(next.to_string())
+            });
+            value_reply(result)
+        }
+        "INCRBYFLOAT" => {
+            let delta = match tokens.next().map(str::parse::<f64>) {
+                Some(Ok(delta)) if delta.is_finite() => delta,
+                _ => return "ERR value is not a valid float\n".to_string(),
+            };
+            let result = store.update(key, COUNTER_GROWTH, || Value::String("0".to_string()), |value| {
+                let current = match value.as_string()?.parse::<f64>() {
+                    Ok(current) if current.is_finite() => current,
+                    _ => return Err(StoreError::InvalidValue("value is not a valid float")),
+                };
+                let next = current + delta;
+                if !next.is_finite() {
+                    return Err(StoreError::InvalidValue("increment would produce NaN or Infinity"));
+                }
+                *value = Value::String(next.to_string());
+                Ok(next.to_string())
+            });
+            value_reply(result)
+        }
+        "TYPE" => {
+            let type_name = store.read(key, |value| value.type_name()).unwrap_or("none");
+            format!("{}\n", type_name)
+        }
+        "LPUSH" | "RPUSH" => {
+            let items: Vec<String> = tokens.map(String::from).collect();
+            if items.is_empty() {
+                return wrong_arguments(command);
+            }
+            let growth = items.iter().map(|i| i.len() + ELEMENT_OVERHEAD).sum();
+            let result = store.update(key, growth, || Value::List(VecDeque::new()), |value| {
+                let list = value.list_mut()?;
+                for item in items {
+                    if command == "LPUSH" {
+                        list.push_front(item);
+                    } else {
+                        list.push_back(item);
+                    }
+                }
+                Ok(list.len())
+            });
+            integer_reply(result)
+        }
+        "LPOP" | "RPOP" => {
+            if store.read(key, |_| ()).is_none() {
+                return "Key not found\n".to_string();
+            }
+            let result = store.update(key, 0, || Value::List(VecDeque::new()), |value| {
+                let list = value.list_mut()?;
+                Ok(if command == "LPOP" { list.pop_front() } else { list.pop_back() })
+            });
+            match result {
+                Ok(item) => format!("{}\n", item.unwrap_or_default()),
+                Err(e) => error_reply(e),
+            }
+        }
+        "LRANGE" => {
+            let (start, stop) = match (tokens.next().map(str::parse::<i64>), tokens.next().map(str::parse::<i64>)) {
+                (Some(Ok(start)), Some(Ok(stop))) => (start, stop),
+                _ => return "ERR value is not an integer or out of range\n".to_string(),
+            };
+            let result = store.read(key, |value| {
+                let list = value.list()?;
+                Ok(list.range(list_range(list.len(), start, stop)).cloned().collect::<Vec<String>>())
+            });
+            list_reply(result.unwrap_or_else(|| Ok(Vec::new())))
+        }
+        "SADD" => {
+            let members: Vec<String> = tokens.map(String::from).collect();
+            if members.is_empty() {
+                return wrong_arguments("SADD");
+            }
+            let growth = members.iter().map(|m| m.len() + ELEMENT_OVERHEAD).sum();
+            let result = store.update(key, growth, || Value::Set(HashSet::new()), |value| {
+                let set = value.set_mut()?;
+                Ok(members.into_iter().filter(|m| set.insert(m.clone())).count())
+            });
+            integer_reply(result)
+        }
+        "SREM" => {
+            let members: Vec<&str> = tokens.collect();
+            if store.read(key, |_| ()).is_none() {
+                return "0\n".to_string();
+            }
+            let result = store.update(key, 0, || Value::Set(HashSet::new()), |value| {
+                let set = value.set_mut()?;
+                Ok(members.iter().filter(|m| set.remove(**m)).count())
+            });
+            integer_reply(result)
+        }
+        "SMEMBERS" => {
+            let result = store.read(key, |value| {
+                let mut members: Vec<String> = value.set()?.iter().cloned().collect();
+                members.sort();
+                Ok(members)
+            });
+            list_reply(result.unwrap_or_else(|| Ok(Vec::new())))
+        }
+        "ZADD" => {
+            let args: Vec<&str> = tokens.collect();
+            if args.is_empty() || !args.len().is_multiple_of(2) {
+                return wrong_arguments("ZADD");
+            }
+            let mut entries = Vec::new();
+            for pair in args.chunks(2) {
+                // Infinite scores are only accepted as range bounds, since
+                // they cannot be persisted as JSON numbers.
+                match value::parse_score(pair[0]) {
+                    Some(score) if score.is_finite() => entries.push((pair[1].to_string(), score)),
+                    _ => return "ERR value is not a valid float\n".to_string(),
+                }
+            }
+            let growth = entries.iter().map(|(m, _)| 2 * (m.len() + ELEMENT_OVERHEAD)).sum();
+            let result = store.update(key, growth, || Value::SortedSet(SortedSet::default()), |value| {
+                let set = value.sorted_set_mut()?;
+                Ok(entries.into_iter().filter(|(member, score)| set.insert(member.clone(), *score)).count())
+            });
+            integer_reply(result)
         }
+        "ZRANGEBYSCORE" => {
+            let (min, max) = match (tokens.next().and_then(ScoreBound::parse), tokens.next().and_then(ScoreBound::parse)) {
+                (Some(min), Some(max)) => (min, max),
+                _ => return "ERR min or max is not a float\n".to_string(),
+            };
+            let with_scores = match tokens.next() {
+                None => false,
+                Some(option) if option.eq_ignore_ascii_case("WITHSCORES") => true,
+                Some(_) => return "ERR syntax error\n".to_string(),
+            };
+            let result = store.read(key, |value| {
+                let mut lines = Vec::new();
+                for (member, score) in value.sorted_set()?.range_by_score(min, max) {
+                    lines.push(member.clone());
+                    if with_scores {
+                        lines.push(score.to_string());
+                    }
+                }
+                Ok(lines)
+            });
+            list_reply(result.unwrap_or_else(|| Ok(Vec::new())))
+        }
+        "HSET" => {
+            let args: Vec<&str> = tokens.collect();
+            if args.is_empty() || !args.len().is_multiple_of(2) {
+                return wrong_arguments("HSET");
+            }
+            let growth = args.iter().map(|a| a.len()).sum::<usize>() + args.len() / 2 * ELEMENT_OVERHEAD;
+            let result = store.update(key, growth, || Value::Hash(HashMap::new()), |value| {
+                let hash = value.hash_mut()?;
+                Ok(args.chunks(2).filter(|pair| hash.insert(pair[0].to_string(), pair[1].to_string()).is_none()).count())
+            });
+            integer_reply(result)
+        }
+        "HGET" => {
+            let field = tokens.next().unwrap_or_default();
+            let result = store.read(key, |value| Ok(value.hash()?.get(field).cloned()));
+            match result.unwrap_or(Ok(None)) {
+                Ok(Some(value)) => format!("{}\n", value),
+                Ok(None) => "Field not found\n".to_string(),
+                Err(e) => error_reply(e),
+            }
+        }
+        "HGETALL" => {
+            let result = store.read(key, |value| {
+                let mut fields: Vec<(&String, &String)> = value.hash()?.iter().collect();
+                fields.sort();
+                Ok(fields.into_iter().flat_map(|(f, v)| vec![f.clone(), v.clone()]).collect())
+            });
+            list_reply(result.unwrap_or_else(|| Ok(Vec::new())))
+        }
+        "JSON.SET" => {
+            let path = match json_path(tokens.next()) {
+                Ok(path) => path,
+                Err(reply) => return reply,
+            };
+            let mut values = match json_values(request, 3) {
+                Ok(values) if values.len() == 1 => values,
+                Ok(_) => return wrong_arguments("JSON.SET"),
+                Err(reply) => return reply,
+            };
+            let new_value = values.remove(0);
+            let added = value::document_size(&new_value);
+            // A missing key starts as null, so only the root path can create it.
+            let result = store.update_measured(
+                key,
+                || Value::Json(serde_json::Value::Null),
+                |value| {
+                    document::set_growth(value.json()?, &path, added)
+                        .ok_or(StoreError::InvalidValue("the path's parent does not exist or cannot hold it"))
+                },
+                |value| {
+                    document::set(value.json_mut()?, &path, new_value);
+                    Ok(())
+                },
+            );
+            match result {
+                Ok(()) => "OK\n".to_string(),
+                Err(e) => error_reply(e),
+            }
+        }
+        "JSON.GET" => {
+            let path = match json_path(Some(tokens.next().unwrap_or("$"))) {
+                Ok(path) => path,
+                Err(reply) => return reply,
+            };
+            let result = store.read(key, |value| {
+                let found = document::get(value.json()?, &path).ok_or(StoreError::InvalidValue("the path does not exist"))?;
+                Ok(found.to_string())
+            });
+            match result {
+                Some(result) => value_reply(result),
+                None => "Key not found\n".to_string(),
+            }
+        }
+        "JSON.DEL" => {
+            let path = match json_path(Some(tokens.next().unwrap_or("$"))) {
+                Ok(path) => path,
+                Err(reply) => return reply,
+            };
+            match store.read(key, |value| value.json().is_ok()) {
+                None => return "0\n".to_string(),
+                Some(false) => return error_reply(StoreError::WrongType),
+                Some(true) => {}
+            }
+            if path.is_root() {
+                store.delete(key);
+                return "1\n".to_string();
+            }
+            let result = store.update_measured(
+                key,
+                || Value::Json(serde_json::Value::Null),
+                |value| Ok(document::delete_growth(value.json()?, &path)),
+                |value| Ok(document::delete(value.json_mut()?, &path) as usize),
+            );
+            integer_reply(result)
+        }
+        "JSON.ARRAPPEND" => {
+            let path = match json_path(tokens.next()) {
+                Ok(path) => path,
+                Err(reply) => return reply,
+            };
+            let values = match json_values(request, 3) {
+                Ok(values) if !values.is_empty() => values,
+                Ok(_) => return wrong_arguments("JSON.ARRAPPEND"),
+                Err(reply) => return reply,
+            };
+            if store.read(key, |_| ()).is_none() {
+                return "Key not found\n".to_string();
+            }
+            let growth = values.iter().map(|v| value::document_size(v) as isize).sum();
+            let result = store.update_measured(
+                key,
+                || Value::Json(serde_json::Value::Null),
+                |value| value.json().map(|_| growth).map_err(StoreError::from),
+                |value| document::array_append(value.json_mut()?, &path, values).map_err(StoreError::InvalidValue),
+            );
+            integer_reply(result)
+        }
+        "JSON.NUMINCRBY" => {
+            let path = match json_path(tokens.next()) {
+                Ok(path) => path,
+                Err(reply) => return reply,
+            };
+            let delta = match json_values(request, 3) {
+                Ok(values) => match values.as_slice() {
+                    [serde_json::Value::Number(delta)] => delta.clone(),
+                    _ => return "ERR the increment is not a number\n".to_string(),
+                },
+                Err(reply) => return reply,
+            };
+            if store.read(key, |_| ()).is_none() {
+                return "Key not found\n".to_string();
+            }
+            // Numbers take no space beyond their node, whatever their value.
+            let result = store.update_measured(
+                key,
+                || Value::Json(serde_json::Value::Null),
+                |value| value.json().map(|_| 0).map_err(StoreError::from),
+                |value| {
+                    let sum = document::increment(value.json_mut()?, &path, &delta).map_err(StoreError::InvalidValue)?;
+                    Ok(sum.to_string())
+                },
+            );
+            value_reply(result)
+        }
+        // command_keys only lets known commands through.
         _ => "Unsupported command\n".to_string(),
     }
 }
 
+fn json_path(path: Option<&str>) -> Result<Path, String> {
+    match path.map(Path::parse) {
+        Some(Ok(path)) => Ok(path),
+        Some(Err(e)) => Err(format!("ERR {}\n", e)),
+        None => Err("ERR a JSON path is required\n".to_string()),
+    }
+}
+
+// The request after its first `skip` words as JSON values, read from the
+// request itself so whitespace inside string values is kept.
+fn json_values(request: &str, skip: usize) -> Result<Vec<serde_json::Value>, String> {
+    let text = request_tail(request, skip).unwrap_or_default();
+    document::parse_values(text).map_err(|e| format!("ERR {}\n", e))
+}
+
+fn error_reply(error: StoreError) -> String {
+    format!("{}\n", error)
+}
+
+fn wrong_arguments(command: &str) -> String {
+    format!("ERR wrong number of arguments for '{}'\n", command)
+}
+
+// Callers lock every key a command names first, so this means a bug rather
+// than a bad request.
+fn not_locked(key: &str) -> String {
+    format!("ERR the shard of key '{}' is not locked\n", key)
+}
+
+fn value_reply(result: Result<String, StoreError>) -> String {
+    match result {
+        Ok(value) => format!("{}\n", value),
+        Err(e) => error_reply(e),
+    }
+}
+
+fn integer_reply(result: Result<usize, StoreError>) -> String {
+    match result {
+        Ok(count) => format!("{}\n", count),
+        Err(e) => error_reply(e),
+    }
+}
+
+// One line per item, as redis-cli shows arrays.
+fn list_reply(result: Result<Vec<String>, StoreError>) -> String {
+    match result {
+        Ok(items) if items.is_empty() => "(empty list)\n".to_string(),
+        Ok(items) => items.into_iter().map(|item| item + "\n").collect(),
+        Err(e) => error_reply(e),
+    }
+}
+
+// Redis list indexes: negative ones count back from the end, and the range
+// is clamped to the list.
+fn list_range(len: usize, start: i64, stop: i64) -> std::ops::Range<usize> {
+    let len = len as i64;
+    let start = if start < 0 { (len + start).max(0) } else { start };
+    let stop = if stop < 0 { len + stop } else { stop.min(len - 1) };
+    if start > stop || start >= len {
+        0..0
+    } else {
+        start as usize..stop as usize + 1
+    }
+}
+
 fn read_from_stream(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> io::Result<usize> {
     let mut temp_buffer = [0; 1024]; // Use a small stack-allocated buffer for reading.
     let size = stream.read(&mut temp_buffer)?;
@@ -85,4 +948,62 @@ fn read_from_stream(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> io::Result<
         buffer.extend_from_slice(&temp_buffer[..size]);
     }
     Ok(size)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_values_come_from_the_request_as_sent() {
+        let values = json_values("JSON.SET  doc $.a \"x  y\"   {\"b\": \"c \\t d\"}", 3).unwrap();
+        assert_eq!(values, vec![serde_json::json!("x  y"), serde_json::json!({"b": "c \t d"})]);
+        assert_eq!(json_values("JSON.SET doc $.a", 3).unwrap(), Vec::<serde_json::Value>::new());
+        assert_eq!(request_tail("EVAL \"return 1\" 0", 1), Some("\"return 1\" 0"));
+        assert_eq!(request_tail("EVAL", 1), None);
+    }
+
+    #[test]
+    fn only_create_and_drop_keyspace_are_keyspace_commands() {
+        let keyspaces = Keyspaces::new(MemoryLimit { max_memory: 0, policy: memory::EvictionPolicy::NoEviction }, 2);
+        let mut session = Session { keyspace: keyspaces.get(DEFAULT_KEYSPACE).unwrap(), transaction: None };
+        assert_eq!(keyspace_command(&["GET", "keyspace"], &keyspaces, &mut session), None);
+        assert_eq!(keyspace_command(&["SET", "KEYSPACE", "v"], &keyspaces, &mut session), None);
+        assert_eq!(keyspace_command(&["create", "keyspace", "team"], &keyspaces, &mut session), Some("OK\n".to_string()));
+        assert_eq!(keyspace_command(&["USE", "team"], &keyspaces, &mut session), Some("OK\n".to_string()));
+        assert_eq!(session.keyspace.name, "team");
+    }
+
+    #[test]
+    fn scripts_are_charged_only_for_what_they_write() {
+        assert!(is_write("SET") && is_write("JSON.DEL"));
+        assert!(!is_write("EVAL") && !is_write("EVALSHA") && !is_write("SCRIPT") && !is_write("set") && !is_write("NOSUCH"));
+        let keyspaces = Arc::new(Keyspaces::new(MemoryLimit { max_memory: 0, policy: memory::EvictionPolicy::NoEviction }, 2));
+        let scripts = Scripts::new(ScriptLimits { time: Duration::from_secs(5), memory: 1024 });
+        let mut session = Session { keyspace: keyspaces.get(DEFAULT_KEYSPACE).unwrap(), transaction: None };
+        let mut request = |line: &str| process_request(line.as_bytes(), &keyspaces, &scripts, &mut session);
+        assert_eq!(request("CREATE KEYSPACE slow WRITERATE 100"), "OK\n");
+        assert_eq!(request("USE slow"), "OK\n");
+        let reading = format!("EVAL \"return get(KEYS[1]) -- {}\" 1 k", "x".repeat(200));
+        assert_eq!(request(&reading), "(nil)\n");
+        assert_eq!(request(&reading), "(nil)\n");
+        let writing = format!("EVAL \"return set(KEYS[1], ARGV[1])\" 1 k {}", "v".repeat(300));
+        assert_eq!(request(&writing), "1\n");
+        assert!(request("SET k v").starts_with("THROTTLED"));
+    }
+
+    #[test]
+    fn a_script_write_the_store_refuses_undoes_the_others() {
+        let keyspaces = Arc::new(Keyspaces::new(MemoryLimit { max_memory: 0, policy: memory::EvictionPolicy::NoEviction }, 2));
+        let scripts = Scripts::new(ScriptLimits { time: Duration::from_secs(5), memory: 1024 });
+        let mut session = Session { keyspace: keyspaces.get(DEFAULT_KEYSPACE).unwrap(), transaction: None };
+        let mut request = |line: &str| process_request(line.as_bytes(), &keyspaces, &scripts, &mut session);
+        assert_eq!(request("CREATE KEYSPACE small MAXVALUESIZE 4"), "OK\n");
+        assert_eq!(request("USE small"), "OK\n");
+        assert_eq!(request("SET a old"), "Value set successfully\n");
+        let reply = request("EVAL \"set(KEYS[1], 'new') set(KEYS[2], 'too long') return 1\" 2 a b");
+        assert!(reply.starts_with("ERR script failed"), "{}", reply);
+        assert_eq!(request("GET a"), "old");
+        assert_eq!(request("EVAL \"set(KEYS[1], 'new') return get(KEYS[1])\" 1 a"), "new\n");
+        assert_eq!(request("GET a"), "new");
+    }
+}
@@ -0,0 +1,778 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::index::{IndexDefinition, IndexQuery, Indexes};
+use crate::sharded::{ShardGuards, Sharded};
+use crate::value::{parse_bytes, Value, WrongType};
+
+// Per-key cost beyond the key and value bytes: the map slot, two String
+// headers, the entry's bookkeeping and its place in the eviction index.
+pub const ENTRY_OVERHEAD: usize = 112;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EvictionPolicy {
+    // Writes that need more memory fail instead.
+    NoEviction,
+    AllKeysLru,
+    // Least recently used among keys that have a TTL.
+    VolatileLru,
+    AllKeysRandom,
+}
+
+impl EvictionPolicy {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "noeviction" => Some(EvictionPolicy::NoEviction),
+            "allkeys-lru" => Some(EvictionPolicy::AllKeysLru),
+            "volatile-lru" => Some(EvictionPolicy::VolatileLru),
+            "allkeys-random" => Some(EvictionPolicy::AllKeysRandom),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            EvictionPolicy::NoEviction => "noeviction",
+            EvictionPolicy::AllKeysLru => "allkeys-lru",
+            EvictionPolicy::VolatileLru => "volatile-lru",
+            EvictionPolicy::AllKeysRandom => "allkeys-random",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StoreError {
+    OutOfMemory,
+    WrongType,
+    // The value cannot take the operation, such as incrementing text.
+    InvalidValue(&'static str),
+    // The value would outgrow the store's max value size.
+    ValueTooLarge(usize),
+    // The whole store is at its limit of keys or bytes. Unlike OutOfMemory,
+    // nothing is evicted to make room.
+    QuotaExceeded { limit: usize, unit: &'static str },
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StoreError::OutOfMemory => write!(f, "OOM command not allowed when used memory > 'maxmemory'"),
+            StoreError::WrongType => write!(f, "{}", WrongType),
+            StoreError::InvalidValue(message) => write!(f, "ERR {}", message),
+            StoreError::ValueTooLarge(max) => write!(f, "ERR value is larger than the max value size of {} bytes", max),
+            StoreError::QuotaExceeded { limit, unit } => write!(f, "QUOTA keyspace is at its limit of {} {}", limit, unit),
+        }
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl From<WrongType> for StoreError {
+    fn from(_: WrongType) -> Self {
+        StoreError::WrongType
+    }
+}
+
+struct Entry {
+    value: Value,
+    // `entry_size` as of the last write, so large values are not walked
+    // again to account for them.
+    size: usize,
+    expires_millis: Option<u64>,
+    last_access: u64,
+}
+
+fn entry_size(key: &str, value: &Value) -> usize {
+    key.len() + value.size() + ENTRY_OVERHEAD
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_millis() as u64).unwrap_or(0)
+}
+
+fn expiry(ttl: Option<Duration>) -> Option<u64> {
+    ttl.map(|ttl| now_millis() + ttl.as_millis() as u64)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct MemoryLimit {
+    // Zero means unlimited.
+    pub max_memory: usize,
+    pub policy: EvictionPolicy,
+}
+
+impl MemoryLimit {
+    // MAXMEMORY takes bytes with an optional kb, mb or gb suffix;
+    // MAXMEMORY_POLICY defaults to noeviction.
+    pub fn from_env() -> Result<Self, String> {
+        let max_memory = match env::var("MAXMEMORY") {
+            Ok(limit) => parse_bytes(&limit).ok_or_else(|| format!("Invalid MAXMEMORY: {}", limit))?,
+            Err(_) => 0,
+        };
+        let policy = match env::var("MAXMEMORY_POLICY") {
+            Ok(name) => EvictionPolicy::parse(&name).ok_or_else(|| format!("Invalid MAXMEMORY_POLICY: {}", name))?,
+            Err(_) => EvictionPolicy::NoEviction,
+        };
+        Ok(MemoryLimit { max_memory, policy })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MemoryStats {
+    pub used: usize,
+    pub max_memory: usize,
+    pub keys: usize,
+    pub evicted_keys: u64,
+    pub expired_keys: u64,
+    pub rejected_writes: u64,
+}
+
+impl MemoryStats {
+    // Stores sharing a limit each report all of it.
+    pub fn add(&mut self, other: &MemoryStats) {
+        self.used += other.used;
+        self.max_memory = self.max_memory.max(other.max_memory);
+        self.keys += other.keys;
+        self.evicted_keys += other.evicted_keys;
+        self.expired_keys += other.expired_keys;
+        self.rejected_writes += other.rejected_writes;
+    }
+
+    // The `INFO memory` section.
+    pub fn info(&self, policy: EvictionPolicy) -> String {
+        format!(
+            "# Memory\r\nused_memory:{}\r\nmaxmemory:{}\r\nmaxmemory_policy:{}\r\nkeys:{}\r\nevicted_keys:{}\r\nexpired_keys:{}\r\nrejected_writes:{}\r\n",
+            self.used,
+            self.max_memory,
+            policy.name(),
+            self.keys,
+            self.evicted_keys,
+            self.expired_keys,
+            self.rejected_writes,
+        )
+    }
+}
+
+// Keys and bytes summed over every shard of a store, so limits on the whole
+// store need not lock them all.
+#[derive(Debug, Default)]
+pub struct SharedUsage {
+    keys: AtomicUsize,
+    bytes: AtomicUsize,
+}
+
+// String store that keeps its estimated footprint under `max_memory` bytes
+// (0 means unlimited), evicting keys per `policy` to make room for writes.
+// Expired keys are removed when they are next touched, and any that are due
+// are purged before a write is refused or evicts. Every shard of every store
+// counts its bytes against one shared total, but each evicts only its own
+// keys.
+pub struct BoundedStore {
+    entries: HashMap<String, Entry>,
+    // Keys by last access, oldest first; the volatile index only holds keys
+    // with a TTL.
+    recency: BTreeMap<u64, String>,
+    volatile: BTreeMap<u64, String>,
+    // Keys with a TTL by expiry time, so expired keys nobody reads again
+    // still give their memory back.
+    expiring: BTreeSet<(u64, String)>,
+    indexes: Indexes,
+    // Includes the index entries for the keys.
+    used: usize,
+    // Bytes used by every store sharing the limit, this one included.
+    memory: Arc<AtomicUsize>,
+    max_memory: usize,
+    policy: EvictionPolicy,
+    // Given to keys written without a TTL of their own.
+    default_ttl: Option<Duration>,
+    // Zero means unlimited.
+    max_value_size: usize,
+    // Limits on the whole store, shared with the other shards through
+    // `usage`. Zero means unlimited. Only the memory limit evicts.
+    store_max_memory: usize,
+    max_keys: usize,
+    max_bytes: usize,
+    usage: Arc<SharedUsage>,
+    tick: u64,
+    evicted_keys: u64,
+    expired_keys: u64,
+    rejected_writes: u64,
+    random: RandomState,
+}
+
+impl BoundedStore {
+    // `max_memory` also covers the other stores counting their bytes in
+    // `memory`.
+    pub fn new(limit: MemoryLimit, memory: Arc<AtomicUsize>) -> Self {
+        let MemoryLimit { max_memory, policy } = limit;
+        BoundedStore {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            volatile: BTreeMap::new(),
+            expiring: BTreeSet::new(),
+            indexes: Indexes::default(),
+            used: 0,
+            memory,
+            max_memory,
+            policy,
+            default_ttl: None,
+            max_value_size: 0,
+            store_max_memory: 0,
+            max_keys: 0,
+            max_bytes: 0,
+            usage: Arc::default(),
+            tick: 0,
+            evicted_keys: 0,
+            expired_keys: 0,
+            rejected_writes: 0,
+            random: RandomState::new(),
+        }
+    }
+
+    pub fn configure(&mut self, default_ttl: Option<Duration>, max_value_size: usize) {
+        self.default_ttl = default_ttl;
+        self.max_value_size = max_value_size;
+    }
+
+    // Only takes effect while the store is empty.
+    pub fn limit_totals(&mut self, usage: Arc<SharedUsage>, max_memory: usize, max_keys: usize, max_bytes: usize) {
+        self.usage = usage;
+        self.store_max_memory = max_memory;
+        self.max_keys = max_keys;
+        self.max_bytes = max_bytes;
+    }
+
+    fn grow(&mut self, bytes: usize) {
+        self.used += bytes;
+        self.memory.fetch_add(bytes, Ordering::Relaxed);
+        self.usage.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    fn shrink(&mut self, bytes: usize) {
+        self.used -= bytes;
+        self.memory.fetch_sub(bytes, Ordering::Relaxed);
+        self.usage.bytes.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    // Refuses a new key once the store as a whole is at its key limit.
+    fn admit_key(&self) -> Result<(), StoreError> {
+        if self.max_keys > 0 && self.usage.keys.load(Ordering::Relaxed) >= self.max_keys {
+            return Err(StoreError::QuotaExceeded { limit: self.max_keys, unit: "keys" });
+        }
+        Ok(())
+    }
+
+    // Moves `key` to the most recently used end.
+    fn touch(&mut self, key: &str) -> Option<&mut Entry> {
+        if self.expire_if_due(key) {
+            return None;
+        }
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        let previous = entry.last_access;
+        entry.last_access = tick;
+        self.recency.remove(&previous);
+        self.recency.insert(tick, key.to_string());
+        if entry.expires_millis.is_some() {
+            self.volatile.remove(&previous);
+            self.volatile.insert(tick, key.to_string());
+        }
+        Some(entry)
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>, StoreError> {
+        match self.touch(key) {
+            Some(entry) => Ok(Some(entry.value.as_string()?.clone())),
+            None => Ok(None),
+        }
+    }
+
+    // Runs `read` against the key's value, if it has one.
+    pub fn read<T, F: FnOnce(&Value) -> T>(&mut self, key: &str, read: F) -> Option<T> {
+        self.touch(key).map(|entry| read(&entry.value))
+    }
+
+    pub fn set(&mut self, key: &str, value: &str, ttl: Option<Duration>) -> Result<(), StoreError> {
+        self.expire_if_due(key);
+        if self.max_value_size > 0 && value.len() > self.max_value_size {
+            return Err(StoreError::ValueTooLarge(self.max_value_size));
+        }
+        let value = Value::String(value.to_string());
+        let old_size = self.entries.get(key).map_or(0, |e| e.size);
+        let needed = entry_size(key, &value);
+        if !self.entries.contains_key(key) {
+            self.admit_key()?;
+        }
+        self.reserve(key, needed, old_size)?;
+        self.remove(key);
+        let expires_millis = expiry(ttl.or(self.default_ttl));
+        self.insert(key, value, expires_millis);
+        Ok(())
+    }
+
+    // Puts back a key saved with `entries`, keeping its expiry time. A key
+    // that expired since is left out.
+    pub fn restore(&mut self, key: &str, value: Value, expires_millis: Option<u64>) -> Result<(), StoreError> {
+        if expires_millis.is_some_and(|expires| expires <= now_millis()) {
+            return Ok(());
+        }
+        let old_size = self.entries.get(key).map_or(0, |e| e.size);
+        if old_size == 0 {
+            self.admit_key()?;
+        }
+        self.reserve(key, entry_size(key, &value), old_size)?;
+        self.remove(key);
+        self.insert(key, value, expires_millis);
+        Ok(())
+    }
+
+    // The key's value and expiry time, as `restore` takes them back.
+    pub fn saved(&mut self, key: &str) -> Option<(Value, Option<u64>)> {
+        self.expire_if_due(key);
+        self.entries.get(key).map(|entry| (entry.value.clone(), entry.expires_millis))
+    }
+
+    // Live keys with their values and expiry times.
+    pub fn entries(&self) -> impl Iterator<Item = (&String, &Value, Option<u64>)> + '_ {
+        let now = now_millis();
+        self.entries
+            .iter()
+            .filter(move |(_, entry)| entry.expires_millis.is_none_or(|expires| expires > now))
+            .map(|(key, entry)| (key, &entry.value, entry.expires_millis))
+    }
+
+    // Adds a key that is not in the store, once room has been made for it.
+    fn insert(&mut self, key: &str, value: Value, expires_millis: Option<u64>) {
+        self.tick += 1;
+        self.recency.insert(self.tick, key.to_string());
+        if let Some(expires) = expires_millis {
+            self.volatile.insert(self.tick, key.to_string());
+            self.expiring.insert((expires, key.to_string()));
+        }
+        let indexed = self.indexes.insert(key, &value);
+        let size = entry_size(key, &value);
+        self.grow(size + indexed);
+        self.usage.keys.fetch_add(1, Ordering::Relaxed);
+        self.entries.insert(key.to_string(), Entry { value, size, expires_millis, last_access: self.tick });
+    }
+
+    // Applies `update` to the key's value, starting from `empty()` if the key
+    // does not exist. `growth` estimates the bytes the update may add, and
+    // room is made for it first. A collection left empty removes the key.
+    pub fn update<T, E, F>(&mut self, key: &str, growth: usize, empty: E, update: F) -> Result<T, StoreError>
+    where
+        E: FnOnce() -> Value,
+        F: FnOnce(&mut Value) -> Result<T, StoreError>,
+    {
+        let old_size = self.touch(key).map_or(0, |entry| entry.size);
+        let created = self.prepare(key, old_size, growth, empty)?;
+        let entry = self.entries.get_mut(key).unwrap();
+        let before = entry.size + self.indexes.remove(key, &entry.value);
+        // Values stay within the max size, so keeping a copy to roll back
+        // to costs at most that much.
+        let backup = if self.max_value_size > 0 { Some(entry.value.clone()) } else { None };
+        let mut result = update(&mut entry.value);
+        if let Some(backup) = backup {
+            if result.is_ok() && entry.value.size() > self.max_value_size {
+                entry.value = backup;
+                result = Err(StoreError::ValueTooLarge(self.max_value_size));
+            }
+        }
+        entry.size = entry_size(key, &entry.value);
+        self.finish_update(key, before, created, result.is_err());
+        result
+    }
+
+    // Like `update`, for changes that can say up front how many bytes they
+    // add to the value, negative if they free some. `measure` is given the
+    // value before `update` changes it, and `update` must leave the value as
+    // it was if it fails. Neither the value nor a copy of it is walked, so
+    // changing one field of a large document costs only that field.
+    pub fn update_measured<T, E, M, F>(&mut self, key: &str, empty: E, measure: M, update: F) -> Result<T, StoreError>
+    where
+        E: FnOnce() -> Value,
+        M: FnOnce(&Value) -> Result<isize, StoreError>,
+        F: FnOnce(&mut Value) -> Result<T, StoreError>,
+    {
+        let (old_size, growth, initial) = match self.touch(key) {
+            Some(entry) => (entry.size, measure(&entry.value)?, None),
+            None => {
+                let initial = empty();
+                let growth = measure(&initial)?;
+                (0, growth, Some(initial))
+            }
+        };
+        let new_size = match &initial {
+            Some(initial) => entry_size(key, initial),
+            None => old_size,
+        }
+        .saturating_add_signed(growth);
+        if self.max_value_size > 0 && new_size.saturating_sub(key.len() + ENTRY_OVERHEAD) > self.max_value_size {
+            return Err(StoreError::ValueTooLarge(self.max_value_size));
+        }
+        // The key is only created when it was missing above, so `initial`
+        // is there.
+        let created = self.prepare(key, old_size, growth.max(0) as usize, || initial.unwrap())?;
+        let entry = self.entries.get_mut(key).unwrap();
+        let before = entry.size + self.indexes.remove(key, &entry.value);
+        let result = update(&mut entry.value);
+        if result.is_ok() {
+            entry.size = new_size;
+        }
+        self.finish_update(key, before, created, result.is_err());
+        result
+    }
+
+    // Makes room for an update adding up to `growth` bytes to a key taking
+    // up `old_size`, and creates the key from `empty()` if that is 0.
+    // Returns whether it was created.
+    fn prepare<E: FnOnce() -> Value>(&mut self, key: &str, old_size: usize, growth: usize, empty: E) -> Result<bool, StoreError> {
+        let needed = if old_size == 0 { entry_size(key, &Value::String(String::new())) } else { old_size } + growth;
+        if old_size == 0 {
+            self.admit_key()?;
+        }
+        self.reserve(key, needed, old_size)?;
+        if old_size == 0 {
+            let expires_millis = expiry(self.default_ttl);
+            self.insert(key, empty(), expires_millis);
+        }
+        Ok(old_size == 0)
+    }
+
+    // Accounts for an updated value that took up `before` bytes with its
+    // index entries, and drops the key if the update emptied it or created
+    // it and then failed.
+    fn finish_update(&mut self, key: &str, before: usize, created: bool, failed: bool) {
+        let entry = &self.entries[key];
+        let after = entry.size + self.indexes.insert(key, &entry.value);
+        let empty = entry.value.is_empty_collection();
+        self.shrink(before);
+        self.grow(after);
+        if empty || (created && failed) {
+            self.remove(key);
+        }
+    }
+
+    pub fn delete(&mut self, key: &str) -> Option<Value> {
+        if self.expire_if_due(key) {
+            return None;
+        }
+        self.remove(key)
+    }
+
+    // Returns false if an index with the name already exists.
+    pub fn create_index(&mut self, definition: IndexDefinition) -> bool {
+        if self.indexes.exists(&definition.name) {
+            return false;
+        }
+        let bytes = self.indexes.create(definition, self.entries.iter().map(|(key, entry)| (key, &entry.value)));
+        self.grow(bytes);
+        true
+    }
+
+    pub fn index_definitions(&self) -> impl Iterator<Item = &IndexDefinition> + '_ {
+        self.indexes.definitions()
+    }
+
+    pub fn drop_index(&mut self, name: &str) -> bool {
+        match self.indexes.drop(name) {
+            Some(bytes) => {
+                self.shrink(bytes);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Keys in this store matching `query`, or None if there is no such index.
+    pub fn find(&mut self, name: &str, query: &IndexQuery) -> Option<Vec<String>> {
+        let keys = self.indexes.find(name, query)?;
+        Some(keys.into_iter().filter(|key| !self.expire_if_due(key)).collect())
+    }
+
+    // Evicts other keys until `key` can grow from `old_size` to `needed`
+    // bytes within the limit.
+    fn reserve(&mut self, key: &str, needed: usize, old_size: usize) -> Result<(), StoreError> {
+        self.purge_expired(key);
+        let over = |limit: usize, total: &AtomicUsize| limit > 0 && (total.load(Ordering::Relaxed) + needed).saturating_sub(old_size) > limit;
+        if self.max_memory > 0 || self.store_max_memory > 0 {
+            while over(self.max_memory, &self.memory) || over(self.store_max_memory, &self.usage.bytes) {
+                if !self.evict_one(key) {
+                    self.rejected_writes += 1;
+                    return Err(StoreError::OutOfMemory);
+                }
+            }
+        }
+        if self.max_bytes > 0 && self.usage.bytes.load(Ordering::Relaxed) - old_size + needed > self.max_bytes {
+            self.rejected_writes += 1;
+            return Err(StoreError::QuotaExceeded { limit: self.max_bytes, unit: "bytes" });
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Value> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.last_access);
+        self.volatile.remove(&entry.last_access);
+        if let Some(expires) = entry.expires_millis {
+            self.expiring.remove(&(expires, key.to_string()));
+        }
+        let bytes = entry.size + self.indexes.remove(key, &entry.value);
+        self.shrink(bytes);
+        self.usage.keys.fetch_sub(1, Ordering::Relaxed);
+        Some(entry.value)
+    }
+
+    fn expire_if_due(&mut self, key: &str) -> bool {
+        let due = match self.entries.get(key).and_then(|e| e.expires_millis) {
+            Some(expires) => expires <= now_millis(),
+            None => false,
+        };
+        if due {
+            self.remove(key);
+            self.expired_keys += 1;
+        }
+        due
+    }
+
+    // Removes every expired key other than `keep`.
+    fn purge_expired(&mut self, keep: &str) {
+        let now = now_millis();
+        let due: Vec<String> = self
+            .expiring
+            .iter()
+            .take_while(|(expires, _)| *expires <= now)
+            .map(|(_, key)| key.clone())
+            .filter(|key| key != keep)
+            .collect();
+        for key in due {
+            self.remove(&key);
+            self.expired_keys += 1;
+        }
+    }
+
+    // Evicts one key other than `keep`. Returns false if the policy allows
+    // no eviction or there is nothing left to evict.
+    fn evict_one(&mut self, keep: &str) -> bool {
+        let victim = match self.policy {
+            EvictionPolicy::NoEviction => None,
+            EvictionPolicy::AllKeysLru => self.recency.values().find(|k| k.as_str() != keep).cloned(),
+            EvictionPolicy::VolatileLru => self.volatile.values().find(|k| k.as_str() != keep).cloned(),
+            EvictionPolicy::AllKeysRandom => self.random_key(keep),
+        };
+        match victim {
+            Some(victim) => {
+                self.remove(&victim);
+                self.evicted_keys += 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    // Picks a random point in the access order, which is close to uniform
+    // without keeping a separate array of keys.
+    fn random_key(&self, keep: &str) -> Option<String> {
+        let first = *self.recency.keys().next()?;
+        let last = *self.recency.keys().next_back()?;
+        let mut hasher = self.random.build_hasher();
+        hasher.write_u64(self.tick);
+        let start = first + hasher.finish() % (last - first + 1);
+        self.recency
+            .range(start..)
+            .chain(self.recency.range(..start))
+            .map(|(_, key)| key)
+            .find(|k| k.as_str() != keep)
+            .cloned()
+    }
+
+    pub fn stats(&self) -> MemoryStats {
+        MemoryStats {
+            used: self.used,
+            max_memory: match (self.max_memory, self.store_max_memory) {
+                (0, limit) | (limit, 0) => limit,
+                (server, store) => server.min(store),
+            },
+            keys: self.entries.len(),
+            evicted_keys: self.evicted_keys,
+            expired_keys: self.expired_keys,
+            rejected_writes: self.rejected_writes,
+        }
+    }
+}
+
+// A dropped store gives its bytes back to the total it shared.
+impl Drop for BoundedStore {
+    fn drop(&mut self) {
+        self.memory.fetch_sub(self.used, Ordering::Relaxed);
+    }
+}
+
+pub type ShardedStore = Sharded<BoundedStore>;
+
+// `limit` covers the bytes of every store counting them in `memory`.
+pub fn sharded_store(limit: MemoryLimit, shard_count: usize, memory: Arc<AtomicUsize>) -> ShardedStore {
+    Sharded::new(shard_count, |_| BoundedStore::new(limit, Arc::clone(&memory)))
+}
+
+// `INFO memory` totals across the locked shards, normally all of them.
+// Expired keys are purged first so they are not counted.
+pub fn memory_info(shards: &mut ShardGuards<BoundedStore>) -> String {
+    let mut total = MemoryStats::default();
+    let mut policy = EvictionPolicy::NoEviction;
+    for shard in shards.iter_mut() {
+        shard.purge_expired("");
+        total.add(&shard.stats());
+        policy = shard.policy;
+    }
+    total.info(policy)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // Room for exactly three one-byte keys with one-byte values.
+    fn store(policy: EvictionPolicy) -> BoundedStore {
+        BoundedStore::new(MemoryLimit { max_memory: 3 * (2 + ENTRY_OVERHEAD), policy }, Arc::default())
+    }
+
+    #[test]
+    fn noeviction_refuses_writes_over_the_limit() {
+        let mut store = store(EvictionPolicy::NoEviction);
+        for key in ["a", "b", "c"].iter() {
+            store.set(key, "v", None).unwrap();
+        }
+        assert!(matches!(store.set("d", "v", None), Err(StoreError::OutOfMemory)));
+        // Overwriting with a value of the same size needs no more room.
+        store.set("a", "w", None).unwrap();
+        let stats = store.stats();
+        assert_eq!((stats.keys, stats.used, stats.rejected_writes), (3, 3 * (2 + ENTRY_OVERHEAD), 1));
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used_key() {
+        let mut store = store(EvictionPolicy::AllKeysLru);
+        for key in ["a", "b", "c"].iter() {
+            store.set(key, "v", None).unwrap();
+        }
+        store.get("a").unwrap();
+        store.set("d", "v", None).unwrap();
+        assert_eq!(store.get("b").unwrap(), None);
+        assert!(store.get("a").unwrap().is_some() && store.get("d").unwrap().is_some());
+        assert_eq!(store.stats().evicted_keys, 1);
+    }
+
+    #[test]
+    fn volatile_lru_only_evicts_keys_with_a_ttl() {
+        let mut store = store(EvictionPolicy::VolatileLru);
+        store.set("a", "v", None).unwrap();
+        store.set("b", "v", Some(Duration::from_secs(60))).unwrap();
+        store.set("c", "v", None).unwrap();
+        store.set("d", "v", None).unwrap();
+        assert_eq!(store.get("b").unwrap(), None);
+        assert!(matches!(store.set("e", "v", None), Err(StoreError::OutOfMemory)));
+        assert!(store.get("a").unwrap().is_some());
+    }
+
+    #[test]
+    fn expired_keys_give_their_memory_back_without_being_read() {
+        let mut store = store(EvictionPolicy::NoEviction);
+        for key in ["a", "b", "c"].iter() {
+            store.set(key, "v", Some(Duration::from_millis(1))).unwrap();
+        }
+        thread::sleep(Duration::from_millis(5));
+        store.set("d", "v", None).unwrap();
+        let stats = store.stats();
+        assert_eq!((stats.keys, stats.expired_keys, stats.rejected_writes), (1, 3, 0));
+    }
+
+    #[test]
+    fn shards_share_one_limit() {
+        let limit = MemoryLimit { max_memory: 4 * (2 + ENTRY_OVERHEAD), policy: EvictionPolicy::NoEviction };
+        let sharded = sharded_store(limit, 8, Arc::default());
+        // More than an eighth of the limit still fits in an empty store.
+        let big = "x".repeat(2 * (2 + ENTRY_OVERHEAD));
+        sharded.lock("a").set("a", &big, None).unwrap();
+        sharded.lock("b").set("b", "v", None).unwrap();
+        assert!(matches!(sharded.lock("c").set("c", &big, None), Err(StoreError::OutOfMemory)));
+        let info = memory_info(&mut sharded.lock_all());
+        assert!(info.contains(&format!("\r\nmaxmemory:{}\r\n", limit.max_memory)));
+    }
+
+    #[test]
+    fn shards_evict_their_own_keys_for_the_shared_limit() {
+        let limit = MemoryLimit { max_memory: 3 * (2 + ENTRY_OVERHEAD), policy: EvictionPolicy::AllKeysLru };
+        let sharded = sharded_store(limit, 2, Arc::default());
+        let shard_of_a = sharded.index_of("a");
+        let others: Vec<String> =
+            (b'b'..=b'z').map(|c| (c as char).to_string()).filter(|k| sharded.index_of(k) != shard_of_a).take(3).collect();
+        sharded.lock("a").set("a", "v", None).unwrap();
+        for key in &others {
+            sharded.lock(key).set(key, "v", None).unwrap();
+        }
+        // "a" is the least recently used key, but the write was in the other shard.
+        assert!(sharded.lock("a").get("a").unwrap().is_some());
+        assert_eq!(sharded.lock(&others[0]).get(&others[0]).unwrap(), None);
+    }
+
+    #[test]
+    fn info_does_not_count_expired_keys() {
+        let sharded = sharded_store(MemoryLimit { max_memory: 0, policy: EvictionPolicy::NoEviction }, 2, Arc::default());
+        sharded.lock("a").set("a", "v", Some(Duration::from_millis(1))).unwrap();
+        sharded.lock("b").set("b", "v", None).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let info = memory_info(&mut sharded.lock_all());
+        assert!(info.contains("\r\nkeys:1\r\n") && info.contains("\r\nexpired_keys:1\r\n"));
+        assert!(info.contains(&format!("\r\nused_memory:{}\r\n", 2 + ENTRY_OVERHEAD)));
+    }
+
+    #[test]
+    fn measured_updates_keep_the_count_exact_and_refuse_oversized_values() {
+        use crate::document::{self, Path};
+        let mut store = BoundedStore::new(MemoryLimit { max_memory: 0, policy: EvictionPolicy::NoEviction }, Arc::default());
+        store.configure(None, 1000);
+        let exact = |store: &BoundedStore| store.entries.iter().map(|(key, entry)| entry_size(key, &entry.value)).sum::<usize>();
+        let set = |store: &mut BoundedStore, path: &str, json: serde_json::Value| {
+            let path = Path::parse(path).unwrap();
+            let added = crate::value::document_size(&json);
+            store.update_measured(
+                "doc",
+                || Value::Json(serde_json::Value::Null),
+                |value| document::set_growth(value.json()?, &path, added).ok_or(StoreError::InvalidValue("no parent")),
+                |value| {
+                    document::set(value.json_mut()?, &path, json);
+                    Ok(())
+                },
+            )
+        };
+        set(&mut store, "$", serde_json::json!({"name": "ada", "tags": ["a", "b"]})).unwrap();
+        set(&mut store, "$.name", serde_json::json!("lovelace")).unwrap();
+        set(&mut store, "$.tags[0]", serde_json::json!({"nested": true})).unwrap();
+        assert_eq!(store.used, exact(&store));
+
+        let result = set(&mut store, "$.name", serde_json::Value::String("x".repeat(1000)));
+        assert!(matches!(result, Err(StoreError::ValueTooLarge(1000))));
+        assert_eq!(store.read("doc", |value| value.json().unwrap()["name"].clone()), Some(serde_json::json!("lovelace")));
+        assert!(set(&mut store, "$.missing.field", serde_json::json!(1)).is_err());
+
+        let path = Path::parse("$.tags").unwrap();
+        store
+            .update_measured(
+                "doc",
+                || Value::Json(serde_json::Value::Null),
+                |value| Ok(document::delete_growth(value.json()?, &path)),
+                |value| Ok(document::delete(value.json_mut()?, &path)),
+            )
+            .unwrap();
+        assert_eq!(store.used, exact(&store));
+    }
+}
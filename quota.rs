@@ -0,0 +1,152 @@
+use std::time::{Duration, Instant};
+
+// Refills at `rate` tokens a second, up to a second's worth.
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        TokenBucket { rate, tokens: rate, last_refill: Instant::now() }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last_refill = now;
+    }
+
+    // How long until `amount` could be taken. Amounts above a second's worth
+    // only wait for a full bucket and leave it in debt.
+    fn wait_for(&self, amount: f64) -> Duration {
+        let needed = amount.min(self.rate) - self.tokens;
+        if needed <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(needed / self.rate)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RateLimit {
+    // Zero means unlimited.
+    pub requests_per_second: u64,
+    // Bytes of write requests; zero means unlimited.
+    pub bytes_per_second: u64,
+}
+
+impl RateLimit {
+    pub fn is_unlimited(&self) -> bool {
+        self.requests_per_second == 0 && self.bytes_per_second == 0
+    }
+}
+
+pub struct RateLimiter {
+    requests: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit) -> Self {
+        let bucket = |rate: u64| if rate == 0 { None } else { Some(TokenBucket::new(rate as f64)) };
+        RateLimiter { requests: bucket(limit.requests_per_second), bytes: bucket(limit.bytes_per_second) }
+    }
+
+    // Admits one request writing `written` bytes, or returns how long to
+    // wait before retrying. A refused request uses up nothing.
+    pub fn admit(&mut self, written: usize) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut wait = Duration::ZERO;
+        if let Some(requests) = self.requests.as_mut() {
+            requests.refill(now);
+            wait = wait.max(requests.wait_for(1.0));
+        }
+        if let Some(bytes) = self.bytes.as_mut() {
+            bytes.refill(now);
+            wait = wait.max(bytes.wait_for(written as f64));
+        }
+        if wait > Duration::ZERO {
+            return Err(wait);
+        }
+        if let Some(requests) = self.requests.as_mut() {
+            requests.tokens -= 1.0;
+        }
+        if let Some(bytes) = self.bytes.as_mut() {
+            bytes.tokens -= written as f64;
+        }
+        Ok(())
+    }
+
+    // Takes `written` bytes for writes only known once they are done, such
+    // as a script's. Later requests wait out any debt this leaves.
+    pub fn charge(&mut self, written: usize) {
+        if let Some(bytes) = self.bytes.as_mut() {
+            bytes.refill(Instant::now());
+            bytes.tokens -= written as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn buckets_refill_at_their_rate_up_to_one_second() {
+        let mut bucket = TokenBucket::new(10.0);
+        let start = bucket.last_refill;
+        bucket.tokens = 0.0;
+        bucket.refill(start + Duration::from_millis(500));
+        assert!((bucket.tokens - 5.0).abs() < 1e-9);
+        assert_eq!(bucket.wait_for(10.0), Duration::from_millis(500));
+        bucket.refill(start + Duration::from_secs(10));
+        assert_eq!(bucket.tokens, 10.0);
+        assert_eq!(bucket.wait_for(1_000.0), Duration::ZERO);
+    }
+
+    #[test]
+    fn requests_over_the_rate_are_refused_with_a_wait() {
+        let mut limiter = RateLimiter::new(RateLimit { requests_per_second: 2, bytes_per_second: 0 });
+        assert!(limiter.admit(0).is_ok());
+        assert!(limiter.admit(1_000_000).is_ok());
+        let wait = limiter.admit(0).unwrap_err();
+        assert!(wait > Duration::ZERO && wait <= Duration::from_millis(500));
+    }
+
+    #[test]
+    fn refused_requests_use_up_nothing_and_large_writes_leave_debt() {
+        let mut limiter = RateLimiter::new(RateLimit { requests_per_second: 100, bytes_per_second: 100 });
+        assert!(limiter.admit(60).is_ok());
+        assert!(limiter.admit(60).is_err());
+        let requests = limiter.requests.as_ref().unwrap().tokens;
+        assert!(requests > 98.9 && requests < 99.5);
+
+        // A write larger than a second's worth waits for a full bucket, then
+        // goes through and leaves the bucket owing.
+        let bytes = limiter.bytes.as_mut().unwrap();
+        bytes.tokens = 100.0;
+        assert!(limiter.admit(250).is_ok());
+        assert!(limiter.bytes.as_ref().unwrap().tokens < -149.0);
+        assert!(limiter.admit(1).unwrap_err() > Duration::from_secs(1));
+    }
+
+    #[test]
+    fn charged_writes_are_owed_by_later_requests() {
+        let mut limiter = RateLimiter::new(RateLimit { requests_per_second: 0, bytes_per_second: 100 });
+        limiter.charge(300);
+        assert!(limiter.bytes.as_ref().unwrap().tokens < -199.0);
+        assert!(limiter.admit(1).unwrap_err() > Duration::from_secs(2));
+    }
+
+    #[test]
+    fn no_limits_is_unlimited() {
+        assert!(RateLimit::default().is_unlimited());
+        let mut limiter = RateLimiter::new(RateLimit::default());
+        for _ in 0..1000 {
+            assert!(limiter.admit(usize::MAX).is_ok());
+        }
+    }
+}
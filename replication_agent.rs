@@ -0,0 +1,61 @@
+// The agent only uses the cluster client side of kv_store.
+#[allow(dead_code)]
+mod kv_store;
+
+use std::env;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use kv_store::cluster_client::ClusterClient;
+use kv_store::replication::{AgentConfig, ConflictPolicy, ReplicationAgent};
+
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+fn list(value: &str) -> Vec<String> {
+    value.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect()
+}
+
+fn connect(seeds: &str) -> std::io::Result<ClusterClient> {
+    let seeds = list(seeds);
+    let seeds: Vec<&str> = seeds.iter().map(|s| s.as_str()).collect();
+    ClusterClient::connect(&seeds, Duration::from_secs(5))
+}
+
+fn main() {
+    dotenv::dotenv().ok();
+
+    let source_seeds = env::var("REPLICATION_SOURCE").expect("REPLICATION_SOURCE must be set");
+    let destination_seeds = env::var("REPLICATION_DESTINATION").expect("REPLICATION_DESTINATION must be set");
+    // Policies are `timestamp` or `priority:first,second,...`.
+    let policy = match env::var("REPLICATION_CONFLICT_POLICY").unwrap_or_else(|_| "timestamp".to_string()) {
+        policy if policy == "timestamp" => ConflictPolicy::Timestamp,
+        policy => match policy.strip_prefix("priority:") {
+            Some(order) => ConflictPolicy::OriginPriority(list(order)),
+            None => panic!("REPLICATION_CONFLICT_POLICY must be timestamp or priority:CLUSTER,..."),
+        },
+    };
+    let config = AgentConfig {
+        source_cluster: env::var("SOURCE_CLUSTER_NAME").expect("SOURCE_CLUSTER_NAME must be set"),
+        destination_cluster: env::var("DESTINATION_CLUSTER_NAME").expect("DESTINATION_CLUSTER_NAME must be set"),
+        prefixes: list(&env::var("REPLICATION_PREFIXES").expect("REPLICATION_PREFIXES must be set")),
+        policy,
+        position_path: PathBuf::from(env::var("REPLICATION_POSITION_PATH").unwrap_or_else(|_| "replication.json".to_string())),
+    };
+    println!(
+        "Replicating {:?} from {} to {}",
+        config.prefixes, config.source_cluster, config.destination_cluster
+    );
+
+    // Positions are saved as entries are applied, so each retry resumes.
+    loop {
+        let result = connect(&source_seeds)
+            .and_then(|source| Ok((source, connect(&destination_seeds)?)))
+            .and_then(|(source, destination)| ReplicationAgent::new(config.clone(), source, destination))
+            .and_then(|mut agent| agent.run());
+        match result {
+            Ok(()) => println!("Source streams closed, reconnecting"),
+            Err(e) => println!("Replication stopped: {}", e),
+        }
+        thread::sleep(RETRY_DELAY);
+    }
+}
@@ -0,0 +1,1152 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt::Write as _;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+// `super`, not `crate`: the cluster node includes this file too.
+use super::value::parse_bytes;
+
+// Scripts are a small Lua subset: nil, booleans, numbers and strings;
+// `local`, assignment, if/elseif/else, while, numeric for, do blocks, break
+// and return; the usual operators including `..` and `#`; and KEYS and ARGV
+// indexed from 1. There are no tables, user functions or globals. Scripts
+// call get, set, delete, cas, tonumber and tostring, and may only touch
+// keys passed in KEYS.
+
+// Deepest nesting of blocks and expressions a script may have. Every
+// operator and index counts, since each adds a level to the tree the
+// interpreter recurses through.
+const MAX_DEPTH: usize = 200;
+// Longest script source accepted, from clients and peers alike.
+pub const MAX_SOURCE_BYTES: usize = 64 * 1024;
+// Steps between checks of the time limit.
+const STEPS_PER_CLOCK_CHECK: u64 = 1024;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Name(String),
+    Number(f64),
+    Str(String),
+    Symbol(&'static str),
+    Eof,
+}
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "if", "local", "nil", "not", "or", "return", "then",
+    "true", "while",
+];
+
+// Longest first, so `..` is not read as two dots.
+const SYMBOLS: &[&str] = &[
+    "==", "~=", "<=", ">=", "..", "(", ")", "[", "]", ",", ";", "=", "<", ">", "+", "-", "*", "/", "%", "^", "#",
+];
+
+fn tokenize(source: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = source;
+    loop {
+        rest = rest.trim_start();
+        if let Some(comment) = rest.strip_prefix("--") {
+            rest = comment.find('\n').map_or("", |end| &comment[end..]);
+            continue;
+        }
+        let c = match rest.chars().next() {
+            Some(c) => c,
+            None => break,
+        };
+        if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            rest = &rest[end..];
+        } else if c.is_ascii_digit() || (c == '.' && rest[1..].starts_with(|c: char| c.is_ascii_digit())) {
+            let mut end = 0;
+            let bytes = rest.as_bytes();
+            while end < bytes.len() {
+                let b = bytes[end];
+                let exponent_sign = (b == b'+' || b == b'-') && end > 0 && matches!(bytes[end - 1], b'e' | b'E');
+                if b.is_ascii_alphanumeric() || b == b'.' || exponent_sign {
+                    end += 1;
+                } else {
+                    break;
+                }
+            }
+            let number = rest[..end].parse::<f64>().map_err(|_| format!("malformed number '{}'", &rest[..end]))?;
+            tokens.push(Token::Number(number));
+            rest = &rest[end..];
+        } else if c == '"' || c == '\'' {
+            let mut value = String::new();
+            let mut chars = rest[1..].char_indices();
+            let end = loop {
+                match chars.next() {
+                    Some((i, ch)) if ch == c => break i + 2,
+                    Some((_, '\\')) => match chars.next() {
+                        Some((_, 'n')) => value.push('\n'),
+                        Some((_, 't')) => value.push('\t'),
+                        Some((_, escaped)) => value.push(escaped),
+                        None => return Err("unfinished string".to_string()),
+                    },
+                    Some((_, '\n')) | None => return Err("unfinished string".to_string()),
+                    Some((_, ch)) => value.push(ch),
+                }
+            };
+            tokens.push(Token::Str(value));
+            rest = &rest[end..];
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected symbol '{}'", c))?;
+            tokens.push(Token::Symbol(symbol));
+            rest = &rest[symbol.len()..];
+        }
+    }
+    tokens.push(Token::Eof);
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Modulo,
+    Power,
+    Concat,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    // Left and right binding power, as in Lua's own parser.
+    fn from_symbol(token: &Token) -> Option<(Self, u8, u8)> {
+        let op = match token {
+            Token::Name(name) if name == "or" => (BinaryOp::Or, 1, 1),
+            Token::Name(name) if name == "and" => (BinaryOp::And, 2, 2),
+            Token::Symbol("==") => (BinaryOp::Equal, 3, 3),
+            Token::Symbol("~=") => (BinaryOp::NotEqual, 3, 3),
+            Token::Symbol("<") => (BinaryOp::Less, 3, 3),
+            Token::Symbol("<=") => (BinaryOp::LessEqual, 3, 3),
+            Token::Symbol(">") => (BinaryOp::Greater, 3, 3),
+            Token::Symbol(">=") => (BinaryOp::GreaterEqual, 3, 3),
+            Token::Symbol("..") => (BinaryOp::Concat, 9, 8),
+            Token::Symbol("+") => (BinaryOp::Add, 10, 10),
+            Token::Symbol("-") => (BinaryOp::Subtract, 10, 10),
+            Token::Symbol("*") => (BinaryOp::Multiply, 11, 11),
+            Token::Symbol("/") => (BinaryOp::Divide, 11, 11),
+            Token::Symbol("%") => (BinaryOp::Modulo, 11, 11),
+            Token::Symbol("^") => (BinaryOp::Power, 14, 13),
+            _ => return None,
+        };
+        Some(op)
+    }
+}
+
+const UNARY_PRIORITY: u8 = 12;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UnaryOp {
+    Not,
+    Negate,
+    Length,
+}
+
+#[derive(Debug, Clone)]
+enum Expr {
+    Literal(Val),
+    Var(String),
+    Index(Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone)]
+enum Stat {
+    Local(String, Option<Expr>),
+    Assign(String, Expr),
+    Call(String, Vec<Expr>),
+    If(Vec<(Expr, Block)>, Option<Block>),
+    While(Expr, Block),
+    For { var: String, start: Expr, end: Expr, step: Option<Expr>, body: Block },
+    Do(Block),
+    Break,
+    Return(Option<Expr>),
+}
+
+type Block = Vec<Stat>;
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        if token != Token::Eof {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Token::Name(name) if name == keyword)
+    }
+
+    fn accept(&mut self, symbol: &str) -> bool {
+        let found = match self.peek() {
+            Token::Symbol(s) => *s == symbol,
+            Token::Name(name) => name == symbol && KEYWORDS.contains(&symbol),
+            _ => false,
+        };
+        if found {
+            self.next();
+        }
+        found
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), String> {
+        if self.accept(symbol) {
+            Ok(())
+        } else {
+            Err(format!("'{}' expected near {}", symbol, describe(self.peek())))
+        }
+    }
+
+    fn name(&mut self) -> Result<String, String> {
+        match self.next() {
+            Token::Name(name) if !KEYWORDS.contains(&name.as_str()) => Ok(name),
+            other => Err(format!("name expected near {}", describe(&other))),
+        }
+    }
+
+    fn enter(&mut self) -> Result<(), String> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err("script nests too deeply".to_string());
+        }
+        Ok(())
+    }
+
+    // Statements up to one of `terminators` or the end of the script.
+    fn block(&mut self, terminators: &[&str]) -> Result<Block, String> {
+        self.enter()?;
+        let mut block = Vec::new();
+        loop {
+            if *self.peek() == Token::Eof || terminators.iter().any(|t| self.at_keyword(t)) {
+                break;
+            }
+            if self.accept(";") {
+                continue;
+            }
+            let stat = self.statement()?;
+            let is_return = matches!(stat, Stat::Return(_));
+            block.push(stat);
+            if is_return {
+                self.accept(";");
+                if *self.peek() != Token::Eof && !terminators.iter().any(|t| self.at_keyword(t)) {
+                    return Err(format!("'end' expected after return near {}", describe(self.peek())));
+                }
+                break;
+            }
+        }
+        self.depth -= 1;
+        Ok(block)
+    }
+
+    fn statement(&mut self) -> Result<Stat, String> {
+        if self.accept("local") {
+            let name = self.name()?;
+            let value = if self.accept("=") { Some(self.expression()?) } else { None };
+            return Ok(Stat::Local(name, value));
+        }
+        if self.accept("if") {
+            let mut branches = Vec::new();
+            let condition = self.expression()?;
+            self.expect("then")?;
+            branches.push((condition, self.block(&["elseif", "else", "end"])?));
+            let mut otherwise = None;
+            loop {
+                if self.accept("elseif") {
+                    let condition = self.expression()?;
+                    self.expect("then")?;
+                    branches.push((condition, self.block(&["elseif", "else", "end"])?));
+                } else if self.accept("else") {
+                    otherwise = Some(self.block(&["end"])?);
+                    self.expect("end")?;
+                    break;
+                } else {
+                    self.expect("end")?;
+                    break;
+                }
+            }
+            return Ok(Stat::If(branches, otherwise));
+        }
+        if self.accept("while") {
+            let condition = self.expression()?;
+            self.expect("do")?;
+            let body = self.block(&["end"])?;
+            self.expect("end")?;
+            return Ok(Stat::While(condition, body));
+        }
+        if self.accept("for") {
+            let var = self.name()?;
+            self.expect("=")?;
+            let start = self.expression()?;
+            self.expect(",")?;
+            let end = self.expression()?;
+            let step = if self.accept(",") { Some(self.expression()?) } else { None };
+            self.expect("do")?;
+            let body = self.block(&["end"])?;
+            self.expect("end")?;
+            return Ok(Stat::For { var, start, end, step, body });
+        }
+        if self.accept("do") {
+            let body = self.block(&["end"])?;
+            self.expect("end")?;
+            return Ok(Stat::Do(body));
+        }
+        if self.accept("break") {
+            return Ok(Stat::Break);
+        }
+        if self.accept("return") {
+            let ends = *self.peek() == Token::Eof
+                || *self.peek() == Token::Symbol(";")
+                || ["end", "else", "elseif"].iter().any(|t| self.at_keyword(t));
+            return Ok(Stat::Return(if ends { None } else { Some(self.expression()?) }));
+        }
+        let name = self.name()?;
+        if self.accept("=") {
+            return Ok(Stat::Assign(name, self.expression()?));
+        }
+        if *self.peek() == Token::Symbol("(") {
+            let args = self.arguments()?;
+            return Ok(Stat::Call(name, args));
+        }
+        Err(format!("syntax error near {}", describe(self.peek())))
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Expr>, String> {
+        self.expect("(")?;
+        let mut args = Vec::new();
+        if !self.accept(")") {
+            loop {
+                args.push(self.expression()?);
+                if self.accept(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+        Ok(args)
+    }
+
+    fn expression(&mut self) -> Result<Expr, String> {
+        self.subexpression(0)
+    }
+
+    // Operators binding tighter than `limit`, by precedence climbing.
+    fn subexpression(&mut self, limit: u8) -> Result<Expr, String> {
+        self.enter()?;
+        let unary = match self.peek() {
+            Token::Name(name) if name == "not" => Some(UnaryOp::Not),
+            Token::Symbol("-") => Some(UnaryOp::Negate),
+            Token::Symbol("#") => Some(UnaryOp::Length),
+            _ => None,
+        };
+        let mut left = match unary {
+            Some(op) => {
+                self.next();
+                Expr::Unary(op, Box::new(self.subexpression(UNARY_PRIORITY)?))
+            }
+            None => self.primary()?,
+        };
+        let depth = self.depth;
+        while let Some((op, left_priority, right_priority)) = BinaryOp::from_symbol(self.peek()) {
+            if left_priority <= limit {
+                break;
+            }
+            self.next();
+            let right = self.subexpression(right_priority)?;
+            left = Expr::Binary(op, Box::new(left), Box::new(right));
+            self.enter()?;
+        }
+        self.depth = depth - 1;
+        Ok(left)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        let mut expr = match self.next() {
+            Token::Number(n) => return Ok(Expr::Literal(Val::Num(n))),
+            Token::Str(s) => return Ok(Expr::Literal(Val::Str(Arc::new(s)))),
+            Token::Name(name) if name == "nil" => return Ok(Expr::Literal(Val::Nil)),
+            Token::Name(name) if name == "true" => return Ok(Expr::Literal(Val::Bool(true))),
+            Token::Name(name) if name == "false" => return Ok(Expr::Literal(Val::Bool(false))),
+            Token::Name(name) if !KEYWORDS.contains(&name.as_str()) => {
+                if *self.peek() == Token::Symbol("(") {
+                    Expr::Call(name, self.arguments()?)
+                } else {
+                    Expr::Var(name)
+                }
+            }
+            Token::Symbol("(") => {
+                let inner = self.expression()?;
+                self.expect(")")?;
+                inner
+            }
+            other => return Err(format!("unexpected {}", describe(&other))),
+        };
+        let depth = self.depth;
+        while self.accept("[") {
+            let index = self.expression()?;
+            self.expect("]")?;
+            expr = Expr::Index(Box::new(expr), Box::new(index));
+            self.enter()?;
+        }
+        self.depth = depth;
+        Ok(expr)
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Name(name) => format!("'{}'", name),
+        Token::Number(n) => format!("'{}'", format_number(*n)),
+        Token::Str(s) => format!("'\"{}\"'", s),
+        Token::Symbol(s) => format!("'{}'", s),
+        Token::Eof => "'<eof>'".to_string(),
+    }
+}
+
+// A compiled script, ready to run any number of times.
+#[derive(Debug)]
+pub struct Script {
+    body: Block,
+}
+
+impl Script {
+    pub fn compile(source: &str) -> Result<Self, String> {
+        if source.len() > MAX_SOURCE_BYTES {
+            return Err(format!("script is longer than {} bytes", MAX_SOURCE_BYTES));
+        }
+        let mut parser = Parser { tokens: tokenize(source)?, position: 0, depth: 0 };
+        let body = parser.block(&[])?;
+        if *parser.peek() != Token::Eof {
+            return Err(format!("'<eof>' expected near {}", describe(parser.peek())));
+        }
+        Ok(Script { body })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Val {
+    Nil,
+    Bool(bool),
+    Num(f64),
+    Str(Arc<String>),
+    List(Arc<Vec<Val>>),
+}
+
+impl Val {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Val::Nil => "nil",
+            Val::Bool(_) => "boolean",
+            Val::Num(_) => "number",
+            Val::Str(_) => "string",
+            Val::List(_) => "table",
+        }
+    }
+
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Val::Nil | Val::Bool(false))
+    }
+
+    // Numbers, and strings that read as numbers, as Lua coerces them.
+    fn to_number(&self) -> Option<f64> {
+        match self {
+            Val::Num(n) => Some(*n),
+            Val::Str(s) => s.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    fn to_text(&self) -> Option<String> {
+        match self {
+            Val::Num(n) => Some(format_number(*n)),
+            Val::Str(s) => Some(s.to_string()),
+            _ => None,
+        }
+    }
+}
+
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < 1e15 {
+        format!("{}", n as i64)
+    } else {
+        format!("{}", n)
+    }
+}
+
+// The reply for a script's return value. False reads as nil, as in Redis.
+pub fn reply(value: &Val) -> String {
+    match value {
+        Val::Nil | Val::Bool(false) => "(nil)\n".to_string(),
+        Val::Bool(true) => "1\n".to_string(),
+        Val::Num(n) => format!("{}\n", format_number(*n)),
+        Val::Str(s) => format!("{}\n", s),
+        Val::List(items) if items.is_empty() => "(empty list)\n".to_string(),
+        Val::List(items) => items.iter().map(reply).collect(),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScriptLimits {
+    pub time: Duration,
+    // Bytes of strings a script may build while it runs.
+    pub memory: usize,
+}
+
+impl ScriptLimits {
+    // SCRIPT_TIME_LIMIT_MS defaults to 5000 and SCRIPT_MAX_MEMORY, which
+    // takes the same suffixes as MAXMEMORY, to 64mb.
+    pub fn from_env() -> Result<Self, String> {
+        let time = match env::var("SCRIPT_TIME_LIMIT_MS") {
+            Ok(ms) => Duration::from_millis(ms.parse().map_err(|_| format!("Invalid SCRIPT_TIME_LIMIT_MS: {}", ms))?),
+            Err(_) => Duration::from_millis(5000),
+        };
+        let memory = match env::var("SCRIPT_MAX_MEMORY") {
+            Ok(limit) => parse_bytes(&limit).ok_or_else(|| format!("Invalid SCRIPT_MAX_MEMORY: {}", limit))?,
+            Err(_) => 64 * 1024 * 1024,
+        };
+        Ok(ScriptLimits { time, memory })
+    }
+}
+
+// Compiled scripts by the SHA-1 of their source, for EVALSHA.
+pub struct Scripts {
+    cache: Mutex<HashMap<String, Arc<Script>>>,
+    pub limits: ScriptLimits,
+}
+
+impl Scripts {
+    pub fn new(limits: ScriptLimits) -> Self {
+        Scripts { cache: Mutex::new(HashMap::new()), limits }
+    }
+
+    // Compiles and caches `source`, returning its hash and the script.
+    pub fn load(&self, source: &str) -> Result<(String, Arc<Script>), String> {
+        let sha = sha1_hex(source.as_bytes());
+        if let Some(script) = self.get(&sha) {
+            return Ok((sha, script));
+        }
+        let script = Arc::new(Script::compile(source)?);
+        self.cache.lock().unwrap().insert(sha.clone(), Arc::clone(&script));
+        Ok((sha, script))
+    }
+
+    pub fn get(&self, sha: &str) -> Option<Arc<Script>> {
+        self.cache.lock().unwrap().get(&sha.to_lowercase()).cloned()
+    }
+
+    pub fn flush(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+}
+
+enum Flow {
+    Normal,
+    Break,
+    Return(Val),
+}
+
+// The keys a script runs against, locked for as long as it runs.
+pub trait ScriptStore {
+    fn get(&mut self, key: &str) -> Result<Option<String>, String>;
+    // Applies a finished script's writes in key order, where `None` deletes
+    // the key.
+    fn commit(&mut self, writes: Vec<(String, Option<String>)>) -> Result<(), String>;
+}
+
+// Runs `script`, returning its value and the bytes of keys and values it
+// wrote. Writes are held back until the script finishes, so one that fails
+// or hits a limit leaves the store untouched.
+pub fn run(
+    script: &Script,
+    keys: Vec<String>,
+    args: Vec<String>,
+    store: &mut dyn ScriptStore,
+    limits: ScriptLimits,
+) -> Result<(Val, usize), String> {
+    let list = |items: &[String]| Val::List(Arc::new(items.iter().map(|s| Val::Str(Arc::new(s.clone()))).collect()));
+    let mut globals = HashMap::new();
+    globals.insert("KEYS".to_string(), list(&keys));
+    globals.insert("ARGV".to_string(), list(&args));
+    let mut interpreter = Interpreter {
+        store,
+        keys,
+        writes: BTreeMap::new(),
+        scopes: vec![globals],
+        started: Instant::now(),
+        limits,
+        memory_left: limits.memory,
+        steps: 0,
+    };
+    let value = match interpreter.block(&script.body)? {
+        Flow::Return(value) => value,
+        Flow::Break => return Err("break outside a loop".to_string()),
+        Flow::Normal => Val::Nil,
+    };
+    let writes: Vec<(String, Option<String>)> = interpreter.writes.into_iter().collect();
+    let written = writes.iter().map(|(key, value)| key.len() + value.as_ref().map_or(0, String::len)).sum();
+    interpreter.store.commit(writes)?;
+    Ok((value, written))
+}
+
+struct Interpreter<'s> {
+    store: &'s mut dyn ScriptStore,
+    keys: Vec<String>,
+    // Values written so far, read back by later calls; `None` is a delete.
+    writes: BTreeMap<String, Option<String>>,
+    scopes: Vec<HashMap<String, Val>>,
+    started: Instant,
+    limits: ScriptLimits,
+    memory_left: usize,
+    steps: u64,
+}
+
+impl Interpreter<'_> {
+    fn step(&mut self) -> Result<(), String> {
+        self.steps += 1;
+        if self.steps.is_multiple_of(STEPS_PER_CLOCK_CHECK) && self.started.elapsed() > self.limits.time {
+            return Err(format!("script exceeded its time limit of {} ms", self.limits.time.as_millis()));
+        }
+        Ok(())
+    }
+
+    fn allocate(&mut self, bytes: usize) -> Result<(), String> {
+        self.memory_left = self
+            .memory_left
+            .checked_sub(bytes)
+            .ok_or_else(|| format!("script exceeded its memory limit of {} bytes", self.limits.memory))?;
+        Ok(())
+    }
+
+    fn text(&mut self, text: String) -> Result<Val, String> {
+        self.allocate(text.len())?;
+        Ok(Val::Str(Arc::new(text)))
+    }
+
+    fn block(&mut self, block: &[Stat]) -> Result<Flow, String> {
+        self.scopes.push(HashMap::new());
+        let mut flow = Ok(Flow::Normal);
+        for stat in block {
+            flow = self.statement(stat);
+            if !matches!(flow, Ok(Flow::Normal)) {
+                break;
+            }
+        }
+        self.scopes.pop();
+        flow
+    }
+
+    fn statement(&mut self, stat: &Stat) -> Result<Flow, String> {
+        self.step()?;
+        match stat {
+            Stat::Local(name, value) => {
+                let value = match value {
+                    Some(value) => self.eval(value)?,
+                    None => Val::Nil,
+                };
+                self.scopes.last_mut().unwrap().insert(name.clone(), value);
+            }
+            Stat::Assign(name, value) => {
+                let value = self.eval(value)?;
+                match self.scopes.iter_mut().rev().find_map(|scope| scope.get_mut(name)) {
+                    Some(slot) => *slot = value,
+                    None => return Err(format!("assignment to undeclared variable '{}'", name)),
+                }
+            }
+            Stat::Call(name, args) => {
+                self.call(name, args)?;
+            }
+            Stat::If(branches, otherwise) => {
+                for (condition, body) in branches {
+                    if self.eval(condition)?.is_truthy() {
+                        return self.block(body);
+                    }
+                }
+                if let Some(body) = otherwise {
+                    return self.block(body);
+                }
+            }
+            Stat::While(condition, body) => {
+                while self.eval(condition)?.is_truthy() {
+                    match self.block(body)? {
+                        Flow::Normal => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+            Stat::For { var, start, end, step, body } => {
+                let number = |value: Val, what: &str| value.to_number().ok_or(format!("'for' {} must be a number", what));
+                let start = number(self.eval(start)?, "initial value")?;
+                let end = number(self.eval(end)?, "limit")?;
+                let step = match step {
+                    Some(step) => number(self.eval(step)?, "step")?,
+                    None => 1.0,
+                };
+                if step == 0.0 {
+                    return Err("'for' step is zero".to_string());
+                }
+                let mut i = start;
+                while (step > 0.0 && i <= end) || (step < 0.0 && i >= end) {
+                    self.step()?;
+                    self.scopes.push(HashMap::from([(var.clone(), Val::Num(i))]));
+                    let flow = self.block(body);
+                    self.scopes.pop();
+                    match flow? {
+                        Flow::Normal => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                    i += step;
+                }
+            }
+            Stat::Do(body) => return self.block(body),
+            Stat::Break => return Ok(Flow::Break),
+            Stat::Return(value) => {
+                let value = match value {
+                    Some(value) => self.eval(value)?,
+                    None => Val::Nil,
+                };
+                return Ok(Flow::Return(value));
+            }
+        }
+        Ok(Flow::Normal)
+    }
+
+    fn eval(&mut self, expr: &Expr) -> Result<Val, String> {
+        self.step()?;
+        match expr {
+            Expr::Literal(value) => Ok(value.clone()),
+            Expr::Var(name) => self
+                .scopes
+                .iter()
+                .rev()
+                .find_map(|scope| scope.get(name))
+                .cloned()
+                .ok_or_else(|| format!("undefined variable '{}'", name)),
+            Expr::Index(target, index) => {
+                let target = self.eval(target)?;
+                let index = self.eval(index)?;
+                match (&target, index.to_number()) {
+                    (Val::List(items), Some(i)) if i.fract() == 0.0 && i >= 1.0 => {
+                        Ok(items.get(i as usize - 1).cloned().unwrap_or(Val::Nil))
+                    }
+                    (Val::List(_), _) => Ok(Val::Nil),
+                    _ => Err(format!("attempt to index a {} value", target.type_name())),
+                }
+            }
+            Expr::Call(name, args) => self.call(name, args),
+            Expr::Unary(op, operand) => {
+                let value = self.eval(operand)?;
+                match op {
+                    UnaryOp::Not => Ok(Val::Bool(!value.is_truthy())),
+                    UnaryOp::Negate => value
+                        .to_number()
+                        .map(|n| Val::Num(-n))
+                        .ok_or_else(|| format!("attempt to perform arithmetic on a {} value", value.type_name())),
+                    UnaryOp::Length => match &value {
+                        Val::Str(s) => Ok(Val::Num(s.len() as f64)),
+                        Val::List(items) => Ok(Val::Num(items.len() as f64)),
+                        _ => Err(format!("attempt to get length of a {} value", value.type_name())),
+                    },
+                }
+            }
+            Expr::Binary(BinaryOp::And, left, right) => {
+                let left = self.eval(left)?;
+                if left.is_truthy() {
+                    self.eval(right)
+                } else {
+                    Ok(left)
+                }
+            }
+            Expr::Binary(BinaryOp::Or, left, right) => {
+                let left = self.eval(left)?;
+                if left.is_truthy() {
+                    Ok(left)
+                } else {
+                    self.eval(right)
+                }
+            }
+            Expr::Binary(op, left, right) => {
+                let left = self.eval(left)?;
+                let right = self.eval(right)?;
+                self.binary(*op, left, right)
+            }
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, left: Val, right: Val) -> Result<Val, String> {
+        match op {
+            BinaryOp::Equal => return Ok(Val::Bool(left == right)),
+            BinaryOp::NotEqual => return Ok(Val::Bool(left != right)),
+            BinaryOp::Concat => {
+                return match (left.to_text(), right.to_text()) {
+                    (Some(a), Some(b)) => self.text(a + &b),
+                    _ => {
+                        let bad = if left.to_text().is_none() { &left } else { &right };
+                        Err(format!("attempt to concatenate a {} value", bad.type_name()))
+                    }
+                };
+            }
+            BinaryOp::Less | BinaryOp::LessEqual | BinaryOp::Greater | BinaryOp::GreaterEqual => {
+                let ordering = match (&left, &right) {
+                    (Val::Num(a), Val::Num(b)) => a.partial_cmp(b),
+                    (Val::Str(a), Val::Str(b)) => Some(a.cmp(b)),
+                    _ => return Err(format!("attempt to compare {} with {}", left.type_name(), right.type_name())),
+                };
+                let result = match (op, ordering) {
+                    (_, None) => false,
+                    (BinaryOp::Less, Some(o)) => o.is_lt(),
+                    (BinaryOp::LessEqual, Some(o)) => o.is_le(),
+                    (BinaryOp::Greater, Some(o)) => o.is_gt(),
+                    (_, Some(o)) => o.is_ge(),
+                };
+                return Ok(Val::Bool(result));
+            }
+            _ => {}
+        }
+        let (a, b) = match (left.to_number(), right.to_number()) {
+            (Some(a), Some(b)) => (a, b),
+            _ => {
+                let bad = if left.to_number().is_none() { &left } else { &right };
+                return Err(format!("attempt to perform arithmetic on a {} value", bad.type_name()));
+            }
+        };
+        let result = match op {
+            BinaryOp::Add => a + b,
+            BinaryOp::Subtract => a - b,
+            BinaryOp::Multiply => a * b,
+            BinaryOp::Divide => a / b,
+            BinaryOp::Modulo => a - (a / b).floor() * b,
+            _ => a.powf(b),
+        };
+        Ok(Val::Num(result))
+    }
+
+    // Scripts may only touch keys they declared, so every shard they use is
+    // already locked.
+    fn key(&self, value: &Val) -> Result<String, String> {
+        let key = value.to_text().ok_or_else(|| format!("a key must be a string, not a {}", value.type_name()))?;
+        if !self.keys.contains(&key) {
+            return Err(format!("script accessed key '{}' that was not passed in KEYS", key));
+        }
+        Ok(key)
+    }
+
+    // The key's value as the script has left it.
+    fn current(&mut self, key: &str) -> Result<Option<String>, String> {
+        match self.writes.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.store.get(key),
+        }
+    }
+
+    // Buffered writes count against the memory limit like any other text.
+    fn write(&mut self, key: String, value: Option<String>) -> Result<(), String> {
+        self.allocate(key.len() + value.as_ref().map_or(0, String::len))?;
+        self.writes.insert(key, value);
+        Ok(())
+    }
+
+    fn call(&mut self, name: &str, args: &[Expr]) -> Result<Val, String> {
+        let mut values = Vec::with_capacity(args.len());
+        for arg in args {
+            values.push(self.eval(arg)?);
+        }
+        let arg = |i: usize| values.get(i).cloned().unwrap_or(Val::Nil);
+        let text = |value: Val, what: &str| value.to_text().ok_or(format!("'{}' needs a string {}", name, what));
+        match name {
+            "get" => {
+                let key = self.key(&arg(0))?;
+                match self.current(&key)? {
+                    Some(value) => self.text(value),
+                    None => Ok(Val::Nil),
+                }
+            }
+            "set" => {
+                let key = self.key(&arg(0))?;
+                let value = text(arg(1), "value")?;
+                self.write(key, Some(value))?;
+                Ok(Val::Bool(true))
+            }
+            "delete" => {
+                let key = self.key(&arg(0))?;
+                let existed = self.current(&key)?.is_some();
+                self.write(key, None)?;
+                Ok(Val::Bool(existed))
+            }
+            // Sets the key to the third argument, or deletes it if that is
+            // nil, only if it holds the second, where nil means missing.
+            "cas" => {
+                let key = self.key(&arg(0))?;
+                let expected = match arg(1) {
+                    Val::Nil => None,
+                    value => Some(text(value, "expected value")?),
+                };
+                if self.current(&key)? != expected {
+                    return Ok(Val::Bool(false));
+                }
+                let value = match arg(2) {
+                    Val::Nil => None,
+                    value => Some(text(value, "new value")?),
+                };
+                self.write(key, value)?;
+                Ok(Val::Bool(true))
+            }
+            "tonumber" => Ok(arg(0).to_number().map_or(Val::Nil, Val::Num)),
+            "tostring" => {
+                let value = arg(0);
+                let text = match &value {
+                    Val::Nil => "nil".to_string(),
+                    Val::Bool(b) => b.to_string(),
+                    Val::List(_) => "table".to_string(),
+                    other => other.to_text().unwrap_or_default(),
+                };
+                self.text(text)
+            }
+            _ => Err(format!("unknown function '{}'", name)),
+        }
+    }
+}
+
+pub fn sha1_hex(data: &[u8]) -> String {
+    let mut state: [u32; 5] = [0x6745_2301, 0xEFCD_AB89, 0x98BA_DCFE, 0x1032_5476, 0xC3D2_E1F0];
+    let mut message = data.to_vec();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64).wrapping_mul(8).to_be_bytes());
+    for chunk in message.chunks(64) {
+        let mut words = [0u32; 80];
+        for (word, bytes) in words.iter_mut().zip(chunk.chunks(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for i in 16..80 {
+            words[i] = (words[i - 3] ^ words[i - 8] ^ words[i - 14] ^ words[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in words.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A82_7999),
+                20..=39 => (b ^ c ^ d, 0x6ED9_EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1B_BCDC),
+                _ => (b ^ c ^ d, 0xCA62_C1D6),
+            };
+            let next = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = next;
+        }
+        for (value, add) in state.iter_mut().zip([a, b, c, d, e]) {
+            *value = value.wrapping_add(add);
+        }
+    }
+    let mut hex = String::with_capacity(40);
+    for value in state {
+        let _ = write!(hex, "{:08x}", value);
+    }
+    hex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keys in a map, recording what was committed.
+    #[derive(Default)]
+    struct MapStore {
+        values: HashMap<String, String>,
+        commits: usize,
+    }
+
+    impl ScriptStore for MapStore {
+        fn get(&mut self, key: &str) -> Result<Option<String>, String> {
+            Ok(self.values.get(key).cloned())
+        }
+
+        fn commit(&mut self, writes: Vec<(String, Option<String>)>) -> Result<(), String> {
+            self.commits += 1;
+            for (key, value) in writes {
+                match value {
+                    Some(value) => self.values.insert(key, value),
+                    None => self.values.remove(&key),
+                };
+            }
+            Ok(())
+        }
+    }
+
+    fn limits() -> ScriptLimits {
+        ScriptLimits { time: Duration::from_secs(5), memory: 1024 * 1024 }
+    }
+
+    fn eval(source: &str, keys: &[&str], args: &[&str], store: &mut MapStore) -> Result<Val, String> {
+        let script = Script::compile(source)?;
+        let owned = |items: &[&str]| items.iter().map(|s| s.to_string()).collect();
+        run(&script, owned(keys), owned(args), store, limits()).map(|(value, _)| value)
+    }
+
+    fn text(s: &str) -> Val {
+        Val::Str(Arc::new(s.to_string()))
+    }
+
+    #[test]
+    fn tokens_cover_numbers_strings_symbols_and_comments() {
+        let tokens = tokenize("x = 1.5e+2 .. 'a\\tb' -- note\n~= .5").unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Name("x".to_string()),
+                Token::Symbol("="),
+                Token::Number(150.0),
+                Token::Symbol(".."),
+                Token::Str("a\tb".to_string()),
+                Token::Symbol("~="),
+                Token::Number(0.5),
+                Token::Eof,
+            ]
+        );
+        assert!(tokenize("'open").is_err());
+        assert!(tokenize("1x").is_err());
+        assert!(tokenize("a @ b").is_err());
+    }
+
+    #[test]
+    fn parse_errors_name_the_token() {
+        assert_eq!(Script::compile("if x then").unwrap_err(), "'end' expected near '<eof>'");
+        assert_eq!(Script::compile("return 1 2").unwrap_err(), "'end' expected after return near '2'");
+        assert_eq!(Script::compile("local end = 1").unwrap_err(), "name expected near 'end'");
+        let nested = format!("return {}1{}", "(".repeat(MAX_DEPTH), ")".repeat(MAX_DEPTH));
+        assert_eq!(Script::compile(&nested).unwrap_err(), "script nests too deeply");
+        let long = "x".repeat(MAX_SOURCE_BYTES + 1);
+        assert_eq!(Script::compile(&long).unwrap_err(), format!("script is longer than {} bytes", MAX_SOURCE_BYTES));
+    }
+
+    #[test]
+    fn long_operator_and_index_chains_count_as_nesting() {
+        let sum = format!("return 1{}", "+1".repeat(10_000));
+        assert_eq!(Script::compile(&sum).unwrap_err(), "script nests too deeply");
+        let indexes = format!("return KEYS{}", "[1]".repeat(10_000));
+        assert_eq!(Script::compile(&indexes).unwrap_err(), "script nests too deeply");
+        let mut store = MapStore::default();
+        let sum = format!("return 1{}", "+1".repeat(MAX_DEPTH - 10));
+        assert_eq!(eval(&sum, &[], &[], &mut store), Ok(Val::Num((MAX_DEPTH - 9) as f64)));
+    }
+
+    #[test]
+    fn operators_follow_lua_precedence() {
+        let mut store = MapStore::default();
+        assert_eq!(eval("return 1 + 2 * 3 ^ 2", &[], &[], &mut store), Ok(Val::Num(19.0)));
+        assert_eq!(eval("return 2 ^ 3 ^ 2", &[], &[], &mut store), Ok(Val::Num(512.0)));
+        assert_eq!(eval("return 'a' .. 1 + 1 .. 'b'", &[], &[], &mut store), Ok(text("a2b")));
+        assert_eq!(eval("return not nil and #ARGV == 2", &[], &["x", "y"], &mut store), Ok(Val::Bool(true)));
+        assert_eq!(eval("return nil or ARGV[1]", &[], &["x"], &mut store), Ok(text("x")));
+    }
+
+    #[test]
+    fn loops_and_scopes() {
+        let mut store = MapStore::default();
+        let source = "local total = 0
+            for i = 1, 10 do
+                if i % 2 == 0 then total = total + i elseif i == 9 then break end
+            end
+            local n = 0
+            while n < 3 do local inner = 1 n = n + inner end
+            return total * 100 + n";
+        assert_eq!(eval(source, &[], &[], &mut store), Ok(Val::Num(2003.0)));
+        assert!(eval("return inner", &[], &[], &mut store).unwrap_err().contains("inner"));
+    }
+
+    #[test]
+    fn scripts_only_touch_declared_keys() {
+        let mut store = MapStore::default();
+        let error = eval("return get('other')", &["k"], &[], &mut store).unwrap_err();
+        assert_eq!(error, "script accessed key 'other' that was not passed in KEYS");
+    }
+
+    #[test]
+    fn writes_are_read_back_and_committed_once_at_the_end() {
+        let mut store = MapStore::default();
+        store.values.insert("gone".to_string(), "1".to_string());
+        let source = "set(KEYS[1], 'a')
+            local seen = get(KEYS[1])
+            local deleted = delete(KEYS[2])
+            local swapped = cas(KEYS[1], 'a', 'b')
+            return seen .. tostring(deleted) .. tostring(swapped) .. tostring(get(KEYS[2]))";
+        assert_eq!(eval(source, &["k", "gone"], &[], &mut store), Ok(text("atruetruenil")));
+        assert_eq!(store.commits, 1);
+        assert_eq!(store.values.get("k").map(String::as_str), Some("b"));
+        assert!(!store.values.contains_key("gone"));
+    }
+
+    #[test]
+    fn failed_scripts_write_nothing() {
+        let mut store = MapStore::default();
+        let error = eval("set(KEYS[1], 'a') return 1 + {}", &["k"], &[], &mut store);
+        assert!(error.is_err());
+        let error = eval("set(KEYS[1], 'a') return nosuch()", &["k"], &[], &mut store).unwrap_err();
+        assert_eq!(error, "unknown function 'nosuch'");
+        assert_eq!((store.commits, store.values.len()), (0, 0));
+    }
+
+    #[test]
+    fn limits_stop_scripts_before_they_write() {
+        let mut store = MapStore::default();
+        let script = Script::compile("set(KEYS[1], 'a') while true do end").unwrap();
+        let limits = ScriptLimits { time: Duration::ZERO, memory: 1024 };
+        let error = run(&script, vec!["k".to_string()], vec![], &mut store, limits).unwrap_err();
+        assert_eq!(error, "script exceeded its time limit of 0 ms");
+
+        let script = Script::compile("local s = 'x' while true do s = s .. s set(KEYS[1], s) end").unwrap();
+        let error = run(&script, vec!["k".to_string()], vec![], &mut store, limits).unwrap_err();
+        assert_eq!(error, "script exceeded its memory limit of 1024 bytes");
+        assert_eq!((store.commits, store.values.len()), (0, 0));
+    }
+
+    #[test]
+    fn written_bytes_count_keys_and_values() {
+        let mut store = MapStore::default();
+        let script = Script::compile("set(KEYS[1], '12345') delete(KEYS[2]) return true").unwrap();
+        let keys = vec!["ab".to_string(), "cde".to_string()];
+        let (value, written) = run(&script, keys, vec![], &mut store, limits()).unwrap();
+        assert_eq!((value, written), (Val::Bool(true), 10));
+    }
+
+    #[test]
+    fn replies_and_hashes() {
+        assert_eq!(reply(&Val::Num(3.0)), "3\n");
+        assert_eq!(reply(&Val::Bool(false)), "(nil)\n");
+        assert_eq!(reply(&Val::List(Arc::new(vec![text("a"), Val::Num(0.5)]))), "a\n0.5\n");
+        assert_eq!(sha1_hex(b"abc"), "a9993e364706816aba3e25717850c26c9cd0d89d");
+        assert_eq!(sha1_hex(b""), "da39a3ee5e6b4b0d3255bfef95601890afd80709");
+    }
+}
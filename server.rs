@@ -1,29 +1,34 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+#[allow(dead_code)]
+mod sharded;
+
+use std::sync::Arc;
 use std::net::{TcpListener, TcpStream};
 use std::io::prelude::*;
 use std::env;
 use serde_json::{self, Value};
+use sharded::ShardedMap;
 
+#[derive(Clone)]
 struct KeyValueStore {
-    data: Arc<Mutex<HashMap<String, String>>>,
+    data: Arc<ShardedMap<String, String>>,
 }
 
 impl KeyValueStore {
     fn new() -> KeyValueStore {
         KeyValueStore {
-            data: Arc::new(Mutex::new(HashMap::new())),
+            data: Arc::new(ShardedMap::with_shards(sharded::default_shard_count())),
         }
     }
 
     fn handle_client_connection(&self, mut connection: TcpStream) {
         let mut buffer = [0; 1024];
         while match connection.read(&mut buffer) {
+            Ok(0) => false,
             Ok(bytes_read) => {
-                let received_data = &buffer[..bytesread];
+                let received_data = &buffer[..bytes_read];
                 if let Ok(request) = serde_json::from_slice::<Value>(received_data) {
                     let response = self.process_request(request);
-                    connection.write(response.as_bytes()).unwrap();
+                    connection.write_all(response.as_bytes()).unwrap();
                     connection.flush().unwrap();
                 }
                 true
@@ -37,13 +42,13 @@ impl KeyValueStore {
             Some("set") => {
                 let key = request["key"].to_string().trim_matches('"').to_owned();
                 let value = request["value"].to_string().trim_matches('"').to_owned();
-                let mut data = self.data.lock().unwrap();
+                let mut data = self.data.lock(&key);
                 data.insert(key, value);
                 "OK\n".to_string()
             },
             Some("get") => {
                 let key = request["key"].to_string().trim_matches('"').to_owned();
-                let data = self.data.lock().unwrap();
+                let data = self.data.lock(&key);
                 if let Some(value) = data.get(&key) {
                     format!("{}\n", value)
                 } else {
@@ -0,0 +1,147 @@
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::env;
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Mutex, MutexGuard};
+use std::thread;
+
+// Shards per core when SHARD_COUNT is not set, so threads working on
+// different keys rarely meet on the same lock.
+const SHARDS_PER_CORE: usize = 4;
+
+pub fn default_shard_count() -> usize {
+    match env::var("SHARD_COUNT").ok().and_then(|n| n.parse::<usize>().ok()) {
+        Some(count) if count > 0 => count,
+        _ => thread::available_parallelism().map_or(1, |n| n.get()) * SHARDS_PER_CORE,
+    }
+}
+
+// State split into independently locked shards by key hash. Operations on
+// several keys lock their shards in index order, so two of them can never
+// wait on each other.
+pub struct Sharded<T> {
+    shards: Vec<Mutex<T>>,
+    hasher: RandomState,
+}
+
+pub type ShardedMap<K, V> = Sharded<HashMap<K, V>>;
+
+impl<T> Sharded<T> {
+    pub fn new<F: FnMut(usize) -> T>(count: usize, mut make: F) -> Self {
+        Sharded {
+            shards: (0..count.max(1)).map(|i| Mutex::new(make(i))).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    pub fn index_of<K: Hash + ?Sized>(&self, key: &K) -> usize {
+        (self.hasher.hash_one(key) % self.shards.len() as u64) as usize
+    }
+
+    pub fn lock<K: Hash + ?Sized>(&self, key: &K) -> MutexGuard<'_, T> {
+        self.shards[self.index_of(key)].lock().unwrap()
+    }
+
+    // Locks every shard holding one of `keys`, each once.
+    pub fn lock_many<K: Hash>(&self, keys: &[K]) -> ShardGuards<'_, T> {
+        let mut indexes: Vec<usize> = keys.iter().map(|key| self.index_of(key)).collect();
+        indexes.sort_unstable();
+        indexes.dedup();
+        self.lock_indexes(indexes)
+    }
+
+    pub fn lock_all(&self) -> ShardGuards<'_, T> {
+        self.lock_indexes((0..self.shards.len()).collect())
+    }
+
+    fn lock_indexes(&self, indexes: Vec<usize>) -> ShardGuards<'_, T> {
+        ShardGuards {
+            owner: self,
+            guards: indexes.into_iter().map(|i| (i, self.shards[i].lock().unwrap())).collect(),
+        }
+    }
+}
+
+impl<K: Hash + Eq, V> ShardedMap<K, V> {
+    pub fn with_shards(count: usize) -> Self {
+        Sharded::new(count, |_| HashMap::new())
+    }
+}
+
+// Locked shards, released together when dropped.
+pub struct ShardGuards<'a, T> {
+    owner: &'a Sharded<T>,
+    guards: Vec<(usize, MutexGuard<'a, T>)>,
+}
+
+impl<'a, T> ShardGuards<'a, T> {
+    // The shard holding `key`, or None if it is not one of those locked.
+    pub fn shard<K: Hash + ?Sized>(&mut self, key: &K) -> Option<&mut T> {
+        let index = self.owner.index_of(key);
+        let position = self.guards.binary_search_by_key(&index, |(i, _)| *i).ok()?;
+        Some(&mut self.guards[position].1)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.guards.iter().map(|(_, guard)| &**guard)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> + use<'_, 'a, T> {
+        self.guards.iter_mut().map(|(_, guard)| &mut **guard)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn shards_lock_once_each_in_index_order() {
+        let map: ShardedMap<String, u32> = ShardedMap::with_shards(8);
+        let keys: Vec<String> = (0..50).rev().map(|i| format!("key:{}", i)).collect();
+        let mut guards = map.lock_many(&keys);
+        let indexes: Vec<usize> = guards.guards.iter().map(|(i, _)| *i).collect();
+        assert!(indexes.windows(2).all(|pair| pair[0] < pair[1]));
+        for (i, key) in keys.iter().enumerate() {
+            guards.shard(key).unwrap().insert(key.clone(), i as u32);
+        }
+        drop(guards);
+        assert_eq!(map.lock_all().iter().map(|shard| shard.len()).sum::<usize>(), 50);
+    }
+
+    #[test]
+    fn keys_outside_the_locked_shards_have_none() {
+        let map: ShardedMap<String, u32> = ShardedMap::with_shards(64);
+        let locked = "a".to_string();
+        let other = (0..).map(|i| i.to_string()).find(|key| map.index_of(key) != map.index_of(&locked)).unwrap();
+        let mut guards = map.lock_many(std::slice::from_ref(&locked));
+        assert!(guards.shard(&locked).is_some());
+        assert!(guards.shard(&other).is_none());
+    }
+
+    #[test]
+    fn overlapping_multi_key_locks_do_not_deadlock() {
+        let map: Arc<ShardedMap<String, u32>> = Arc::new(ShardedMap::with_shards(4));
+        let keys: Vec<String> = (0..8).map(|i| i.to_string()).collect();
+        let workers: Vec<_> = (0..4)
+            .map(|t| {
+                let map = Arc::clone(&map);
+                let mut keys = keys.clone();
+                if t % 2 == 1 {
+                    keys.reverse();
+                }
+                thread::spawn(move || {
+                    for _ in 0..1_000 {
+                        let mut guards = map.lock_many(&keys);
+                        *guards.shard("0").unwrap().entry("0".to_string()).or_default() += 1;
+                    }
+                })
+            })
+            .collect();
+        for worker in workers {
+            worker.join().unwrap();
+        }
+        assert_eq!(map.lock("0").get("0"), Some(&4_000));
+    }
+}
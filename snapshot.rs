@@ -0,0 +1,217 @@
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use crate::document;
+use crate::index::IndexDefinition;
+use crate::keyspace::{Keyspace, Keyspaces, DEFAULT_KEYSPACE};
+use crate::value::Value;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+
+// One line of a snapshot. Indexes and entries belong to the keyspace
+// written before them, and entries keep their expiry time as a wall clock
+// time.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Record<'a> {
+    Keyspace { name: Cow<'a, str>, options: Cow<'a, [String]> },
+    Index { name: Cow<'a, str>, prefix: Cow<'a, str>, path: String },
+    Entry { key: Cow<'a, str>, value: Cow<'a, Value>, expires_millis: Option<u64> },
+}
+
+#[derive(Debug, Clone)]
+pub struct SnapshotConfig {
+    pub path: PathBuf,
+    pub interval: Duration,
+}
+
+impl SnapshotConfig {
+    // Snapshots are off unless SNAPSHOT_PATH is set. SNAPSHOT_INTERVAL is in
+    // seconds and defaults to 60.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let path = match env::var("SNAPSHOT_PATH") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => return Ok(None),
+        };
+        let interval = match env::var("SNAPSHOT_INTERVAL") {
+            Ok(seconds) => match seconds.parse::<u64>() {
+                Ok(seconds) if seconds > 0 => Duration::from_secs(seconds),
+                _ => return Err(format!("Invalid SNAPSHOT_INTERVAL: {}", seconds)),
+            },
+            Err(_) => DEFAULT_INTERVAL,
+        };
+        Ok(Some(SnapshotConfig { path, interval }))
+    }
+}
+
+// Saves every keyspace each interval. Writes made since the last snapshot
+// are lost if the server stops.
+pub fn start(keyspaces: Arc<Keyspaces>, config: SnapshotConfig) {
+    thread::spawn(move || loop {
+        thread::sleep(config.interval);
+        if let Err(e) = save(&keyspaces, &config.path) {
+            println!("Failed to save snapshot to {}: {}", config.path.display(), e);
+        }
+    });
+}
+
+// Writes a temporary file and renames it over `path`, syncing the file
+// before the rename and the directory after it, so a crash leaves either
+// the old snapshot or the new one.
+pub fn save(keyspaces: &Keyspaces, path: &Path) -> io::Result<()> {
+    let temp_path = path.with_extension("tmp");
+    {
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        for keyspace in keyspaces.all() {
+            write_keyspace(&mut writer, &keyspace)?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+    }
+    fs::rename(&temp_path, path)?;
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+// All of a keyspace's shards stay locked while it is written, so a
+// transaction or script is saved whole or not at all.
+fn write_keyspace<W: Write>(writer: &mut W, keyspace: &Keyspace) -> io::Result<()> {
+    let record = Record::Keyspace { name: Cow::Borrowed(&keyspace.name), options: Cow::Borrowed(&keyspace.options) };
+    write_record(writer, &record)?;
+    let shards = keyspace.store.lock_all();
+    // Every shard holds the same indexes. They are written before the
+    // entries, so loading fills them as it goes.
+    if let Some(shard) = shards.iter().next() {
+        for index in shard.index_definitions() {
+            let path = index.path.to_string();
+            write_record(writer, &Record::Index { name: Cow::Borrowed(&index.name), prefix: Cow::Borrowed(&index.prefix), path })?;
+        }
+    }
+    for shard in shards.iter() {
+        for (key, value, expires_millis) in shard.entries() {
+            write_record(writer, &Record::Entry { key: Cow::Borrowed(key), value: Cow::Borrowed(value), expires_millis })?;
+        }
+    }
+    Ok(())
+}
+
+fn write_record<W: Write>(writer: &mut W, record: &Record) -> io::Result<()> {
+    serde_json::to_writer(&mut *writer, record)?;
+    writer.write_all(b"\n")
+}
+
+// Creates the keyspaces saved at `path` and puts back their keys. Returns
+// the number of keys read, or 0 if there is no snapshot yet.
+pub fn load(keyspaces: &Keyspaces, path: &Path) -> io::Result<usize> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    let invalid = |line: usize, message: String| {
+        io::Error::new(io::ErrorKind::InvalidData, format!("{} line {}: {}", path.display(), line, message))
+    };
+    let mut current: Option<Arc<Keyspace>> = None;
+    let mut count = 0;
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        match serde_json::from_str::<Record>(&line).map_err(|e| invalid(number + 1, e.to_string()))? {
+            Record::Keyspace { name, options } => {
+                if name != DEFAULT_KEYSPACE {
+                    let options: Vec<&str> = options.iter().map(String::as_str).collect();
+                    keyspaces.create(&name, &options).map_err(|e| invalid(number + 1, e))?;
+                }
+                current = keyspaces.get(&name);
+            }
+            Record::Index { name, prefix, path } => {
+                let keyspace = current.as_ref().ok_or_else(|| invalid(number + 1, "index before any keyspace".to_string()))?;
+                let path = document::Path::parse(&path).map_err(|e| invalid(number + 1, e))?;
+                let definition = IndexDefinition { name: name.into_owned(), prefix: prefix.into_owned(), path };
+                for shard in keyspace.store.lock_all().iter_mut() {
+                    shard.create_index(definition.clone());
+                }
+            }
+            Record::Entry { key, value, expires_millis } => {
+                let keyspace = current.as_ref().ok_or_else(|| invalid(number + 1, "entry before any keyspace".to_string()))?;
+                let mut shard = keyspace.store.lock(key.as_ref());
+                shard.restore(&key, value.into_owned(), expires_millis).map_err(|e| invalid(number + 1, e.to_string()))?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::index::{IndexKey, IndexQuery};
+    use crate::memory::{EvictionPolicy, MemoryLimit};
+    use std::collections::VecDeque;
+
+    fn keyspaces() -> Keyspaces {
+        Keyspaces::new(MemoryLimit { max_memory: 0, policy: EvictionPolicy::NoEviction }, 4)
+    }
+
+    #[test]
+    fn keyspaces_and_typed_values_survive_a_restart() {
+        let dir = std::env::temp_dir().join(format!("snapshot-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.jsonl");
+        assert_eq!(load(&keyspaces(), &path).unwrap(), 0);
+
+        let before = keyspaces();
+        let default = before.get(DEFAULT_KEYSPACE).unwrap();
+        default.store.lock("name").set("name", "ada", Some(Duration::from_secs(600))).unwrap();
+        default.store.lock("gone").set("gone", "soon", Some(Duration::from_millis(1))).unwrap();
+        assert_eq!(before.create("team", &["MAXKEYS", "10"]), Ok(true));
+        let team = before.get("team").unwrap();
+        let list = Value::List(VecDeque::from(vec!["a".to_string(), "b".to_string()]));
+        let copy = list.clone();
+        team.store.lock("list").update("list", 0, || copy, |_| Ok(())).unwrap();
+        let definition = IndexDefinition { name: "by_age".to_string(), prefix: "user:".to_string(), path: document::Path::parse("$['a.b'].age").unwrap() };
+        for shard in team.store.lock_all().iter_mut() {
+            shard.create_index(definition.clone());
+        }
+        let user = Value::Json(serde_json::json!({"a.b": {"age": 36}}));
+        team.store.lock("user:1").update("user:1", 0, || user, |_| Ok(())).unwrap();
+        thread::sleep(Duration::from_millis(5));
+        save(&before, &path).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let after = keyspaces();
+        assert_eq!(load(&after, &path).unwrap(), 3);
+        assert_eq!(after.names(), vec!["default", "team"]);
+        let default = after.get(DEFAULT_KEYSPACE).unwrap();
+        assert_eq!(default.store.lock("name").get("name").unwrap(), Some("ada".to_string()));
+        assert_eq!(default.store.lock("gone").get("gone").unwrap(), None);
+        let team = after.get("team").unwrap();
+        assert_eq!(team.options, vec!["MAXKEYS", "10"]);
+        assert_eq!(team.store.lock("list").read("list", |value| value.clone()), Some(list));
+        let query = IndexQuery::Equals(IndexKey::parse("36"));
+        let found: Vec<String> = team.store.lock_all().iter_mut().flat_map(|shard| shard.find("by_age", &query).unwrap()).collect();
+        assert_eq!(found, vec!["user:1"]);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn a_corrupt_line_fails_the_load() {
+        let dir = std::env::temp_dir().join(format!("snapshot-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("dump.jsonl");
+        fs::write(&path, "{\"keyspace\":{\"name\":\"default\",\"options\":[]}}\nnot json\n").unwrap();
+        let error = load(&keyspaces(), &path).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("line 2"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
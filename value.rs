@@ -0,0 +1,360 @@
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt;
+
+// Rough cost of each element of a collection beyond its own bytes.
+pub const ELEMENT_OVERHEAD: usize = 48;
+// Rough cost of each node of a JSON document beyond its own text.
+const NODE_OVERHEAD: usize = 32;
+
+#[derive(Debug)]
+pub struct WrongType;
+
+impl fmt::Display for WrongType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WRONGTYPE Operation against a key holding the wrong kind of value")
+    }
+}
+
+impl std::error::Error for WrongType {}
+
+// A stored value. Collections never stay empty: the store removes a key
+// once its last element goes.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Value {
+    String(String),
+    List(VecDeque<String>),
+    Set(HashSet<String>),
+    #[serde(rename = "zset")]
+    SortedSet(SortedSet),
+    Hash(HashMap<String, String>),
+    Json(serde_json::Value),
+}
+
+impl Value {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::String(_) => "string",
+            Value::List(_) => "list",
+            Value::Set(_) => "set",
+            Value::SortedSet(_) => "zset",
+            Value::Hash(_) => "hash",
+            Value::Json(_) => "json",
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        match self {
+            Value::String(s) => s.len(),
+            Value::List(items) => items.iter().map(|i| i.len() + ELEMENT_OVERHEAD).sum(),
+            Value::Set(members) => members.iter().map(|m| m.len() + ELEMENT_OVERHEAD).sum(),
+            // Members are held twice, once per index.
+            Value::SortedSet(set) => set.scores.keys().map(|m| 2 * m.len() + 2 * ELEMENT_OVERHEAD).sum(),
+            Value::Hash(fields) => fields.iter().map(|(f, v)| f.len() + v.len() + ELEMENT_OVERHEAD).sum(),
+            Value::Json(document) => document_size(document),
+        }
+    }
+
+    pub fn is_empty_collection(&self) -> bool {
+        match self {
+            Value::String(_) | Value::Json(_) => false,
+            Value::List(items) => items.is_empty(),
+            Value::Set(members) => members.is_empty(),
+            Value::SortedSet(set) => set.is_empty(),
+            Value::Hash(fields) => fields.is_empty(),
+        }
+    }
+
+    pub fn as_string(&self) -> Result<&String, WrongType> {
+        match self {
+            Value::String(s) => Ok(s),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn list(&self) -> Result<&VecDeque<String>, WrongType> {
+        match self {
+            Value::List(items) => Ok(items),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn list_mut(&mut self) -> Result<&mut VecDeque<String>, WrongType> {
+        match self {
+            Value::List(items) => Ok(items),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn set(&self) -> Result<&HashSet<String>, WrongType> {
+        match self {
+            Value::Set(members) => Ok(members),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn set_mut(&mut self) -> Result<&mut HashSet<String>, WrongType> {
+        match self {
+            Value::Set(members) => Ok(members),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn sorted_set(&self) -> Result<&SortedSet, WrongType> {
+        match self {
+            Value::SortedSet(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn sorted_set_mut(&mut self) -> Result<&mut SortedSet, WrongType> {
+        match self {
+            Value::SortedSet(set) => Ok(set),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn hash(&self) -> Result<&HashMap<String, String>, WrongType> {
+        match self {
+            Value::Hash(fields) => Ok(fields),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn hash_mut(&mut self) -> Result<&mut HashMap<String, String>, WrongType> {
+        match self {
+            Value::Hash(fields) => Ok(fields),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn json(&self) -> Result<&serde_json::Value, WrongType> {
+        match self {
+            Value::Json(document) => Ok(document),
+            _ => Err(WrongType),
+        }
+    }
+
+    pub fn json_mut(&mut self) -> Result<&mut serde_json::Value, WrongType> {
+        match self {
+            Value::Json(document) => Ok(document),
+            _ => Err(WrongType),
+        }
+    }
+}
+
+pub fn document_size(document: &serde_json::Value) -> usize {
+    use serde_json::Value as Json;
+    NODE_OVERHEAD
+        + match document {
+            Json::String(s) => s.len(),
+            Json::Array(items) => items.iter().map(document_size).sum(),
+            Json::Object(fields) => fields.iter().map(|(name, v)| name.len() + document_size(v)).sum(),
+            _ => 0,
+        }
+}
+
+// Scores ordered with `total_cmp`; callers reject NaN before it gets here.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+// Members ordered by score, then by member for equal scores. Serialized as
+// a plain member to score map.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(from = "HashMap<String, f64>", into = "HashMap<String, f64>")]
+pub struct SortedSet {
+    scores: HashMap<String, f64>,
+    order: BTreeSet<(Score, String)>,
+}
+
+impl SortedSet {
+    // Returns whether `member` is new.
+    pub fn insert(&mut self, member: String, score: f64) -> bool {
+        let previous = self.scores.insert(member.clone(), score);
+        if let Some(previous) = previous {
+            self.order.remove(&(Score(previous), member.clone()));
+        }
+        self.order.insert((Score(score), member));
+        previous.is_none()
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.scores.remove(member) {
+            Some(score) => self.order.remove(&(Score(score), member.to_string())),
+            None => false,
+        }
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    // Members with scores between `min` and `max`, lowest first. Exclusive
+    // bounds leave out members scoring exactly the bound.
+    pub fn range_by_score(&self, min: ScoreBound, max: ScoreBound) -> Vec<(&String, f64)> {
+        self.order
+            .range((Score(min.value), String::new())..)
+            .take_while(|(score, _)| if max.exclusive { score.0 < max.value } else { score.0 <= max.value })
+            .filter(|(score, _)| !min.exclusive || score.0 > min.value)
+            .map(|(score, member)| (member, score.0))
+            .collect()
+    }
+}
+
+impl From<HashMap<String, f64>> for SortedSet {
+    fn from(scores: HashMap<String, f64>) -> Self {
+        let mut set = SortedSet::default();
+        for (member, score) in scores {
+            set.insert(member, score);
+        }
+        set
+    }
+}
+
+impl From<SortedSet> for HashMap<String, f64> {
+    fn from(set: SortedSet) -> Self {
+        set.scores
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ScoreBound {
+    pub value: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    // Accepts a number, `-inf`/`+inf`, or `(number` for an exclusive bound.
+    pub fn parse(bound: &str) -> Option<Self> {
+        let (exclusive, number) = match bound.strip_prefix('(') {
+            Some(rest) => (true, rest),
+            None => (false, bound),
+        };
+        let value = parse_score(number)?;
+        Some(ScoreBound { value, exclusive })
+    }
+}
+
+pub fn parse_score(score: &str) -> Option<f64> {
+    let value = match score.to_lowercase().as_str() {
+        "inf" | "+inf" => f64::INFINITY,
+        "-inf" => f64::NEG_INFINITY,
+        other => other.parse::<f64>().ok()?,
+    };
+    if value.is_nan() {
+        None
+    } else {
+        // Folds -0 into 0 so both sort as one score.
+        Some(value + 0.0)
+    }
+}
+
+// A size in bytes, optionally with a k, m or g unit as in MAXMEMORY.
+pub fn parse_bytes(limit: &str) -> Option<usize> {
+    let limit = limit.trim().to_lowercase();
+    let (number, unit) = match limit.find(|c: char| !c.is_ascii_digit()) {
+        Some(position) => limit.split_at(position),
+        None => (limit.as_str(), ""),
+    };
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "kb" | "k" => 1024,
+        "mb" | "m" => 1024 * 1024,
+        "gb" | "g" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    number.parse::<usize>().ok().map(|n| n * multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn operations_check_the_type() {
+        let mut value = Value::List(VecDeque::new());
+        assert!(value.list_mut().is_ok());
+        assert!(value.set().is_err() && value.hash().is_err() && value.as_string().is_err());
+        assert_eq!(value.type_name(), "list");
+        assert!(value.is_empty_collection());
+        assert!(!Value::String(String::new()).is_empty_collection());
+    }
+
+    #[test]
+    fn sorted_sets_order_by_score_then_member() {
+        let mut set = SortedSet::default();
+        assert!(set.insert("b".to_string(), 1.0));
+        assert!(set.insert("a".to_string(), 1.0));
+        assert!(set.insert("c".to_string(), 0.5));
+        assert!(!set.insert("c".to_string(), 2.0));
+        let all = |set: &SortedSet| -> Vec<String> {
+            let bound = |value| ScoreBound { value, exclusive: false };
+            set.range_by_score(bound(f64::NEG_INFINITY), bound(f64::INFINITY)).into_iter().map(|(m, _)| m.clone()).collect()
+        };
+        assert_eq!(all(&set), vec!["a", "b", "c"]);
+        let above_one = set.range_by_score(ScoreBound::parse("(1").unwrap(), ScoreBound::parse("+inf").unwrap());
+        assert_eq!(above_one, vec![(&"c".to_string(), 2.0)]);
+        assert!(set.remove("a") && !set.remove("a"));
+        assert_eq!((set.len(), set.score("b")), (2, Some(1.0)));
+    }
+
+    #[test]
+    fn scores_parse_infinities_and_reject_nan() {
+        assert_eq!(parse_score("-inf"), Some(f64::NEG_INFINITY));
+        assert_eq!(parse_score("1.5"), Some(1.5));
+        assert!(parse_score("-0").unwrap().is_sign_positive());
+        assert_eq!(parse_score("nan"), None);
+        assert_eq!(parse_score("x"), None);
+    }
+
+    #[test]
+    fn values_survive_serialization() {
+        let mut set = SortedSet::default();
+        set.insert("m".to_string(), 3.0);
+        let values = vec![
+            Value::String("s".to_string()),
+            Value::List(vec!["a".to_string(), "b".to_string()].into()),
+            Value::Set(std::iter::once("x".to_string()).collect()),
+            Value::SortedSet(set),
+            Value::Hash(std::iter::once(("f".to_string(), "v".to_string())).collect()),
+            Value::Json(serde_json::json!({"a": [1, 2]})),
+        ];
+        for value in values {
+            let json = serde_json::to_string(&value).unwrap();
+            assert_eq!(serde_json::from_str::<Value>(&json).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn bytes_parse_with_a_unit() {
+        assert_eq!(parse_bytes("100"), Some(100));
+        assert_eq!(parse_bytes("2kb"), Some(2048));
+        assert_eq!(parse_bytes("1 MB"), None);
+        assert_eq!(parse_bytes("1M"), Some(1024 * 1024));
+        assert_eq!(parse_bytes("1tb"), None);
+    }
+}